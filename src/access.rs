@@ -0,0 +1,249 @@
+//! Who may connect to a server or relay, set in the config:
+//!
+//! ```toml
+//! [access]
+//! allow = "192.168.0.0/16, fd00::/8"  # only these, everybody by default
+//! deny = "192.168.1.13"               # never these, even if allowed
+//! attempts = "10/1m"                  # connections per address, unlimited by default
+//! ```
+//!
+//! Turned away clients are dropped right after accepting, before any handshake. A list which
+//! doesn't parse keeps the server from starting, rather than letting in whoever it was meant
+//! to keep out.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::{IpAddr, TcpStream},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{config::Config, timestamp};
+
+/// Config table holding the lists.
+pub const TABLE: &str = "access";
+/// Addresses whose attempts are forgotten again once there are this many.
+const TRACKED: usize = 4096;
+
+/// Range of addresses, like `10.0.0.0/8`. A single address is a range of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let parsed: IpAddr = network
+            .parse()
+            .map_err(|_| format!("{s} isn't an address or range like 10.0.0.0/8"))?;
+        let network = parsed.to_canonical();
+        // an IPv4 range written as IPv6, its prefix counts the 96 bits in front as well
+        let mapped = parsed.is_ipv6() && network.is_ipv4();
+        let bits = if parsed.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            "" => bits,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= bits && (!mapped || p >= 96))
+                .ok_or_else(|| format!("{s} has an invalid prefix length"))?,
+        };
+        let prefix = if mapped { prefix - 96 } else { prefix };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Decides on every client before it is served.
+#[derive(Debug, Default)]
+pub struct Gate {
+    /// Empty lets everybody in
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    /// Connections an address may make in the period
+    attempts: Option<(usize, Duration)>,
+    recent: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl Gate {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut gate = Self::default();
+        let entries = config.strings(TABLE);
+        for key in ["allow", "deny", "attempts"] {
+            if config.has(TABLE, key) && !entries.iter().any(|(k, _)| k == key) {
+                return Err(format!("access.{key} has to be a string"));
+            }
+        }
+        for (key, value) in entries {
+            let ranges = || {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|r| !r.is_empty())
+                    .map(|r| r.parse().map_err(|e| format!("access.{key}: {e}")))
+                    .collect::<Result<Vec<Cidr>, String>>()
+            };
+            match key.as_str() {
+                "allow" => {
+                    gate.allow = ranges()?;
+                    if gate.allow.is_empty() {
+                        return Err("access.allow lists nobody, leave it out to allow everybody"
+                            .to_string());
+                    }
+                }
+                "deny" => gate.deny = ranges()?,
+                "attempts" => {
+                    gate.attempts = Some(parse_rate(&value).ok_or_else(|| {
+                        format!("access.attempts is {value:?}, expected like 10/1m")
+                    })?)
+                }
+                _ => warn!("Ignoring unknown access option {key}"),
+            }
+        }
+        Ok(gate)
+    }
+
+    /// Why `ip` is turned away, if it is.
+    pub fn check(&self, ip: IpAddr) -> Result<(), String> {
+        let ip = ip.to_canonical();
+        if let Some(range) = self.deny.iter().find(|r| r.contains(ip)) {
+            return Err(format!("{ip} is denied by {range}"));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|r| r.contains(ip)) {
+            return Err(format!("{ip} isn't allowed"));
+        }
+        let Some((limit, period)) = self.attempts else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut recent = self.recent.lock().expect("access lock is poisoned");
+        if recent.len() >= TRACKED {
+            recent.retain(|_, times| times.back().is_some_and(|t| now - *t < period));
+        }
+        let times = recent.entry(ip).or_default();
+        while times.front().is_some_and(|t| now - *t >= period) {
+            times.pop_front();
+        }
+        // attempts over the limit count as well, whoever keeps trying stays out
+        times.push_back(now);
+        if times.len() > limit {
+            times.pop_front();
+            return Err(format!("{ip} connects too often"));
+        }
+        Ok(())
+    }
+}
+
+/// `<count>/<period>`, like `10/1m`.
+fn parse_rate(s: &str) -> Option<(usize, Duration)> {
+    let (count, period) = s.split_once('/')?;
+    Some((
+        count.trim().parse().ok()?,
+        timestamp::parse_duration(period.trim())?,
+    ))
+}
+
+/// Checks the client who just connected on `stream`.
+pub fn admit(gate: &Gate, stream: &TcpStream) -> Result<(), String> {
+    let address = stream
+        .peer_addr()
+        .map_err(|e| format!("unknown address: {e}"))?;
+    gate.check(address.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_contain_their_addresses() {
+        let v4: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(v4.contains(ip("192.168.1.13")));
+        assert!(!v4.contains(ip("192.169.0.1")));
+        assert!(!v4.contains(ip("fd00::1")));
+        let single: Cidr = "10.0.0.1".parse().unwrap();
+        assert!(single.contains(ip("10.0.0.1")));
+        assert!(!single.contains(ip("10.0.0.2")));
+        let everybody: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everybody.contains(ip("203.0.113.7")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+
+        // a dual stack listener sees IPv4 clients like this
+        assert!(v4.contains(ip("::ffff:192.168.1.13")));
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(mapped, "10.0.0.0/8".parse().unwrap());
+        assert!(mapped.contains(ip("::ffff:10.1.2.3")));
+        assert!(mapped.contains(ip("10.1.2.3")));
+        assert!("::ffff:10.0.0.0/64".parse::<Cidr>().is_err());
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn rates_are_a_count_per_period() {
+        assert_eq!(parse_rate("10/1m"), Some((10, Duration::from_secs(60))));
+        assert_eq!(parse_rate(" 3 / 30s "), Some((3, Duration::from_secs(30))));
+        assert_eq!(parse_rate("10"), None);
+        assert_eq!(parse_rate("ten/1m"), None);
+        assert_eq!(parse_rate("10/soon"), None);
+    }
+
+    #[test]
+    fn a_list_which_doesnt_parse_keeps_everybody_out() {
+        let gate = |toml: &str| Gate::from_config(&toml.parse().unwrap());
+        assert!(gate("[access]\nallow = \"192.168.0.0/33\"").is_err());
+        assert!(gate("[access]\nallow = \"10.0.0.0/8, nonsense\"").is_err());
+        assert!(gate("[access]\nallow = \" , \"").is_err());
+        assert!(gate("[access]\nallow = [\"10.0.0.0/8\"]").is_err());
+        assert!(gate("[access]\ndeny = \"nonsense\"").is_err());
+        assert!(gate("[access]\nattempts = \"often\"").is_err());
+
+        let gate = gate("[access]\nallow = \"10.0.0.0/8\"\ndeny = \"10.0.0.13\"").unwrap();
+        assert!(gate.check(ip("10.1.2.3")).is_ok());
+        assert!(gate.check(ip("10.0.0.13")).is_err());
+        assert!(gate.check(ip("192.168.1.1")).is_err());
+    }
+}
@@ -0,0 +1,1069 @@
+//! Certificate of a public relay from an ACME authority like Let's Encrypt:
+//!
+//! ```toml
+//! [acme]
+//! domain = "relay.example.org"
+//! email = "admin@example.org"   # for the notices of the authority, optional
+//! directory = "https://acme-staging-v02.api.letsencrypt.org/directory"  # Let's Encrypt by default
+//! ```
+//!
+//! The relay then only takes TLS connections, clients connect with `--tls`. The domain is proven
+//! with the TLS-ALPN-01 challenge: the authority connects to port 443 of the domain asking for a
+//! certificate the relay makes up for it, so the relay has to be reachable there. The account key,
+//! the certificate and its key are kept in `acme/` of the data directory, and the certificate is
+//! renewed in the background once a month is left.
+//!
+//! Everything but the HTTP goes through the system's OpenSSL, like TLS.
+
+use std::{
+    ffi::{c_char, c_int, c_long, c_void, CStr, CString},
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::Config,
+    dirs,
+    json::{self, Value},
+    tasks,
+    tls::{self, ffi::EVP_PKEY, Challenges, Identity},
+};
+
+/// Config table holding the options.
+pub const TABLE: &str = "acme";
+const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// A certificate is renewed once fewer days are left.
+const RENEW_DAYS: c_int = 30;
+/// Between looks at the expiry, and after a failed attempt to renew.
+const CHECK_EVERY: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+/// Between looks at an authorization or order the authority works on, and how many there are.
+const POLL_EVERY: Duration = Duration::from_secs(2);
+const POLLS: usize = 30;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const RSA_BITS: usize = 2048;
+/// Extension of the challenge certificate holding the hash of the key authorization.
+const ACME_IDENTIFIER: &str = "1.3.6.1.5.5.7.1.31";
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub domain: String,
+    /// Contact for the notices of the authority
+    pub email: Option<String>,
+    /// URL of the directory of the authority
+    pub directory: String,
+}
+
+impl Options {
+    /// `None` without a domain.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let mut options = Self {
+            domain: String::new(),
+            email: None,
+            directory: LETS_ENCRYPT.to_string(),
+        };
+        for (key, value) in config.strings(TABLE) {
+            match key.as_str() {
+                "domain" => options.domain = value,
+                "email" => options.email = Some(value),
+                "directory" => options.directory = value,
+                _ => warn!("Ignoring unknown acme option {key}"),
+            }
+        }
+        (!options.domain.is_empty()).then_some(options)
+    }
+
+    fn dir() -> io::Result<PathBuf> {
+        let dir = dirs::data_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory"))?
+            .join("acme");
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// Certificate and key of the domain.
+    fn files(&self) -> io::Result<(PathBuf, PathBuf)> {
+        let dir = Self::dir()?;
+        Ok((
+            dir.join(format!("{}.crt", self.domain)),
+            dir.join(format!("{}.key", self.domain)),
+        ))
+    }
+}
+
+/// TLS of the relay, with the certificate of the moment.
+pub type Certificate = Arc<RwLock<Arc<tls::Context>>>;
+
+/// Sets up TLS for the domain, getting and renewing its certificate in the background.
+pub fn serve(options: Options) -> io::Result<Certificate> {
+    let challenges = Arc::new(Challenges::default());
+    let (cert, key) = options.files()?;
+    let existing = (cert.exists() && key.exists()).then_some((cert.as_path(), key.as_path()));
+    let context = tls::Context::acme(existing, Arc::clone(&challenges))?;
+    let certificate = Arc::new(RwLock::new(Arc::new(context)));
+    let current = Arc::clone(&certificate);
+    tasks::spawn("acme", move || loop {
+        let wait = match renew_if_due(&options, &challenges, &current) {
+            Ok(()) => CHECK_EVERY,
+            Err(e) => {
+                warn!("Failed to get a certificate for {}: {e}", options.domain);
+                RETRY_AFTER
+            }
+        };
+        tasks::set_state(format!(
+            "next look in {}",
+            crate::timestamp::format_duration(wait)
+        ));
+        thread::sleep(wait);
+    });
+    Ok(certificate)
+}
+
+fn renew_if_due(
+    options: &Options,
+    challenges: &Arc<Challenges>,
+    current: &Certificate,
+) -> io::Result<()> {
+    let (cert, key) = options.files()?;
+    if let Some(days) = days_left(&cert) {
+        if days >= RENEW_DAYS {
+            debug!(
+                "the certificate of {} is good for {days} days",
+                options.domain
+            );
+            return Ok(());
+        }
+    }
+    info!("getting a certificate for {}", options.domain);
+    tasks::set_state("getting a certificate");
+    obtain(options, challenges, &cert, &key)?;
+    let context = tls::Context::acme(Some((&cert, &key)), Arc::clone(challenges))?;
+    // connections keep the one they started with
+    *current.write().expect("certificate lock is poisoned") = Arc::new(context);
+    info!("got a certificate for {}", options.domain);
+    Ok(())
+}
+
+/// Days until the certificate in the file expires, `None` without one.
+fn days_left(path: &Path) -> Option<c_int> {
+    let pem = fs::read(path).ok()?;
+    let bio = Bio::from_slice(&pem).ok()?;
+    // SAFETY: a valid buffer, the certificate is freed right after reading its expiry
+    unsafe {
+        let cert = ffi::PEM_read_bio_X509(bio.0, std::ptr::null_mut(), None, std::ptr::null_mut());
+        if cert.is_null() {
+            return None;
+        }
+        let (mut days, mut seconds) = (0, 0);
+        let ok = ffi::ASN1_TIME_diff(
+            &mut days,
+            &mut seconds,
+            std::ptr::null(),
+            ffi::X509_get0_notAfter(cert),
+        );
+        tls::ffi::X509_free(cert);
+        (ok == 1).then_some(days)
+    }
+}
+
+/// Orders a certificate for the domain and writes it and its key to the files.
+fn obtain(
+    options: &Options,
+    challenges: &Challenges,
+    cert_path: &Path,
+    key_path: &Path,
+) -> io::Result<()> {
+    let mut client = Client::new(&options.directory)?;
+    client.register(options.email.as_deref())?;
+    let (order_url, order) = client.order(&options.domain)?;
+    let urls = |name| -> io::Result<Vec<String>> {
+        match order.get(name) {
+            Some(Value::Array(items)) => Ok(items
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect()),
+            _ => Err(invalid(format!("the order has no {name}"))),
+        }
+    };
+    for authorization in urls("authorizations")? {
+        client.authorize(&authorization, &options.domain, challenges)?;
+    }
+    let finalize = order
+        .get("finalize")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("the order can't be finalized"))?;
+    let key = Key::generate()?;
+    let csr = key.request(&options.domain)?;
+    client.post(
+        finalize,
+        Some(&object(vec![("csr", Value::String(base64url(&csr)))])),
+    )?;
+    let order = client.wait_until_valid(&order_url, "order")?;
+    let url = order
+        .get("certificate")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("the order has no certificate"))?;
+    let chain = client.post(url, None)?.body;
+    write_private(key_path, &key.to_pem()?)?;
+    fs::write(cert_path, chain)
+}
+
+/// Account with the authority.
+struct Client {
+    tls: Arc<tls::Context>,
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    key: Key,
+    /// URL of the account once registered, requests are signed for it from then on
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    fn new(directory: &str) -> io::Result<Self> {
+        let options = tls::Options {
+            tls: true,
+            ..tls::Options::default()
+        };
+        let tls = tls::Context::new(&options, false)?.expect("since TLS is asked for");
+        let directory = request(&tls, "GET", directory, None)?.json()?;
+        let url = |name| {
+            directory
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| invalid(format!("the directory has no {name}")))
+        };
+        let path = Options::dir()?.join("account.key");
+        let key = match fs::read(&path) {
+            Ok(pem) => Key::from_pem(&pem)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = Key::generate()?;
+                write_private(&path, &key.to_pem()?)?;
+                key
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            new_nonce: url("newNonce")?,
+            new_account: url("newAccount")?,
+            new_order: url("newOrder")?,
+            tls,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn nonce(&mut self) -> io::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        request(&self.tls, "HEAD", &self.new_nonce, None)?
+            .header("replay-nonce")
+            .map(str::to_string)
+            .ok_or_else(|| invalid("the authority sent no nonce"))
+    }
+
+    /// Signed request with the payload, fetching what is at `url` without one.
+    fn post(&mut self, url: &str, payload: Option<&Value>) -> io::Result<Response> {
+        let mut retried = false;
+        loop {
+            let key = match &self.kid {
+                Some(kid) => ("kid", Value::String(kid.clone())),
+                None => ("jwk", self.key.jwk()?),
+            };
+            let protected = object(vec![
+                ("alg", Value::String("RS256".to_string())),
+                key,
+                ("nonce", Value::String(self.nonce()?)),
+                ("url", Value::String(url.to_string())),
+            ]);
+            let protected = base64url(protected.to_string().as_bytes());
+            let payload = payload.map_or(String::new(), |p| base64url(p.to_string().as_bytes()));
+            let signature = self.key.sign(format!("{protected}.{payload}").as_bytes())?;
+            let body = object(vec![
+                ("protected", Value::String(protected)),
+                ("payload", Value::String(payload)),
+                ("signature", Value::String(base64url(&signature))),
+            ]);
+            let response = request(&self.tls, "POST", url, Some(&body.to_string()))?;
+            self.nonce = response.header("replay-nonce").map(str::to_string);
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem = response.json().unwrap_or(Value::Null);
+            let kind = problem.get("type").and_then(Value::as_str);
+            // nonces expire, a fresh one is in the answer
+            if kind == Some("urn:ietf:params:acme:error:badNonce") && !retried {
+                retried = true;
+                continue;
+            }
+            let detail = problem.get("detail").and_then(Value::as_str).map_or_else(
+                || String::from_utf8_lossy(&response.body).into_owned(),
+                str::to_string,
+            );
+            return Err(io::Error::other(format!(
+                "the authority answered {}: {detail}",
+                response.status
+            )));
+        }
+    }
+
+    /// Creates the account, or looks up the one of the key.
+    fn register(&mut self, email: Option<&str>) -> io::Result<()> {
+        let mut payload = vec![("termsOfServiceAgreed", Value::Bool(true))];
+        if let Some(email) = email {
+            payload.push((
+                "contact",
+                Value::Array(vec![Value::String(format!("mailto:{email}"))]),
+            ));
+        }
+        let url = self.new_account.clone();
+        let response = self.post(&url, Some(&object(payload)))?;
+        self.kid = Some(response.location()?);
+        Ok(())
+    }
+
+    /// Places an order for the domain, returns its URL and itself.
+    fn order(&mut self, domain: &str) -> io::Result<(String, Value)> {
+        let identifier = object(vec![
+            ("type", Value::String("dns".to_string())),
+            ("value", Value::String(domain.to_string())),
+        ]);
+        let url = self.new_order.clone();
+        let payload = object(vec![("identifiers", Value::Array(vec![identifier]))]);
+        let response = self.post(&url, Some(&payload))?;
+        Ok((response.location()?, response.json()?))
+    }
+
+    /// Proves control of the domain, answering the challenge with a certificate made up for it.
+    fn authorize(&mut self, url: &str, domain: &str, challenges: &Challenges) -> io::Result<()> {
+        let authorization = self.post(url, None)?.json()?;
+        if status(&authorization) == "valid" {
+            return Ok(());
+        }
+        let challenge = match authorization.get("challenges") {
+            Some(Value::Array(challenges)) => challenges
+                .iter()
+                .find(|c| c.get("type").and_then(Value::as_str) == Some("tls-alpn-01")),
+            _ => None,
+        }
+        .ok_or_else(|| invalid("the authority doesn't offer the TLS-ALPN-01 challenge"))?;
+        let (Some(token), Some(challenge_url)) = (
+            challenge.get("token").and_then(Value::as_str),
+            challenge.get("url").and_then(Value::as_str),
+        ) else {
+            return Err(invalid("the challenge has no token or URL"));
+        };
+        let key_authorization = format!("{token}.{}", self.key.thumbprint()?);
+        let identity =
+            Key::generate()?.challenge(domain, &sha256(key_authorization.as_bytes())?)?;
+        challenges.insert(domain, identity);
+        // ready to be checked
+        let res = self
+            .post(challenge_url, Some(&object(Vec::new())))
+            .and_then(|_| self.wait_until_valid(url, "authorization"));
+        challenges.remove(domain);
+        res.map(drop)
+    }
+
+    /// Waits for the authority to be done with what is at `url`.
+    fn wait_until_valid(&mut self, url: &str, what: &str) -> io::Result<Value> {
+        for _ in 0..POLLS {
+            let value = self.post(url, None)?.json()?;
+            match status(&value) {
+                "valid" => return Ok(value),
+                "pending" | "processing" | "ready" => thread::sleep(POLL_EVERY),
+                status => {
+                    return Err(io::Error::other(format!(
+                        "the {what} is {status}{}",
+                        problem(&value).map_or(String::new(), |p| format!(": {p}"))
+                    )))
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("the {what} took too long"),
+        ))
+    }
+}
+
+fn status(value: &Value) -> &str {
+    value.get("status").and_then(Value::as_str).unwrap_or("")
+}
+
+/// Why the authority rejected an authorization or order, the challenges say for the first.
+fn problem(value: &Value) -> Option<&str> {
+    fn detail(v: &Value) -> Option<&str> {
+        v.get("error")?.get("detail")?.as_str()
+    }
+    detail(value).or_else(|| match value.get("challenges") {
+        Some(Value::Array(challenges)) => challenges.iter().find_map(detail),
+        _ => None,
+    })
+}
+
+fn object(entries: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+fn invalid(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+/// Writes a file only we can read.
+fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content)
+}
+
+/// Base64 of the URL safe alphabet, without padding, as JOSE has it.
+fn base64url(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | u32::from(b) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+fn sha256(data: &[u8]) -> io::Result<[u8; 32]> {
+    let mut digest = [0; 32];
+    let mut len = 0;
+    // SAFETY: the digest has room for a SHA-256
+    let ok = unsafe {
+        tls::ffi::EVP_Digest(
+            data.as_ptr().cast(),
+            data.len(),
+            digest.as_mut_ptr(),
+            &mut len,
+            tls::ffi::EVP_sha256(),
+            std::ptr::null_mut(),
+        )
+    };
+    match ok {
+        1 => Ok(digest),
+        _ => Err(io::Error::other("failed to hash")),
+    }
+}
+
+/// Answer of the authority.
+struct Response {
+    status: u16,
+    /// Names in lower case
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn location(&self) -> io::Result<String> {
+        self.header("location")
+            .map(str::to_string)
+            .ok_or_else(|| invalid("the authority sent no location"))
+    }
+
+    fn json(&self) -> io::Result<Value> {
+        json::parse(&String::from_utf8_lossy(&self.body)).map_err(invalid)
+    }
+}
+
+/// HTTPS request, one per connection.
+fn request(
+    tls: &tls::Context,
+    method: &str,
+    url: &str,
+    body: Option<&str>,
+) -> io::Result<Response> {
+    let rest = url
+        .strip_prefix("https://")
+        .ok_or_else(|| invalid(format!("{url} isn't an https URL")))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| invalid(format!("{url} has an invalid port")))?,
+        ),
+        None => (authority, 443),
+    };
+    let tcp = TcpStream::connect((host, port))?;
+    let (mut stream, _) = tls.wrap(tcp, Some(host))?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    let mut head = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: chatterbox/{}\r\n\
+         Connection: close\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    if let Some(body) = body {
+        head.push_str(&format!(
+            "Content-Type: application/jose+json\r\nContent-Length: {}\r\n",
+            body.len()
+        ));
+    }
+    head.push_str("\r\n");
+    head.push_str(body.unwrap_or_default());
+    stream.write_all(head.as_bytes())?;
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw)?;
+    debug!("{method} {url}: {} bytes", raw.len());
+    parse_response(&raw)
+        .ok_or_else(|| invalid(format!("{url} answered with something else than HTTP")))
+}
+
+fn parse_response(raw: &[u8]) -> Option<Response> {
+    let end = raw.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&raw[..end]).ok()?;
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    let mut body = raw[end + 4..].to_vec();
+    let chunked = headers
+        .iter()
+        .any(|(n, v)| n == "transfer-encoding" && v.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        body = dechunk(&body)?;
+    }
+    Some(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Body sent in chunks, each after its length in hex.
+fn dechunk(mut rest: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let end = rest.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&rest[..end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        let chunk = rest.get(end + 2..end + 2 + size)?;
+        body.extend_from_slice(chunk);
+        rest = rest.get(end + 4 + size..)?;
+    }
+}
+
+/// Memory buffer of OpenSSL, freed when dropped.
+struct Bio(*mut ffi::BIO);
+
+impl Bio {
+    fn new() -> io::Result<Self> {
+        // SAFETY: plain constructor, checked for failure below
+        let bio = unsafe { ffi::BIO_new(ffi::BIO_s_mem()) };
+        match bio.is_null() {
+            true => Err(io::Error::other("failed to allocate a buffer")),
+            false => Ok(Self(bio)),
+        }
+    }
+
+    /// Reads from `data`, which has to outlive it.
+    fn from_slice(data: &[u8]) -> io::Result<Self> {
+        // SAFETY: the buffer is only read, for its length
+        let bio = unsafe { ffi::BIO_new_mem_buf(data.as_ptr().cast(), data.len() as c_int) };
+        match bio.is_null() {
+            true => Err(io::Error::other("failed to allocate a buffer")),
+            false => Ok(Self(bio)),
+        }
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        let mut data: *mut c_char = std::ptr::null_mut();
+        // SAFETY: a memory buffer, the data stays valid until it is written to again
+        unsafe {
+            let len = ffi::BIO_ctrl(
+                self.0,
+                ffi::BIO_CTRL_INFO,
+                0,
+                (&mut data as *mut *mut c_char).cast(),
+            );
+            std::slice::from_raw_parts(data.cast::<u8>(), len as usize).to_vec()
+        }
+    }
+}
+
+impl Drop for Bio {
+    fn drop(&mut self) {
+        // SAFETY: created by `new` or `from_slice` and not used after this
+        unsafe { ffi::BIO_free(self.0) };
+    }
+}
+
+/// RSA key, freed when dropped.
+struct Key(*mut EVP_PKEY);
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        // SAFETY: owned by the key
+        unsafe { tls::ffi::EVP_PKEY_free(self.0) };
+    }
+}
+
+impl Key {
+    fn generate() -> io::Result<Self> {
+        // SAFETY: the variable arguments of RSA are the size in bits
+        let key = unsafe {
+            ffi::EVP_PKEY_Q_keygen(
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                c"RSA".as_ptr(),
+                RSA_BITS,
+            )
+        };
+        match key.is_null() {
+            true => Err(io::Error::other("failed to generate a key")),
+            false => Ok(Self(key)),
+        }
+    }
+
+    fn from_pem(pem: &[u8]) -> io::Result<Self> {
+        let bio = Bio::from_slice(pem)?;
+        // SAFETY: a valid buffer, without a passphrase
+        let key = unsafe {
+            ffi::PEM_read_bio_PrivateKey(bio.0, std::ptr::null_mut(), None, std::ptr::null_mut())
+        };
+        match key.is_null() {
+            true => Err(invalid("failed to read the account key")),
+            false => Ok(Self(key)),
+        }
+    }
+
+    fn to_pem(&self) -> io::Result<Vec<u8>> {
+        let bio = Bio::new()?;
+        // SAFETY: a valid key and buffer, written without encryption
+        let ok = unsafe {
+            ffi::PEM_write_bio_PrivateKey(
+                bio.0,
+                self.0,
+                std::ptr::null(),
+                std::ptr::null(),
+                0,
+                None,
+                std::ptr::null_mut(),
+            )
+        };
+        match ok {
+            1 => Ok(bio.to_vec()),
+            _ => Err(io::Error::other("failed to write the key")),
+        }
+    }
+
+    /// RS256 signature of `data`.
+    fn sign(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        // SAFETY: a valid key, the signature is asked for its length before it is written
+        unsafe {
+            let ctx = ffi::EVP_MD_CTX_new();
+            if ctx.is_null() {
+                return Err(io::Error::other("failed to sign"));
+            }
+            let mut len = 0;
+            let mut signature = Vec::new();
+            let ok = ffi::EVP_DigestSignInit(
+                ctx,
+                std::ptr::null_mut(),
+                tls::ffi::EVP_sha256(),
+                std::ptr::null_mut(),
+                self.0,
+            ) == 1
+                && ffi::EVP_DigestSign(
+                    ctx,
+                    std::ptr::null_mut(),
+                    &mut len,
+                    data.as_ptr(),
+                    data.len(),
+                ) == 1
+                && {
+                    signature.resize(len, 0);
+                    ffi::EVP_DigestSign(
+                        ctx,
+                        signature.as_mut_ptr(),
+                        &mut len,
+                        data.as_ptr(),
+                        data.len(),
+                    ) == 1
+                };
+            ffi::EVP_MD_CTX_free(ctx);
+            signature.truncate(len);
+            match ok {
+                true => Ok(signature),
+                false => Err(io::Error::other("failed to sign")),
+            }
+        }
+    }
+
+    /// Public half as a JSON web key, its members in the order of the thumbprint.
+    fn jwk(&self) -> io::Result<Value> {
+        let number = |name: &CStr| -> io::Result<String> {
+            let mut bn = std::ptr::null_mut();
+            // SAFETY: a valid key, the number is copied and freed right after
+            unsafe {
+                if ffi::EVP_PKEY_get_bn_param(self.0, name.as_ptr(), &mut bn) != 1 {
+                    return Err(io::Error::other("failed to read the key"));
+                }
+                let mut bytes = vec![0; (ffi::BN_num_bits(bn) as usize).div_ceil(8)];
+                ffi::BN_bn2bin(bn, bytes.as_mut_ptr());
+                ffi::BN_free(bn);
+                Ok(base64url(&bytes))
+            }
+        };
+        Ok(object(vec![
+            ("e", Value::String(number(c"e")?)),
+            ("kty", Value::String("RSA".to_string())),
+            ("n", Value::String(number(c"n")?)),
+        ]))
+    }
+
+    fn thumbprint(&self) -> io::Result<String> {
+        Ok(base64url(&sha256(self.jwk()?.to_string().as_bytes())?))
+    }
+
+    /// Signing request for a certificate of the domain, in DER.
+    fn request(&self, domain: &str) -> io::Result<Vec<u8>> {
+        let failed = || io::Error::other("failed to create the certificate request");
+        // SAFETY: a valid key, the request is freed before returning and keeps copies of what is
+        // added to it
+        unsafe {
+            let req = ffi::X509_REQ_new();
+            if req.is_null() {
+                return Err(failed());
+            }
+            let extensions = ffi::OPENSSL_sk_new_null();
+            let san = extension("subjectAltName", &format!("DNS:{domain}"));
+            let mut ok = !extensions.is_null()
+                && !san.is_null()
+                && ffi::X509_REQ_set_pubkey(req, self.0) == 1
+                && name_host(ffi::X509_REQ_get_subject_name(req), domain)
+                && ffi::OPENSSL_sk_push(extensions, san.cast()) > 0
+                && ffi::X509_REQ_add_extensions(req, extensions) == 1
+                && ffi::X509_REQ_sign(req, self.0, tls::ffi::EVP_sha256()) > 0;
+            let mut der = Vec::new();
+            if ok {
+                let len = ffi::i2d_X509_REQ(req, std::ptr::null_mut());
+                der.resize(len.max(0) as usize, 0);
+                let mut out = der.as_mut_ptr();
+                ok = len > 0 && ffi::i2d_X509_REQ(req, &mut out) == len;
+            }
+            ffi::X509_EXTENSION_free(san);
+            ffi::OPENSSL_sk_free(extensions);
+            ffi::X509_REQ_free(req);
+            match ok {
+                true => Ok(der),
+                false => Err(failed()),
+            }
+        }
+    }
+
+    /// Self-signed certificate for the domain answering the TLS-ALPN-01 challenge, with the hash
+    /// of the key authorization in it.
+    fn challenge(self, domain: &str, digest: &[u8; 32]) -> io::Result<Identity> {
+        // SAFETY: plain constructor, checked for failure below
+        let cert = unsafe { ffi::X509_new() };
+        if cert.is_null() {
+            return Err(io::Error::other("failed to create a certificate"));
+        }
+        // freed together from here on
+        let identity = Identity { cert, key: self.0 };
+        std::mem::forget(self);
+        // an OCTET STRING of the hash
+        let value = format!("critical,DER:04:20:{}", hex::encode(digest));
+        // SAFETY: a fresh certificate and a valid key, the extensions are copied
+        let ok = unsafe {
+            let (key, name) = (identity.key, ffi::X509_get_subject_name(cert));
+            let san = extension("subjectAltName", &format!("DNS:{domain}"));
+            let acme = extension(ACME_IDENTIFIER, &value);
+            let ok = ffi::X509_set_version(cert, 2) == 1
+                && ffi::ASN1_INTEGER_set(ffi::X509_get_serialNumber(cert), 1) == 1
+                && !ffi::X509_gmtime_adj(ffi::X509_getm_notBefore(cert), 0).is_null()
+                && !ffi::X509_gmtime_adj(ffi::X509_getm_notAfter(cert), 7 * 24 * 60 * 60).is_null()
+                && ffi::X509_set_pubkey(cert, key) == 1
+                && name_host(name, domain)
+                && ffi::X509_set_issuer_name(cert, name) == 1
+                && !san.is_null()
+                && !acme.is_null()
+                && ffi::X509_add_ext(cert, san, -1) == 1
+                && ffi::X509_add_ext(cert, acme, -1) == 1
+                && ffi::X509_sign(cert, key, tls::ffi::EVP_sha256()) > 0;
+            ffi::X509_EXTENSION_free(san);
+            ffi::X509_EXTENSION_free(acme);
+            ok
+        };
+        match ok {
+            true => Ok(identity),
+            false => Err(io::Error::other(
+                "failed to create the challenge certificate",
+            )),
+        }
+    }
+}
+
+/// Extension as the config of OpenSSL writes it, null if it can't be made.
+fn extension(name: &str, value: &str) -> *mut ffi::X509_EXTENSION {
+    let (Ok(name), Ok(value)) = (CString::new(name), CString::new(value)) else {
+        return std::ptr::null_mut();
+    };
+    // SAFETY: nul terminated strings, neither a config nor a context is needed for these
+    unsafe {
+        ffi::X509V3_EXT_nconf(
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            name.as_ptr(),
+            value.as_ptr(),
+        )
+    }
+}
+
+/// Sets the common name to the domain.
+///
+/// # Safety
+///
+/// `name` has to be a valid name.
+unsafe fn name_host(name: *mut ffi::X509_NAME, domain: &str) -> bool {
+    let Ok(domain) = CString::new(domain) else {
+        return false;
+    };
+    ffi::X509_NAME_add_entry_by_txt(
+        name,
+        c"CN".as_ptr(),
+        ffi::MBSTRING_ASC,
+        domain.as_ptr().cast(),
+        -1,
+        -1,
+        0,
+    ) == 1
+}
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+mod ffi {
+    use super::*;
+    pub use crate::tls::ffi::{ENGINE, EVP_MD, EVP_PKEY, X509};
+
+    pub enum BIO {}
+    pub enum BIO_METHOD {}
+    pub enum BIGNUM {}
+    pub enum EVP_MD_CTX {}
+    pub enum EVP_PKEY_CTX {}
+    pub enum X509_REQ {}
+    pub enum X509_NAME {}
+    pub enum X509_EXTENSION {}
+    pub enum ASN1_TIME {}
+    pub enum ASN1_INTEGER {}
+    pub enum OPENSSL_STACK {}
+
+    pub const BIO_CTRL_INFO: c_int = 3;
+    pub const MBSTRING_ASC: c_int = 0x1001;
+
+    type PasswordCallback =
+        Option<unsafe extern "C" fn(*mut c_char, c_int, c_int, *mut c_void) -> c_int>;
+
+    #[link(name = "crypto")]
+    extern "C" {
+        pub fn EVP_PKEY_Q_keygen(
+            libctx: *mut c_void,
+            propq: *const c_char,
+            kind: *const c_char,
+            ...
+        ) -> *mut EVP_PKEY;
+        pub fn EVP_PKEY_get_bn_param(
+            key: *const EVP_PKEY,
+            name: *const c_char,
+            bn: *mut *mut BIGNUM,
+        ) -> c_int;
+        pub fn BN_num_bits(bn: *const BIGNUM) -> c_int;
+        pub fn BN_bn2bin(bn: *const BIGNUM, to: *mut u8) -> c_int;
+        pub fn BN_free(bn: *mut BIGNUM);
+        pub fn EVP_MD_CTX_new() -> *mut EVP_MD_CTX;
+        pub fn EVP_MD_CTX_free(ctx: *mut EVP_MD_CTX);
+        pub fn EVP_DigestSignInit(
+            ctx: *mut EVP_MD_CTX,
+            pctx: *mut *mut EVP_PKEY_CTX,
+            kind: *const EVP_MD,
+            engine: *mut ENGINE,
+            key: *mut EVP_PKEY,
+        ) -> c_int;
+        pub fn EVP_DigestSign(
+            ctx: *mut EVP_MD_CTX,
+            signature: *mut u8,
+            len: *mut usize,
+            data: *const u8,
+            data_len: usize,
+        ) -> c_int;
+        pub fn BIO_new(kind: *const BIO_METHOD) -> *mut BIO;
+        pub fn BIO_s_mem() -> *const BIO_METHOD;
+        pub fn BIO_new_mem_buf(data: *const c_void, len: c_int) -> *mut BIO;
+        pub fn BIO_ctrl(bio: *mut BIO, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+        pub fn BIO_free(bio: *mut BIO) -> c_int;
+        pub fn PEM_read_bio_PrivateKey(
+            bio: *mut BIO,
+            key: *mut *mut EVP_PKEY,
+            callback: PasswordCallback,
+            arg: *mut c_void,
+        ) -> *mut EVP_PKEY;
+        pub fn PEM_write_bio_PrivateKey(
+            bio: *mut BIO,
+            key: *const EVP_PKEY,
+            cipher: *const c_void,
+            passphrase: *const u8,
+            len: c_int,
+            callback: PasswordCallback,
+            arg: *mut c_void,
+        ) -> c_int;
+        pub fn PEM_read_bio_X509(
+            bio: *mut BIO,
+            cert: *mut *mut X509,
+            callback: PasswordCallback,
+            arg: *mut c_void,
+        ) -> *mut X509;
+        pub fn X509_get0_notAfter(cert: *const X509) -> *const ASN1_TIME;
+        pub fn ASN1_TIME_diff(
+            days: *mut c_int,
+            seconds: *mut c_int,
+            from: *const ASN1_TIME,
+            to: *const ASN1_TIME,
+        ) -> c_int;
+        pub fn X509_new() -> *mut X509;
+        pub fn X509_set_version(cert: *mut X509, version: c_long) -> c_int;
+        pub fn X509_get_serialNumber(cert: *mut X509) -> *mut ASN1_INTEGER;
+        pub fn ASN1_INTEGER_set(integer: *mut ASN1_INTEGER, value: c_long) -> c_int;
+        pub fn X509_getm_notBefore(cert: *const X509) -> *mut ASN1_TIME;
+        pub fn X509_getm_notAfter(cert: *const X509) -> *mut ASN1_TIME;
+        pub fn X509_gmtime_adj(time: *mut ASN1_TIME, seconds: c_long) -> *mut ASN1_TIME;
+        pub fn X509_set_pubkey(cert: *mut X509, key: *mut EVP_PKEY) -> c_int;
+        pub fn X509_get_subject_name(cert: *const X509) -> *mut X509_NAME;
+        pub fn X509_set_issuer_name(cert: *mut X509, name: *const X509_NAME) -> c_int;
+        pub fn X509_NAME_add_entry_by_txt(
+            name: *mut X509_NAME,
+            field: *const c_char,
+            kind: c_int,
+            bytes: *const u8,
+            len: c_int,
+            loc: c_int,
+            set: c_int,
+        ) -> c_int;
+        pub fn X509V3_EXT_nconf(
+            conf: *mut c_void,
+            ctx: *mut c_void,
+            name: *const c_char,
+            value: *const c_char,
+        ) -> *mut X509_EXTENSION;
+        pub fn X509_EXTENSION_free(extension: *mut X509_EXTENSION);
+        pub fn X509_add_ext(cert: *mut X509, extension: *mut X509_EXTENSION, loc: c_int) -> c_int;
+        pub fn X509_sign(cert: *mut X509, key: *mut EVP_PKEY, md: *const EVP_MD) -> c_int;
+        pub fn X509_REQ_new() -> *mut X509_REQ;
+        pub fn X509_REQ_free(req: *mut X509_REQ);
+        pub fn X509_REQ_set_pubkey(req: *mut X509_REQ, key: *mut EVP_PKEY) -> c_int;
+        pub fn X509_REQ_get_subject_name(req: *const X509_REQ) -> *mut X509_NAME;
+        pub fn X509_REQ_add_extensions(
+            req: *mut X509_REQ,
+            extensions: *const OPENSSL_STACK,
+        ) -> c_int;
+        pub fn X509_REQ_sign(req: *mut X509_REQ, key: *mut EVP_PKEY, md: *const EVP_MD) -> c_int;
+        pub fn i2d_X509_REQ(req: *const X509_REQ, out: *mut *mut u8) -> c_int;
+        pub fn OPENSSL_sk_new_null() -> *mut OPENSSL_STACK;
+        pub fn OPENSSL_sk_push(stack: *mut OPENSSL_STACK, data: *const c_void) -> c_int;
+        pub fn OPENSSL_sk_free(stack: *mut OPENSSL_STACK);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_need_a_domain() {
+        assert!(Options::from_config(&"".parse::<Config>().unwrap()).is_none());
+        let config = "[acme]\ndomain = \"chat.example\"\nemail = \"ada@example\"\n";
+        let options = Options::from_config(&config.parse::<Config>().unwrap()).unwrap();
+        assert_eq!(options.domain, "chat.example");
+        assert_eq!(options.email.as_deref(), Some("ada@example"));
+        assert_eq!(options.directory, LETS_ENCRYPT);
+    }
+
+    #[test]
+    fn base64url_has_no_padding() {
+        assert_eq!(base64url(b""), "");
+        assert_eq!(base64url(b"f"), "Zg");
+        assert_eq!(base64url(b"fo"), "Zm8");
+        assert_eq!(base64url(b"foo"), "Zm9v");
+        assert_eq!(base64url(&[0xfb, 0xff, 0xfe]), "-__-");
+        assert_eq!(
+            base64url(&sha256(b"abc").unwrap()),
+            "ungWv48Bz-pBQUDeXa4iI7ADYaOWF3qctBD_YfIAFa0"
+        );
+    }
+
+    #[test]
+    fn responses_are_parsed_with_chunked_bodies() {
+        let raw = b"HTTP/1.1 201 Created\r\nLocation: https://ca/order/1\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n\
+                    7\r\n{\"statu\r\n5;ext\r\ns\":\"v\r\n5\r\nalid\"\r\n1\r\n}\r\n0\r\n\r\n";
+        let response = parse_response(raw).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.location().unwrap(), "https://ca/order/1");
+        assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+        assert_eq!(status(&response.json().unwrap()), "valid");
+
+        let raw = b"HTTP/1.1 204 No Content\r\n\r\n";
+        let response = parse_response(raw).unwrap();
+        assert!(response.body.is_empty());
+        assert!(response.location().is_err());
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_none());
+        assert!(dechunk(b"5\r\nab").is_none());
+        assert!(dechunk(b"zz\r\n").is_none());
+    }
+
+    #[test]
+    fn problems_come_from_the_challenges_too() {
+        let value = json::parse(r#"{"status":"invalid","error":{"detail":"no"}}"#).unwrap();
+        assert_eq!(problem(&value), Some("no"));
+        let value =
+            json::parse(r#"{"challenges":[{"status":"pending"},{"error":{"detail":"timeout"}}]}"#)
+                .unwrap();
+        assert_eq!(problem(&value), Some("timeout"));
+        assert_eq!(status(&value), "");
+        assert_eq!(problem(&json::parse("{}").unwrap()), None);
+    }
+}
@@ -0,0 +1,315 @@
+//! How much each conversation notifies of, set with `/notify` or in the config:
+//!
+//! ```toml
+//! [notify]
+//! default = "all"       # all, mentions or none
+//! "#ops" = "mentions"   # a relay room or peer, a contact alias or the address of a peer
+//! ```
+//!
+//! A mention is a message with our name in it. The triggers notify regardless, they are asked
+//! for explicitly.
+//!
+//! Messages left unread get louder the longer they wait, if the conversation asks for it:
+//!
+//! ```toml
+//! [escalate]
+//! default = "never 30m"  # ring the bell after, then notify again every, `never` for neither
+//! "#ops" = "5m 15m"
+//! ```
+//!
+//! Before that the count in the title of the messages pane is all there is.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{config::Config, timestamp};
+
+/// Config table holding the levels.
+pub const TABLE: &str = "notify";
+/// Config table holding the escalations.
+pub const ESCALATE_TABLE: &str = "escalate";
+/// Key of the level and escalation of conversations without one of their own.
+pub const DEFAULT: &str = "default";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Level {
+    #[default]
+    All,
+    Mentions,
+    None,
+}
+
+impl Level {
+    /// What notifies, for the user.
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::All => "every message",
+            Self::Mentions => "mentions of you",
+            Self::None => "nothing",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "mentions" => Ok(Self::Mentions),
+            "none" => Ok(Self::None),
+            _ => Err(format!("{s} isn't a level, all, mentions or none")),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::All => "all",
+            Self::Mentions => "mentions",
+            Self::None => "none",
+        })
+    }
+}
+
+/// How long unread messages wait before getting louder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Escalation {
+    /// Until the bell rings, once
+    pub bell: Option<Duration>,
+    /// Between desktop notifications
+    pub repeat: Option<Duration>,
+}
+
+impl FromStr for Escalation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let wait = |s: &str| match s {
+            "never" => Ok(None),
+            _ => timestamp::parse_duration(s)
+                .map(Some)
+                .ok_or_else(|| format!("{s} isn't a duration like 5m or never")),
+        };
+        match s.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [bell, repeat] => Ok(Self {
+                bell: wait(bell)?,
+                repeat: wait(repeat)?,
+            }),
+            _ => Err(format!(
+                "{s:?} isn't two durations, the bell and the repeat"
+            )),
+        }
+    }
+}
+
+/// Unread messages, since when they wait and how loud they got.
+#[derive(Debug, Clone, Copy)]
+pub struct Missed {
+    since: Instant,
+    rang: bool,
+    notified: Instant,
+}
+
+/// What [`Missed::escalate`] asks for.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Due {
+    pub bell: bool,
+    pub notification: bool,
+}
+
+impl Missed {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            since: now,
+            rang: false,
+            notified: now,
+        }
+    }
+
+    /// What is due at `now`, which is then taken care of.
+    pub fn escalate(&mut self, escalation: Escalation, now: Instant) -> Due {
+        let mut due = Due::default();
+        if let Some(bell) = escalation.bell {
+            if !self.rang && now.duration_since(self.since) >= bell {
+                self.rang = true;
+                due.bell = true;
+            }
+        }
+        if let Some(repeat) = escalation.repeat {
+            if now.duration_since(self.notified) >= repeat {
+                self.notified = now;
+                due.notification = true;
+            }
+        }
+        due
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Rules {
+    default: Level,
+    conversations: HashMap<String, Level>,
+    default_escalation: Escalation,
+    escalations: HashMap<String, Escalation>,
+    /// Our name, which makes a message a mention
+    pub name: String,
+}
+
+impl Rules {
+    pub fn from_config(config: &Config, name: String) -> Self {
+        let mut rules = Self {
+            name,
+            ..Self::default()
+        };
+        for (key, value) in config.strings(TABLE) {
+            match value.parse() {
+                Ok(level) if key == DEFAULT => rules.default = level,
+                Ok(level) => {
+                    rules.conversations.insert(key, level);
+                }
+                Err(e) => warn!("Ignoring notify.{key}: {e}"),
+            }
+        }
+        for (key, value) in config.strings(ESCALATE_TABLE) {
+            match value.parse() {
+                Ok(escalation) if key == DEFAULT => rules.default_escalation = escalation,
+                Ok(escalation) => {
+                    rules.escalations.insert(key, escalation);
+                }
+                Err(e) => warn!("Ignoring escalate.{key}: {e}"),
+            }
+        }
+        rules
+    }
+
+    pub fn escalation(&self, conversation: &str) -> Escalation {
+        self.escalations
+            .get(conversation)
+            .copied()
+            .unwrap_or(self.default_escalation)
+    }
+
+    pub fn level(&self, conversation: &str) -> Level {
+        self.conversations
+            .get(conversation)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    pub fn set(&mut self, conversation: &str, level: Level) {
+        match conversation {
+            DEFAULT => self.default = level,
+            _ => {
+                self.conversations.insert(conversation.to_string(), level);
+            }
+        }
+    }
+
+    /// Whether `text` arriving in `conversation` should notify.
+    pub fn wants(&self, conversation: &str, text: &str) -> bool {
+        match self.level(conversation) {
+            Level::All => true,
+            Level::Mentions => mentions(text, &self.name),
+            Level::None => false,
+        }
+    }
+}
+
+/// Whether `name` appears in `text` as a word of its own, ignoring case.
+pub fn mentions(text: &str, name: &str) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+    let (text, name) = (text.to_lowercase(), name.to_lowercase());
+    text.match_indices(&name).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + name.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_are_whole_words() {
+        assert!(mentions("hey Ada, lunch?", "ada"));
+        assert!(mentions("@ada", "ada"));
+        assert!(!mentions("canada", "ada"));
+        assert!(!mentions("ada2", "ada"));
+        assert!(!mentions("anything", ""));
+    }
+
+    #[test]
+    fn every_conversation_has_its_level() {
+        let config: Config =
+            "[notify]\ndefault = \"mentions\"\n\"#ops\" = \"all\"\nbob = \"loud\"\n"
+                .parse()
+                .unwrap();
+        let mut rules = Rules::from_config(&config, "ada".to_string());
+        assert!(rules.wants("#ops", "deploy done"));
+        assert!(!rules.wants("bob", "deploy done"));
+        assert!(rules.wants("bob", "ada: deploy done"));
+        rules.set("#ops", Level::None);
+        rules.set(DEFAULT, Level::All);
+        assert!(!rules.wants("#ops", "ada: deploy done"));
+        assert!(rules.wants("bob", "deploy done"));
+        assert_eq!(
+            "loud".parse::<Level>(),
+            Err("loud isn't a level, all, mentions or none".to_string())
+        );
+    }
+
+    #[test]
+    fn unread_messages_ring_once_then_notify_again() {
+        let escalation: Escalation = "5m 15m".parse().unwrap();
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        let start = Instant::now();
+        let mut missed = Missed::new(start);
+        let due = |missed: &mut Missed, m| missed.escalate(escalation, start + minutes(m));
+        assert_eq!(due(&mut missed, 4), Due::default());
+        assert_eq!(
+            due(&mut missed, 5),
+            Due {
+                bell: true,
+                notification: false,
+            }
+        );
+        assert_eq!(due(&mut missed, 14), Due::default());
+        assert_eq!(
+            due(&mut missed, 15),
+            Due {
+                bell: false,
+                notification: true,
+            }
+        );
+        assert_eq!(due(&mut missed, 29), Due::default());
+        assert!(due(&mut missed, 30).notification);
+
+        assert_eq!(
+            "never 30m".parse(),
+            Ok(Escalation {
+                bell: None,
+                repeat: Some(minutes(30)),
+            })
+        );
+        assert!("5m".parse::<Escalation>().is_err());
+        assert!("5m soon".parse::<Escalation>().is_err());
+
+        let config: Config = "[escalate]\ndefault = \"never never\"\n\"#ops\" = \"5m 15m\"\n"
+            .parse()
+            .unwrap();
+        let rules = Rules::from_config(&config, String::new());
+        assert_eq!(rules.escalation("#ops"), escalation);
+        assert_eq!(rules.escalation("bob"), Escalation::default());
+    }
+}
@@ -0,0 +1,74 @@
+//! Shortcuts for slash commands, kept in the `[aliases]` table of the config. `/name` enters the
+//! lines of the alias in turn, messages or commands, with whatever follows the name added to the
+//! last one. The lines aren't looked up as aliases again, so an alias may stand in for a command
+//! of the same name.
+//!
+//! ```toml
+//! [aliases]
+//! j = "/join"
+//! brb = "be right back\n/nick sam (away)"
+//! ```
+
+use std::collections::BTreeMap;
+
+use tracing::warn;
+
+use crate::config::Config;
+
+/// Config table holding the aliases.
+pub const TABLE: &str = "aliases";
+
+#[derive(Debug, Default)]
+pub struct Aliases(BTreeMap<String, String>);
+
+impl Aliases {
+    pub fn from_config(config: &Config) -> Self {
+        let mut aliases = BTreeMap::new();
+        for (name, text) in config.strings(TABLE) {
+            if name.is_empty() || name.contains(char::is_whitespace) || name.starts_with('/') {
+                warn!("Ignoring aliases.{name}, the name of an alias is a single word");
+            } else if text.trim().is_empty() {
+                warn!("Ignoring aliases.{name}, it stands for nothing");
+            } else {
+                aliases.insert(name, text);
+            }
+        }
+        Self(aliases)
+    }
+
+    /// Lines `command`, typed without its `/`, stands for if it starts with an alias.
+    pub fn expand(&self, command: &str) -> Option<Vec<String>> {
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        let text = self.0.get(name)?;
+        let mut lines: Vec<String> = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect();
+        if let Some(last) = lines.last_mut().filter(|_| !args.trim().is_empty()) {
+            last.push(' ');
+            last.push_str(args.trim());
+        }
+        Some(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_go_to_the_last_line() {
+        let aliases = Aliases(BTreeMap::from([
+            ("j".to_string(), "/join".to_string()),
+            ("brb".to_string(), "be right back\n/nick sam".to_string()),
+        ]));
+        assert_eq!(aliases.expand("j #rust").unwrap(), ["/join #rust"]);
+        assert_eq!(
+            aliases.expand("brb (away)").unwrap(),
+            ["be right back", "/nick sam (away)"]
+        );
+        assert_eq!(aliases.expand("join #rust"), None);
+    }
+}
@@ -0,0 +1,209 @@
+//! Colors and text attributes sent as ANSI escape codes, e.g. by bots streaming their logs.
+//!
+//! Only SGR sequences (`ESC [ … m`) are interpreted. Everything else which could move the cursor,
+//! change the title or otherwise take over the terminal is dropped.
+//!
+//! Text shown without interpreting them goes through [`sanitize`] instead, so a hostile peer can't
+//! mess up the screen or disguise what it sent.
+
+use std::borrow::Cow;
+
+use ratatui::prelude::*;
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{7}';
+
+/// Attributes set so far, they carry over to the following lines of the message.
+#[derive(Debug, Default)]
+pub struct Sgr {
+    style: Style,
+}
+
+impl Sgr {
+    /// Spans of a line on top of `base`, without any escape codes.
+    pub fn spans(&mut self, line: &str, base: Style) -> Vec<Span<'static>> {
+        let mut spans = Vec::new();
+        let mut text = String::new();
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ESC => {
+                    let sequence = match chars.next() {
+                        Some('[') => csi(&mut chars),
+                        // OSC, ends with BEL or ESC \
+                        Some(']') => {
+                            while let Some(c) = chars.next() {
+                                if c == BEL || (c == ESC && chars.next_if_eq(&'\\').is_some()) {
+                                    break;
+                                }
+                            }
+                            None
+                        }
+                        _ => None,
+                    };
+                    if let Some(params) = sequence {
+                        if !text.is_empty() {
+                            spans.push(Span::styled(
+                                std::mem::take(&mut text),
+                                base.patch(self.style),
+                            ));
+                        }
+                        self.apply(&params);
+                    }
+                }
+                c => text.push_str(&sanitize(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        if !text.is_empty() {
+            spans.push(Span::styled(text, base.patch(self.style)));
+        }
+        spans
+    }
+
+    fn apply(&mut self, params: &str) {
+        let mut codes = params.split(';').map(|p| p.parse::<u8>().unwrap_or(0));
+        while let Some(code) = codes.next() {
+            let style = self.style;
+            self.style = match code {
+                0 => Style::default(),
+                1 => style.add_modifier(Modifier::BOLD),
+                2 => style.add_modifier(Modifier::DIM),
+                3 => style.add_modifier(Modifier::ITALIC),
+                4 => style.add_modifier(Modifier::UNDERLINED),
+                5 | 6 => style.add_modifier(Modifier::SLOW_BLINK),
+                7 => style.add_modifier(Modifier::REVERSED),
+                9 => style.add_modifier(Modifier::CROSSED_OUT),
+                22 => style.remove_modifier(Modifier::BOLD | Modifier::DIM),
+                23 => style.remove_modifier(Modifier::ITALIC),
+                24 => style.remove_modifier(Modifier::UNDERLINED),
+                25 => style.remove_modifier(Modifier::SLOW_BLINK),
+                27 => style.remove_modifier(Modifier::REVERSED),
+                29 => style.remove_modifier(Modifier::CROSSED_OUT),
+                30..=37 => style.fg(Color::Indexed(code - 30)),
+                38 => match color(&mut codes) {
+                    Some(color) => style.fg(color),
+                    None => style,
+                },
+                39 => Style { fg: None, ..style },
+                40..=47 => style.bg(Color::Indexed(code - 40)),
+                48 => match color(&mut codes) {
+                    Some(color) => style.bg(color),
+                    None => style,
+                },
+                49 => Style { bg: None, ..style },
+                90..=97 => style.fg(Color::Indexed(code - 90 + 8)),
+                100..=107 => style.bg(Color::Indexed(code - 100 + 8)),
+                _ => style,
+            };
+        }
+    }
+}
+
+/// Parameters of a CSI sequence if it is SGR, the rest of it is skipped.
+fn csi(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut params = String::new();
+    for c in chars {
+        match c {
+            '0'..='9' | ';' => params.push(c),
+            // subparameters, as in `38:5:n`
+            ':' => params.push(';'),
+            // the final byte
+            '\u{40}'..='\u{7e}' => return (c == 'm').then_some(params),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Extended color following 38 or 48, `5;n` from the palette or `2;r;g;b`.
+fn color(codes: &mut impl Iterator<Item = u8>) -> Option<Color> {
+    match codes.next()? {
+        5 => Some(Color::Indexed(codes.next()?)),
+        2 => Some(Color::Rgb(codes.next()?, codes.next()?, codes.next()?)),
+        _ => None,
+    }
+}
+
+/// Whether `c` changes the direction of the text around it, the bidi module takes care of that.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Text safe to draw: control characters but tabs and newlines are shown as their symbol, like
+/// `␛`, and bidi overrides are dropped.
+pub fn sanitize(text: &str) -> Cow<'_, str> {
+    let unsafe_char = |c: char| (c.is_control() && c != '\n' && c != '\t') || is_bidi_control(c);
+    if !text.contains(unsafe_char) {
+        return Cow::Borrowed(text);
+    }
+    text.chars()
+        .filter(|&c| !is_bidi_control(c))
+        .map(|c| match c {
+            '\n' | '\t' => c,
+            '\0'..='\u{1f}' => char::from_u32(0x2400 + c as u32).unwrap_or('\u{fffd}'),
+            '\u{7f}' => '\u{2421}',
+            c if c.is_control() => '\u{fffd}',
+            c => c,
+        })
+        .collect::<String>()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_shown_as_symbols() {
+        assert!(matches!(sanitize("plain\ttext\n"), Cow::Borrowed(_)));
+        assert_eq!(sanitize("\u{1b}[2J\r\0"), "␛[2J␍␀");
+        assert_eq!(sanitize("rm\u{7f}"), "rm␡");
+        assert_eq!(sanitize("\u{85}"), "\u{fffd}");
+    }
+
+    #[test]
+    fn bidi_overrides_and_isolates_are_dropped() {
+        assert_eq!(sanitize("invoice\u{202E}fdp.exe"), "invoicefdp.exe");
+        assert_eq!(sanitize("\u{2066}a\u{2069}\u{202A}b\u{202C}"), "ab");
+    }
+
+    fn texts(spans: &[Span]) -> Vec<String> {
+        spans.iter().map(|s| s.content.to_string()).collect()
+    }
+
+    #[test]
+    fn only_sgr_sequences_are_kept() {
+        let mut sgr = Sgr::default();
+        let spans = sgr.spans("\u{1b}[1;31merror\u{1b}[0m done", Style::default());
+        assert_eq!(texts(&spans), ["error", " done"]);
+        assert_eq!(
+            spans[0].style,
+            Style::default()
+                .fg(Color::Indexed(1))
+                .add_modifier(Modifier::BOLD)
+        );
+        assert_eq!(spans[1].style, Style::default());
+
+        // cursor movement, clearing and titles vanish
+        let spans = sgr.spans(
+            "a\u{1b}[2J\u{1b}[10;10Hb\u{1b}]0;owned\u{7}c\u{1b}]8;;x\u{1b}\\d",
+            Style::default(),
+        );
+        assert_eq!(texts(&spans), ["abcd"]);
+        assert_eq!(texts(&sgr.spans("\u{1b}7x\u{7}", Style::default())), ["x␇"]);
+    }
+
+    #[test]
+    fn attributes_carry_over_to_the_next_line() {
+        let mut sgr = Sgr::default();
+        let base = Style::default().fg(Color::Gray);
+        sgr.spans("\u{1b}[38;2;1;2;3m\u{1b}[48:5:200m", base);
+        let spans = sgr.spans("still colored", base);
+        assert_eq!(spans[0].style.fg, Some(Color::Rgb(1, 2, 3)));
+        assert_eq!(spans[0].style.bg, Some(Color::Indexed(200)));
+        sgr.spans("\u{1b}[39;49;94m", base);
+        assert_eq!(sgr.spans("x", base)[0].style.fg, Some(Color::Indexed(12)));
+        sgr.spans("\u{1b}[m", base);
+        assert_eq!(sgr.spans("x", base)[0].style, base);
+    }
+}
@@ -0,0 +1,3554 @@
+//! State of a conversation, and what becomes of typed input and of the lines of the peer.
+
+use crossterm::event::{KeyCode, KeyEvent};
+use notify_rust::Notification;
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, VecDeque},
+    io::{self, BufRead},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, error, info, instrument, warn};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    alerts::{self, Rules},
+    aliases::Aliases,
+    beep::Beeper,
+    bridge, broadcast, cbor,
+    clipboard::Clipboard,
+    clock::SharedClock,
+    commands::Command,
+    config::Config,
+    connection::Established,
+    contacts::{self, Contacts},
+    deniable, detach, diag, dirs, discovery,
+    draft::Autosave,
+    encryption, exec,
+    fault::{self, Fault},
+    json,
+    keys::Bindings,
+    live::{self, Live},
+    location,
+    macros::{self, Macros},
+    message,
+    message::Message,
+    outbox::Outbox,
+    pane, protocol, relay,
+    reminders::Reminders,
+    rng::SharedRng,
+    seal::{self, Seal},
+    skew::{self, Skew},
+    snippets::{self, Snippets},
+    socket,
+    stateful_list::StatefulList,
+    store,
+    store::Store,
+    talk, tasks,
+    theme::Theme,
+    timestamp, tls, transfer,
+    transport::Transport,
+    triggers,
+    triggers::Triggers,
+};
+
+/// Set by the reciever when the connection dropped
+pub static RESET: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+/// Set whenever the interface has to be drawn again
+pub static REDRAW: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
+/// Set while the terminal isn't focused, when messages are counted as unread
+pub static NOTIFY: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+static NEXT_CONNECTION_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+/// Archived messages loaded at once when scrolling back.
+const ARCHIVE_PAGE: usize = 200;
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%H:%M";
+/// Archived messages shown when a conversation starts.
+const RESTORED: usize = 50;
+
+/// The `count` messages of `archive` before those already handed out.
+fn archived(archive: &mut store::Archive, count: usize) -> Vec<Message> {
+    archive
+        .older(count)
+        .into_iter()
+        .map(archived_message)
+        .collect()
+}
+
+fn archived_message(record: store::Record) -> Message {
+    let mut msg = Message::new(record.kind, record.text);
+    msg.at = record.at;
+    msg.author = record.author;
+    msg
+}
+
+/// Desktop notification, unless the user is looking at the terminal anyway.
+#[instrument()]
+pub fn notify(msg: &str) {
+    if NOTIFY.load(Ordering::Acquire) {
+        show_notification(msg);
+    }
+}
+
+fn show_notification(msg: &str) {
+    if let Err(e) = Notification::new()
+        .summary("Chatterbox")
+        .body(msg)
+        .appname("ChatterBox")
+        .show()
+    {
+        warn!("Failed to send notification {e}")
+    }
+}
+
+/// Everything the reciever hands incoming messages over to.
+struct Inbound {
+    dest: Arc<Mutex<StatefulList<Message>>>,
+    triggers: Arc<Mutex<Triggers>>,
+    /// Auto replies requested by triggers
+    replies: mpsc::Sender<String>,
+    store: Option<Store>,
+    stats: Arc<diag::Stats>,
+    /// Name of the peer on the relay, others get their name in front of the message
+    relay_peer: Option<String>,
+    /// Our own name on the relay
+    relay_name: Option<String>,
+    /// Messages which arrived while the terminal wasn't focused
+    unread: Arc<AtomicUsize>,
+    /// Commands the peer asks to run
+    exec_requests: mpsc::Sender<String>,
+    /// Pane the peer shares
+    pane: Arc<Mutex<Option<pane::View>>>,
+    /// Topic and pins of the relay room we talk in
+    room: Arc<Mutex<relay::RoomInfo>>,
+    /// Who is connected to the `--multi` server
+    presence: Arc<Mutex<BTreeMap<String, bool>>>,
+    /// Passes the messages of the peer on to the bridge
+    bridge: Option<mpsc::Sender<String>>,
+    /// Name the peer is shown with on the bridge, and of the conversation for the notify rules
+    peer_name: String,
+    alerts: Arc<Mutex<Rules>>,
+    live: Arc<Live>,
+    cbor: Arc<cbor::Negotiation>,
+    skew: Arc<Skew>,
+    /// Id of the latest message of the peer the user hasn't seen, 0 if none
+    unseen: Arc<AtomicU64>,
+    beep: Arc<Beeper>,
+    /// Decrypts the payloads, if the conversation is encrypted
+    seal: Option<Arc<Seal>>,
+    /// The peer sent plaintext although the conversation is sealed
+    plaintext: Arc<AtomicBool>,
+    /// Half of the peer in the talk mode
+    peer_talk: Arc<Mutex<talk::Window>>,
+    /// Lines to send back, like telling the peer we type live as well
+    controls: mpsc::Sender<String>,
+    /// Lines about files, taken care of by the interface
+    files: mpsc::Sender<protocol::Payload>,
+    /// Pages of the directory of the relay
+    listings: mpsc::Sender<relay::Listing>,
+    /// What went wrong with the lines of the peer
+    faults: mpsc::Sender<Fault>,
+    /// Faults since the last line which was fine
+    faulty: Cell<usize>,
+}
+
+impl Inbound {
+    /// Shows a message of the peer, running the triggers and notifying as they say.
+    fn incoming(
+        &self,
+        sender: Option<&str>,
+        text: String,
+        data: Option<json::Value>,
+        envelope: Option<protocol::Envelope>,
+        room: Option<String>,
+        unauthenticated: bool,
+    ) {
+        // the relay knows who it is, the name in the envelope is what the peer says
+        let author = sender
+            .map(str::to_string)
+            .or_else(|| envelope.as_ref().map(|e| e.sender.clone()));
+        if let Some(bridge) = &self.bridge {
+            let name = author.as_deref().unwrap_or(&self.peer_name);
+            let _ = bridge.send(format!("{name}: {text}"));
+        }
+        // a relay tells about the delivery itself
+        if let (Some(id), None) = (envelope.as_ref().and_then(|e| e.id), &self.relay_peer) {
+            let receipt = match NOTIFY.load(Ordering::Acquire) {
+                true => {
+                    self.unseen.store(id, Ordering::Release);
+                    protocol::received(id)
+                }
+                false => protocol::seen(id),
+            };
+            let _ = self.controls.send(receipt);
+        }
+        let mut msg = Message::incoming(text);
+        msg.unauthenticated = unauthenticated;
+        if envelope.is_some() {
+            msg.skew = self.skew.offset();
+        }
+        msg.envelope = envelope;
+        msg.author = author;
+        msg.data = data;
+        msg.room = room;
+        let line = msg.line();
+        let text = msg.text.clone();
+        let text = text.as_str();
+        let mut notified = false;
+        if let Ok(triggers) = self.triggers.lock() {
+            for action in triggers.matching(text) {
+                match action {
+                    triggers::Action::Notify if !notified => {
+                        show_notification(&line);
+                        notified = true;
+                    }
+                    triggers::Action::Notify => {}
+                    triggers::Action::Highlight => msg.highlighted = true,
+                    triggers::Action::Command(cmd) => {
+                        triggers::run_command(cmd, text, msg.data.as_ref())
+                    }
+                    triggers::Action::Reply(reply) => {
+                        let _ = self.replies.send(reply.clone());
+                    }
+                }
+            }
+        }
+        self.record(msg);
+        self.beep.play(text);
+        if NOTIFY.load(Ordering::Acquire) {
+            self.unread.fetch_add(1, Ordering::Relaxed);
+        } else if let Some(store) = &self.store {
+            store.mark_read();
+        }
+        let wanted = self
+            .alerts
+            .lock()
+            .expect("alerts lock is poisoned")
+            .wants(&self.peer_name, text);
+        if !notified && wanted {
+            notify(&line);
+        }
+    }
+
+    /// Keeps up with the room we talk in, frames of other rooms are ignored.
+    fn room_frame(&self, frame: relay::Frame) {
+        let mut room = self.room.lock().expect("room lock is poisoned");
+        let text = match frame {
+            relay::Frame::Topic { room: r, by, topic } if self.is_our_room(r) => {
+                room.topic = Some(protocol::unescape(topic)).filter(|t| !t.is_empty());
+                match &room.topic {
+                    Some(topic) => format!("{by} set the topic: {topic}"),
+                    None => format!("{by} cleared the topic"),
+                }
+            }
+            relay::Frame::Pin { room: r, by, text } if self.is_our_room(r) => {
+                if text.is_empty() {
+                    room.pins.clear();
+                    format!("{by} removed the pins")
+                } else {
+                    let text = protocol::unescape(text);
+                    room.pins.push((by.to_string(), text.clone()));
+                    format!("{by} pinned: {text}")
+                }
+            }
+            relay::Frame::Kick { room: r, by, name } if self.is_our_room(r) => {
+                if Some(name) == self.relay_name.as_deref() {
+                    format!("{by} kicked you out of {r}")
+                } else {
+                    format!("{by} kicked {name}")
+                }
+            }
+            relay::Frame::Role {
+                room: r,
+                by,
+                name,
+                role,
+            } if self.is_our_room(r) => format!("{by} made {name} {}", role.name()),
+            relay::Frame::Code { room, code } => {
+                format!("join code for {room}: {code}, it works once with --join-code")
+            }
+            relay::Frame::Invite { room, by } => {
+                format!("{by} invited you to {room}, talk there with --to '{room}'")
+            }
+            _ => return,
+        };
+        drop(room);
+        self.record(Message::system(text));
+    }
+
+    /// Payload of `line`, decrypted if the conversation is, and whether it came in the clear
+    /// when it shouldn't have. `None` if it can't be decrypted.
+    fn open(&self, line: &str) -> Option<(String, bool)> {
+        let Some(seal) = &self.seal else {
+            return Some((line.to_string(), false));
+        };
+        match seal.open(line) {
+            seal::Opened::Plain(payload) => Some((payload, false)),
+            // nothing to decrypt
+            seal::Opened::Unsealed(payload) if payload.is_empty() => Some((payload, false)),
+            seal::Opened::Unsealed(payload) => {
+                if !self.plaintext.swap(true, Ordering::AcqRel) {
+                    let mut msg = Message::system(
+                        "WARNING: the peer sent plaintext although the conversation is sealed, \
+                         someone in the middle may have stripped the encryption"
+                            .to_string(),
+                    );
+                    msg.highlighted = true;
+                    self.record(msg);
+                }
+                Some((payload, true))
+            }
+            seal::Opened::Failed => {
+                self.fault(Fault::Undecryptable);
+                None
+            }
+        }
+    }
+
+    /// Starts counting the faults in a row again, unless there were more since `faulty`.
+    fn settle(&self, faulty: usize) {
+        if self.faulty.get() == faulty {
+            self.faulty.set(0);
+        }
+    }
+
+    fn fault(&self, fault: Fault) {
+        warn!("Fault in the lines of the peer: {fault:?}");
+        self.faulty.set(self.faulty.get() + 1);
+        let _ = self.faults.send(fault);
+    }
+
+    fn is_our_room(&self, room: &str) -> bool {
+        Some(room) == self.relay_peer.as_deref()
+    }
+
+    fn record(&self, msg: Message) {
+        if let Some(store) = &self.store {
+            store.append(msg.kind, &msg.text);
+        }
+        if let Ok(mut lock) = self.dest.lock() {
+            lock.push(msg);
+            REDRAW.store(true, Ordering::Release);
+        }
+    }
+
+    /// Applies an edit to the latest message reading `old`, shows it as new if there is none.
+    fn edit(&self, kind: message::Kind, old: &str, new: String) {
+        if let Ok(mut lock) = self.dest.lock() {
+            let found = lock
+                .iter_mut()
+                .rev()
+                .find(|m| m.kind == kind && m.text == old);
+            if let Some(msg) = found {
+                msg.edit(new);
+                REDRAW.store(true, Ordering::Release);
+                return;
+            }
+        }
+        let mut msg = Message::new(kind, old.to_string());
+        msg.edit(new);
+        self.record(msg);
+    }
+
+    /// Handles anything the peer sent but a message.
+    fn control(&self, payload: protocol::Payload, unauthenticated: bool) {
+        match payload {
+            // handled by the reciever
+            protocol::Payload::Text(_)
+            | protocol::Payload::Message { .. }
+            | protocol::Payload::Talk(_) => {}
+            protocol::Payload::Edit { old, new } => self.edit(message::Kind::Incoming, &old, new),
+            protocol::Payload::Exec(name) if unauthenticated => {
+                warn!("ignoring unauthenticated request to run {name}");
+                self.record(Message::system(format!(
+                    "ignored an unauthenticated request to run {name}"
+                )));
+            }
+            protocol::Payload::Exec(name) => {
+                let _ = self.exec_requests.send(name);
+            }
+            protocol::Payload::ExecOutput(line) => {
+                self.record(Message::incoming(format!("│ {line}")))
+            }
+            protocol::Payload::ExecDone(status) => {
+                self.record(Message::incoming(format!("└ {status}")))
+            }
+            protocol::Payload::Pane(text) => {
+                let mut lock = self.pane.lock().expect("pane lock is poisoned");
+                let started = lock.as_ref().is_none_or(|view| view.ended.is_some());
+                *lock = Some(pane::View { text, ended: None });
+                drop(lock);
+                if started {
+                    self.record(Message::system(
+                        "the peer shares a pane, p closes it".to_string(),
+                    ));
+                }
+                REDRAW.store(true, Ordering::Release);
+            }
+            protocol::Payload::Location(point) => self.record(Message::incoming(point.describe())),
+            protocol::Payload::Live(enabled) => {
+                if !self.live.set_peer(enabled) {
+                    return;
+                }
+                let text = match (enabled, self.live.is_enabled()) {
+                    (true, true) => {
+                        // it may have turned it on before we did, and doesn't know we did
+                        let _ = self.controls.send(protocol::live(true));
+                        "peer types live now, you see its drafts"
+                    }
+                    (true, false) => "peer types live, /live to see each other's drafts",
+                    (false, _) => "peer stopped typing live",
+                };
+                self.record(Message::system(text.to_string()));
+            }
+            protocol::Payload::Preview(draft) if self.live.is_enabled() => {
+                self.live.set_preview(draft);
+                REDRAW.store(true, Ordering::Release);
+            }
+            protocol::Payload::Preview(_) => {}
+            payload @ (protocol::Payload::FileOffer { .. }
+            | protocol::Payload::FileAnswer { .. }
+            | protocol::Payload::FileChunk { .. }
+            | protocol::Payload::FileEnd { .. }
+            | protocol::Payload::FileAbort { .. }) => {
+                let _ = self.files.send(payload);
+            }
+            protocol::Payload::Typing(typing) => {
+                self.live.set_peer_typing(typing);
+                REDRAW.store(true, Ordering::Release);
+            }
+            protocol::Payload::Goodbye => {
+                self.record(Message::system("peer left the chat".to_string()))
+            }
+            protocol::Payload::Clock(sent) => {
+                let _ = self
+                    .controls
+                    .send(protocol::clock_echo(sent, timestamp::now_millis()));
+            }
+            protocol::Payload::ClockEcho { sent, peer } => {
+                let offset = self.skew.estimate(sent, peer, timestamp::now_millis());
+                info!("the clock of the peer is {offset}ms ahead of ours");
+                if offset.abs() >= skew::NOTABLE {
+                    let apart =
+                        timestamp::format_duration(Duration::from_millis(offset.unsigned_abs()));
+                    let side = if offset > 0 { "ahead of" } else { "behind" };
+                    self.record(Message::system(format!(
+                        "the clock of the peer is {apart} {side} ours, its times are shown by ours"
+                    )));
+                }
+            }
+            protocol::Payload::Received(id) => self.receipt(id, false),
+            protocol::Payload::Seen(id) => self.receipt(id, true),
+            protocol::Payload::Capabilities(names) => {
+                let offered = names.iter().any(|n| n == cbor::CAPABILITY);
+                if self.cbor.set_peer(offered) && self.cbor.is_active() {
+                    info!("the peer takes CBOR frames, sending them");
+                }
+            }
+            protocol::Payload::PaneEnd(status) => {
+                if let Some(view) = self.pane.lock().expect("pane lock is poisoned").as_mut() {
+                    view.ended = Some(status);
+                }
+                REDRAW.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Marks our messages up to `id` as seen, or only that one as received.
+    fn receipt(&self, id: u64, seen: bool) {
+        let Ok(mut lock) = self.dest.lock() else {
+            return;
+        };
+        for msg in lock.iter_mut().rev() {
+            match (msg.id, seen) {
+                (Some(i), false) if i == id && msg.delivery.is_none() => {
+                    msg.delivery = Some(relay::Delivery::Delivered)
+                }
+                (Some(i), true) if i <= id => msg.delivery = Some(relay::Delivery::Seen),
+                _ => continue,
+            }
+            REDRAW.store(true, Ordering::Release);
+        }
+    }
+
+    /// Updates the delivery state of the sent message `id`.
+    fn acknowledge(&self, id: u64, state: relay::Delivery) {
+        if let Ok(mut lock) = self.dest.lock() {
+            if let Some(msg) = lock.iter_mut().rev().find(|m| m.relay_id == Some(id)) {
+                msg.delivery = Some(state);
+                REDRAW.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+
+#[instrument(parent = &span, skip_all)]
+fn reciever(
+    mut reader: std::io::BufReader<Box<dyn Transport>>,
+    mut verifier: Option<deniable::Verifier>,
+    inbound: Inbound,
+    span: tracing::Span,
+) {
+    let mut buf = String::new();
+    tasks::set_state("reading");
+
+    'read: while !RESET.load(std::sync::atomic::Ordering::Acquire) {
+        let faulty = inbound.faulty.get();
+        if faulty >= fault::HOPELESS {
+            inbound.fault(Fault::Hopeless);
+            RESET.store(true, Ordering::Release);
+            break 'read;
+        }
+        match reader.read_line(&mut buf) {
+            Ok(size) => {
+                if size == 0 {
+                    warn!("May be other end is closed!");
+                    RESET.store(true, Ordering::Release);
+                    break 'read;
+                };
+                debug!("recieved data: {:?}", buf.as_bytes());
+                inbound.stats.record_received(size);
+                // the room of a --multi server it was written in
+                let mut room = None;
+                let (sender, line) = match relay::parse(buf.trim()) {
+                    relay::Frame::Other(line) => match broadcast::parse_presence(line) {
+                        Some((name, online)) => {
+                            inbound
+                                .presence
+                                .lock()
+                                .expect("presence lock is poisoned")
+                                .insert(name.to_string(), online);
+                            REDRAW.store(true, Ordering::Release);
+                            (None, "")
+                        }
+                        None => (None, line),
+                    },
+                    relay::Frame::From { sender, payload } => (Some(sender), payload),
+                    relay::Frame::Ack { id, state } => {
+                        inbound.acknowledge(id, state);
+                        (None, "")
+                    }
+                    relay::Frame::Sent { to, payload } => {
+                        let payload = inbound.open(payload).map(|(p, _)| p).unwrap_or_default();
+                        match (protocol::decode(&payload), inbound.relay_peer.as_deref()) {
+                            (protocol::Payload::Edit { old, new }, _) => {
+                                inbound.edit(message::Kind::Outgoing, &old, new)
+                            }
+                            (
+                                protocol::Payload::Text(text)
+                                | protocol::Payload::Message { text, .. },
+                                Some(peer),
+                            ) if peer != to => {
+                                inbound.record(Message::outgoing(format!("to {to}: {text}")))
+                            }
+                            (
+                                protocol::Payload::Text(text)
+                                | protocol::Payload::Message { text, .. },
+                                _,
+                            ) => inbound.record(Message::outgoing(text)),
+                            // the other device deals with its commands itself
+                            _ => {}
+                        }
+                        (None, "")
+                    }
+                    relay::Frame::Room {
+                        room,
+                        sender,
+                        payload,
+                    } if Some(room) == inbound.relay_peer.as_deref() => (Some(sender), payload),
+                    relay::Frame::Room {
+                        room: joined,
+                        sender,
+                        payload,
+                    } if inbound.relay_peer.is_none() => {
+                        room = Some(joined.to_string());
+                        (Some(sender), payload)
+                    }
+                    relay::Frame::Room {
+                        room: other,
+                        sender,
+                        ..
+                    } => {
+                        debug!("ignoring message of {sender} in {other}, we talk in another one");
+                        (None, "")
+                    }
+                    frame @ (relay::Frame::Topic { .. }
+                    | relay::Frame::Pin { .. }
+                    | relay::Frame::Kick { .. }
+                    | relay::Frame::Role { .. }
+                    | relay::Frame::Code { .. }
+                    | relay::Frame::Invite { .. }) => {
+                        inbound.room_frame(frame);
+                        (None, "")
+                    }
+                    relay::Frame::Listing(listing) => {
+                        let _ = inbound.listings.send(listing);
+                        (None, "")
+                    }
+                    relay::Frame::Read => {
+                        inbound.unread.store(0, Ordering::Relaxed);
+                        if let Some(store) = &inbound.store {
+                            store.mark_read();
+                        }
+                        REDRAW.store(true, Ordering::Release);
+                        (None, "")
+                    }
+                    relay::Frame::Error(reason) => {
+                        inbound.record(Message::system(format!("relay: {reason}")));
+                        (None, "")
+                    }
+                };
+                let (text, unauthenticated) = match verifier.as_mut().map(|v| v.verify(line)) {
+                    None => (line, false),
+                    Some(deniable::Incoming::Authentic(text)) => (text, false),
+                    Some(deniable::Incoming::Unauthenticated(text)) => (text, true),
+                    Some(deniable::Incoming::Revealed) => {
+                        inbound.record(Message::system(
+                            "peer published the session key, transcript is now deniable"
+                                .to_string(),
+                        ));
+                        ("", false)
+                    }
+                };
+                let (text, unauthenticated) = match inbound.open(text) {
+                    Some((text, unsealed)) => (text, unauthenticated || unsealed),
+                    None => (String::new(), unauthenticated),
+                };
+                // no point in printing empty message
+                if !text.is_empty() {
+                    let (text, envelope, data) = match protocol::decode(&text) {
+                        protocol::Payload::Text(text) => (text, None, None),
+                        protocol::Payload::Message {
+                            envelope,
+                            text,
+                            data,
+                        } => (text, Some(envelope), data),
+                        protocol::Payload::Talk(keys) => {
+                            let typed = inbound
+                                .peer_talk
+                                .lock()
+                                .expect("talk lock is poisoned")
+                                .apply(&keys);
+                            if typed.rang {
+                                talk::ring();
+                            }
+                            for line in typed.lines.into_iter().filter(|l| !l.is_empty()) {
+                                inbound.incoming(
+                                    sender,
+                                    line,
+                                    None,
+                                    None,
+                                    room.clone(),
+                                    unauthenticated,
+                                );
+                            }
+                            REDRAW.store(true, Ordering::Release);
+                            buf.clear();
+                            inbound.settle(faulty);
+                            continue;
+                        }
+                        payload => {
+                            inbound.control(payload, unauthenticated);
+                            buf.clear();
+                            inbound.settle(faulty);
+                            continue;
+                        }
+                    };
+                    // sent, it's not a draft anymore
+                    inbound.live.clear_preview();
+                    inbound.live.set_peer_typing(false);
+                    inbound
+                        .peer_talk
+                        .lock()
+                        .expect("talk lock is poisoned")
+                        .append_line(&text);
+                    inbound.incoming(sender, text, data, envelope, room, unauthenticated);
+                }
+                inbound.settle(faulty);
+            }
+            // the line is gone, the next one may be fine
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => inbound.fault(Fault::InvalidUtf8),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                inbound.fault(Fault::Io(e));
+                RESET.store(true, Ordering::Release);
+                break 'read;
+            }
+        }
+        buf.clear();
+    }
+}
+
+pub enum InputMode {
+    Normal,
+    Editing,
+}
+
+/// Whether the user compared the short authentication string with the peer
+pub enum Verification {
+    /// There is no authenticated session to verify
+    Unavailable,
+    /// Waiting for the user to compare the contained string
+    Pending(String),
+    Verified,
+    Rejected,
+}
+
+/// Text shown over everything else until closed with Esc
+pub struct Popup {
+    pub title: String,
+    pub lines: Vec<String>,
+}
+
+/// Quitting held up by unfinished work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quit {
+    /// The popup asks whether to wait, stay or quit anyway
+    Asking,
+    /// Quits once the queued messages are sent
+    WhenSent,
+}
+
+/// App holds the state of the application
+pub struct App {
+    /// Current value of the input box
+    pub input: String,
+    /// Byte offset of the cursor in `input`, always between two grapheme clusters so an emoji or
+    /// a letter with its accents is passed over as a whole
+    pub cursor_position: usize,
+    /// Current input mode
+    pub input_mode: InputMode,
+    /// Keys of the normal mode
+    pub keys: Bindings,
+    /// Draft and its cursor, put aside while the input holds a search
+    pub searching: Option<(String, usize)>,
+    /// Last text searched for, lowercase, its matches are highlighted
+    pub search: Option<String>,
+    pub macros: Macros,
+    /// History of recorded messages along with the selected one
+    pub messages: Arc<Mutex<StatefulList<Message>>>,
+    /// Signs outgoing messages when running in deniable mode
+    pub signer: Option<deniable::Signer>,
+    /// Verification state of the authenticated session, shown in the status bar
+    pub verification: Verification,
+    /// One off feedback shown in the status bar, e.g. for a mistyped command
+    pub notice: Option<String>,
+    /// Whether there is a peer to send messages to
+    pub connected: bool,
+    /// Thread reading the lines of the current connection
+    pub reader: Option<std::thread::JoinHandle<()>>,
+    /// Messages waiting for a connection
+    pub outbox: Outbox,
+    /// Position of each outbox entry in `messages`, in the same order
+    pub queued: VecDeque<usize>,
+    /// Settings, written back when changed from within the app
+    pub config: Config,
+    /// Canned responses expanded from `!name`
+    pub snippets: Snippets,
+    /// Commands standing for other commands and messages
+    pub aliases: Aliases,
+    /// Commands reaching the system clipboard
+    pub clipboard: Clipboard,
+    /// Colors of the interface, from the config
+    pub theme: Arc<Theme>,
+    /// Keeps the draft on disk
+    pub autosave: Autosave,
+    /// Draft of a run which didn't quit normally, while the popup offers it back
+    pub recovered_draft: Option<String>,
+    /// Rules run by the reciever against incoming messages
+    pub triggers: Arc<Mutex<Triggers>>,
+    /// Auto replies requested by triggers, handed over to `replies`
+    pub reply_sender: mpsc::Sender<String>,
+    pub replies: mpsc::Receiver<String>,
+    /// Scheduled with `/remind`
+    pub reminders: Reminders,
+    /// What timers and reminders go by
+    pub clock: SharedClock,
+    /// Where keys and nonces come from
+    pub rng: SharedRng,
+    /// Whether system messages are shown in the messages pane
+    pub show_system: bool,
+    /// strftime format of the time in front of the messages
+    pub timestamp_format: Arc<str>,
+    pub show_timestamps: bool,
+    /// Show messages as typed, without rendering math or tables
+    pub raw: bool,
+    /// Interpret ANSI colors in incoming messages
+    pub ansi: bool,
+    /// Horizontal scroll position of wide tables
+    pub table_scroll: usize,
+    /// Where histories are kept, `None` if nowhere
+    pub storage: Option<Arc<dyn store::Storage>>,
+    /// History of the current peer
+    pub store: Option<Store>,
+    /// History of the peer from before this run, read when scrolling past the first message
+    pub archive: Option<store::Archive>,
+    /// Recent log lines for the diagnostics bundle
+    pub logs: diag::LogRing,
+    pub stats: Arc<diag::Stats>,
+    /// Counters shown over the messages, for diagnosing a slow interface
+    pub hud: Option<diag::Hud>,
+    /// Span of the current connection
+    pub span: Option<tracing::Span>,
+    /// Id and address of the current peer
+    pub connection: Option<(u64, String)>,
+    /// Our end of the current connection
+    pub local_address: Option<std::net::SocketAddr>,
+    /// Interface or address the connections are bound to
+    pub source: Option<String>,
+    /// Options of the connections, from the config
+    pub socket: socket::Tuning,
+    /// Encryption of the current connection
+    pub tls: Option<tls::Session>,
+    /// Set when talking through a relay
+    pub relay: Option<relay::Route>,
+    /// Messages which arrived while the terminal wasn't focused, shared with the reciever
+    pub unread: Arc<AtomicUsize>,
+    /// Commands the peer may run
+    pub exec: exec::Exec,
+    /// Requests of the peer to run a command, handed over to `exec_requests`
+    pub exec_request_sender: mpsc::Sender<String>,
+    pub exec_requests: mpsc::Receiver<String>,
+    /// Control lines produced in the background, like the output of commands run for the peer
+    pub control_sender: mpsc::Sender<String>,
+    pub controls: mpsc::Receiver<String>,
+    /// Process id of the command shared with `/share-pane`
+    pub sharing: Option<u32>,
+    /// File we send, or offered
+    pub sending: Option<transfer::Outgoing>,
+    /// File the peer sends, or offered
+    pub receiving: Option<transfer::Incoming>,
+    /// Id of the next file we offer
+    pub next_file: u64,
+    /// Lines of the peer about files, handed over to `file_lines`
+    pub file_line_sender: mpsc::Sender<protocol::Payload>,
+    pub file_lines: mpsc::Receiver<protocol::Payload>,
+    /// What the reciever found wrong with the lines of the peer, handed over to `faults`
+    pub fault_sender: mpsc::Sender<Fault>,
+    pub faults: mpsc::Receiver<Fault>,
+    /// Pane the peer shares, shared with the reciever
+    pub pane: Arc<Mutex<Option<pane::View>>>,
+    /// Topic and pins of the relay room we talk in
+    pub room: Arc<Mutex<relay::RoomInfo>>,
+    /// Rooms of the `--multi` server we got into with `/join`
+    pub joined: Vec<String>,
+    /// Joined room our messages go to and the messages pane shows, everybody without one
+    pub current_room: Option<String>,
+    /// Whether each client of the `--multi` server is connected, as the server told, shared with
+    /// the reciever
+    pub presence: Arc<Mutex<BTreeMap<String, bool>>>,
+    /// Whether the sidebar lists them
+    pub show_users: bool,
+    /// Address book
+    pub contacts: Contacts,
+    pub popup: Option<Popup>,
+    /// Unread messages getting louder, counted from the first
+    pub missed: Option<alerts::Missed>,
+    pub quit: Option<Quit>,
+    /// Where messages for the bridge go
+    pub bridge: Option<mpsc::Sender<String>>,
+    /// Events of the bridge, handed over to `bridge_events`
+    pub bridge_event_sender: mpsc::Sender<bridge::Event>,
+    pub bridge_events: mpsc::Receiver<bridge::Event>,
+    /// Users on the other side of the bridge
+    pub roster: Vec<String>,
+    /// Messages posted to the webhook, handed over to `webhooks`
+    pub webhook_sender: mpsc::Sender<String>,
+    pub webhooks: mpsc::Receiver<String>,
+    /// Requests on the control socket of a detached session, handed over to `session_requests`
+    pub session_sender: mpsc::Sender<detach::Control>,
+    pub session_requests: mpsc::Receiver<detach::Control>,
+    /// Contact connected to with `--to`
+    pub dialing: Option<String>,
+    /// Name our messages are sent with, see [`App::set_name`]
+    pub name: String,
+    /// How much each conversation notifies of
+    pub alerts: Arc<Mutex<Rules>>,
+    /// Name of the current or last conversation, for the notify rules
+    pub conversation: Option<String>,
+    /// Alias of the current peer, if it is a contact
+    pub peer_alias: Option<String>,
+    /// Live typing, shared with the reciever
+    pub live: Arc<Live>,
+    /// Whether frames go out in CBOR, offered with --cbor
+    pub cbor: Arc<cbor::Negotiation>,
+    /// How far the clock of the peer is off ours, estimated by the reciever
+    pub skew: Arc<Skew>,
+    /// Id of the latest message of the peer the user hasn't seen, 0 if none, noted by the
+    /// reciever
+    pub unseen: Arc<AtomicU64>,
+    /// Id of the last message numbered for the receipts
+    pub last_id: u64,
+    /// Plays incoming messages as Morse code
+    pub beep: Arc<Beeper>,
+    /// Encrypts every payload, set with --key-phrase
+    pub seal: Option<Arc<Seal>>,
+    /// Encryption of the current connection
+    pub encryption: Option<encryption::Status>,
+    /// Conversations which were encrypted, any of them in plaintext is a downgrade
+    pub encrypted: encryption::Known,
+    /// The peer sent plaintext although the conversation is sealed, shared with the reciever
+    pub plaintext: Arc<AtomicBool>,
+    /// Our half of the screen, set in the talk mode
+    pub talking: Option<talk::Window>,
+    /// Half of the peer in the talk mode, shared with the reciever
+    pub peer_talk: Arc<Mutex<talk::Window>>,
+    /// Draft the peer saw last
+    pub previewed: String,
+    /// Draft when it was last looked at, and when it changed
+    pub typed: (String, Instant),
+    /// When the peer was last told we are typing, `None` while we aren't
+    pub told_typing: Option<Instant>,
+    /// Answers of the location provider, handed over to `locations`
+    pub location_sender: mpsc::Sender<Result<location::Point, String>>,
+    pub locations: mpsc::Receiver<Result<location::Point, String>>,
+    /// What `/who` of the others gets to hear, set with --discoverable
+    pub announcement: Option<Arc<Mutex<discovery::Announcement>>>,
+    /// Answers to `/who`, handed over to `who_answers`
+    pub who_sender: mpsc::Sender<io::Result<Vec<discovery::Peer>>>,
+    pub who_answers: mpsc::Receiver<io::Result<Vec<discovery::Peer>>>,
+    /// Pages of the directory of the relay, handed over to `listings`
+    pub listing_sender: mpsc::Sender<relay::Listing>,
+    pub listings: mpsc::Receiver<relay::Listing>,
+}
+
+/// Columns `text` takes in the input box, where a tab is shown as an arrow.
+pub fn input_width(text: &str) -> usize {
+    text.width() + text.matches('\t').count()
+}
+
+/// Login name of the user, the name messages are sent with.
+fn default_name() -> String {
+    ["USER", "LOGNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|name| !name.is_empty()))
+        .unwrap_or_else(|| "anonymous".to_string())
+}
+
+impl Default for App {
+    fn default() -> App {
+        let (reply_sender, replies) = mpsc::channel();
+        let (exec_request_sender, exec_requests) = mpsc::channel();
+        let (file_line_sender, file_lines) = mpsc::channel();
+        let (fault_sender, faults) = mpsc::channel();
+        let (control_sender, controls) = mpsc::channel();
+        let (location_sender, locations) = mpsc::channel();
+        let (who_sender, who_answers) = mpsc::channel();
+        let (listing_sender, listings) = mpsc::channel();
+        let (bridge_event_sender, bridge_events) = mpsc::channel();
+        let (webhook_sender, webhooks) = mpsc::channel();
+        let (session_sender, session_requests) = mpsc::channel();
+        App {
+            input: String::new(),
+            input_mode: InputMode::Normal,
+            keys: Bindings::default(),
+            searching: None,
+            search: None,
+            macros: Macros::default(),
+            messages: Arc::new(Mutex::new(StatefulList::default())),
+            cursor_position: 0,
+            signer: None,
+            verification: Verification::Unavailable,
+            notice: None,
+            connected: false,
+            reader: None,
+            outbox: Outbox::default(),
+            queued: VecDeque::new(),
+            config: Config::default(),
+            snippets: Snippets::default(),
+            aliases: Aliases::default(),
+            clipboard: Clipboard::default(),
+            theme: Arc::default(),
+            autosave: Autosave::default(),
+            recovered_draft: None,
+            triggers: Arc::default(),
+            reply_sender,
+            replies,
+            reminders: Reminders::default(),
+            clock: SharedClock::default(),
+            rng: SharedRng::default(),
+            show_system: true,
+            timestamp_format: Arc::from(DEFAULT_TIMESTAMP_FORMAT),
+            show_timestamps: true,
+            raw: false,
+            ansi: false,
+            table_scroll: 0,
+            storage: None,
+            store: None,
+            archive: None,
+            logs: diag::LogRing::default(),
+            stats: Arc::default(),
+            hud: None,
+            span: None,
+            connection: None,
+            local_address: None,
+            source: None,
+            socket: socket::Tuning::default(),
+            tls: None,
+            relay: None,
+            unread: Arc::default(),
+            exec: exec::Exec::default(),
+            exec_request_sender,
+            exec_requests,
+            sending: None,
+            receiving: None,
+            next_file: 1,
+            file_line_sender,
+            file_lines,
+            fault_sender,
+            faults,
+            control_sender,
+            controls,
+            sharing: None,
+            pane: Arc::default(),
+            room: Arc::default(),
+            joined: Vec::new(),
+            current_room: None,
+            presence: Arc::default(),
+            show_users: true,
+            location_sender,
+            locations,
+            announcement: None,
+            who_sender,
+            who_answers,
+            listing_sender,
+            listings,
+            contacts: Contacts::default(),
+            popup: None,
+            missed: None,
+            quit: None,
+            bridge: None,
+            bridge_event_sender,
+            bridge_events,
+            roster: Vec::new(),
+            webhook_sender,
+            session_sender,
+            session_requests,
+            webhooks,
+            dialing: None,
+            name: default_name(),
+            alerts: Arc::default(),
+            conversation: None,
+            peer_alias: None,
+            live: Arc::default(),
+            cbor: Arc::default(),
+            unseen: Arc::default(),
+            last_id: 0,
+            skew: Arc::default(),
+            beep: Arc::default(),
+            seal: None,
+            encryption: None,
+            encrypted: encryption::Known::default(),
+            plaintext: Arc::default(),
+            previewed: String::new(),
+            typed: (String::new(), Instant::now()),
+            told_typing: None,
+            talking: None,
+            peer_talk: Arc::default(),
+        }
+    }
+}
+
+impl App {
+    pub fn move_cursor_left(&mut self) {
+        self.cursor_position = self.input[..self.cursor_position]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i);
+    }
+
+    pub fn move_cursor_right(&mut self) {
+        let cursor = self.cursor_position;
+        if let Some(grapheme) = self.input[cursor..].graphemes(true).next() {
+            self.cursor_position = cursor + grapheme.len();
+        }
+    }
+
+    /// Moves the cursor to the line above, keeping its column where the line is long enough.
+    pub fn move_cursor_up(&mut self) {
+        let (line, column) = self.cursor_line();
+        if line > 0 {
+            self.cursor_position = self.position_at(line - 1, column);
+        }
+    }
+
+    pub fn move_cursor_down(&mut self) {
+        let (line, column) = self.cursor_line();
+        if line + 1 < self.input.split('\n').count() {
+            self.cursor_position = self.position_at(line + 1, column);
+        }
+    }
+
+    /// Line of the input the cursor is on and the column it is shown in there, both counted
+    /// from 0. Wide characters like CJK take two columns.
+    pub fn cursor_line(&self) -> (usize, usize) {
+        let before = &self.input[..self.cursor_position];
+        let line = before.matches('\n').count();
+        let column = input_width(before.rsplit('\n').next().unwrap_or_default());
+        (line, column)
+    }
+
+    /// Position shown in `column` on `line` of the input, or the one just before when a wide
+    /// character covers it, or the end of a shorter line.
+    fn position_at(&self, line: usize, column: usize) -> usize {
+        let lines: Vec<_> = self.input.split('\n').collect();
+        let before: usize = lines[..line].iter().map(|l| l.len() + 1).sum();
+        let mut width = 0;
+        for (i, grapheme) in lines[line].grapheme_indices(true) {
+            width += input_width(grapheme);
+            if width > column {
+                return before + i;
+            }
+        }
+        before + lines[line].len()
+    }
+
+    pub fn enter_char(&mut self, new_char: char) {
+        // characters committed by an input method are often several bytes long
+        self.input.insert(self.cursor_position, new_char);
+        // a combining mark or joiner may make it part of the cluster after it
+        self.cursor_position = self.clamp_cursor(self.cursor_position + new_char.len_utf8());
+    }
+
+    /// Inserts pasted text at the cursor, line breaks included.
+    pub fn paste(&mut self, text: &str) {
+        for c in text.replace("\r\n", "\n").chars() {
+            self.enter_char(if c == '\r' { '\n' } else { c });
+        }
+    }
+
+    /// Deletes the grapheme cluster before the cursor, an emoji goes as a whole.
+    pub fn delete_char(&mut self) {
+        let end = self.cursor_position;
+        self.move_cursor_left();
+        self.input.replace_range(self.cursor_position..end, "");
+    }
+
+    /// Whether it can quit right away, otherwise it asks what to do about the unfinished work.
+    pub fn request_quit(&mut self) -> bool {
+        let queued = self.outbox.pending().count();
+        let mut lines = Vec::new();
+        if queued > 0 {
+            lines.push(format!("{queued} queued messages, kept for the next run"));
+        }
+        if !self.input.is_empty() {
+            lines.push("the draft in the input box is lost".to_string());
+        }
+        if let Some(outgoing) = self.sending.as_ref().filter(|o| o.lines.is_some()) {
+            lines.push(format!("sending {} is cut off", outgoing.name));
+        }
+        if let Some(incoming) = self.receiving.as_ref().filter(|i| i.is_accepted()) {
+            lines.push(format!("receiving {} is cut off", incoming.name));
+        }
+        if lines.is_empty() {
+            return true;
+        }
+        lines.push(String::new());
+        if queued > 0 {
+            lines.push("w waits until the queued messages are sent".to_string());
+        }
+        lines.push("f quits anyway".to_string());
+        self.popup = Some(Popup {
+            title: "Quit?".to_string(),
+            lines,
+        });
+        self.quit = Some(Quit::Asking);
+        false
+    }
+
+    /// Whether what quitting waited for is done.
+    pub fn ready_to_quit(&self) -> bool {
+        self.quit == Some(Quit::WhenSent) && self.outbox.is_empty()
+    }
+
+    /// Says goodbye to the peer and closes the connection once the reciever is done with it.
+    /// Publishes the deniable session key first, so that the transcript can't be attributed to
+    /// anyone.
+    pub fn end_session(&mut self, mut stream: &mut dyn Transport) {
+        if let Some(signer) = self.signer.take() {
+            if let Err(e) = stream.write_all(signer.reveal().as_bytes()) {
+                warn!("Failed to reveal session key {e}");
+            }
+        }
+        // on a relay the conversation goes on without us
+        if self.relay.is_none() {
+            self.send_control(Some(&mut stream), &protocol::goodbye());
+        }
+        if let Err(e) = stream.flush().and_then(|()| stream.shutdown()) {
+            warn!("Failed to close the connection {e}");
+        }
+        if let Some(reader) = self.reader.take() {
+            if reader.join().is_err() {
+                warn!("The reciever panicked");
+            }
+        }
+    }
+
+    /// Whether `msg` passes the filters of the messages pane.
+    pub fn is_shown(&self, msg: &Message) -> bool {
+        match msg.kind {
+            message::Kind::System => self.show_system,
+            _ => msg.room == self.current_room,
+        }
+    }
+
+    /// Talks in the next joined room, after the last one to everybody.
+    pub fn next_room(&mut self) {
+        if self.joined.is_empty() {
+            self.notice = Some("no rooms joined, /join one".to_string());
+            return;
+        }
+        let next = match &self.current_room {
+            None => self.joined.first(),
+            Some(room) => self.joined.iter().skip_while(|r| *r != room).nth(1),
+        };
+        self.switch_room(next.cloned());
+    }
+
+    pub fn toggle_users(&mut self) {
+        if self
+            .presence
+            .lock()
+            .expect("presence lock is poisoned")
+            .is_empty()
+        {
+            self.notice =
+                Some("nobody to list, only --multi servers tell who is there".to_string());
+            return;
+        }
+        self.show_users = !self.show_users;
+    }
+
+    fn switch_room(&mut self, room: Option<String>) {
+        self.notice = Some(match &room {
+            Some(room) => format!("talking in {room}"),
+            None => "talking to everybody".to_string(),
+        });
+        self.current_room = room;
+        self.unselect_message();
+    }
+
+    /// What `f` makes of the selected message, if any.
+    fn with_selected<T>(&self, f: impl FnOnce(&Message) -> T) -> Option<T> {
+        let lock = self.messages.lock().expect("messages lock is poisoned");
+        lock.selected().and_then(|i| lock.get(i)).map(f)
+    }
+
+    /// Copies the selected message to the system clipboard.
+    pub fn yank(&mut self) {
+        let Some(text) = self.with_selected(|m| m.text.clone()) else {
+            self.notice = Some("select a message to copy it, j and k move".to_string());
+            return;
+        };
+        self.notice = Some(match self.clipboard.copy(&text) {
+            Ok(()) => "copied the message".to_string(),
+            Err(e) => format!("failed to copy: {e}"),
+        });
+    }
+
+    /// Starts a reply below the input with the selected message quoted, every line behind `> `.
+    pub fn quote(&mut self) {
+        let Some(text) = self.with_selected(|m| m.text.clone()) else {
+            self.notice = Some("select a message to quote it, j and k move".to_string());
+            return;
+        };
+        if !self.input.is_empty() {
+            self.input.push('\n');
+        }
+        for line in text.lines() {
+            self.input.push_str("> ");
+            self.input.push_str(line);
+            self.input.push('\n');
+        }
+        self.unselect_message();
+        self.append();
+    }
+
+    /// Shows everything known about the selected message in a popup.
+    pub fn show_details(&mut self) {
+        let Some(lines) = self.with_selected(details) else {
+            self.notice = Some("select a message to see its details, j and k move".to_string());
+            return;
+        };
+        self.popup = Some(Popup {
+            title: "Message".to_string(),
+            lines,
+        });
+    }
+
+    /// Inserts the text on the system clipboard at the cursor.
+    pub fn paste_clipboard(&mut self) {
+        match self.clipboard.paste() {
+            Ok(text) => self.paste(&text),
+            Err(e) => self.notice = Some(format!("failed to paste: {e}")),
+        }
+    }
+
+    pub fn select_next_message(&mut self) {
+        if let Ok(mut lock) = self.messages.lock() {
+            lock.select_next(|m| self.is_shown(m));
+        }
+    }
+
+    pub fn select_previous_message(&mut self) {
+        if let Ok(mut lock) = self.messages.lock() {
+            let before = lock.selected();
+            lock.select_previous(|m| self.is_shown(m));
+            if lock.selected() != before {
+                return;
+            }
+            // at the top, go on with what was archived
+            let Some(archive) = &mut self.archive else {
+                return;
+            };
+            lock.prepend(archived(archive, ARCHIVE_PAGE));
+            lock.select_previous(|m| self.is_shown(m));
+        }
+    }
+
+    /// Shows the end of the history, going back as far as the messages which weren't read when
+    /// we last left.
+    fn restore(&mut self, store: &Store) {
+        let (Some(archive), Ok(mut lock)) = (&mut self.archive, self.messages.lock()) else {
+            return;
+        };
+        let marker = store.read_marker();
+        let mut records = archive.older(RESTORED);
+        while let (Some(marker), Some(first)) = (marker, records.first()) {
+            if first.at <= marker {
+                break;
+            }
+            let older = archive.older(ARCHIVE_PAGE);
+            if older.is_empty() {
+                break;
+            }
+            records.splice(0..0, older);
+        }
+        let is_unread = |r: &store::Record| {
+            r.kind == message::Kind::Incoming && marker.is_some_and(|m| r.at > m)
+        };
+        let first_unread = records.iter().position(is_unread);
+        let unread = records.iter().filter(|r| is_unread(r)).count();
+        let mut restored: Vec<Message> = records.into_iter().map(archived_message).collect();
+        if let Some(i) = first_unread {
+            restored.insert(i, Message::system("unread since you left".to_string()));
+            self.unread.fetch_add(unread, Ordering::Relaxed);
+        }
+        lock.prepend(restored);
+    }
+
+    /// Changes the name our messages are sent with, which is also what makes a message a mention.
+    pub fn set_name(&mut self, name: String) {
+        self.alerts.lock().expect("alerts lock is poisoned").name = name.clone();
+        if let Some(announcement) = &self.announcement {
+            announcement
+                .lock()
+                .expect("announcement lock is poisoned")
+                .name = name.clone();
+        }
+        self.name = name;
+    }
+
+    /// Scrolls the messages back by `lines`, dropping the selection which would pin them.
+    pub fn scroll_back(&mut self, lines: usize) {
+        if let Ok(mut lock) = self.messages.lock() {
+            lock.unselect();
+            lock.scroll_back(lines);
+        }
+    }
+
+    pub fn scroll_forward(&mut self, lines: usize) {
+        if let Ok(mut lock) = self.messages.lock() {
+            lock.unselect();
+            lock.scroll_forward(lines);
+        }
+    }
+
+    pub fn toggle_system_messages(&mut self) {
+        self.show_system = !self.show_system;
+    }
+
+    pub fn toggle_timestamps(&mut self) {
+        self.show_timestamps = !self.show_timestamps;
+    }
+
+    /// Unselects the message and stops highlighting the matches of the search.
+    pub fn unselect_message(&mut self) {
+        self.search = None;
+        if let Ok(mut lock) = self.messages.lock() {
+            lock.unselect();
+        }
+    }
+
+    /// Selects the oldest message there is without going back to the archive.
+    pub fn select_first_message(&mut self) {
+        if let Ok(mut lock) = self.messages.lock() {
+            lock.unselect();
+            lock.select_next(|m| self.is_shown(m));
+        }
+    }
+
+    /// Goes back to the newest messages, following them as they come.
+    pub fn select_last_message(&mut self) {
+        self.scroll_forward(usize::MAX);
+    }
+
+    /// Starts editing with the cursor at the end of the input.
+    pub fn append(&mut self) {
+        self.cursor_position = self.input.len();
+        self.input_mode = InputMode::Editing;
+    }
+
+    /// Starts editing on a new line below the input, unless there is nothing above it.
+    pub fn open_line(&mut self) {
+        self.append();
+        if !self.input.is_empty() {
+            self.enter_char('\n');
+        }
+    }
+
+    /// Puts the draft aside while a search is typed in the input.
+    pub fn start_search(&mut self) {
+        self.searching = Some((std::mem::take(&mut self.input), self.cursor_position));
+        self.cursor_position = 0;
+        self.input_mode = InputMode::Editing;
+    }
+
+    /// Brings the draft back, having selected the message closest above the selection which
+    /// contains what was typed when `run`.
+    pub fn finish_search(&mut self, run: bool) {
+        let Some((draft, cursor)) = self.searching.take() else {
+            return;
+        };
+        let query = std::mem::replace(&mut self.input, draft).to_lowercase();
+        self.cursor_position = cursor;
+        self.input_mode = InputMode::Normal;
+        let query = query.trim();
+        if !run || query.is_empty() {
+            return;
+        }
+        self.search = Some(query.to_string());
+        self.find(true);
+    }
+
+    /// Text searched for, what is typed while searching, highlighted in the messages pane.
+    pub fn search_query(&self) -> Option<String> {
+        match &self.searching {
+            Some(_) => Some(self.input.trim().to_lowercase()).filter(|q| !q.is_empty()),
+            None => self.search.clone(),
+        }
+    }
+
+    pub fn matches(&self, msg: &Message, query: &str) -> bool {
+        self.is_shown(msg) && msg.text.to_lowercase().contains(query)
+    }
+
+    /// Selects the closest match of the last search above the selection, or below it unless
+    /// `older`, and scrolls it to the top.
+    pub fn find(&mut self, older: bool) {
+        let Some(query) = self.search.clone() else {
+            self.notice = Some("nothing searched for yet, / searches".to_string());
+            return;
+        };
+        if let Ok(mut lock) = self.messages.lock() {
+            let before = lock.selected();
+            match older {
+                true => lock.select_previous(|m| self.matches(m, &query)),
+                // nothing selected is below the newest message already
+                false if before.is_none() => {}
+                false => lock.select_next(|m| self.matches(m, &query)),
+            }
+            if lock.selected() == before {
+                let place = if older { "above" } else { "below" };
+                self.notice = Some(format!("no message {place} contains {query:?}"));
+                return;
+            }
+            lock.show_selected(|m| self.is_shown(m));
+        }
+    }
+
+    /// Starts recording into, or replays, the register typed after `q` or `@`.
+    pub fn macro_register(&mut self, awaiting: macros::Awaiting, key: &KeyEvent) {
+        let register = match key.code {
+            KeyCode::Char(c) if macros::is_register(c) => c,
+            KeyCode::Char('@') if awaiting == macros::Awaiting::Replay => '@',
+            KeyCode::Esc => return,
+            _ => {
+                self.notice = Some("registers are lowercase letters and digits".to_string());
+                return;
+            }
+        };
+        match awaiting {
+            macros::Awaiting::Record => self.macros.start(register),
+            macros::Awaiting::Replay => {
+                if self.macros.replay(register).is_none() {
+                    self.notice = Some(format!("nothing recorded in @{register}"));
+                }
+            }
+        }
+    }
+
+    /// Ends recording a macro and saves it in the config.
+    pub fn stop_recording(&mut self) {
+        let Some((register, keys)) = self.macros.stop() else {
+            return;
+        };
+        let text = macros::encode(keys);
+        let register = register.to_string();
+        if let Err(e) = self.config.set_string(macros::TABLE, &register, &text) {
+            error!("Failed to save macro {register}: {e}");
+            self.notice = Some(format!("macro is not saved: {e}"));
+        }
+    }
+
+    /// First position at or after `position` which is between two grapheme clusters.
+    pub fn clamp_cursor(&self, position: usize) -> usize {
+        self.input
+            .grapheme_indices(true)
+            .map(|(i, _)| i)
+            .find(|&i| i >= position)
+            .unwrap_or(self.input.len())
+    }
+    pub fn reset_cursor(&mut self) {
+        self.cursor_position = 0;
+    }
+
+    /// Replaces the `!name` word right before the cursor with its snippet.
+    pub fn expand_snippet(&mut self) {
+        let cursor = self.cursor_position;
+        let start = self.input[..cursor]
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + 1);
+        if let Some(snippet) = self.snippets.lookup(&self.input[start..cursor]) {
+            let snippet = snippet.to_string();
+            self.input.replace_range(start..cursor, &snippet);
+            self.cursor_position = start + snippet.len();
+        }
+    }
+
+    pub fn load_config(&mut self, config: Config) {
+        self.snippets = Snippets::from_config(&config);
+        self.aliases = Aliases::from_config(&config);
+        self.clipboard = Clipboard::from_config(&config);
+        self.theme = Arc::new(Theme::from_config(&config));
+        self.exec = exec::Exec::from_config(&config);
+        self.storage = store::from_config(&config);
+        self.socket = socket::Tuning::from_config(&config);
+        self.keys = Bindings::from_config(&config);
+        self.macros = Macros::from_config(&config);
+        *self.alerts.lock().expect("alerts lock is poisoned") =
+            Rules::from_config(&config, self.name.clone());
+        self.config = config;
+        self.reload_triggers();
+    }
+
+    pub fn reload_triggers(&mut self) {
+        let triggers = Triggers::from_config(&self.config);
+        *self.triggers.lock().expect("triggers lock is poisoned") = triggers;
+    }
+
+    pub fn run_command(&mut self, writer: Option<&mut impl std::io::Write>, command: Command) {
+        match command {
+            Command::Confirm | Command::Deny
+                if !matches!(self.verification, Verification::Pending(_)) =>
+            {
+                self.notice = Some("nothing to verify".to_string());
+            }
+            Command::Confirm => {
+                self.verification = Verification::Verified;
+                self.record(Message::system("session verified".to_string()));
+            }
+            Command::Deny => {
+                warn!("user reported a short authentication string mismatch");
+                self.verification = Verification::Rejected;
+                self.record(Message::system(
+                    "authentication strings differ, the peer may be impersonated".to_string(),
+                ));
+            }
+            Command::Snippets => {
+                let names: Vec<_> = self.snippets.names().collect();
+                self.notice = Some(if names.is_empty() {
+                    "no snippets, add one with /snippet add <name> <text>".to_string()
+                } else {
+                    format!("snippets: {}", names.join(", "))
+                });
+            }
+            Command::SnippetAdd { name, text } => {
+                if let Err(e) = self.config.set_string(snippets::TABLE, &name, &text) {
+                    error!("Failed to save snippet {name}: {e}");
+                    self.notice = Some(format!("snippet is not saved: {e}"));
+                }
+                self.snippets.insert(name, text);
+            }
+            Command::SnippetRemove(name) => {
+                if !self.snippets.remove(&name) {
+                    self.notice = Some(format!("no snippet named {name}"));
+                } else if let Err(e) = self.config.remove(snippets::TABLE, &name) {
+                    error!("Failed to remove snippet {name}: {e}");
+                    self.notice = Some(format!("snippet is not removed from config: {e}"));
+                }
+            }
+            Command::Triggers => {
+                let triggers = self.triggers.lock().expect("triggers lock is poisoned");
+                let list: Vec<_> = triggers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, t)| format!("{}: {t}", i + 1))
+                    .collect();
+                self.notice = Some(if list.is_empty() {
+                    "no triggers, add one with /triggers add <action> <pattern>".to_string()
+                } else {
+                    list.join(", ")
+                });
+            }
+            Command::TriggerAdd { pattern, action } => {
+                if let Err(e) = regex::Regex::new(&pattern) {
+                    self.notice = Some(format!("invalid pattern: {e}"));
+                    return;
+                }
+                let mut entries = vec![("pattern", pattern.as_str()), ("action", action.name())];
+                entries.extend(action.argument().map(|arg| ("argument", arg)));
+                if let Err(e) = self.config.push_table(triggers::TABLE, &entries) {
+                    error!("Failed to save trigger: {e}");
+                    self.notice = Some(format!("trigger is not saved: {e}"));
+                }
+                self.reload_triggers();
+            }
+            Command::TriggerRemove(position) => {
+                let index = self
+                    .triggers
+                    .lock()
+                    .expect("triggers lock is poisoned")
+                    .config_index(position - 1);
+                match index {
+                    Some(index) => {
+                        if let Err(e) = self.config.remove_table(triggers::TABLE, index) {
+                            error!("Failed to remove trigger: {e}");
+                            self.notice = Some(format!("trigger is not removed from config: {e}"));
+                        }
+                        self.reload_triggers();
+                    }
+                    None => self.notice = Some(format!("no trigger #{position}")),
+                }
+            }
+            Command::Reminders => {
+                let list: Vec<_> = self
+                    .reminders
+                    .pending()
+                    .map(|(left, text)| format!("in {}: {text}", timestamp::format_duration(left)))
+                    .collect();
+                self.notice = Some(if list.is_empty() {
+                    "no reminders, set one with /remind <duration> <text>".to_string()
+                } else {
+                    list.join(", ")
+                });
+            }
+            Command::Remind { after, text } => {
+                self.notice = Some(format!(
+                    "reminder set for {} from now",
+                    timestamp::format_duration(after)
+                ));
+                self.reminders.add(after, text);
+            }
+            Command::Edit(text) => self.edit_message(writer, text),
+            Command::Exec(_) if writer.is_none() => {
+                self.notice = Some("not connected".to_string());
+            }
+            Command::Exec(name) => {
+                self.send_control(writer, &protocol::exec(&name));
+                self.record(Message::system(format!("asked the peer to run {name}")));
+            }
+            Command::Accept | Command::Refuse
+                if self.exec.pending.is_none() && !self.file_offered() =>
+            {
+                self.notice = Some("the peer isn't asking for anything".to_string());
+            }
+            Command::Accept if self.exec.pending.is_none() => self.accept_file(writer),
+            Command::Refuse if self.exec.pending.is_none() => {
+                let incoming = self.receiving.take().expect("checked above");
+                self.send_control(writer, &protocol::file_answer(incoming.id, false));
+                self.record(Message::system(format!("declined {}", incoming.name)));
+            }
+            Command::SendFile(_) if writer.is_none() => {
+                self.notice = Some("not connected".to_string());
+            }
+            Command::SendFile(_) if self.relay.is_some() => {
+                self.notice = Some("sending files needs a direct connection".to_string());
+            }
+            Command::SendFile(_) if self.sending.is_some() => {
+                self.notice = Some("already sending a file, one at a time".to_string());
+            }
+            Command::SendFile(path) => match transfer::Outgoing::new(self.next_file, &path) {
+                Ok(mut outgoing) => {
+                    self.next_file += 1;
+                    let line = protocol::file_offer(outgoing.id, outgoing.size, &outgoing.name);
+                    self.send_control(writer, &line);
+                    outgoing.shown = format!(
+                        "offered {} ({}), waiting for the peer to accept",
+                        outgoing.name,
+                        transfer::describe_size(outgoing.size)
+                    );
+                    self.record(Message::system(outgoing.shown.clone()));
+                    self.sending = Some(outgoing);
+                }
+                Err(e) => self.notice = Some(format!("can't send {}: {e}", path.display())),
+            },
+            Command::Accept => {
+                let name = self.exec.pending.take().expect("checked above");
+                let cmd = self
+                    .exec
+                    .command(&name)
+                    .expect("only allowed ones are pending");
+                let cmd = cmd.to_string();
+                match exec::spawn(&cmd, self.control_sender.clone()) {
+                    Ok(()) => self.record(Message::system(format!("running {cmd} for the peer"))),
+                    Err(e) => {
+                        error!("Failed to run {cmd:?}: {e}");
+                        self.send_control(writer, &protocol::exec_done(&e.to_string()));
+                        self.record(Message::system(format!("failed to run {cmd}: {e}")))
+                    }
+                };
+            }
+            Command::SharePane(Some(_)) if self.sharing.is_some() => {
+                self.notice = Some("already sharing a pane, /share-pane stops it".to_string());
+            }
+            Command::SharePane(Some(cmd)) => match pane::share(&cmd, self.control_sender.clone()) {
+                Ok(pid) => {
+                    self.sharing = Some(pid);
+                    self.record(Message::system(format!("sharing the pane of {cmd}")));
+                }
+                Err(e) => {
+                    error!("Failed to share {cmd:?}: {e}");
+                    self.notice = Some(format!("failed to share {cmd}: {e}"));
+                }
+            },
+            Command::SharePane(None) => match self.sharing {
+                Some(pid) => pane::stop(pid),
+                None => self.notice = Some("usage: /share-pane <command>".to_string()),
+            },
+            Command::Location(Some(point)) => self.share_location(writer, Ok(point)),
+            Command::Location(None) => match location::provider(&self.config) {
+                Some(cmd) => {
+                    location::locate(cmd, self.location_sender.clone());
+                    self.notice = Some("locating…".to_string());
+                }
+                None => self.notice = Some(
+                    "usage: /location <latitude> <longitude>, or set a provider under [location]"
+                        .to_string(),
+                ),
+            },
+            Command::Contacts => {
+                let list: Vec<_> = self.contacts.iter().map(ToString::to_string).collect();
+                self.notice = Some(if list.is_empty() {
+                    "no contacts, add one with /contacts add <alias> <address>[:<port>]".to_string()
+                } else {
+                    list.join(", ")
+                });
+            }
+            Command::ContactAdd {
+                alias,
+                address,
+                port,
+            } => {
+                self.notice = Some(format!("added {alias}"));
+                self.contacts.insert(contacts::Contact {
+                    alias,
+                    address: Some(address),
+                    port,
+                    ..contacts::Contact::default()
+                });
+            }
+            Command::ContactSet {
+                alias,
+                field,
+                value,
+            } => {
+                if let Err(e) = self.contacts.set(&alias, &field, &value) {
+                    self.notice = Some(e);
+                }
+            }
+            Command::Live if self.relay.is_some() => {
+                self.notice = Some("live typing needs a direct connection".to_string());
+            }
+            Command::Live => {
+                let enabled = !self.live.is_enabled();
+                self.live.set_enabled(enabled);
+                self.previewed.clear();
+                self.send_control(writer, &protocol::live(enabled));
+                self.record(Message::system(
+                    match (enabled, self.live.is_active()) {
+                        (true, true) => "typing live, the peer sees your drafts",
+                        (true, false) => "typing live once the peer turns it on as well",
+                        (false, _) => "stopped typing live",
+                    }
+                    .to_string(),
+                ));
+            }
+            Command::Nick(None) => self.notice = Some(format!("you are {}", self.name)),
+            Command::Nick(Some(_)) if self.relay.is_some() => {
+                self.notice =
+                    Some("the relay knows you by the name you connected with".to_string());
+            }
+            Command::Nick(Some(name)) => {
+                self.record(Message::system(format!("you are now known as {name}")));
+                self.set_name(name);
+            }
+            Command::Clear => {
+                if let Ok(mut lock) = self.messages.lock() {
+                    lock.clear();
+                }
+                // or scrolling back to the top would bring it back
+                self.archive = None;
+            }
+            Command::Purge { confirmed } => match self.store.clone() {
+                None => self.notice = Some("no conversation, no history".to_string()),
+                Some(store) if !confirmed => {
+                    self.notice = Some(format!(
+                        "/purge yes deletes the whole history with {} for good",
+                        store.peer()
+                    ));
+                }
+                Some(store) => match store.purge() {
+                    Ok(()) => {
+                        // nothing left to scroll back to
+                        self.archive = None;
+                        self.notice = Some(format!("deleted the history with {}", store.peer()));
+                    }
+                    Err(e) => self.notice = Some(format!("history is not deleted: {e}")),
+                },
+            },
+            Command::ReloadHistory => match self.store.clone().map(|s| (s.archive(), s)) {
+                None => self.notice = Some("no conversation, no history".to_string()),
+                Some((Ok(archive), store)) => {
+                    if let Ok(mut lock) = self.messages.lock() {
+                        lock.clear();
+                    }
+                    self.archive = Some(archive);
+                    // counted again from how far it was read
+                    self.unread.store(0, Ordering::Relaxed);
+                    self.restore(&store);
+                }
+                Some((Err(e), _)) => self.notice = Some(format!("history is not reloaded: {e}")),
+            },
+            Command::Notify {
+                level,
+                conversation,
+            } => {
+                let Some(conversation) = conversation.or_else(|| self.conversation.clone()) else {
+                    self.notice = Some("no conversation yet, name one or default".to_string());
+                    return;
+                };
+                let mut rules = self.alerts.lock().expect("alerts lock is poisoned");
+                let Some(level) = level else {
+                    let level = rules.level(&conversation);
+                    self.notice = Some(format!("{conversation} notifies of {}", level.describe()));
+                    return;
+                };
+                rules.set(&conversation, level);
+                drop(rules);
+                if let Err(e) =
+                    self.config
+                        .set_string(alerts::TABLE, &conversation, &level.to_string())
+                {
+                    error!("Failed to save the notify level of {conversation}: {e}");
+                    self.notice = Some(format!("notify level is not saved: {e}"));
+                }
+                self.record(Message::system(format!(
+                    "{conversation} notifies of {}",
+                    level.describe()
+                )));
+            }
+            Command::Beep if self.beep.is_enabled() => {
+                self.beep.disable();
+                self.record(Message::system("stopped beeping".to_string()));
+            }
+            Command::Beep => {
+                self.beep.enable(&self.config);
+                self.record(Message::system(
+                    "playing incoming messages as Morse code".to_string(),
+                ));
+            }
+            Command::PeerInfo => {
+                let mut lines = vec![match &self.connection {
+                    Some((_, peer)) => format!("peer: {peer}"),
+                    None => "peer: not connected".to_string(),
+                }];
+                lines.extend(self.local_address.map(|a| format!("local address: {a}")));
+                lines.push(format!(
+                    "bound to: {}",
+                    self.source.as_deref().unwrap_or("whatever the routes pick")
+                ));
+                if self.connection.is_some() {
+                    lines.push(match &self.tls {
+                        Some(session) => format!("encryption: {}", session.protocol),
+                        None => "encryption: none".to_string(),
+                    });
+                }
+                lines.extend(
+                    self.tls
+                        .as_ref()
+                        .and_then(|s| s.certificate.as_ref())
+                        .map(|c| format!("certificate: sha256 {c}")),
+                );
+                lines.extend(
+                    self.tls
+                        .as_ref()
+                        .and_then(|s| s.public_key.as_ref())
+                        .map(|k| format!("public key: sha256 {k}")),
+                );
+                lines.extend(
+                    self.relay
+                        .as_ref()
+                        .map(|r| format!("relay: {} talking to {}", r.name, r.to)),
+                );
+                self.popup = Some(Popup {
+                    title: "Connection".to_string(),
+                    lines,
+                });
+            }
+            Command::PinCert => {
+                let pin = self
+                    .tls
+                    .as_ref()
+                    .and_then(|s| Some((s.server.clone()?, s.certificate.clone()?)));
+                let Some((server, certificate)) = pin else {
+                    self.notice = Some("nothing to pin, connect to a TLS server first".to_string());
+                    return;
+                };
+                match self.config.set_string(tls::PINS_TABLE, &server, &certificate) {
+                    Ok(()) => self.record(Message::system(format!(
+                        "pinned {server} to sha256 {certificate}, only it is accepted from the next start on"
+                    ))),
+                    Err(e) => self.record(Message::system(format!("failed to save the pin: {e}"))),
+                };
+            }
+            Command::Tasks => {
+                self.popup = Some(Popup {
+                    title: "Background tasks".to_string(),
+                    lines: tasks::list(),
+                });
+            }
+            Command::Who => {
+                discovery::ping(self.who_sender.clone());
+                self.notice = Some("asking who is around…".to_string());
+            }
+            Command::Topic(_)
+            | Command::Pin(_)
+            | Command::Unpin
+            | Command::Kick(_)
+            | Command::SetRole { .. }
+            | Command::InviteCode
+            | Command::Invite(_)
+                if !self.relay.as_ref().is_some_and(relay::Route::is_room) =>
+            {
+                self.notice = Some("only in relay rooms, --to '#room'".to_string());
+            }
+            Command::Topic(None) => {
+                let topic = self
+                    .room
+                    .lock()
+                    .expect("room lock is poisoned")
+                    .topic
+                    .clone();
+                self.notice = Some(topic.unwrap_or_else(|| "no topic".to_string()));
+            }
+            Command::Pin(None) => {
+                let room = self.room.lock().expect("room lock is poisoned");
+                let lines = room
+                    .pins
+                    .iter()
+                    .map(|(by, text)| format!("{by}: {text}"))
+                    .collect();
+                drop(room);
+                self.popup = Some(Popup {
+                    title: "Pinned messages".to_string(),
+                    lines,
+                });
+            }
+            Command::Topic(Some(_))
+            | Command::Pin(Some(_))
+            | Command::Unpin
+            | Command::Kick(_)
+            | Command::SetRole { .. }
+            | Command::InviteCode
+            | Command::Invite(_) => {
+                let res = match (&self.relay, writer) {
+                    (Some(route), Some(writer)) => match command {
+                        Command::Topic(Some(topic)) => route.set_topic(writer, &topic),
+                        Command::Pin(Some(text)) => route.pin(writer, &text),
+                        Command::Unpin => route.pin(writer, ""),
+                        Command::Kick(name) => route.kick(writer, &name),
+                        Command::SetRole { name, role } => route.set_role(writer, &name, role),
+                        Command::InviteCode => route.request_code(writer),
+                        Command::Invite(name) => route.invite(writer, &name),
+                        _ => unreachable!("not a room command"),
+                    },
+                    _ => Err(io::ErrorKind::NotConnected.into()),
+                };
+                if let Err(e) = res {
+                    self.notice = Some(format!("failed to reach the relay: {e}"));
+                }
+            }
+            Command::List { .. } if self.relay.is_none() => {
+                self.notice = Some("only on a relay, --relay".to_string());
+            }
+            Command::List { rooms, page } => {
+                let res = match (&self.relay, writer) {
+                    (Some(route), Some(writer)) => route.list(writer, rooms, page),
+                    _ => Err(io::ErrorKind::NotConnected.into()),
+                };
+                if let Err(e) = res {
+                    self.notice = Some(format!("failed to reach the relay: {e}"));
+                }
+            }
+            Command::Join(_) | Command::Leave if self.relay.is_some() => {
+                self.notice = Some("on a relay, talk in a room with --to '#room'".to_string());
+            }
+            Command::Join(room) => {
+                let Some(writer) = writer else {
+                    self.notice = Some("not connected".to_string());
+                    return;
+                };
+                if !self.joined.contains(&room) {
+                    if let Err(e) = writer.write_all(relay::join(&room).as_bytes()) {
+                        self.notice = Some(format!("failed to join {room}: {e}"));
+                        return;
+                    }
+                    self.joined.push(room.clone());
+                }
+                self.switch_room(Some(room));
+            }
+            Command::Leave => {
+                let Some(room) = self.current_room.clone() else {
+                    self.notice = Some("not in a room, /join one".to_string());
+                    return;
+                };
+                // the server forgets about us anyway once we are gone
+                if let Some(writer) = writer {
+                    let _ = writer.write_all(relay::part(&room).as_bytes());
+                }
+                self.joined.retain(|r| *r != room);
+                self.switch_room(None);
+                self.notice = Some(format!("left {room}"));
+            }
+            Command::Roster if self.bridge.is_none() => {
+                self.notice = Some("no bridge, start with --bridge <url>".to_string());
+            }
+            Command::Roster => {
+                self.notice = Some(if self.roster.is_empty() {
+                    "nobody else on the bridge".to_string()
+                } else {
+                    format!("on the bridge: {}", self.roster.join(", "))
+                });
+            }
+            Command::ContactShow(alias) => self.show_contact(&alias),
+            Command::ContactFind(query) => {
+                let lines: Vec<_> = self
+                    .contacts
+                    .search(&query)
+                    .map(ToString::to_string)
+                    .collect();
+                if lines.is_empty() {
+                    self.notice = Some(format!("no contact mentions {query}"));
+                } else {
+                    self.popup = Some(Popup {
+                        title: format!("Contacts mentioning {query}"),
+                        lines,
+                    });
+                }
+            }
+            Command::Note { alias, change } => {
+                if let Err(e) = self.contacts.note(&alias, change) {
+                    self.notice = Some(e);
+                }
+            }
+            Command::ContactRemove(alias) => {
+                if !self.contacts.remove(&alias) {
+                    self.notice = Some(format!("no contact named {alias}"));
+                }
+            }
+            Command::Refuse => {
+                let name = self.exec.pending.take().expect("checked above");
+                self.send_control(writer, &protocol::exec_done("refused"));
+                self.record(Message::system(format!("refused to run {name}")));
+            }
+            Command::Diag(path) => match self.write_diagnostics(path) {
+                Ok(path) => {
+                    self.notice = Some(format!("diagnostics written to {}", path.display()))
+                }
+                Err(e) => {
+                    error!("Failed to write diagnostics: {e}");
+                    self.notice = Some(format!("failed to write diagnostics: {e}"));
+                }
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub fn write_diagnostics(
+        &self,
+        path: Option<std::path::PathBuf>,
+    ) -> io::Result<std::path::PathBuf> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                let dir = dirs::data_dir()
+                    .ok_or_else(|| io::Error::other("couldn't determine data directory"))?;
+                std::fs::create_dir_all(&dir)?;
+                dir.join(format!("diag-{}.txt", timestamp::now_millis()))
+            }
+        };
+        let verification = match &self.verification {
+            Verification::Unavailable => "none",
+            Verification::Pending(_) => "pending",
+            Verification::Verified => "verified",
+            Verification::Rejected => "rejected",
+        };
+        let connection = match &self.connection {
+            Some((id, peer)) => format!(
+                "connection #{id} to {peer}\nauthenticated session: {verification}\nqueued messages: {}",
+                self.queued.len()
+            ),
+            None => format!("disconnected\nqueued messages: {}", self.queued.len()),
+        };
+        let bundle = diag::bundle(
+            &self.logs,
+            &self.config.snapshot(),
+            &self.stats,
+            &connection,
+        );
+        std::fs::write(&path, bundle)?;
+        info!("diagnostics written to {}", path.display());
+        Ok(path)
+    }
+
+    /// Shows a page of the directory of the relay.
+    pub fn show_listing(&mut self, listing: relay::Listing) {
+        let kind = if listing.rooms { "rooms" } else { "users" };
+        let mut lines: Vec<String> = listing
+            .entries
+            .iter()
+            .map(|entry| {
+                if !listing.rooms {
+                    return entry.clone();
+                }
+                let mut fields = entry.splitn(4, ' ');
+                let (room, members, access) = (
+                    fields.next().unwrap_or_default(),
+                    fields.next().unwrap_or_default(),
+                    fields.next().unwrap_or_default(),
+                );
+                let members = match members {
+                    "1" => "1 member".to_string(),
+                    n => format!("{n} members"),
+                };
+                match fields.next().map(protocol::unescape) {
+                    Some(topic) if !topic.is_empty() => {
+                        format!("{room}, {members}, {access}: {topic}")
+                    }
+                    _ => format!("{room}, {members}, {access}"),
+                }
+            })
+            .collect();
+        if lines.is_empty() {
+            lines.push(format!("no {kind} to list"));
+        }
+        if listing.page < listing.pages {
+            lines.push(String::new());
+            lines.push(format!(
+                "/list {kind} {} shows the next page",
+                listing.page + 1
+            ));
+        }
+        self.popup = Some(Popup {
+            title: format!(
+                "{} on the relay, page {} of {}",
+                if listing.rooms { "Rooms" } else { "Users" },
+                listing.page,
+                listing.pages
+            ),
+            lines,
+        });
+    }
+
+    /// Lists who answered `/who`.
+    pub fn show_who(&mut self, answers: io::Result<Vec<discovery::Peer>>) {
+        match answers {
+            Ok(peers) if peers.is_empty() => {
+                self.notice = Some("nobody around answered, they need --discoverable".to_string());
+            }
+            Ok(peers) => {
+                self.notice = None;
+                self.popup = Some(Popup {
+                    title: "Who is around".to_string(),
+                    lines: peers.iter().map(ToString::to_string).collect(),
+                });
+            }
+            Err(e) => self.notice = Some(format!("failed to ask who is around: {e}")),
+        }
+    }
+
+    /// Shows the reminders which are due.
+    pub fn fire_reminders(&mut self) {
+        for reminder in self.reminders.take_due() {
+            show_notification(&reminder.text);
+            let mut msg = Message::system(format!("reminder: {}", reminder.text));
+            msg.highlighted = true;
+            self.record(msg);
+            REDRAW.store(true, Ordering::Release);
+        }
+    }
+
+    /// Rings and notifies again about messages left unread, as their conversation asks.
+    pub fn escalate(&mut self) {
+        let unread = self.unread.load(Ordering::Relaxed);
+        if unread == 0 {
+            self.missed = None;
+            return;
+        }
+        let now = Instant::now();
+        let missed = self.missed.get_or_insert_with(|| alerts::Missed::new(now));
+        let Some(conversation) = &self.conversation else {
+            return;
+        };
+        let escalation = self
+            .alerts
+            .lock()
+            .expect("alerts lock is poisoned")
+            .escalation(conversation);
+        let due = missed.escalate(escalation, now);
+        if due.bell {
+            talk::ring();
+        }
+        if due.notification {
+            notify(&format!("{unread} unread messages from {conversation}"));
+        }
+    }
+
+    pub fn submit_message(&mut self, writer: Option<&mut impl std::io::Write>) {
+        let input = std::mem::take(&mut self.input);
+        self.submit(writer, &input);
+        self.reset_cursor();
+    }
+
+    /// Runs `input` if it is a command, sends it otherwise.
+    pub fn submit(&mut self, mut writer: Option<&mut impl std::io::Write>, input: &str) {
+        self.notice = None;
+        let expanded = input
+            .trim()
+            .strip_prefix('/')
+            .and_then(|command| self.aliases.expand(command));
+        match expanded {
+            Some(lines) => {
+                for line in lines {
+                    self.enter(writer.as_deref_mut(), &line);
+                }
+            }
+            None => self.enter(writer, input),
+        }
+    }
+
+    /// Runs a single line, aliases already expanded.
+    fn enter(&mut self, writer: Option<&mut impl std::io::Write>, input: &str) {
+        let usr_str = input.trim();
+        if let Some(command) = usr_str.strip_prefix('/') {
+            match command.parse() {
+                Ok(command) => self.run_command(writer, command),
+                Err(e) => self.notice = Some(e),
+            }
+        } else if !usr_str.is_empty() {
+            let msg = self.snippets.expand(usr_str);
+            self.send_message(writer, msg);
+        }
+    }
+
+    /// Answer to a request on the control socket of the session.
+    pub fn control(
+        &mut self,
+        writer: Option<&mut impl std::io::Write>,
+        request: detach::Request,
+    ) -> String {
+        match request {
+            detach::Request::Send(text) => {
+                self.submit(writer, &text);
+                match self.notice.take() {
+                    Some(notice) => format!("ERROR {notice}"),
+                    None => "OK".to_string(),
+                }
+            }
+            detach::Request::Status => format!(
+                "STATUS {} {}",
+                if self.connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                },
+                self.unread.load(Ordering::Relaxed)
+            ),
+        }
+    }
+
+    /// Replaces the selected sent message, or the last one, and tells the peer.
+    pub fn edit_message(&mut self, writer: Option<&mut impl std::io::Write>, text: String) {
+        let Some(writer) = writer else {
+            self.notice = Some("not connected, messages can only be edited once sent".to_string());
+            return;
+        };
+        let editable = |m: &Message| m.kind == message::Kind::Outgoing && !m.queued;
+        let messages = Arc::clone(&self.messages);
+        let mut lock = messages.lock().expect("messages lock is poisoned");
+        let index = lock
+            .selected()
+            .filter(|&i| lock.get(i).is_some_and(editable))
+            .or_else(|| {
+                (0..lock.len())
+                    .rev()
+                    .find(|&i| lock.get(i).is_some_and(editable))
+            });
+        let Some(msg) = index.and_then(|i| lock.get_mut(i)) else {
+            self.notice = Some("no sent message to edit".to_string());
+            return;
+        };
+        let relay_id = self.relay.as_mut().map(relay::Route::next_id);
+        match self.send(writer, &protocol::edit(&msg.text, &text), relay_id) {
+            Ok(()) => msg.edit(text),
+            Err(e) => {
+                error!("Failed to send edit {e}");
+                self.notice = Some(format!("edit is not sent: {e}"));
+            }
+        }
+    }
+
+    /// Shows or hides the changes of the selected message's last edit.
+    pub fn toggle_diff(&mut self) {
+        let mut lock = self.messages.lock().expect("messages lock is poisoned");
+        if let Some(msg) = lock.selected().and_then(|i| lock.get_mut(i)) {
+            msg.show_diff = !msg.show_diff;
+        }
+    }
+
+    /// Sends `msg` if connected, otherwise queues it in the outbox.
+    pub fn send_message(&mut self, writer: Option<&mut impl std::io::Write>, msg: String) {
+        self.send_annotated(writer, msg, None);
+    }
+
+    /// Sends `msg` along with `data` for the bots on the other side, which the peer doesn't see.
+    pub fn send_annotated(
+        &mut self,
+        writer: Option<&mut impl std::io::Write>,
+        msg: String,
+        data: Option<json::Value>,
+    ) {
+        if let Some(bridge) = &self.bridge {
+            let _ = bridge.send(msg.clone());
+        }
+        // shown as queued until written, the relay may acknowledge it right away
+        let mut pending = Message::outgoing(msg.clone());
+        pending.queued = true;
+        pending.author = Some(self.name.clone());
+        pending.data = data;
+        pending.room = self.current_room.clone();
+        let index = self.record(pending);
+        // anything queued has to go out first to keep the order
+        if let Some(writer) = writer.filter(|_| self.outbox.is_empty()) {
+            match self.deliver(writer, index, &msg) {
+                Ok(()) => return,
+                Err(e) => error!("Failed to send message {e}"),
+            }
+        }
+        self.queued.push_back(index);
+        self.outbox.push(msg);
+    }
+
+    /// Sends the message recorded at `index` and marks it as sent.
+    pub fn deliver(
+        &mut self,
+        writer: &mut impl std::io::Write,
+        index: usize,
+        msg: &str,
+    ) -> io::Result<()> {
+        let relay_id = self.relay.as_mut().map(relay::Route::next_id);
+        // the peer tells about receiving it, unless the relay does
+        let id = match relay_id {
+            Some(_) => None,
+            None => {
+                self.last_id += 1;
+                Some(self.last_id)
+            }
+        };
+        let (data, room) = self
+            .messages
+            .lock()
+            .expect("messages lock is poisoned")
+            .get_mut(index)
+            .map(|item| {
+                item.relay_id = relay_id;
+                item.id = id;
+                (item.data.clone(), item.room.clone())
+            })
+            .unwrap_or_default();
+        let envelope = protocol::Envelope {
+            sender: self.name.clone(),
+            sent: timestamp::now_millis(),
+            id,
+        };
+        let line = match self.cbor.is_active() {
+            true => cbor::message(&envelope, msg, data.as_ref()),
+            false => protocol::message(&envelope, msg, data.as_ref()),
+        };
+        self.write_line(writer, &line, relay_id, room.as_deref())?;
+        if let Some(item) = self
+            .messages
+            .lock()
+            .expect("messages lock is poisoned")
+            .get_mut(index)
+        {
+            item.queued = false;
+        }
+        if let Some(store) = &self.store {
+            store.append(message::Kind::Outgoing, msg);
+        }
+        Ok(())
+    }
+
+    /// Writes a line in wire format, signed and addressed as the session requires.
+    pub fn send(
+        &mut self,
+        writer: &mut impl std::io::Write,
+        payload: &str,
+        relay_id: Option<u64>,
+    ) -> io::Result<()> {
+        self.write_line(writer, payload, relay_id, None)
+    }
+
+    /// Like [`App::send`], only to the members of `room` of the `--multi` server if given.
+    fn write_line(
+        &mut self,
+        writer: &mut impl std::io::Write,
+        payload: &str,
+        relay_id: Option<u64>,
+        room: Option<&str>,
+    ) -> io::Result<()> {
+        let _span = self.span.as_ref().map(tracing::Span::enter);
+        let sealed;
+        let payload = match &self.seal {
+            Some(seal) => {
+                sealed = seal.seal(payload)?;
+                &sealed
+            }
+            None => payload,
+        };
+        let mut line = match self.signer.as_mut() {
+            Some(signer) => signer.sign(payload),
+            None => format!("{payload}\n"),
+        };
+        if let (Some(route), Some(id)) = (&self.relay, relay_id) {
+            line = route.wrap(id, line.trim_end());
+        } else if let Some(room) = room {
+            line = relay::post(room, line.trim_end());
+        }
+        writer.write_all(line.as_bytes())?;
+        debug!("sent {} bytes", line.len());
+        self.stats.record_sent(line.len());
+        Ok(())
+    }
+
+    /// Types `keys` into our half of the talk mode, sending them right away.
+    pub fn talk(&mut self, writer: Option<&mut impl std::io::Write>, keys: &str) {
+        let Some(window) = self.talking.as_mut() else {
+            return;
+        };
+        let typed = window.apply(keys);
+        // the peer puts the line together itself
+        for line in typed.lines.into_iter().filter(|l| !l.is_empty()) {
+            let mut msg = Message::outgoing(line);
+            msg.author = Some(self.name.clone());
+            self.record(msg);
+        }
+        self.send_control(writer, &protocol::talk(keys));
+    }
+
+    /// Shows the peer the draft in the input box, if both type live and it changed.
+    pub fn share_draft(&mut self, writer: Option<&mut impl std::io::Write>) {
+        if !self.live.is_active() {
+            return;
+        }
+        // commands and searches are none of the peer's business
+        let draft = match self.input.starts_with('/') || self.searching.is_some() {
+            true => "",
+            false => self.input.as_str(),
+        };
+        if draft == self.previewed {
+            return;
+        }
+        self.previewed = draft.to_string();
+        let line = protocol::preview(draft);
+        self.send_control(writer, &line);
+    }
+
+    /// Tells the peer whether we are typing a message, as it changes and again while it goes on.
+    pub fn note_typing(&mut self, writer: Option<&mut impl std::io::Write>) {
+        if self.live.expire_peer_typing() {
+            REDRAW.store(true, Ordering::Release);
+        }
+        if self.typed.0 != self.input {
+            self.typed = (self.input.clone(), Instant::now());
+        }
+        // whatever the relay holds for the peer would be stale by the time it gets it
+        let typing = matches!(self.input_mode, InputMode::Editing)
+            && !self.input.is_empty()
+            && !self.input.starts_with('/')
+            && self.searching.is_none()
+            && self.talking.is_none()
+            && self.relay.is_none()
+            && self.typed.1.elapsed() < live::TYPING_IDLE;
+        let Some(writer) = writer else {
+            self.told_typing = None;
+            return;
+        };
+        match (typing, self.told_typing) {
+            (true, Some(at)) if at.elapsed() < live::TYPING_REFRESH => {}
+            (true, _) => {
+                self.told_typing = Some(Instant::now());
+                self.send_control(Some(writer), &protocol::typing(true));
+            }
+            (false, Some(_)) => {
+                self.told_typing = None;
+                self.send_control(Some(writer), &protocol::typing(false));
+            }
+            (false, None) => {}
+        }
+    }
+
+    fn file_offered(&self) -> bool {
+        self.receiving.as_ref().is_some_and(|i| !i.is_accepted())
+    }
+
+    fn accept_file(&mut self, writer: Option<&mut impl std::io::Write>) {
+        let mut incoming = self.receiving.take().expect("only called when offered");
+        match incoming.accept() {
+            Ok(path) => {
+                self.send_control(writer, &protocol::file_answer(incoming.id, true));
+                incoming.shown = format!("receiving {} to {}", incoming.name, path.display());
+                self.record(Message::system(incoming.shown.clone()));
+                self.receiving = Some(incoming);
+            }
+            Err(e) => {
+                error!("Failed to save {}: {e}", incoming.name);
+                let reason = "the peer couldn't save it";
+                self.send_control(writer, &protocol::file_abort(incoming.id, reason));
+                self.record(Message::system(format!(
+                    "failed to save {}: {e}",
+                    incoming.name
+                )));
+            }
+        }
+    }
+
+    /// Takes care of a line of the peer about a file.
+    pub fn file_line(&mut self, writer: Option<&mut impl std::io::Write>, line: protocol::Payload) {
+        match line {
+            protocol::Payload::FileOffer { id, .. } if self.receiving.is_some() => {
+                let reason = "the peer is busy with another file";
+                self.send_control(writer, &protocol::file_abort(id, reason));
+            }
+            protocol::Payload::FileOffer { id, size, name } => {
+                let incoming = transfer::Incoming::new(id, size, &name);
+                self.record(Message::system(format!(
+                    "the peer sends {} ({}), /accept or /refuse",
+                    incoming.name,
+                    transfer::describe_size(size)
+                )));
+                self.receiving = Some(incoming);
+            }
+            protocol::Payload::FileAnswer { id, accepted } => {
+                let Some(mut outgoing) = self.sending.take().filter(|o| o.id == id) else {
+                    return;
+                };
+                if !accepted {
+                    let text = format!("the peer declined {}", outgoing.name);
+                    self.record(Message::system(text));
+                    return;
+                }
+                match outgoing.start(self.cbor.is_active()) {
+                    Ok(()) => self.sending = Some(outgoing),
+                    Err(e) => {
+                        let reason = format!("failed to read: {e}");
+                        self.send_control(writer, &protocol::file_abort(id, &reason));
+                        self.record(Message::system(format!(
+                            "failed to send {}: {e}",
+                            outgoing.name
+                        )));
+                    }
+                }
+            }
+            protocol::Payload::FileChunk { id, data } => {
+                let Some(incoming) = self.receiving.as_mut().filter(|i| i.id == id) else {
+                    return;
+                };
+                if let Err(e) = incoming.write(&data) {
+                    let incoming = self.receiving.take().expect("checked above");
+                    let text = format!("failed to save {}: {e}", incoming.name);
+                    self.send_control(writer, &protocol::file_abort(id, &e.to_string()));
+                    incoming.discard();
+                    self.record(Message::system(text));
+                    return;
+                }
+                let before =
+                    transfer::percent(incoming.received - data.len() as u64, incoming.size);
+                let done = transfer::percent(incoming.received, incoming.size);
+                if before != done {
+                    let text = format!("receiving {}: {done}%", incoming.name);
+                    let shown = std::mem::replace(&mut incoming.shown, text.clone());
+                    self.update_message(&shown, text);
+                }
+            }
+            protocol::Payload::FileEnd { id, digest } => {
+                let Some(incoming) = self.receiving.take_if(|i| i.id == id) else {
+                    return;
+                };
+                let (name, shown) = (incoming.name.clone(), incoming.shown.clone());
+                let text = match incoming.finish(&digest) {
+                    Ok(path) => format!("received {name}, saved to {}", path.display()),
+                    Err(e) => format!("failed to receive {name}: {e}"),
+                };
+                self.update_message(&shown, text);
+            }
+            protocol::Payload::FileAbort { id, reason } => {
+                if let Some(outgoing) = self.sending.take_if(|o| o.id == id) {
+                    let text = format!("sending {} stopped, {reason}", outgoing.name);
+                    self.update_message(&outgoing.shown, text);
+                }
+                if let Some(incoming) = self.receiving.take_if(|i| i.id == id) {
+                    let (text, shown) = (
+                        format!("receiving {} stopped, {reason}", incoming.name),
+                        incoming.shown.clone(),
+                    );
+                    incoming.discard();
+                    match shown.is_empty() {
+                        true => {
+                            self.record(Message::system(text));
+                        }
+                        false => self.update_message(&shown, text),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Sends what is read of the file by now, for a moment at most.
+    pub fn send_file(&mut self, mut writer: Option<&mut impl std::io::Write>) {
+        let started = Instant::now();
+        while started.elapsed() < transfer::SEND_SLICE {
+            let Some(lines) = self.sending.as_ref().and_then(|o| o.lines.as_ref()) else {
+                return;
+            };
+            let Ok(line) = lines.try_recv() else {
+                return;
+            };
+            self.send_control(writer.as_deref_mut(), &line);
+            let outgoing = self.sending.as_mut().expect("checked above");
+            let (before, shown) = (outgoing.sent, outgoing.shown.clone());
+            let text = match protocol::decode(&line) {
+                protocol::Payload::FileChunk { data, .. } => {
+                    outgoing.sent += data.len() as u64;
+                    let done = transfer::percent(outgoing.sent, outgoing.size);
+                    if transfer::percent(before, outgoing.size) == done && before > 0 {
+                        continue;
+                    }
+                    outgoing.shown = format!("sending {}: {done}%", outgoing.name);
+                    outgoing.shown.clone()
+                }
+                protocol::Payload::FileEnd { .. } => {
+                    let outgoing = self.sending.take().expect("checked above");
+                    format!("sent {}", outgoing.name)
+                }
+                protocol::Payload::FileAbort { reason, .. } => {
+                    let outgoing = self.sending.take().expect("checked above");
+                    format!("failed to send {}: {reason}", outgoing.name)
+                }
+                _ => continue,
+            };
+            self.update_message(&shown, text);
+        }
+    }
+
+    /// Replaces the text of the latest system message reading `old`, or adds one if it's gone.
+    fn update_message(&mut self, old: &str, new: String) {
+        let mut lock = self.messages.lock().expect("messages lock is poisoned");
+        let found = lock
+            .iter_mut()
+            .rev()
+            .find(|m| m.kind == message::Kind::System && m.text == old);
+        match found {
+            Some(msg) => msg.set_text(new),
+            None => {
+                drop(lock);
+                self.record(Message::system(new));
+            }
+        }
+        REDRAW.store(true, Ordering::Release);
+    }
+
+    /// Sends a line which isn't a message, there is nobody to tell without a connection.
+    pub fn send_control(&mut self, writer: Option<&mut impl std::io::Write>, line: &str) {
+        let Some(writer) = writer else {
+            return;
+        };
+        let relay_id = self.relay.as_mut().map(relay::Route::next_id);
+        if let Err(e) = self.send(writer, line, relay_id) {
+            error!("Failed to send control line {e}");
+        }
+    }
+
+    /// Shows what happened on the bridge, its messages go on to the peer as well.
+    pub fn bridged(&mut self, writer: Option<&mut impl std::io::Write>, event: bridge::Event) {
+        match event {
+            bridge::Event::Message { from, text } => {
+                let envelope = protocol::Envelope {
+                    sender: from.clone(),
+                    sent: timestamp::now_millis(),
+                    id: None,
+                };
+                self.send_control(writer, &protocol::message(&envelope, &text, None));
+                let mut msg = Message::incoming(text);
+                msg.author = Some(from);
+                self.record(msg);
+            }
+            bridge::Event::Joined(name) => {
+                self.record(Message::system(format!("{name} joined the bridge")));
+            }
+            bridge::Event::Left(name) => {
+                self.record(Message::system(format!("{name} left the bridge")));
+            }
+            bridge::Event::Roster(roster) => self.roster = roster,
+            bridge::Event::Status(status) => {
+                self.record(Message::system(status));
+            }
+        }
+    }
+
+    pub fn show_contact(&mut self, alias: &str) {
+        match self.contacts.get(alias) {
+            Some(contact) => {
+                self.popup = Some(Popup {
+                    title: contact.alias.clone(),
+                    lines: contact.info(),
+                })
+            }
+            None => self.notice = Some(format!("no contact named {alias}")),
+        }
+    }
+
+    /// Sends a location and shows it, or why there is none.
+    pub fn share_location(
+        &mut self,
+        writer: Option<&mut impl std::io::Write>,
+        point: Result<location::Point, String>,
+    ) {
+        match (point, writer) {
+            (Ok(point), Some(writer)) => {
+                let relay_id = self.relay.as_mut().map(relay::Route::next_id);
+                match self.send(writer, &protocol::location(point), relay_id) {
+                    Ok(()) => {
+                        self.notice = None;
+                        self.record(Message::outgoing(point.describe()));
+                    }
+                    Err(e) => {
+                        error!("Failed to send location {e}");
+                        self.notice = Some(format!("location is not sent: {e}"));
+                    }
+                }
+            }
+            (Ok(_), None) => self.notice = Some("not connected".to_string()),
+            (Err(e), _) => self.notice = Some(e),
+        }
+    }
+
+    /// Asks the user whether the peer may run `name`, refuses right away if it isn't allowed or
+    /// another request waits for an answer, which `/accept` would run instead.
+    pub fn exec_requested(&mut self, writer: Option<&mut impl std::io::Write>, name: String) {
+        if let Some(waiting) = self.exec.pending.clone() {
+            warn!("peer asked to run {name} while {waiting} waits for an answer");
+            self.send_control(
+                writer,
+                &protocol::exec_done(&format!("{waiting} waits for an answer, ask again later")),
+            );
+            self.record(Message::system(format!(
+                "the peer asked to run {name} as well, refused while {waiting} waits"
+            )));
+            return;
+        }
+        match self.exec.command(&name) {
+            Some(cmd) => {
+                let text = format!("the peer asks to run {name}: {cmd}, /accept or /refuse");
+                self.exec.pending = Some(name);
+                self.record(Message::system(text));
+            }
+            None => {
+                warn!("peer asked to run {name}, which isn't allowed");
+                self.send_control(
+                    writer,
+                    &protocol::exec_done(&format!("{name} isn't allowed")),
+                );
+                self.record(Message::system(format!(
+                    "the peer asked to run {name}, which isn't allowed"
+                )));
+            }
+        }
+    }
+
+    /// Adds a message to the list, returns its position.
+    ///
+    /// Anything but queued messages goes to the history as well.
+    pub fn record(&mut self, msg: Message) -> usize {
+        if let (Some(store), false) = (&self.store, msg.queued) {
+            store.append(msg.kind, &msg.text);
+        }
+        let mut lock = self.messages.lock().expect("messages lock is poisoned");
+        lock.push(msg);
+        lock.len() - 1
+    }
+
+    pub fn record_queued(&mut self, text: String) {
+        let mut msg = Message::outgoing(text);
+        msg.queued = true;
+        let index = self.record(msg);
+        self.queued.push_back(index);
+    }
+
+    /// Asks whether to put `draft`, left by a run which didn't quit normally, back into the input.
+    pub fn offer_draft(&mut self, draft: String) {
+        const PREVIEW: usize = 5;
+        let mut lines: Vec<_> = draft.lines().take(PREVIEW).map(str::to_string).collect();
+        if draft.lines().count() > PREVIEW {
+            lines.push("…".to_string());
+        }
+        lines.push(String::new());
+        lines.push("y puts it back into the input box, n drops it".to_string());
+        self.popup = Some(Popup {
+            title: "Restore the draft?".to_string(),
+            lines,
+        });
+        self.recovered_draft = Some(draft);
+    }
+
+    /// Puts the recovered draft into the input box when `restore`, otherwise forgets it.
+    pub fn answer_draft(&mut self, restore: bool) {
+        self.popup = None;
+        let Some(recovered) = self.recovered_draft.take() else {
+            return;
+        };
+        if !restore {
+            self.autosave.discard();
+            return;
+        }
+        if !self.input.is_empty() {
+            self.input.push('\n');
+        }
+        self.input.push_str(&recovered);
+        self.append();
+    }
+
+    /// Shows the messages left in the outbox from a previous run as queued.
+    pub fn restore_outbox(&mut self, outbox: Outbox) {
+        for msg in outbox.pending() {
+            self.record_queued(msg.to_string());
+        }
+        self.outbox = outbox;
+    }
+
+    /// Sends the queued messages in order, stops at the first failure.
+    #[instrument(skip_all)]
+    pub fn flush_outbox(&mut self, writer: &mut impl std::io::Write) {
+        if self.outbox.is_empty() {
+            return;
+        }
+        let mut sent = 0;
+        let mut failed = None;
+        while let (Some(msg), Some(&index)) =
+            (self.outbox.front().map(str::to_string), self.queued.front())
+        {
+            if let Err(e) = self.deliver(writer, index, &msg) {
+                error!("Failed to send queued message {e}");
+                failed = Some(e);
+                break;
+            }
+            self.outbox.pop_front();
+            self.queued.pop_front();
+            sent += 1;
+        }
+        self.outbox.persist();
+        if sent > 0 {
+            self.record(Message::system(format!("sent {sent} queued message(s)")));
+        }
+        if let Some(e) = failed {
+            let left = self.outbox.pending().count();
+            self.record(Message::system(format!(
+                "failed to send queued messages, {left} still waiting: {e}"
+            )));
+        }
+        REDRAW.store(true, Ordering::Release);
+    }
+
+    /// Sets up a freshly established connection and sends whatever was queued meanwhile.
+    pub fn start_session(
+        &mut self,
+        established: Established,
+        deniable: Option<&str>,
+    ) -> io::Result<Box<dyn Transport>> {
+        RESET.store(false, Ordering::Release);
+        // a --multi server tells again who is there
+        self.presence
+            .lock()
+            .expect("presence lock is poisoned")
+            .clear();
+        let Established { mut stream, tls } = established;
+        // the stream of an encrypted connection is a local one
+        let (address, local_address, peer) = match &tls {
+            Some(session) => (
+                Ok(session.peer),
+                Ok(session.local),
+                session.peer.to_string(),
+            ),
+            None => (
+                stream.peer_address(),
+                stream.local_address(),
+                stream.describe(),
+            ),
+        };
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        // room is filled in by whatever joins one
+        let span = tracing::info_span!("connection", id, %peer, room = tracing::field::Empty);
+        let _span = span.enter();
+        info!("connection established");
+        let mut reader = std::io::BufReader::new(stream.try_clone()?);
+        let fingerprint = deniable.map(deniable::fingerprint);
+        // the port of a client changes with every connection, its address may as well
+        let mut lobby = Vec::new();
+        let history_peer = match &mut self.relay {
+            Some(route) => {
+                lobby = route.identify(&mut reader, &mut stream)?;
+                if route.to.is_empty() {
+                    route.to = lobby.first().cloned().ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            "the relay has no lobby, give --to",
+                        )
+                    })?;
+                }
+                if route.is_room() {
+                    route.join(&mut stream)?;
+                    // members get back in without them
+                    route.join_code = None;
+                    route.private = false;
+                }
+                Some(route.to.clone())
+            }
+            None => match &fingerprint {
+                Some(fingerprint) => Some(format!("fingerprint-{fingerprint}")),
+                None => address.as_ref().ok().map(|a| a.ip().to_string()),
+            },
+        };
+        // a --multi server forgets the rooms of a connection which is gone
+        for room in &self.joined {
+            stream.write_all(relay::join(room).as_bytes())?;
+        }
+        self.store = self
+            .storage
+            .as_ref()
+            .zip(history_peer)
+            .map(|(storage, peer)| Store::new(Arc::clone(storage), &peer));
+        // kept across reconnects, what was said since is in the messages pane already
+        if let Some(store) = self.store.clone() {
+            if !self.archive.as_ref().is_some_and(|a| a.is_of(&store)) {
+                self.archive = store
+                    .archive()
+                    .map_err(|e| warn!("Failed to open the history: {e}"))
+                    .ok();
+                self.restore(&store);
+            }
+        }
+        // the TLS session of a relay is with the relay, not the peer
+        self.verification = match (&tls, &self.relay) {
+            (Some(session), None) => Verification::Pending(session.sas.clone()),
+            _ => Verification::Unavailable,
+        };
+        let verifier = match deniable {
+            Some(secret) => {
+                let (signer, verifier) =
+                    deniable::handshake(secret, &*self.rng, &mut reader, &mut stream)?;
+                self.signer = Some(signer);
+                Some(verifier)
+            }
+            None => None,
+        };
+        self.peer_alias = match (&self.relay, &address) {
+            (None, Ok(addr)) => self.dialing.clone().or_else(|| {
+                let contact = fingerprint
+                    .as_deref()
+                    .and_then(|f| self.contacts.find_by_fingerprint(f))
+                    .or_else(|| {
+                        self.contacts
+                            .find_by_address(&addr.ip().to_string(), addr.port())
+                    });
+                contact.map(|c| c.alias.clone())
+            }),
+            _ => None,
+        };
+        let conversation = match (&self.relay, &self.peer_alias) {
+            (Some(route), _) => route.to.clone(),
+            (None, Some(alias)) => alias.clone(),
+            (None, None) => address
+                .as_ref()
+                .map_or_else(|_| "peer".to_string(), |a| a.ip().to_string()),
+        };
+        self.plaintext.store(false, Ordering::Release);
+        // what went out on the last connection may have been lost with it
+        if let Some(seal) = &self.seal {
+            seal.restart();
+        }
+        let inbound = Inbound {
+            dest: Arc::clone(&self.messages),
+            triggers: Arc::clone(&self.triggers),
+            replies: self.reply_sender.clone(),
+            store: self.store.clone(),
+            stats: Arc::clone(&self.stats),
+            relay_peer: self.relay.as_ref().map(|r| r.to.clone()),
+            relay_name: self.relay.as_ref().map(|r| r.name.clone()),
+            unread: Arc::clone(&self.unread),
+            exec_requests: self.exec_request_sender.clone(),
+            pane: Arc::clone(&self.pane),
+            room: Arc::clone(&self.room),
+            presence: Arc::clone(&self.presence),
+            bridge: self.bridge.clone(),
+            live: Arc::clone(&self.live),
+            cbor: Arc::clone(&self.cbor),
+            unseen: Arc::clone(&self.unseen),
+            skew: Arc::clone(&self.skew),
+            beep: Arc::clone(&self.beep),
+            seal: self.seal.clone(),
+            plaintext: Arc::clone(&self.plaintext),
+            peer_talk: Arc::clone(&self.peer_talk),
+            controls: self.control_sender.clone(),
+            files: self.file_line_sender.clone(),
+            listings: self.listing_sender.clone(),
+            faults: self.fault_sender.clone(),
+            faulty: Cell::new(0),
+            peer_name: conversation.clone(),
+            alerts: Arc::clone(&self.alerts),
+        };
+        self.conversation = Some(conversation);
+        let reciever_span = span.clone();
+        self.reader = Some(tasks::spawn("reader", move || {
+            reciever(reader, verifier, inbound, reciever_span)
+        }));
+        self.stats.connected();
+        self.connected = true;
+        self.record(Message::system(match (&self.relay, &self.peer_alias) {
+            (Some(route), _) => format!("connected to relay {peer} as {}", route.name),
+            (None, Some(alias)) => format!("connected to {alias} ({peer})"),
+            (None, None) => match &fingerprint {
+                // to be saved with the contact, which is then recognized wherever it connects from
+                Some(fingerprint) => format!("connected to {peer}, fingerprint {fingerprint}"),
+                None => format!("connected to {peer}"),
+            },
+        }));
+        if !lobby.is_empty() {
+            self.record(Message::system(format!(
+                "the relay put you in {}",
+                lobby.join(", ")
+            )));
+        }
+        if let Verification::Pending(sas) = &self.verification {
+            self.record(Message::system(format!(
+                "compare {sas} with the peer, then /confirm or /deny"
+            )));
+        }
+        drop(_span);
+        self.span = Some(span);
+        self.connection = Some((id, peer));
+        self.local_address = local_address.ok();
+        if let Some(session) = &tls {
+            self.record(Message::system(format!(
+                "encrypted with {}",
+                session.protocol
+            )));
+        }
+        let status = encryption::Status {
+            tls: tls.as_ref().map(|session| session.protocol.clone()),
+            sealed: self.seal.is_some(),
+        };
+        let conversation = self.conversation.clone().unwrap_or_default();
+        if self.encrypted.note(&conversation, &status) {
+            let text = format!(
+                "WARNING: {conversation} was encrypted before and this connection is plaintext, \
+                 someone in the middle may have stripped the encryption"
+            );
+            show_notification(&text);
+            let mut msg = Message::system(text);
+            msg.highlighted = true;
+            self.record(msg);
+        }
+        self.encryption = Some(status);
+        self.tls = tls;
+        if self.live.is_enabled() {
+            self.send_control(Some(&mut stream), &protocol::live(true));
+        }
+        // a relay holds lines for a while, the round trip would tell nothing
+        if self.relay.is_none() {
+            let clock = protocol::clock(timestamp::now_millis());
+            self.send_control(Some(&mut stream), &clock);
+        }
+        // a relay passes it to everyone in the room, who may not all take it
+        if self.cbor.is_offered() && self.relay.is_none() {
+            let caps = protocol::capabilities(&[cbor::CAPABILITY]);
+            self.send_control(Some(&mut stream), &caps);
+        }
+        if self.talking.is_some() {
+            self.record(Message::system("ringing the peer".to_string()));
+            self.send_control(Some(&mut stream), &protocol::talk(&talk::BELL.to_string()));
+        }
+        self.flush_outbox(&mut stream);
+        Ok(stream)
+    }
+
+    /// Clears the unread count, on the other devices as well when using a relay, and tells the
+    /// peer what was seen.
+    pub fn mark_read(&mut self, writer: Option<&mut impl std::io::Write>) {
+        self.unread.store(0, Ordering::Relaxed);
+        if let Some(store) = &self.store {
+            store.mark_read();
+        }
+        let Some(writer) = writer else {
+            return;
+        };
+        // the others may have counted messages which arrived while we were focused
+        if let Some(route) = &self.relay {
+            if let Err(e) = route.mark_read(writer) {
+                warn!("Failed to sync read state {e}");
+            }
+            return;
+        }
+        match self.unseen.swap(0, Ordering::AcqRel) {
+            0 => {}
+            id => self.send_control(Some(writer), &protocol::seen(id)),
+        }
+    }
+
+    pub fn end_connection(&mut self) {
+        self.record(Message::system("disconnected".to_string()));
+        if let Some(span) = self.span.take() {
+            span.in_scope(|| info!("connection closed"));
+        }
+        self.stats.disconnected();
+        self.connection = None;
+        self.local_address = None;
+        self.tls = None;
+        self.encryption = None;
+        self.peer_alias = None;
+        self.store = None;
+        self.connected = false;
+        self.signer = None;
+        self.verification = Verification::Unavailable;
+        self.exec.pending = None;
+        self.live.set_peer(false);
+        self.live.set_peer_typing(false);
+        self.cbor.set_peer(false);
+        self.skew.reset();
+        self.unseen.store(0, Ordering::Release);
+        if let Some(outgoing) = self.sending.take() {
+            self.record(Message::system(format!(
+                "sending {} stopped",
+                outgoing.name
+            )));
+        }
+        if let Some(incoming) = self.receiving.take() {
+            let text = format!("receiving {} stopped", incoming.name);
+            incoming.discard();
+            self.record(Message::system(text));
+        }
+        self.previewed.clear();
+        self.told_typing = None;
+        // sent again when joining
+        *self.room.lock().expect("room lock is poisoned") = relay::RoomInfo::default();
+    }
+}
+
+/// Lines of the details popup of `msg`.
+fn details(msg: &Message) -> Vec<String> {
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    let mut lines = Vec::new();
+    let kind = match msg.kind {
+        message::Kind::Incoming => "received",
+        message::Kind::Outgoing => "sent",
+        message::Kind::System => "noted",
+    };
+    lines.push(format!(
+        "{kind} at {}",
+        timestamp::format_local(msg.at, FORMAT)
+    ));
+    if let Some(envelope) = &msg.envelope {
+        lines.push(format!("from {}", envelope.sender));
+        let sent = skew::adjust(envelope.sent, msg.skew);
+        lines.push(format!(
+            "written at {}",
+            timestamp::format_local(sent, FORMAT)
+        ));
+        if msg.skew != 0 {
+            lines.push(format!(
+                "{} by the clock of the sender",
+                timestamp::format_local(envelope.sent, FORMAT)
+            ));
+        }
+    } else if let Some(author) = &msg.author {
+        lines.push(format!("from {author}"));
+    }
+    if let Some(room) = &msg.room {
+        lines.push(format!("in {room}"));
+    }
+    if msg.queued {
+        lines.push("queued, not sent yet".to_string());
+    }
+    match (msg.delivery, msg.relay_id) {
+        (Some(delivery), Some(id)) => {
+            lines.push(format!("{} by the relay as #{id}", delivery.name()))
+        }
+        (Some(relay::Delivery::Delivered), None) => lines.push("received by the peer".to_string()),
+        (Some(delivery), None) => lines.push(format!("{} by the peer", delivery.name())),
+        (None, Some(id)) => lines.push(format!("sent through the relay as #{id}")),
+        (None, None) => {}
+    }
+    if msg.unauthenticated {
+        lines.push("failed authentication".to_string());
+    }
+    if msg.highlighted {
+        lines.push("matched a highlight".to_string());
+    }
+    if let Some(before) = &msg.edited_from {
+        lines.push(format!("edited, it read: {before}"));
+    }
+    lines.push(format!(
+        "{} characters on {} lines",
+        msg.text.chars().count(),
+        msg.text.lines().count().max(1)
+    ));
+    if let Some(data) = &msg.data {
+        lines.push(format!("data: {data}"));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters_of_several_bytes_go_where_the_cursor_is() {
+        let mut app = App::default();
+        app.paste("漢字");
+        app.move_cursor_left();
+        app.enter_char('é');
+        assert_eq!(app.input, "漢é字");
+        // the composition of an input method is drawn after the wide character
+        assert_eq!(app.cursor_line(), (0, 3));
+        app.delete_char();
+        app.delete_char();
+        assert_eq!(app.input, "字");
+        assert_eq!(app.cursor_line(), (0, 0));
+    }
+
+    /// Store of a history of incoming messages sent at 1, 2, … `count`, read up to `marker`.
+    fn history(count: u64, marker: Option<u64>) -> Store {
+        let storage = store::Memory::default();
+        if let Some(marker) = marker {
+            store::Storage::set_read_marker(&storage, "ada", marker).unwrap();
+        }
+        let store = Store::new(Arc::new(storage), "ada");
+        let records: Vec<_> = (1..=count)
+            .map(|at| store::Record {
+                at,
+                kind: message::Kind::Incoming,
+                author: Some("ada".to_string()),
+                text: at.to_string(),
+            })
+            .collect();
+        store.append_records(&records);
+        store
+    }
+
+    fn texts(app: &App) -> Vec<String> {
+        let lock = app.messages.lock().unwrap();
+        (0..lock.len())
+            .filter_map(|i| lock.get(i))
+            .map(|m| m.text.clone())
+            .collect()
+    }
+
+    /// App which just started a conversation with `store`.
+    fn restored(store: &Store) -> App {
+        let mut app = App {
+            archive: store.archive().ok(),
+            ..App::default()
+        };
+        app.restore(store);
+        app
+    }
+
+    #[test]
+    fn the_end_of_the_history_is_shown_first() {
+        let app = restored(&history(60, None));
+        let texts = texts(&app);
+        assert_eq!(texts.len(), RESTORED);
+        assert_eq!((texts[0].as_str(), texts[49].as_str()), ("11", "60"));
+        let first = app.messages.lock().unwrap().get(0).unwrap().clone();
+        assert_eq!((first.at, first.author.as_deref()), (11, Some("ada")));
+    }
+
+    #[test]
+    fn messages_since_the_read_marker_are_marked_unread() {
+        // a marker far back brings in older pages up to it
+        let store = history(300, Some(100));
+        let app = restored(&store);
+        let shown = texts(&app);
+        assert_eq!(shown.len(), 250 + 1);
+        assert_eq!(shown[50], "unread since you left");
+        assert_eq!(shown[51], "101");
+        assert_eq!(app.unread.load(Ordering::Relaxed), 200);
+
+        store.mark_read();
+        let app = restored(&store);
+        assert_eq!(texts(&app).len(), RESTORED);
+        assert_eq!(app.unread.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn the_history_is_cleared_reloaded_and_purged() {
+        let store = history(5, None);
+        let mut app = restored(&store);
+        app.store = Some(store.clone());
+        let submit = |app: &mut App, line| app.submit(None::<&mut Vec<u8>>, line);
+        submit(&mut app, "/clear");
+        assert!(texts(&app).is_empty());
+        submit(&mut app, "/reload-history");
+        assert_eq!(texts(&app).len(), 5);
+
+        submit(&mut app, "/purge");
+        assert_eq!(
+            app.notice.as_deref(),
+            Some("/purge yes deletes the whole history with ada for good")
+        );
+        assert_eq!(store.read().unwrap().len(), 5);
+        submit(&mut app, "/purge yes");
+        assert!(store.read().unwrap().is_empty());
+        submit(&mut app, "/reload-history");
+        assert!(texts(&app).is_empty());
+    }
+
+    #[test]
+    fn quitting_asks_about_unfinished_work() {
+        let mut app = App::default();
+        assert!(app.request_quit());
+        assert!(app.popup.is_none());
+
+        app.submit(None::<&mut Vec<u8>>, "still offline");
+        app.paste("half a th");
+        assert!(!app.request_quit());
+        assert_eq!(app.quit, Some(Quit::Asking));
+        assert_eq!(
+            app.popup.take().unwrap().lines,
+            [
+                "1 queued messages, kept for the next run",
+                "the draft in the input box is lost",
+                "",
+                "w waits until the queued messages are sent",
+                "f quits anyway",
+            ]
+        );
+        app.quit = Some(Quit::WhenSent);
+        assert!(!app.ready_to_quit());
+    }
+
+    #[test]
+    fn the_cursor_moves_between_lines_of_the_input() {
+        let mut app = App::default();
+        app.paste("first line");
+        app.enter_char('\n');
+        app.paste("ab\nlonger third");
+        assert_eq!(app.cursor_line(), (2, 12));
+        app.move_cursor_up();
+        assert_eq!(app.cursor_line(), (1, 2));
+        app.move_cursor_up();
+        app.move_cursor_up();
+        assert_eq!(app.cursor_line(), (0, 2));
+        app.enter_char('X');
+        assert_eq!(app.input, "fiXrst line\nab\nlonger third");
+        // the column of a shorter line is kept from then on
+        app.move_cursor_down();
+        app.move_cursor_down();
+        app.move_cursor_down();
+        assert_eq!(app.cursor_line(), (2, 2));
+    }
+
+    #[test]
+    fn the_cursor_passes_over_whole_clusters() {
+        let mut app = App::default();
+        app.paste("e\u{301}👍漢");
+        assert_eq!(app.cursor_line(), (0, 5));
+        app.move_cursor_left();
+        assert_eq!(app.cursor_line(), (0, 3));
+        app.delete_char();
+        assert_eq!(app.input, "e\u{301}漢");
+        assert_eq!(app.cursor_line(), (0, 1));
+        app.move_cursor_left();
+        assert_eq!(app.cursor_position, 0);
+        app.move_cursor_right();
+        assert_eq!(app.cursor_position, "e\u{301}".len());
+
+        // a wide character covering the column takes the cursor in front of it
+        let mut app = App::default();
+        app.paste("漢字\nabc");
+        app.move_cursor_up();
+        assert_eq!(app.cursor_line(), (0, 2));
+    }
+
+    #[test]
+    fn the_peer_is_told_while_a_message_is_typed() {
+        let mut app = App {
+            input_mode: InputMode::Editing,
+            ..App::default()
+        };
+        let mut sent = Vec::new();
+        app.note_typing(Some(&mut sent));
+        assert!(sent.is_empty());
+        app.paste("hel");
+        app.note_typing(Some(&mut sent));
+        app.paste("lo");
+        app.note_typing(Some(&mut sent));
+        assert_eq!(String::from_utf8_lossy(&sent), "\u{1}TYPING on\n");
+
+        // commands aren't messages
+        sent.clear();
+        app.input = "/nick".to_string();
+        app.note_typing(Some(&mut sent));
+        assert_eq!(String::from_utf8_lossy(&sent), "\u{1}TYPING off\n");
+    }
+
+    fn selected_text(app: &App) -> Option<String> {
+        let lock = app.messages.lock().unwrap();
+        lock.selected()
+            .and_then(|i| lock.get(i))
+            .map(|m| m.text.clone())
+    }
+
+    #[test]
+    fn searches_go_through_the_matches_and_keep_the_draft() {
+        let mut app = restored(&history(12, None));
+        app.find(true);
+        assert_eq!(
+            app.notice.take().as_deref(),
+            Some("nothing searched for yet, / searches")
+        );
+        app.paste("draft");
+        app.start_search();
+        assert_eq!(app.input, "");
+        app.paste(" 1 ");
+        assert_eq!(app.search_query().as_deref(), Some("1"));
+        app.finish_search(true);
+        assert_eq!((app.input.as_str(), app.cursor_position), ("draft", 5));
+        assert!(matches!(app.input_mode, InputMode::Normal));
+        assert_eq!(selected_text(&app).as_deref(), Some("12"));
+        for older in ["11", "10", "1"] {
+            app.find(true);
+            assert_eq!(selected_text(&app).as_deref(), Some(older));
+        }
+        app.find(true);
+        assert_eq!(
+            app.notice.take().as_deref(),
+            Some("no message above contains \"1\"")
+        );
+        app.find(false);
+        assert_eq!(selected_text(&app).as_deref(), Some("10"));
+
+        // given up, the search doesn't change
+        app.start_search();
+        app.paste("5");
+        app.finish_search(false);
+        assert_eq!(app.search_query().as_deref(), Some("1"));
+        assert_eq!(selected_text(&app).as_deref(), Some("10"));
+        app.unselect_message();
+        assert_eq!(app.search_query(), None);
+        assert_eq!(selected_text(&app), None);
+    }
+
+    #[test]
+    fn the_selected_message_is_quoted_below_the_input() {
+        let mut app = App::default();
+        app.quote();
+        assert_eq!(
+            app.notice.take().as_deref(),
+            Some("select a message to quote it, j and k move")
+        );
+        {
+            let mut lock = app.messages.lock().unwrap();
+            lock.push(Message::new(
+                message::Kind::Incoming,
+                "one\ntwo".to_string(),
+            ));
+            lock.select_previous(|_| true);
+        }
+        app.paste("as you said");
+        app.input_mode = InputMode::Normal;
+        app.quote();
+        assert_eq!(app.input, "as you said\n> one\n> two\n");
+        assert_eq!(app.cursor_position, app.input.len());
+        assert!(matches!(app.input_mode, InputMode::Editing));
+        assert_eq!(selected_text(&app), None);
+    }
+
+    #[test]
+    fn details_tell_everything_about_the_message() {
+        let mut msg = Message::new(message::Kind::Incoming, "hi\nthere".to_string());
+        msg.envelope = Some(protocol::Envelope {
+            sender: "ada".to_string(),
+            sent: 1_000,
+            id: None,
+        });
+        msg.skew = 500;
+        msg.relay_id = Some(7);
+        msg.highlighted = true;
+        msg.edited_from = Some("hey".to_string());
+        let lines = details(&msg);
+        assert!(lines[0].starts_with("received at "));
+        assert_eq!(lines[1], "from ada");
+        assert!(lines[2].starts_with("written at "));
+        assert!(lines[3].ends_with(" by the clock of the sender"));
+        assert_eq!(
+            lines[4..],
+            [
+                "sent through the relay as #7",
+                "matched a highlight",
+                "edited, it read: hey",
+                "8 characters on 2 lines",
+            ]
+        );
+
+        let mut app = App::default();
+        app.show_details();
+        assert!(app.popup.is_none());
+        {
+            let mut lock = app.messages.lock().unwrap();
+            lock.push(msg);
+            lock.select_previous(|_| true);
+        }
+        app.show_details();
+        let popup = app.popup.unwrap();
+        assert_eq!((popup.title.as_str(), popup.lines), ("Message", lines));
+    }
+}
@@ -0,0 +1,259 @@
+//! Incoming messages played as Morse code, `/beep` turns it on and off.
+//!
+//! The terminal bell can't hold a tone, it rings at the start of each one and the rhythm tells
+//! dots from dashes, like on a telegraph sounder. A command in the config plays real tones:
+//!
+//! ```toml
+//! [beep]
+//! command = "beep -f 700 -l $CHATTERBOX_TONE_MS"  # run for every tone, until it is over
+//! wpm = "20"                                      # words per minute
+//! ```
+
+use std::{
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{error, warn};
+
+use crate::{config::Config, talk, tasks};
+
+/// Config table holding the options.
+pub const TABLE: &str = "beep";
+const DEFAULT_WPM: u32 = 20;
+
+/// Plays the tones of the code.
+pub trait Sound: Send {
+    /// Sounds for `length`, returning once it is over.
+    fn tone(&mut self, length: Duration);
+}
+
+/// Rings the bell of the terminal.
+pub struct Bell;
+
+impl Sound for Bell {
+    fn tone(&mut self, length: Duration) {
+        talk::ring();
+        std::thread::sleep(length);
+    }
+}
+
+/// Runs a shell command for every tone, with its length in `CHATTERBOX_TONE_MS`.
+pub struct Shell(pub String);
+
+impl Sound for Shell {
+    fn tone(&mut self, length: Duration) {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&self.0)
+            .env("CHATTERBOX_TONE_MS", length.as_millis().to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if let Err(e) = status {
+            error!("Failed to run beep command {:?}: {e}", self.0);
+            // keep the rhythm anyway
+            std::thread::sleep(length);
+        }
+    }
+}
+
+/// Plays messages one after the other in the background while enabled.
+#[derive(Default)]
+pub struct Beeper {
+    player: Mutex<Option<Player>>,
+}
+
+/// Queue of the background task and whether it should go on.
+struct Player {
+    queue: mpsc::Sender<String>,
+    playing: Arc<AtomicBool>,
+}
+
+impl Beeper {
+    pub fn is_enabled(&self) -> bool {
+        self.player.lock().expect("beep lock is poisoned").is_some()
+    }
+
+    /// Starts playing, with the sound and speed set in the config.
+    pub fn enable(&self, config: &Config) {
+        let mut sound: Box<dyn Sound> = Box::new(Bell);
+        let mut wpm = DEFAULT_WPM;
+        for (key, value) in config.strings(TABLE) {
+            match key.as_str() {
+                "command" => sound = Box::new(Shell(value)),
+                "wpm" => match value.parse() {
+                    Ok(w @ 1..=60) => wpm = w,
+                    _ => warn!("Ignoring invalid beep option wpm = {value:?}"),
+                },
+                _ => warn!("Ignoring unknown beep option {key}"),
+            }
+        }
+        let (queue, rx) = mpsc::channel::<String>();
+        let playing = Arc::new(AtomicBool::new(true));
+        let enabled = Arc::clone(&playing);
+        tasks::spawn("beeper", move || {
+            // PARIS is 50 units long, the standard word
+            let unit = Duration::from_millis(u64::from(1200 / wpm));
+            for text in rx {
+                if !enabled.load(Ordering::Relaxed) {
+                    break;
+                }
+                for symbol in encode(&text).chars() {
+                    if !enabled.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    match symbol {
+                        '.' => sound.tone(unit),
+                        '-' => sound.tone(unit * 3),
+                        // on top of the gap after the last tone
+                        ' ' => std::thread::sleep(unit * 2),
+                        _ => std::thread::sleep(unit * 6),
+                    }
+                    std::thread::sleep(unit);
+                }
+                std::thread::sleep(unit * 6);
+            }
+        });
+        let player = Player { queue, playing };
+        if let Some(old) = self
+            .player
+            .lock()
+            .expect("beep lock is poisoned")
+            .replace(player)
+        {
+            old.playing.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Stops, cutting short what is playing.
+    pub fn disable(&self) {
+        if let Some(player) = self.player.lock().expect("beep lock is poisoned").take() {
+            player.playing.store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub fn play(&self, text: &str) {
+        if let Some(player) = self.player.lock().expect("beep lock is poisoned").as_ref() {
+            let _ = player.queue.send(text.to_string());
+        }
+    }
+}
+
+/// Morse code of `text`, letters separated by a space and words by `/`. Characters without a
+/// code are left out.
+pub fn encode(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter_map(|c| code(c.to_ascii_lowercase()))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn code(c: char) -> Option<&'static str> {
+    Some(match c {
+        'a' => ".-",
+        'b' => "-...",
+        'c' => "-.-.",
+        'd' => "-..",
+        'e' => ".",
+        'f' => "..-.",
+        'g' => "--.",
+        'h' => "....",
+        'i' => "..",
+        'j' => ".---",
+        'k' => "-.-",
+        'l' => ".-..",
+        'm' => "--",
+        'n' => "-.",
+        'o' => "---",
+        'p' => ".--.",
+        'q' => "--.-",
+        'r' => ".-.",
+        's' => "...",
+        't' => "-",
+        'u' => "..-",
+        'v' => "...-",
+        'w' => ".--",
+        'x' => "-..-",
+        'y' => "-.--",
+        'z' => "--..",
+        '0' => "-----",
+        '1' => ".----",
+        '2' => "..---",
+        '3' => "...--",
+        '4' => "....-",
+        '5' => ".....",
+        '6' => "-....",
+        '7' => "--...",
+        '8' => "---..",
+        '9' => "----.",
+        '.' => ".-.-.-",
+        ',' => "--..--",
+        '?' => "..--..",
+        '\'' => ".----.",
+        '!' => "-.-.--",
+        '/' => "-..-.",
+        '(' => "-.--.",
+        ')' => "-.--.-",
+        '&' => ".-...",
+        ':' => "---...",
+        ';' => "-.-.-.",
+        '=' => "-...-",
+        '+' => ".-.-.",
+        '-' => "-....-",
+        '"' => ".-..-.",
+        '@' => ".--.-.",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Instant};
+
+    use super::*;
+
+    #[test]
+    fn text_is_encoded_a_letter_and_a_word_at_a_time() {
+        assert_eq!(encode("SOS"), "... --- ...");
+        assert_eq!(encode("  hi,  you "), ".... .. --..--/-.-- --- ..-");
+        assert_eq!(encode("é ok"), "--- -.-");
+    }
+
+    #[test]
+    fn tones_are_as_long_as_their_symbol() {
+        let log = std::env::temp_dir().join(format!("chatterbox-beep-{}", std::process::id()));
+        let config: Config = format!(
+            "[beep]\ncommand = \"echo $CHATTERBOX_TONE_MS >> {}\"\nwpm = \"60\"\n",
+            log.display()
+        )
+        .parse()
+        .unwrap();
+        let beeper = Beeper::default();
+        beeper.play("ignored while disabled");
+        beeper.enable(&config);
+        assert!(beeper.is_enabled());
+        beeper.play("et");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut tones = String::new();
+        while tones.lines().count() < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+            tones = fs::read_to_string(&log).unwrap_or_default();
+        }
+        assert_eq!(tones, "20\n60\n");
+        beeper.disable();
+        assert!(!beeper.is_enabled());
+        fs::remove_file(log).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+//! Just enough of the unicode bidirectional algorithm to show Hebrew and Arabic in a terminal,
+//! which draws every character left to right.
+//!
+//! Characters are resolved to strong directions only, neutrals take the direction around them
+//! and fall back to the one of the paragraph. No embeddings, no shaping.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Ltr,
+    Rtl,
+    Neutral,
+}
+
+fn is_rtl(c: char) -> bool {
+    matches!(c,
+        '\u{0590}'..='\u{08FF}' | '\u{FB1D}'..='\u{FDFF}' | '\u{FE70}'..='\u{FEFF}')
+}
+
+/// Hebrew points and Arabic harakat, they stay behind the letter they belong to.
+fn is_mark(c: char) -> bool {
+    matches!(c, '\u{0591}'..='\u{05C7}' | '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{06D6}'..='\u{06ED}')
+}
+
+fn direction(c: char) -> Direction {
+    if is_rtl(c) {
+        Direction::Rtl
+    } else if c.is_alphanumeric() {
+        Direction::Ltr
+    } else {
+        Direction::Neutral
+    }
+}
+
+fn mirror(c: char) -> char {
+    match c {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        '«' => '»',
+        '»' => '«',
+        c => c,
+    }
+}
+
+pub fn contains_rtl(text: &str) -> bool {
+    text.chars().any(is_rtl)
+}
+
+/// Whether the first strong character is right to left, which makes it the paragraph direction.
+pub fn is_rtl_paragraph(text: &str) -> bool {
+    text.chars()
+        .map(direction)
+        .find(|&d| d != Direction::Neutral)
+        == Some(Direction::Rtl)
+}
+
+/// `line` in the order it has to be drawn from left to right.
+pub fn reorder(line: &str) -> String {
+    let base = if is_rtl_paragraph(line) {
+        Direction::Rtl
+    } else {
+        Direction::Ltr
+    };
+    // letters with their marks, so that reversing keeps them together
+    let mut clusters: Vec<String> = Vec::new();
+    for c in line.chars() {
+        match clusters.last_mut() {
+            Some(cluster) if is_mark(c) => cluster.push(c),
+            _ => clusters.push(c.to_string()),
+        }
+    }
+    let mut dirs: Vec<Direction> = clusters
+        .iter()
+        .map(|c| c.chars().next().map_or(Direction::Neutral, direction))
+        .collect();
+    let mut i = 0;
+    while i < dirs.len() {
+        if dirs[i] != Direction::Neutral {
+            i += 1;
+            continue;
+        }
+        let end = (i..dirs.len())
+            .find(|&j| dirs[j] != Direction::Neutral)
+            .unwrap_or(dirs.len());
+        let before = i.checked_sub(1).map_or(base, |j| dirs[j]);
+        let after = dirs.get(end).copied().unwrap_or(base);
+        let resolved = if before == after { before } else { base };
+        dirs[i..end].fill(resolved);
+        i = end;
+    }
+    let mut runs: Vec<String> = Vec::new();
+    let mut start = 0;
+    while start < clusters.len() {
+        let dir = dirs[start];
+        let end = (start..clusters.len())
+            .find(|&j| dirs[j] != dir)
+            .unwrap_or(clusters.len());
+        let run = &clusters[start..end];
+        runs.push(match dir {
+            Direction::Rtl => run
+                .iter()
+                .rev()
+                .map(|c| c.chars().map(mirror).collect::<String>())
+                .collect(),
+            _ => run.concat(),
+        });
+        start = end;
+    }
+    if base == Direction::Rtl {
+        runs.reverse();
+    }
+    runs.concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn right_to_left_runs_are_reversed() {
+        assert_eq!(reorder("plain text"), "plain text");
+        assert_eq!(reorder("say שלום to them"), "say םולש to them");
+        // the paragraph is right to left, so the latin word goes to the left
+        assert_eq!(reorder("שלום hello"), "hello םולש");
+        assert_eq!(reorder("(שלום)"), "(םולש)");
+        // points stay behind their letter
+        assert_eq!(reorder("שָׁלוֹם"), "םוֹלשָׁ");
+    }
+
+    #[test]
+    fn the_first_strong_character_sets_the_direction() {
+        assert!(is_rtl_paragraph("… مرحبا hi"));
+        assert!(!is_rtl_paragraph("hi مرحبا"));
+        assert!(!is_rtl_paragraph("... !"));
+        assert!(contains_rtl("hi مرحبا"));
+        assert!(!contains_rtl("hi"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Bridges carrying the conversation over to another chat network.
+//!
+//! A bridge is driven from a thread of its own: it is connected, then polled for events with
+//! [`Bridge::receive`] while the messages written here are handed to [`Bridge::send`] in between.
+//! Whatever arrives through it is shown and passed on to the peer, and the other way round.
+
+use std::{io, sync::mpsc, time::Duration};
+
+use tracing::{info, warn};
+
+use crate::{irc::Irc, tasks};
+
+/// Pause before connecting again after the other network dropped us.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Something that happened on the other network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Message {
+        from: String,
+        text: String,
+    },
+    Joined(String),
+    Left(String),
+    /// Everybody on the other side, sent whenever it changes
+    Roster(Vec<String>),
+    /// Connection state worth telling the user about
+    Status(String),
+}
+
+pub trait Bridge: Send {
+    /// Short name of the network, shown next to the names of its users.
+    fn name(&self) -> &str;
+
+    /// Establishes the connection, blocking until messages can be sent.
+    fn connect(&mut self) -> io::Result<()>;
+
+    /// Waits a short while for the next event, `None` if nothing happened meanwhile.
+    fn receive(&mut self) -> io::Result<Option<Event>>;
+
+    fn send(&mut self, text: &str) -> io::Result<()>;
+
+    /// Names of the users on the other side.
+    fn roster(&self) -> Vec<String>;
+}
+
+/// Bridge for a url like `irc://nick@host:6667/channel`.
+pub fn open(url: &str) -> Result<Box<dyn Bridge>, String> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| format!("{url} is not a url"))?;
+    match scheme {
+        "irc" => Ok(Box::new(Irc::from_url(rest)?)),
+        _ => Err(format!("no bridge for {scheme}, only irc is supported")),
+    }
+}
+
+/// Runs the bridge in the background, returns where to write messages for the other network.
+pub fn spawn(mut bridge: Box<dyn Bridge>, events: mpsc::Sender<Event>) -> mpsc::Sender<String> {
+    let (tx, outgoing) = mpsc::channel::<String>();
+    let name = bridge.name().to_string();
+    tasks::spawn(format!("{name} bridge"), move || {
+        'connect: loop {
+            tasks::set_state("connecting");
+            if let Err(e) = bridge.connect() {
+                warn!("Failed to connect {name} bridge: {e}");
+                tasks::set_state(format!("waiting to reconnect, {e}"));
+                let _ = events.send(Event::Status(format!("{name} bridge failed: {e}")));
+                std::thread::sleep(RETRY_INTERVAL);
+                continue;
+            }
+            info!("{name} bridge connected");
+            tasks::set_state("connected");
+            let _ = events.send(Event::Status(format!("{name} bridge connected")));
+            let mut roster = Vec::new();
+            loop {
+                loop {
+                    match outgoing.try_recv() {
+                        Ok(text) => {
+                            if let Err(e) = bridge.send(&text) {
+                                warn!("Failed to send to {name} bridge: {e}");
+                            }
+                        }
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        // nobody left to bridge for
+                        Err(mpsc::TryRecvError::Disconnected) => return,
+                    }
+                }
+                match bridge.receive() {
+                    Ok(Some(event)) => {
+                        if events.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("{name} bridge disconnected: {e}");
+                        let _ = events.send(Event::Status(format!("{name} bridge lost: {e}")));
+                        tasks::set_state(format!("waiting to reconnect, {e}"));
+                        std::thread::sleep(RETRY_INTERVAL);
+                        continue 'connect;
+                    }
+                }
+                let current = bridge.roster();
+                if current != roster {
+                    roster = current;
+                    let _ = events.send(Event::Roster(roster.clone()));
+                }
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bridges_are_picked_by_scheme() {
+        assert_eq!(open("irc://sam@localhost/chat").unwrap().name(), "irc");
+        assert_eq!(
+            open("matrix://sam@localhost").err(),
+            Some("no bridge for matrix, only irc is supported".to_string())
+        );
+        assert!(open("localhost").is_err());
+    }
+}
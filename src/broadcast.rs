@@ -0,0 +1,346 @@
+//! Server mode for more than two, `--server --multi`: every line of a client goes to all others.
+//!
+//! Lines are passed on like the [relay](crate::relay) does, as `FROM <address> <line>`, so the
+//! clients can tell who wrote them. The one running the server takes part through a connection of
+//! its own, as `host`, the first and only one accepted on a listener of its own.
+//! Every client has a thread writing to it, one which stops reading is dropped rather than
+//! holding up the others.
+//!
+//! Clients get into named rooms with `JOIN <#room>` and out with `PART <#room>`, what they send
+//! with `TO <#room> <id> <line>` only goes to the other members, as `ROOM <#room> <name> <line>`.
+//! Lines without a room still go to everybody.
+//!
+//! Everybody hears of who comes and goes through `PRESENCE <name> <online|offline>` lines, and
+//! gets one for each of those already there when connecting.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, BufReader, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{access, protocol, relay, tasks};
+
+/// Name the others see the lines of the server's own interface with.
+const HOST: &str = "host";
+/// Sender of the notices about clients coming and going.
+const SERVER: &str = "server";
+const PRESENCE: &str = "\u{1}PRESENCE ";
+/// Lines waiting for a client, one which falls further behind is dropped.
+const QUEUE: usize = 1024;
+/// Time a write to a client may take before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Line telling whether `name` is connected.
+fn presence(name: &str, online: bool) -> String {
+    let status = if online { "online" } else { "offline" };
+    format!("{PRESENCE}{name} {status}\n")
+}
+
+/// Name and whether it is connected, for a presence line.
+pub fn parse_presence(line: &str) -> Option<(&str, bool)> {
+    match line.strip_prefix(PRESENCE)?.rsplit_once(' ')? {
+        (name, "online") => Some((name, true)),
+        (name, "offline") => Some((name, false)),
+        _ => None,
+    }
+}
+
+struct Client {
+    name: String,
+    /// Lines for the thread writing them, the registry never waits for a client
+    lines: SyncSender<String>,
+    rooms: HashSet<String>,
+}
+
+impl Client {
+    /// Starts writing to `stream`, which is shut down once the client is dropped or a write
+    /// fails, ending the reading side as well.
+    fn spawn(name: String, mut stream: TcpStream) -> io::Result<Self> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let (lines, queue) = mpsc::sync_channel::<String>(QUEUE);
+        let writing = name.clone();
+        tasks::spawn(format!("writer of {name}"), move || {
+            for line in queue {
+                if let Err(e) = stream.write_all(line.as_bytes()) {
+                    debug!("failed to write to {writing}: {e}");
+                    break;
+                }
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+        Ok(Self {
+            name,
+            lines,
+            rooms: HashSet::new(),
+        })
+    }
+
+    /// Queues `line`, false if the client is gone or too far behind.
+    fn send(&self, line: &str) -> bool {
+        match self.lines.try_send(line.to_string()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("dropping {}, it doesn't keep up", self.name);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// Everybody connected, by id.
+#[derive(Default)]
+struct Registry {
+    clients: HashMap<u64, Client>,
+    next_id: u64,
+}
+
+impl Registry {
+    fn add(&mut self, client: Client) -> u64 {
+        for other in self.clients.values() {
+            client.send(&presence(&other.name, true));
+        }
+        self.next_id += 1;
+        self.clients.insert(self.next_id, client);
+        self.next_id
+    }
+
+    /// Writes `line` to everybody but `except`, dropping whoever can't take it.
+    fn broadcast(&mut self, except: u64, line: &str) {
+        self.send_where(except, line, |_| true);
+    }
+
+    /// Writes `line` to the members of `room` but `except`.
+    fn broadcast_in(&mut self, except: u64, room: &str, line: &str) {
+        self.send_where(except, line, |client| client.rooms.contains(room));
+    }
+
+    fn send_where(&mut self, except: u64, line: &str, to: impl Fn(&Client) -> bool) {
+        self.clients
+            .retain(|id, client| *id == except || !to(client) || client.send(line));
+    }
+
+    /// Acts on a line of client `id`, passing it on to whoever it is for.
+    fn handle(&mut self, id: u64, line: &str) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        let name = client.name.clone();
+        match relay::parse_request(line) {
+            Some(relay::Request::Join(room)) => {
+                if client.rooms.insert(room.to_string()) {
+                    let notice = relay::forward_in(room, SERVER, &format!("{name} joined {room}"));
+                    self.broadcast_in(0, room, &notice);
+                }
+            }
+            Some(relay::Request::Part(room)) => {
+                if client.rooms.remove(room) {
+                    let notice = relay::forward_in(room, SERVER, &format!("{name} left {room}"));
+                    self.broadcast_in(id, room, &notice);
+                }
+            }
+            Some(relay::Request::Post { room, payload }) if client.rooms.contains(room) => {
+                self.broadcast_in(id, room, &relay::forward_in(room, &name, payload));
+            }
+            Some(relay::Request::Post { room, .. }) => {
+                client.send(&relay::error(&format!("you aren't in {room}")));
+            }
+            // the presence lines tell who left, and every client has a clock of its own
+            None if protocol::is_peer_to_peer(line) => {}
+            None => self.broadcast(id, &relay::forward(&name, line)),
+        }
+    }
+}
+
+/// Accepts clients on the listeners in the background, returns where the host connects to.
+pub fn spawn(listeners: Vec<TcpListener>, gate: access::Gate) -> io::Result<SocketAddr> {
+    let registry = Arc::new(Mutex::new(Registry::default()));
+    let host = TcpListener::bind(("127.0.0.1", 0))?;
+    let host_address = host.local_addr()?;
+    let hosts = Arc::clone(&registry);
+    tasks::spawn("host listener", move || {
+        // the interface connects right away, nobody coming later may pose as the host
+        match host.accept() {
+            Ok((stream, _)) => join(&hosts, HOST.to_string(), stream),
+            Err(e) => warn!("Failed to accept the host: {e}"),
+        }
+    });
+    let gate = Arc::new(gate);
+    for listener in listeners {
+        let (registry, gate) = (Arc::clone(&registry), Arc::clone(&gate));
+        tasks::spawn("broadcast listener", move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Failed to accept client: {e}");
+                        continue;
+                    }
+                };
+                if let Err(reason) = access::admit(&gate, &stream) {
+                    info!("turned away: {reason}");
+                    continue;
+                }
+                let name = stream.peer_addr().map_or_else(
+                    |_| "?".to_string(),
+                    |a| SocketAddr::new(a.ip().to_canonical(), a.port()).to_string(),
+                );
+                join(&registry, name, stream);
+            }
+        });
+    }
+    Ok(host_address)
+}
+
+/// Registers the client and passes its lines on until it leaves.
+fn join(registry: &Arc<Mutex<Registry>>, name: String, stream: TcpStream) {
+    let client = stream
+        .try_clone()
+        .and_then(|reader| Ok((BufReader::new(reader), Client::spawn(name.clone(), stream)?)));
+    let (reader, client) = match client {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Failed to serve {name}: {e}");
+            return;
+        }
+    };
+    info!("{name} joined");
+    let id = {
+        let mut registry = registry.lock().expect("registry lock is poisoned");
+        registry.broadcast(0, &relay::forward(SERVER, &format!("{name} joined")));
+        let id = registry.add(client);
+        registry.broadcast(0, &presence(&name, true));
+        id
+    };
+    let registry = Arc::clone(registry);
+    tasks::spawn(format!("client {name}"), move || {
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+            registry
+                .lock()
+                .expect("registry lock is poisoned")
+                .handle(id, &line);
+        }
+        info!("{name} left");
+        let mut registry = registry.lock().expect("registry lock is poisoned");
+        registry.clients.remove(&id);
+        registry.broadcast(id, &relay::forward(SERVER, &format!("{name} left")));
+        registry.broadcast(id, &presence(&name, false));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Client {
+        name: String,
+        stream: TcpStream,
+        reader: BufReader<TcpStream>,
+    }
+
+    impl Client {
+        fn connect(server: SocketAddr) -> Self {
+            let stream = TcpStream::connect(server).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let name = stream.local_addr().unwrap().to_string();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            Self {
+                name,
+                stream,
+                reader,
+            }
+        }
+
+        fn send(&mut self, line: &str) {
+            self.stream.write_all(line.as_bytes()).unwrap();
+        }
+
+        fn next(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line
+        }
+    }
+
+    #[test]
+    fn lines_go_to_everybody_or_the_room() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let server = listener.local_addr().unwrap();
+        spawn(vec![listener], access::Gate::default()).unwrap();
+        let mut ada = Client::connect(server);
+        assert_eq!(ada.next(), presence(&ada.name, true));
+        let mut bob = Client::connect(server);
+        assert_eq!(bob.next(), presence(&ada.name, true));
+        assert_eq!(bob.next(), presence(&bob.name, true));
+        let joined = relay::forward(SERVER, &format!("{} joined", bob.name));
+        assert_eq!(ada.next(), joined);
+        assert_eq!(
+            parse_presence(ada.next().trim_end()),
+            Some((bob.name.as_str(), true))
+        );
+
+        ada.send("hi\n");
+        assert_eq!(bob.next(), relay::forward(&ada.name, "hi"));
+
+        bob.send(&relay::join("#x"));
+        let notice = format!("{} joined #x", bob.name);
+        assert_eq!(bob.next(), relay::forward_in("#x", SERVER, &notice));
+        ada.send(&relay::post("#x", "let me in"));
+        assert_eq!(ada.next(), relay::error("you aren't in #x"));
+        ada.send(&relay::join("#x"));
+        // the notices of ada joining
+        bob.next();
+        ada.next();
+        ada.send(&relay::post("#x", "secret"));
+        assert_eq!(bob.next(), relay::forward_in("#x", &ada.name, "secret"));
+    }
+
+    #[test]
+    fn only_the_first_host_is_let_in() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let server = listener.local_addr().unwrap();
+        let host = spawn(vec![listener], access::Gate::default()).unwrap();
+        let _host = Client::connect(host);
+        let mut ada = Client::connect(server);
+        assert_eq!(ada.next(), presence(HOST, true));
+
+        // refused, or let in only to be closed
+        if let Ok(impostor) = TcpStream::connect(host) {
+            impostor
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut line = String::new();
+            let read = BufReader::new(impostor).read_line(&mut line);
+            assert!(matches!(read, Ok(0) | Err(_)), "{line}");
+        }
+    }
+
+    #[test]
+    fn clients_which_stop_reading_are_dropped() {
+        let mut registry = Registry::default();
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let _stalled = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let id = registry.add(super::Client::spawn("stalled".to_string(), stream).unwrap());
+        let line = format!("{}\n", "x".repeat(4096));
+        // the socket buffers fill up first, then the queue
+        for _ in 0..100_000 {
+            registry.broadcast(0, &line);
+            if registry.clients.is_empty() {
+                break;
+            }
+        }
+        assert!(!registry.clients.contains_key(&id));
+    }
+}
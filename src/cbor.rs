@@ -0,0 +1,404 @@
+//! Compact encoding of the frames heavy on bytes, for peers on slow or metered links.
+//!
+//! Peers started with `--cbor` announce it with `\u{1}CAPS cbor` once connected, and while both
+//! did, messages and file chunks go out as `\u{1}CBOR <base64>` lines holding a CBOR array,
+//! `[0, sender, sent, text, data]` for a message, `data` being the JSON annotation as CBOR or
+//! null, and `[1, id, bytes]` for a piece of a file. Chunks take a third less than in hex.
+//! These lines are always understood, whether we offered it or not, and decode to the same
+//! [`Payload`] as the text frames.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    base64, json,
+    protocol::{Envelope, Payload},
+};
+
+pub const FRAME: &str = "\u{1}CBOR ";
+/// Name of the encoding among the capabilities.
+pub const CAPABILITY: &str = "cbor";
+/// Arrays and maps nested at most, deeper ones are refused rather than overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+const MESSAGE: u64 = 0;
+const FILE_CHUNK: u64 = 1;
+
+/// Whether frames go out in CBOR, which takes both sides. Shared with the reciever.
+#[derive(Debug, Default)]
+pub struct Negotiation {
+    /// We offered it
+    offered: AtomicBool,
+    /// The peer offered it
+    peer: AtomicBool,
+}
+
+impl Negotiation {
+    pub fn is_offered(&self) -> bool {
+        self.offered.load(Ordering::Acquire)
+    }
+
+    pub fn set_offered(&self, offered: bool) {
+        self.offered.store(offered, Ordering::Release);
+    }
+
+    /// Records whether the peer offered it, returns whether it changed.
+    pub fn set_peer(&self, offered: bool) -> bool {
+        self.peer.swap(offered, Ordering::AcqRel) != offered
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_offered() && self.peer.load(Ordering::Acquire)
+    }
+}
+
+/// Data item, as much of CBOR as the frames need.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Unsigned(u64),
+    /// `-1 - n`
+    Negative(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Item>),
+    Map(Vec<(Item, Item)>),
+    Bool(bool),
+    Null,
+    Float(f64),
+}
+
+impl Item {
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Item::Unsigned(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn into_text(self) -> Option<String> {
+        match self {
+            Item::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode(item: &Item) -> Vec<u8> {
+    let mut out = Vec::new();
+    write(&mut out, item);
+    out
+}
+
+fn write(out: &mut Vec<u8>, item: &Item) {
+    match item {
+        Item::Unsigned(n) => head(out, 0, *n),
+        Item::Negative(n) => head(out, 1, *n),
+        Item::Bytes(bytes) => {
+            head(out, 2, bytes.len() as u64);
+            out.extend_from_slice(bytes);
+        }
+        Item::Text(text) => {
+            head(out, 3, text.len() as u64);
+            out.extend_from_slice(text.as_bytes());
+        }
+        Item::Array(items) => {
+            head(out, 4, items.len() as u64);
+            items.iter().for_each(|item| write(out, item));
+        }
+        Item::Map(entries) => {
+            head(out, 5, entries.len() as u64);
+            for (key, value) in entries {
+                write(out, key);
+                write(out, value);
+            }
+        }
+        Item::Bool(false) => out.push(0xf4),
+        Item::Bool(true) => out.push(0xf5),
+        Item::Null => out.push(0xf6),
+        Item::Float(n) => {
+            out.push(0xfb);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+/// Major type and the argument following it, in as few bytes as it fits.
+fn head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, n as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+/// The single item `bytes` hold.
+pub fn decode(bytes: &[u8]) -> Result<Item, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let item = reader.item(0)?;
+    match reader.pos == bytes.len() {
+        true => Ok(item),
+        false => Err(format!("{} bytes after the item", bytes.len() - reader.pos)),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("the item is cut short")?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn argument(&mut self, info: u8) -> Result<u64, String> {
+        let len = match info {
+            0..=23 => return Ok(info.into()),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            31 => return Err("indefinite lengths aren't supported".to_string()),
+            info => return Err(format!("reserved additional information {info}")),
+        };
+        Ok(self
+            .take(len)?
+            .iter()
+            .fold(0, |n, byte| n << 8 | u64::from(*byte)))
+    }
+
+    /// Length of a string or a container, which can't be more than what is left.
+    fn length(&mut self, info: u8) -> Result<usize, String> {
+        let len = self.argument(info)?;
+        match usize::try_from(len) {
+            Ok(len) if len <= self.bytes.len() - self.pos => Ok(len),
+            _ => Err("the item is cut short".to_string()),
+        }
+    }
+
+    fn item(&mut self, depth: usize) -> Result<Item, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("nested deeper than {MAX_DEPTH}"));
+        }
+        let initial = self.take(1)?[0];
+        let info = initial & 0x1f;
+        Ok(match initial >> 5 {
+            0 => Item::Unsigned(self.argument(info)?),
+            1 => Item::Negative(self.argument(info)?),
+            2 => {
+                let len = self.length(info)?;
+                Item::Bytes(self.take(len)?.to_vec())
+            }
+            3 => {
+                let len = self.length(info)?;
+                let text = std::str::from_utf8(self.take(len)?).map_err(|e| e.to_string())?;
+                Item::Text(text.to_string())
+            }
+            4 => {
+                let len = self.length(info)?;
+                let items = (0..len).map(|_| self.item(depth + 1));
+                Item::Array(items.collect::<Result<_, _>>()?)
+            }
+            5 => {
+                let len = self.length(info)?;
+                let mut entries = Vec::with_capacity(len);
+                for _ in 0..len {
+                    entries.push((self.item(depth + 1)?, self.item(depth + 1)?));
+                }
+                Item::Map(entries)
+            }
+            6 => return Err("tags aren't supported".to_string()),
+            _ => match info {
+                20 => Item::Bool(false),
+                21 => Item::Bool(true),
+                22 => Item::Null,
+                25 => Item::Float(half(self.argument(info)? as u16)),
+                26 => Item::Float(f32::from_bits(self.argument(info)? as u32).into()),
+                27 => Item::Float(f64::from_bits(self.argument(info)?)),
+                info => return Err(format!("unsupported simple value {info}")),
+            },
+        })
+    }
+}
+
+/// Half precision float, which other encoders pick for numbers like 1.5.
+fn half(bits: u16) -> f64 {
+    let exponent = i32::from(bits >> 10 & 0x1f);
+    let mantissa = f64::from(bits & 0x3ff);
+    let value = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        exponent => (1024.0 + mantissa) * 2f64.powi(exponent - 25),
+    };
+    match bits >> 15 {
+        0 => value,
+        _ => -value,
+    }
+}
+
+/// Same value in CBOR, whole numbers as integers.
+pub fn from_json(value: &json::Value) -> Item {
+    // beyond this, f64 doesn't tell whole numbers apart
+    const EXACT: f64 = 9_007_199_254_740_992.0;
+    match value {
+        json::Value::Null => Item::Null,
+        json::Value::Bool(b) => Item::Bool(*b),
+        json::Value::Number(n) if n.fract() == 0.0 && n.abs() <= EXACT => match *n >= 0.0 {
+            true => Item::Unsigned(*n as u64),
+            false => Item::Negative((-1.0 - n) as u64),
+        },
+        json::Value::Number(n) => Item::Float(*n),
+        json::Value::String(s) => Item::Text(s.clone()),
+        json::Value::Array(items) => Item::Array(items.iter().map(from_json).collect()),
+        json::Value::Object(entries) => Item::Map(
+            entries
+                .iter()
+                .map(|(k, v)| (Item::Text(k.clone()), from_json(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Same value in JSON, `None` for byte strings and maps with keys other than text.
+pub fn to_json(item: Item) -> Option<json::Value> {
+    Some(match item {
+        Item::Unsigned(n) => json::Value::Number(n as f64),
+        Item::Negative(n) => json::Value::Number(-1.0 - n as f64),
+        Item::Float(n) => json::Value::Number(n),
+        Item::Bytes(_) => return None,
+        Item::Text(text) => json::Value::String(text),
+        Item::Array(items) => {
+            json::Value::Array(items.into_iter().map(to_json).collect::<Option<_>>()?)
+        }
+        Item::Map(entries) => json::Value::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| Some((k.into_text()?, to_json(v)?)))
+                .collect::<Option<_>>()?,
+        ),
+        Item::Bool(b) => json::Value::Bool(b),
+        Item::Null => json::Value::Null,
+    })
+}
+
+pub fn message(envelope: &Envelope, text: &str, data: Option<&json::Value>) -> String {
+    frame(Item::Array(vec![
+        Item::Unsigned(MESSAGE),
+        Item::Text(envelope.sender.clone()),
+        Item::Unsigned(envelope.sent),
+        Item::Text(text.to_string()),
+        data.map_or(Item::Null, from_json),
+        envelope.id.map_or(Item::Null, Item::Unsigned),
+    ]))
+}
+
+pub fn file_chunk(id: u64, data: &[u8]) -> String {
+    frame(Item::Array(vec![
+        Item::Unsigned(FILE_CHUNK),
+        Item::Unsigned(id),
+        Item::Bytes(data.to_vec()),
+    ]))
+}
+
+fn frame(item: Item) -> String {
+    format!("{FRAME}{}", base64::encode(&encode(&item)))
+}
+
+/// What the base64 after [`FRAME`] holds, `None` if it isn't a frame we know.
+pub fn decode_frame(encoded: &str) -> Option<Payload> {
+    let Item::Array(items) = decode(&base64::decode(encoded)?).ok()? else {
+        return None;
+    };
+    let mut items = items.into_iter();
+    match items.next()?.as_u64()? {
+        MESSAGE => {
+            let sender = items.next()?.into_text()?;
+            let sent = items.next()?.as_u64()?;
+            let text = items.next()?.into_text()?;
+            let data = match items.next() {
+                None | Some(Item::Null) => None,
+                Some(data) => to_json(data),
+            };
+            let id = items.next().and_then(|id| id.as_u64());
+            Some(Payload::Message {
+                envelope: Envelope { sender, sent, id },
+                text,
+                data,
+            })
+        }
+        FILE_CHUNK => {
+            let id = items.next()?.as_u64()?;
+            let Item::Bytes(data) = items.next()? else {
+                return None;
+            };
+            Some(Payload::FileChunk { id, data })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    #[test]
+    fn values_read_back_the_same_from_either_encoding() {
+        for text in [
+            r#"{"kind":"build","ok":true,"took":1.5,"steps":[1,-2,300000,null],"by":{"name":"ci"}}"#,
+            r#"[-1,0,23,24,255,256,65536,4294967296,"","ünï"]"#,
+            r#""just a string""#,
+        ] {
+            let value = json::parse(text).unwrap();
+            let bytes = encode(&from_json(&value));
+            assert_eq!(to_json(decode(&bytes).unwrap()).unwrap(), value, "{text}");
+        }
+        // as RFC 8949 encodes them
+        assert_eq!(encode(&Item::Unsigned(100)), [0x18, 0x64]);
+        assert_eq!(encode(&Item::Negative(999)), [0x39, 0x03, 0xe7]);
+        assert_eq!(decode(&[0xf9, 0x3e, 0x00]).unwrap(), Item::Float(1.5));
+        assert!(decode(&[0x9f, 0x01, 0xff]).is_err());
+        assert!(decode(&[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn frames_decode_like_their_text_counterparts() {
+        let envelope = Envelope {
+            sender: "alice".to_string(),
+            sent: 1_700_000_000_000,
+            id: Some(12),
+        };
+        let data = json::parse(r#"{"build":42,"green":true}"#).unwrap();
+        assert_eq!(
+            protocol::decode(&message(&envelope, "tab\tand\nnewline", Some(&data))),
+            protocol::decode(&protocol::message(
+                &envelope,
+                "tab\tand\nnewline",
+                Some(&data)
+            ))
+        );
+        let bytes: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let (compact, text) = (file_chunk(7, &bytes), protocol::file_chunk(7, &bytes));
+        assert_eq!(protocol::decode(&compact), protocol::decode(&text));
+        assert!(compact.len() < text.len() * 7 / 10);
+    }
+}
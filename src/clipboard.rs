@@ -0,0 +1,166 @@
+//! The system clipboard, Ctrl+V pastes from it while editing and `y` copies the selected message.
+//!
+//! It goes through the usual tools, wl-copy and wl-paste on Wayland, xclip or xsel on X11 and
+//! pbcopy and pbpaste on macOS. Without any of them copying falls back to the OSC 52 escape
+//! sequence, which most terminals take, over SSH as well. Other commands go in the config:
+//!
+//! ```toml
+//! [clipboard]
+//! copy = "tmux load-buffer -"   # given the text on stdin
+//! paste = "tmux save-buffer -"  # printing it
+//! ```
+
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use tracing::{debug, warn};
+
+use crate::{base64, config::Config};
+
+/// Config table holding the commands.
+pub const TABLE: &str = "clipboard";
+/// Exit status of `sh` for a command it doesn't find.
+const NOT_FOUND: i32 = 127;
+
+#[derive(Debug, Default, Clone)]
+pub struct Clipboard {
+    copy: Option<String>,
+    paste: Option<String>,
+}
+
+impl Clipboard {
+    pub fn from_config(config: &Config) -> Self {
+        let mut clipboard = Self::default();
+        for (key, command) in config.strings(TABLE) {
+            match key.as_str() {
+                "copy" => clipboard.copy = Some(command),
+                "paste" => clipboard.paste = Some(command),
+                _ => warn!("Ignoring clipboard.{key}, only copy and paste are known"),
+            }
+        }
+        clipboard
+    }
+
+    /// Puts `text` on the clipboard.
+    pub fn copy(&self, text: &str) -> io::Result<()> {
+        for command in self.commands(true) {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .stdin(Stdio::piped())
+                // the X11 tools stay around to serve the selection, holding on to any pipe
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(text.as_bytes())?;
+            }
+            match child.wait()?.code() {
+                Some(0) => return Ok(()),
+                Some(NOT_FOUND) if self.copy.is_none() => debug!("{command} isn't installed"),
+                _ => return Err(io::Error::other(format!("{command} failed"))),
+            }
+        }
+        // the terminal sets the clipboard itself
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b]52;c;{}\x07", base64::encode(text.as_bytes()))?;
+        stdout.flush()
+    }
+
+    /// What is on the clipboard.
+    pub fn paste(&self) -> io::Result<String> {
+        for command in self.commands(false) {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .stdin(Stdio::null())
+                .output()?;
+            match output.status.code() {
+                Some(0) => return Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+                Some(NOT_FOUND) if self.paste.is_none() => debug!("{command} isn't installed"),
+                _ => {
+                    let error = String::from_utf8_lossy(&output.stderr);
+                    return Err(io::Error::other(format!(
+                        "{command} failed: {}",
+                        error.trim()
+                    )));
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no clipboard tool found, set paste in [clipboard]",
+        ))
+    }
+
+    /// The command of the config, or those the system may have.
+    fn commands(&self, copying: bool) -> Vec<String> {
+        let configured = match copying {
+            true => &self.copy,
+            false => &self.paste,
+        };
+        if let Some(command) = configured {
+            return vec![command.clone()];
+        }
+        let set = |var| std::env::var_os(var).is_some_and(|v| !v.is_empty());
+        let mut commands = Vec::new();
+        if set("WAYLAND_DISPLAY") {
+            commands.push(if copying {
+                "wl-copy"
+            } else {
+                "wl-paste --no-newline"
+            });
+        }
+        if set("DISPLAY") {
+            commands.extend(match copying {
+                true => ["xclip -selection clipboard", "xsel --clipboard --input"],
+                false => ["xclip -selection clipboard -o", "xsel --clipboard --output"],
+            });
+        }
+        if cfg!(target_os = "macos") {
+            commands.push(if copying { "pbcopy" } else { "pbpaste" });
+        }
+        commands.into_iter().map(str::to_string).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured_commands_copy_and_paste() {
+        let path = std::env::temp_dir().join(format!("chatterbox-clip-{}", std::process::id()));
+        let config: Config = format!(
+            "[clipboard]\ncopy = \"cat > {0}\"\npaste = \"cat {0}\"\n",
+            path.display()
+        )
+        .parse()
+        .unwrap();
+        let clipboard = Clipboard::from_config(&config);
+        clipboard.copy("hello\nthere").unwrap();
+        assert_eq!(clipboard.paste().unwrap(), "hello\nthere");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn failing_commands_are_errors() {
+        let config: Config = "[clipboard]\ncopy = \"exit 1\"\npaste = \"echo nope >&2; exit 1\"\n"
+            .parse()
+            .unwrap();
+        let clipboard = Clipboard::from_config(&config);
+        assert!(clipboard.copy("x").is_err());
+        let error = clipboard.paste().unwrap_err();
+        assert_eq!(error.to_string(), "echo nope >&2; exit 1 failed: nope");
+    }
+
+    #[test]
+    fn a_missing_configured_command_is_an_error() {
+        let config: Config = "[clipboard]\npaste = \"chatterbox-no-such-tool\"\n"
+            .parse()
+            .unwrap();
+        assert!(Clipboard::from_config(&config).paste().is_err());
+    }
+}
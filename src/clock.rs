@@ -0,0 +1,115 @@
+//! Time as the engine sees it, so that tests can move it along instead of sleeping.
+//!
+//! Everything that waits or expires asks a [`Clock`]: the backoff between connection attempts,
+//! messages held by the relay and reminders. [`System`] is the real one, [`Manual`] only moves
+//! when told to, or when something sleeps on it.
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Monotonic time, for intervals and deadlines
+    fn now(&self) -> Instant;
+    /// Wall clock, milliseconds since the unix epoch
+    fn millis(&self) -> u64;
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct System;
+
+impl Clock for System {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn millis(&self) -> u64 {
+        crate::timestamp::now_millis()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Clock which stands still, sleeping on it advances it right away.
+#[derive(Debug)]
+pub struct Manual {
+    start: Instant,
+    /// Wall clock at the start
+    epoch: u64,
+    elapsed: Mutex<Duration>,
+}
+
+impl Manual {
+    /// Starts at `epoch`, in milliseconds since the unix epoch.
+    pub fn new(epoch: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            epoch,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().expect("clock lock is poisoned") += duration;
+    }
+
+    /// How far it was moved since it was made.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("clock lock is poisoned")
+    }
+}
+
+impl Clock for Manual {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn millis(&self) -> u64 {
+        self.epoch + self.elapsed().as_millis() as u64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Clock shared by whoever needs one, the system clock unless given another.
+#[derive(Debug, Clone)]
+pub struct SharedClock(pub Arc<dyn Clock>);
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self(Arc::new(System))
+    }
+}
+
+impl Deref for SharedClock {
+    type Target = dyn Clock;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clocks_move_when_slept_on() {
+        let clock = Manual::new(1_000);
+        let start = clock.now();
+        assert_eq!(clock.millis(), 1_000);
+        clock.advance(Duration::from_millis(250));
+        clock.sleep(Duration::from_secs(2));
+        assert_eq!(clock.elapsed(), Duration::from_millis(2_250));
+        assert_eq!(clock.now() - start, Duration::from_millis(2_250));
+        assert_eq!(clock.millis(), 3_250);
+    }
+}
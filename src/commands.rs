@@ -0,0 +1,615 @@
+//! Slash commands typed into the input box.
+
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    alerts::Level, contacts::NoteChange, location::Point, relay::Role, timestamp, triggers::Action,
+};
+
+/// Anything entered in the input box starting with `/`.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Short authentication string matches the one shown to the peer
+    Confirm,
+    /// Short authentication string differs from the one shown to the peer
+    Deny,
+    /// List the known snippets
+    Snippets,
+    SnippetAdd {
+        name: String,
+        text: String,
+    },
+    SnippetRemove(String),
+    /// List the configured triggers
+    Triggers,
+    TriggerAdd {
+        pattern: String,
+        action: Action,
+    },
+    /// Removes the trigger at the given position of the list, counting from 1
+    TriggerRemove(usize),
+    /// List the pending reminders
+    Reminders,
+    Remind {
+        after: Duration,
+        text: String,
+    },
+    /// Write a diagnostics bundle, to the given file or the data directory
+    Diag(Option<PathBuf>),
+    /// Replace the selected sent message, or the last one, with the given text
+    Edit(String),
+    /// Ask the peer to run the command it allowed under that name
+    Exec(String),
+    /// Let the peer run the command it asked for, or take the file it offered
+    Accept,
+    Refuse,
+    /// Offer the peer a file
+    SendFile(PathBuf),
+    /// Share the terminal of a command with the peer, stop sharing without one
+    SharePane(Option<String>),
+    /// Send the given location, or the one of the configured provider
+    Location(Option<Point>),
+    /// List the known contacts
+    Contacts,
+    ContactAdd {
+        alias: String,
+        address: String,
+        port: Option<u16>,
+    },
+    ContactSet {
+        alias: String,
+        field: String,
+        value: String,
+    },
+    ContactRemove(String),
+    /// Show everything known about a contact
+    ContactShow(String),
+    /// List the contacts mentioning the text somewhere
+    ContactFind(String),
+    Note {
+        alias: String,
+        change: NoteChange,
+    },
+    /// List the users on the other side of the bridge
+    Roster,
+    /// List the background tasks and what they are doing
+    Tasks,
+    /// Ask who is around on the local network
+    Who,
+    /// Show both ends of the connection and what it is bound to
+    PeerInfo,
+    /// Only accept the certificate of the TLS server from now on, like `--pin-cert`
+    PinCert,
+    /// Turn showing the draft to the peer while typing on or off
+    Live,
+    /// Turn playing incoming messages as Morse code on or off
+    Beep,
+    /// Change the name messages are sent with, show it without one
+    Nick(Option<String>),
+    /// Set how much a conversation notifies of, the current one if none is named
+    Notify {
+        level: Option<Level>,
+        conversation: Option<String>,
+    },
+    /// Empty the messages pane, the history stays
+    Clear,
+    /// Delete the history with the peer, only once confirmed with `/purge yes`
+    Purge {
+        confirmed: bool,
+    },
+    /// Show the end of the history again, as when the conversation started
+    ReloadHistory,
+    /// Set the topic of the relay room, show it without one
+    Topic(Option<String>),
+    /// Pin a message in the relay room, list the pins without one
+    Pin(Option<String>),
+    /// Remove the pins of the relay room
+    Unpin,
+    Kick(String),
+    /// Change the role of a member of the relay room, `/op` and `/deop`
+    SetRole {
+        name: String,
+        role: Role,
+    },
+    /// Ask the relay for a code to get into the room
+    InviteCode,
+    /// Let somebody into the relay room, even if it is invitation only
+    Invite(String),
+    /// Ask the relay for a page of its directory, of rooms or of users
+    List {
+        rooms: bool,
+        page: usize,
+    },
+    /// Get into a room of the `--multi` server and talk in it
+    Join(String),
+    /// Leave the room talked in, talking to everybody again
+    Leave,
+}
+
+impl std::str::FromStr for Command {
+    type Err = String;
+
+    /// Parses the command line without the leading `/`.
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        match name {
+            "confirm" => Ok(Command::Confirm),
+            "deny" => Ok(Command::Deny),
+            "snippet" => parse_snippet(args.trim()),
+            "triggers" => parse_triggers(args.trim()),
+            "remind" => parse_remind(args.trim()),
+            "diag" => Ok(Command::Diag(
+                Some(args.trim())
+                    .filter(|p| !p.is_empty())
+                    .map(PathBuf::from),
+            )),
+            "edit" if !args.trim().is_empty() => Ok(Command::Edit(args.trim().to_string())),
+            "edit" => Err("usage: /edit <text>".to_string()),
+            "exec" if !args.trim().is_empty() => Ok(Command::Exec(args.trim().to_string())),
+            "exec" => Err("usage: /exec <name>".to_string()),
+            "accept" => Ok(Command::Accept),
+            "send" if !args.trim().is_empty() => Ok(Command::SendFile(PathBuf::from(args.trim()))),
+            "send" => Err("usage: /send <path>".to_string()),
+            "refuse" => Ok(Command::Refuse),
+            "contacts" => parse_contacts(args.trim()),
+            "note" => parse_note(args.trim()),
+            "roster" => Ok(Command::Roster),
+            "tasks" => Ok(Command::Tasks),
+            "who" => Ok(Command::Who),
+            "peerinfo" => Ok(Command::PeerInfo),
+            "pin-cert" => Ok(Command::PinCert),
+            "live" => Ok(Command::Live),
+            "beep" => Ok(Command::Beep),
+            "nick" => Ok(Command::Nick(
+                Some(args.trim())
+                    .filter(|n| !n.is_empty())
+                    .map(str::to_string),
+            )),
+            "notify" => parse_notify(args.trim()),
+            "clear" => Ok(Command::Clear),
+            "purge" => match args.trim() {
+                "" => Ok(Command::Purge { confirmed: false }),
+                "yes" => Ok(Command::Purge { confirmed: true }),
+                _ => Err("usage: /purge [yes]".to_string()),
+            },
+            "reload-history" => Ok(Command::ReloadHistory),
+            "topic" => Ok(Command::Topic(
+                Some(args.trim())
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string),
+            )),
+            "pin" => Ok(Command::Pin(
+                Some(args.trim())
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string),
+            )),
+            "unpin" => Ok(Command::Unpin),
+            "invite-code" => Ok(Command::InviteCode),
+            "kick" | "op" | "deop" | "invite"
+                if args.trim().is_empty() || args.trim().contains(' ') =>
+            {
+                Err(format!("usage: /{name} <name>"))
+            }
+            "kick" => Ok(Command::Kick(args.trim().to_string())),
+            "invite" => Ok(Command::Invite(args.trim().to_string())),
+            "op" | "deop" => Ok(Command::SetRole {
+                name: args.trim().to_string(),
+                role: if name == "op" { Role::Op } else { Role::Member },
+            }),
+            "join" if args.trim().starts_with('#') && !args.trim().contains(' ') => {
+                Ok(Command::Join(args.trim().to_string()))
+            }
+            "join" => Err("usage: /join <#room>".to_string()),
+            "list" => parse_list(args.trim()),
+            "leave" => Ok(Command::Leave),
+            "location" if args.trim().is_empty() => Ok(Command::Location(None)),
+            "location" => args
+                .parse()
+                .map(|point| Command::Location(Some(point)))
+                .map_err(|e| format!("{e}, usage: /location [<latitude> <longitude>]")),
+            "share-pane" => Ok(Command::SharePane(
+                Some(args.trim())
+                    .filter(|c| !c.is_empty())
+                    .map(str::to_string),
+            )),
+            _ => Err(format!("unknown command /{name}")),
+        }
+    }
+}
+
+fn parse_list(args: &str) -> Result<Command, String> {
+    const USAGE: &str = "usage: /list rooms|users [<page>]";
+    let (kind, page) = args.split_once(' ').unwrap_or((args, "1"));
+    let rooms = match kind {
+        "rooms" => true,
+        "users" => false,
+        _ => return Err(USAGE.to_string()),
+    };
+    match page.trim().parse() {
+        Ok(page) if page > 0 => Ok(Command::List { rooms, page }),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn parse_snippet(args: &str) -> Result<Command, String> {
+    const USAGE: &str = "usage: /snippet [list | add <name> <text> | remove <name>]";
+    let (action, rest) = args.split_once(' ').unwrap_or((args, ""));
+    match (action, rest.trim().split_once(' ')) {
+        ("" | "list", _) => Ok(Command::Snippets),
+        ("add", Some((name, text))) if !text.trim().is_empty() => Ok(Command::SnippetAdd {
+            name: name.to_string(),
+            text: text.trim().to_string(),
+        }),
+        ("remove", None) if !rest.trim().is_empty() => {
+            Ok(Command::SnippetRemove(rest.trim().to_string()))
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn parse_notify(args: &str) -> Result<Command, String> {
+    const USAGE: &str = "usage: /notify [all | mentions | none [<conversation> | default]]";
+    let words: Vec<&str> = args.split_whitespace().collect();
+    let (level, conversation) = match words.as_slice() {
+        [] => (None, None),
+        [level] => (Some(level.parse().map_err(|_| USAGE)?), None),
+        [level, conversation] => (
+            Some(level.parse().map_err(|_| USAGE)?),
+            Some(conversation.to_string()),
+        ),
+        _ => return Err(USAGE.to_string()),
+    };
+    Ok(Command::Notify {
+        level,
+        conversation,
+    })
+}
+
+fn parse_contacts(args: &str) -> Result<Command, String> {
+    const USAGE: &str = "usage: /contacts [list | add <alias> <address>[:<port>] \
+        | set <alias> <field> <value> | remove <alias> | show <alias> | find <text>]";
+    let words: Vec<&str> = args.split_whitespace().collect();
+    match words.as_slice() {
+        [] | ["list"] => Ok(Command::Contacts),
+        ["add", alias, address] => {
+            // a single colon separates the port, more make it an IPv6 address
+            let (address, port) = match address.split_once(':') {
+                Some((host, port)) if !port.contains(':') => {
+                    (host, Some(port.parse().map_err(|_| USAGE)?))
+                }
+                _ => (*address, None),
+            };
+            Ok(Command::ContactAdd {
+                alias: alias.to_string(),
+                address: address.to_string(),
+                port,
+            })
+        }
+        ["set", alias, field, value @ ..] => Ok(Command::ContactSet {
+            alias: alias.to_string(),
+            field: field.to_string(),
+            value: value.join(" "),
+        }),
+        ["remove", alias] => Ok(Command::ContactRemove(alias.to_string())),
+        ["show", alias] => Ok(Command::ContactShow(alias.to_string())),
+        ["find", query @ ..] if !query.is_empty() => Ok(Command::ContactFind(query.join(" "))),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn parse_note(args: &str) -> Result<Command, String> {
+    const USAGE: &str = "usage: /note <alias> <key>=<value> | <key>= | +<tag> | -<tag> | <text>";
+    let (alias, note) = args.split_once(' ').ok_or(USAGE)?;
+    let note = note.trim();
+    let tag = |prefix| {
+        note.strip_prefix(prefix)
+            .filter(|t: &&str| !t.contains(' '))
+    };
+    let change = if let Some(tag) = tag('+').filter(|t| !t.is_empty()) {
+        NoteChange::Tag(tag.to_string())
+    } else if let Some(tag) = tag('-').filter(|t| !t.is_empty()) {
+        NoteChange::Untag(tag.to_string())
+    } else {
+        match note.split_once('=') {
+            // a key is a single word, anything else is the text of a note
+            Some((key, "")) if !key.is_empty() && !key.contains(' ') => {
+                NoteChange::Remove(key.to_string())
+            }
+            Some((key, value)) if !key.is_empty() && !key.contains(' ') => NoteChange::Set {
+                key: key.to_string(),
+                value: value.trim().to_string(),
+            },
+            _ => NoteChange::Set {
+                key: crate::contacts::NOTE.to_string(),
+                value: note.to_string(),
+            },
+        }
+    };
+    Ok(Command::Note {
+        alias: alias.to_string(),
+        change,
+    })
+}
+
+fn parse_triggers(args: &str) -> Result<Command, String> {
+    const USAGE: &str =
+        "usage: /triggers [list | add <action> <pattern> [=> <argument>] | remove <number>]";
+    let (action, rest) = args.split_once(' ').unwrap_or((args, ""));
+    match action {
+        "" | "list" => Ok(Command::Triggers),
+        "add" => {
+            let (action, rest) = rest.trim().split_once(' ').ok_or(USAGE)?;
+            let (pattern, argument) = match rest.split_once(" => ") {
+                Some((pattern, argument)) => (pattern, Some(argument.trim())),
+                None => (rest, None),
+            };
+            Ok(Command::TriggerAdd {
+                pattern: pattern.trim().to_string(),
+                action: Action::parse(action, argument)?,
+            })
+        }
+        "remove" => match rest.trim().parse() {
+            Ok(n) if n > 0 => Ok(Command::TriggerRemove(n)),
+            _ => Err(USAGE.to_string()),
+        },
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn parse_remind(args: &str) -> Result<Command, String> {
+    const USAGE: &str = "usage: /remind [<duration like 15m or 1h30m> <text>]";
+    if args.is_empty() {
+        return Ok(Command::Reminders);
+    }
+    match args.split_once(' ') {
+        Some((after, text)) if !text.trim().is_empty() => Ok(Command::Remind {
+            after: timestamp::parse_duration(after).ok_or(USAGE)?,
+            text: text.trim().to_string(),
+        }),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authentication_strings_are_confirmed_or_denied() {
+        assert_eq!("confirm".parse(), Ok(Command::Confirm));
+        assert_eq!("deny".parse(), Ok(Command::Deny));
+        assert_eq!(
+            "confirmed".parse::<Command>(),
+            Err("unknown command /confirmed".to_string())
+        );
+    }
+
+    #[test]
+    fn snippets_are_listed_added_and_removed() {
+        assert_eq!("snippet".parse(), Ok(Command::Snippets));
+        assert_eq!("snippet list".parse(), Ok(Command::Snippets));
+        assert_eq!(
+            "snippet add sig  -- sam ".parse(),
+            Ok(Command::SnippetAdd {
+                name: "sig".to_string(),
+                text: "-- sam".to_string(),
+            })
+        );
+        assert_eq!(
+            "snippet remove sig".parse(),
+            Ok(Command::SnippetRemove("sig".to_string()))
+        );
+        for line in [
+            "snippet add sig",
+            "snippet remove",
+            "snippet remove a b",
+            "snippet edit",
+        ] {
+            assert!(line.parse::<Command>().is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn triggers_are_added_with_an_optional_argument() {
+        assert_eq!("triggers".parse(), Ok(Command::Triggers));
+        assert_eq!(
+            "triggers add reply ^ping$ => pong".parse(),
+            Ok(Command::TriggerAdd {
+                pattern: "^ping$".to_string(),
+                action: Action::Reply("pong".to_string()),
+            })
+        );
+        assert_eq!(
+            "triggers add notify (?i)urgent news".parse(),
+            Ok(Command::TriggerAdd {
+                pattern: "(?i)urgent news".to_string(),
+                action: Action::Notify,
+            })
+        );
+        assert_eq!("triggers remove 2".parse(), Ok(Command::TriggerRemove(2)));
+        for line in [
+            "triggers add reply ping",
+            "triggers remove 0",
+            "triggers add",
+        ] {
+            assert!(line.parse::<Command>().is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn reminders_are_set_after_a_duration() {
+        assert_eq!("remind".parse(), Ok(Command::Reminders));
+        assert_eq!(
+            "remind 1h30m  stand up ".parse(),
+            Ok(Command::Remind {
+                after: Duration::from_secs(90 * 60),
+                text: "stand up".to_string(),
+            })
+        );
+        for line in ["remind 15m", "remind 15 stand up", "remind soon stand up"] {
+            assert!(line.parse::<Command>().is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn diagnostics_go_to_an_optional_path() {
+        assert_eq!("diag".parse(), Ok(Command::Diag(None)));
+        assert_eq!(
+            "diag  /tmp/diag.txt ".parse(),
+            Ok(Command::Diag(Some(PathBuf::from("/tmp/diag.txt"))))
+        );
+    }
+
+    #[test]
+    fn edits_need_the_new_text() {
+        assert_eq!(
+            "edit  see you at 6 ".parse(),
+            Ok(Command::Edit("see you at 6".to_string()))
+        );
+        assert!("edit".parse::<Command>().is_err());
+        assert!("edit  ".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn exec_names_a_command() {
+        assert_eq!(
+            "exec uptime".parse(),
+            Ok(Command::Exec("uptime".to_string()))
+        );
+        assert!("exec".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn locations_are_typed_or_asked_for() {
+        assert_eq!("location".parse(), Ok(Command::Location(None)));
+        assert_eq!(
+            "location 48.8566 2.3522".parse(),
+            Ok(Command::Location(Some(Point {
+                lat: 48.8566,
+                lon: 2.3522,
+            })))
+        );
+        assert_eq!(
+            "location 91 0".parse::<Command>(),
+            Err("latitude goes up to 90°, longitude up to 180°, \
+                 usage: /location [<latitude> <longitude>]"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn contacts_are_added_with_an_optional_port() {
+        assert_eq!("contacts".parse(), Ok(Command::Contacts));
+        assert_eq!(
+            "contacts add ada 10.0.0.2:7000".parse(),
+            Ok(Command::ContactAdd {
+                alias: "ada".to_string(),
+                address: "10.0.0.2".to_string(),
+                port: Some(7000),
+            })
+        );
+        assert_eq!(
+            "contacts add bob fd00::2".parse(),
+            Ok(Command::ContactAdd {
+                alias: "bob".to_string(),
+                address: "fd00::2".to_string(),
+                port: None,
+            })
+        );
+        assert_eq!(
+            "contacts set ada nick Ada  L.".parse(),
+            Ok(Command::ContactSet {
+                alias: "ada".to_string(),
+                field: "nick".to_string(),
+                value: "Ada L.".to_string(),
+            })
+        );
+        for line in [
+            "contacts add ada",
+            "contacts add ada host:port",
+            "contacts drop ada",
+        ] {
+            assert!(line.parse::<Command>().is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn notes_set_keys_tags_or_text() {
+        let note = |line: &str| match line.parse() {
+            Ok(Command::Note { alias, change }) if alias == "ada" => change,
+            other => panic!("{line} gave {other:?}"),
+        };
+        assert_eq!(
+            note("note ada tz=CET"),
+            NoteChange::Set {
+                key: "tz".to_string(),
+                value: "CET".to_string(),
+            }
+        );
+        assert_eq!(note("note ada tz="), NoteChange::Remove("tz".to_string()));
+        assert_eq!(note("note ada +work"), NoteChange::Tag("work".to_string()));
+        assert_eq!(
+            note("note ada -work"),
+            NoteChange::Untag("work".to_string())
+        );
+        assert_eq!(
+            note("note ada met at FOSDEM, 2+2=4"),
+            NoteChange::Set {
+                key: crate::contacts::NOTE.to_string(),
+                value: "met at FOSDEM, 2+2=4".to_string(),
+            }
+        );
+        assert!("note ada".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn topics_are_set_or_shown() {
+        assert_eq!("topic".parse(), Ok(Command::Topic(None)));
+        assert_eq!(
+            "topic  no spam ".parse(),
+            Ok(Command::Topic(Some("no spam".to_string())))
+        );
+    }
+
+    #[test]
+    fn nicks_are_set_or_shown() {
+        assert_eq!("nick".parse(), Ok(Command::Nick(None)));
+        assert_eq!(
+            "nick  ada ".parse(),
+            Ok(Command::Nick(Some("ada".to_string())))
+        );
+    }
+
+    #[test]
+    fn notify_levels_are_shown_or_set() {
+        assert_eq!(
+            "notify".parse(),
+            Ok(Command::Notify {
+                level: None,
+                conversation: None,
+            })
+        );
+        assert_eq!(
+            "notify mentions #ops".parse(),
+            Ok(Command::Notify {
+                level: Some(Level::Mentions),
+                conversation: Some("#ops".to_string()),
+            })
+        );
+        for line in ["notify loud", "notify all #ops bob"] {
+            assert!(line.parse::<Command>().is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn purging_takes_a_confirmation() {
+        assert_eq!("clear".parse(), Ok(Command::Clear));
+        assert_eq!("reload-history".parse(), Ok(Command::ReloadHistory));
+        assert_eq!("purge".parse(), Ok(Command::Purge { confirmed: false }));
+        assert_eq!("purge yes".parse(), Ok(Command::Purge { confirmed: true }));
+        assert_eq!(
+            "purge now".parse::<Command>(),
+            Err("usage: /purge [yes]".to_string())
+        );
+    }
+}
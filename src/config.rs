@@ -0,0 +1,177 @@
+//! User configuration, `$XDG_CONFIG_HOME/chatterbox/config.toml`.
+//!
+//! Settings changed from within the app are written back by editing the document in place, so
+//! the user's comments and formatting survive.
+
+use std::{fs, io, path::PathBuf, str::FromStr};
+
+use toml_edit::{value, ArrayOfTables, Document, Item, Table, TomlError};
+use tracing::{error, instrument, warn};
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// File to write changes to, `None` if the config is in memory only.
+    path: Option<PathBuf>,
+    doc: Document,
+}
+
+impl Config {
+    #[instrument]
+    pub fn load() -> Self {
+        let Some(path) = crate::dirs::config_dir().map(|d| d.join("config.toml")) else {
+            warn!("Couldn't determine config directory, using defaults");
+            return Self::default();
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                error!("Failed to read config {}: {e}", path.display());
+                return Self::default();
+            }
+        };
+        match content.parse() {
+            Ok(doc) => Self {
+                path: Some(path),
+                doc,
+            },
+            Err(e) => {
+                // don't write back, it would clobber whatever the user was in the middle of
+                error!("Invalid config {}: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    /// All string entries of `[table]`.
+    pub fn strings(&self, table: &str) -> Vec<(String, String)> {
+        self.doc
+            .get(table)
+            .and_then(Item::as_table_like)
+            .into_iter()
+            .flat_map(|t| t.iter())
+            .filter_map(|(key, item)| match item.as_str() {
+                Some(s) => Some((key.to_string(), s.to_string())),
+                None => {
+                    warn!("Ignoring non string config entry {table}.{key}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Whether `[table]` has `key`, whatever its type.
+    pub fn has(&self, table: &str, key: &str) -> bool {
+        self.doc
+            .get(table)
+            .and_then(Item::as_table_like)
+            .is_some_and(|t| t.contains_key(key))
+    }
+
+    /// String entries of every table in the `[[name]]` array.
+    pub fn tables(&self, name: &str) -> Vec<Vec<(String, String)>> {
+        self.doc
+            .get(name)
+            .and_then(Item::as_array_of_tables)
+            .into_iter()
+            .flat_map(|tables| tables.iter())
+            .map(|table| {
+                table
+                    .iter()
+                    .filter_map(|(key, item)| Some((key.to_string(), item.as_str()?.to_string())))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Appends a table with the given entries to the `[[name]]` array and saves the file.
+    pub fn push_table(&mut self, name: &str, entries: &[(&str, &str)]) -> io::Result<()> {
+        let mut table = Table::new();
+        for (key, val) in entries {
+            table.insert(key, value(*val));
+        }
+        match self.doc[name]
+            .or_insert(Item::ArrayOfTables(ArrayOfTables::new()))
+            .as_array_of_tables_mut()
+        {
+            Some(tables) => tables.push(table),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "config entry isn't an array of tables",
+                ))
+            }
+        }
+        self.save()
+    }
+
+    /// Removes the `index`th table of the `[[name]]` array and saves the file.
+    pub fn remove_table(&mut self, name: &str, index: usize) -> io::Result<()> {
+        if let Some(tables) = self
+            .doc
+            .get_mut(name)
+            .and_then(Item::as_array_of_tables_mut)
+        {
+            if index < tables.len() {
+                tables.remove(index);
+            }
+        }
+        self.save()
+    }
+
+    /// Sets `table.key` and saves the file.
+    pub fn set_string(&mut self, table: &str, key: &str, val: &str) -> io::Result<()> {
+        let table = self.doc[table].or_insert(Item::Table(Table::new()));
+        match table.as_table_like_mut() {
+            Some(table) => {
+                table.insert(key, value(val));
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "config entry isn't a table",
+                ))
+            }
+        }
+        self.save()
+    }
+
+    /// Removes `table.key` and saves the file.
+    pub fn remove(&mut self, table: &str, key: &str) -> io::Result<()> {
+        if let Some(table) = self.doc.get_mut(table).and_then(Item::as_table_like_mut) {
+            table.remove(key);
+        }
+        self.save()
+    }
+
+    /// The whole config as it would be written to disk.
+    pub fn snapshot(&self) -> String {
+        let path = self
+            .path
+            .as_ref()
+            .map_or("in memory only".to_string(), |p| p.display().to_string());
+        format!("# {path}\n{}", self.doc)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.doc.to_string())
+    }
+}
+
+/// Config in memory only, never saved.
+impl FromStr for Config {
+    type Err = TomlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            path: None,
+            doc: s.parse()?,
+        })
+    }
+}
@@ -0,0 +1,499 @@
+//! Link to the peer, connecting or waiting for it again whenever the connection drops.
+
+use std::{
+    io,
+    net::TcpStream,
+    path::PathBuf,
+    sync::{atomic::Ordering, mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    access,
+    app::{notify, REDRAW, RESET},
+    clock::SharedClock,
+    fault,
+    message::Message,
+    protocol, proxy, socket, source, tasks, tls,
+    transport::{self, Transport},
+    App,
+};
+
+/// Addresses the server waits for a client on, empty while connected
+pub static LISTENING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// How the client is getting on with reaching the peer, `None` while connected
+pub static ATTEMPT: Mutex<Option<Attempt>> = Mutex::new(None);
+
+/// First wait after a failed attempt, doubled with every further one.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// Wait between failed attempts, doubled with every one up to [`MAX_RETRY`].
+#[derive(Debug)]
+pub struct Backoff {
+    clock: SharedClock,
+    retry: Duration,
+}
+
+impl Backoff {
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            clock,
+            retry: FIRST_RETRY,
+        }
+    }
+
+    /// How long the next wait takes.
+    pub fn delay(&self) -> Duration {
+        self.retry
+    }
+
+    /// When the next wait would be over, if it started now.
+    pub fn deadline(&self) -> Instant {
+        self.clock.now() + self.retry
+    }
+
+    /// Waits until `deadline`, calling `tick` every second of it, and doubles the next wait.
+    pub fn wait(&mut self, deadline: Instant, mut tick: impl FnMut()) {
+        while let Some(left) = deadline.checked_duration_since(self.clock.now()) {
+            if left.is_zero() {
+                break;
+            }
+            self.clock.sleep(left.min(Duration::from_secs(1)));
+            tick();
+        }
+        self.retry = (self.retry * 2).min(MAX_RETRY);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Attempt {
+    Connecting(String),
+    /// Waiting to try again after `failed` attempts
+    Waiting {
+        failed: u32,
+        error: String,
+        next: Instant,
+    },
+}
+
+/// Where to find the peer.
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    /// Address to connect to, or to listen on as the server
+    pub address: Option<String>,
+    pub port: u16,
+    /// Wait for the peer to connect instead
+    pub server: bool,
+    /// Secret of a deniable session
+    pub deniable: Option<String>,
+    pub source: source::Options,
+    /// Proxy the connections go through
+    pub proxy: Option<proxy::Proxy>,
+    pub socket: socket::Tuning,
+    /// Set when the connection is encrypted
+    pub tls: Option<Arc<tls::Context>>,
+    /// Who may connect as the server
+    pub access: Arc<access::Gate>,
+    /// Times the attempts to connect
+    pub clock: SharedClock,
+    /// Unix socket to talk over instead of the address
+    pub unix: Option<PathBuf>,
+    /// Serve the connection the process was started with, inetd style, and no other
+    pub inherited: bool,
+}
+
+/// Connection which was just set up.
+pub struct Established {
+    pub stream: Box<dyn Transport>,
+    /// Set when encrypted, the stream is then a local connection to the task encrypting it
+    pub tls: Option<tls::Session>,
+}
+
+/// Connection to the peer, established again in the background whenever it drops.
+pub struct Connection {
+    /// Current connection, `None` while there is none
+    pub stream: Option<Box<dyn Transport>>,
+    /// New connections, or why there won't be any
+    connections: mpsc::Receiver<io::Result<Established>>,
+    tx: mpsc::Sender<io::Result<Established>>,
+    target: Target,
+    /// The inherited connection is gone, there won't be another
+    finished: bool,
+}
+
+impl Connection {
+    /// Starts connecting, or waiting for the peer, in the background.
+    pub fn new(target: Target) -> Self {
+        let (tx, connections) = mpsc::channel();
+        let mut connection = Self {
+            stream: None,
+            connections,
+            tx,
+            target,
+            finished: false,
+        };
+        match connection.target.inherited {
+            true => {
+                let established =
+                    transport::inherited().map(|stream| Established { stream, tls: None });
+                let _ = connection.tx.send(established);
+            }
+            false => connection.spawn_connector(),
+        }
+        connection
+    }
+
+    /// Whether the connection handed over with `--inetd` ended.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    fn spawn_connector(&mut self) {
+        if self.target.inherited {
+            // the descriptor is closed along with the stream
+            self.finished = true;
+            return;
+        }
+        let (target, tx) = (self.target.clone(), self.tx.clone());
+        tasks::spawn("connector", move || connector(&target, tx));
+    }
+
+    /// Takes care of a dropped or a freshly established connection and of the auto replies.
+    pub fn poll(&mut self, app: &mut App) {
+        // before the connection is ended for them
+        let faults: Vec<_> = app.faults.try_iter().collect();
+        if !faults.is_empty() {
+            for text in fault::summarize(faults) {
+                app.record(Message::system(text));
+            }
+            REDRAW.store(true, Ordering::Release);
+        }
+        if self.stream.is_some() && RESET.load(Ordering::Acquire) {
+            self.stream = None;
+            app.end_connection();
+            REDRAW.store(true, Ordering::Release);
+            self.spawn_connector();
+        }
+        if let Ok(established) = self.connections.try_recv() {
+            let res = established.and_then(|established| {
+                app.start_session(established, self.target.deniable.as_deref())
+            });
+            match res {
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    warn!("Giving up on the peer: {e}");
+                    app.record(Message::system(format!("not connecting again, {e}")));
+                }
+                Ok(new_stream) => self.stream = Some(new_stream),
+                Err(e) => {
+                    warn!("Failed to start session: {e}");
+                    app.record(Message::system(format!("failed to start session: {e}")));
+                    self.spawn_connector();
+                }
+            }
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(reply) = app.replies.try_recv() {
+            app.send_message(self.stream.as_mut(), reply);
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(event) = app.bridge_events.try_recv() {
+            app.bridged(self.stream.as_mut(), event);
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(control) = app.session_requests.try_recv() {
+            let answer = app.control(self.stream.as_mut(), control.request);
+            let _ = control.reply.send(answer);
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(text) = app.webhooks.try_recv() {
+            let text = format!("webhook: {text}");
+            notify(&text);
+            app.record(Message::incoming(text));
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(listing) = app.listings.try_recv() {
+            app.show_listing(listing);
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(answers) = app.who_answers.try_recv() {
+            app.show_who(answers);
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(point) = app.locations.try_recv() {
+            app.share_location(self.stream.as_mut(), point);
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(line) = app.file_lines.try_recv() {
+            app.file_line(self.stream.as_mut(), line);
+            REDRAW.store(true, Ordering::Release);
+        }
+        app.send_file(self.stream.as_mut());
+        while let Ok(name) = app.exec_requests.try_recv() {
+            app.exec_requested(self.stream.as_mut(), name);
+            REDRAW.store(true, Ordering::Release);
+        }
+        while let Ok(line) = app.controls.try_recv() {
+            app.send_control(self.stream.as_mut(), &line);
+            match protocol::decode(&line) {
+                protocol::Payload::ExecDone(status) => {
+                    app.record(Message::system(format!(
+                        "command for the peer ended: {status}"
+                    )));
+                }
+                protocol::Payload::PaneEnd(status) => {
+                    app.sharing = None;
+                    app.record(Message::system(format!("shared pane ended: {status}")));
+                }
+                _ => continue,
+            }
+            REDRAW.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Keeps trying to reach the peer in the background, the connection is handed over through `tx`.
+#[instrument(skip(tx))]
+fn connector(target: &Target, tx: mpsc::Sender<io::Result<Established>>) {
+    let mut failed = 0;
+    let mut backoff = Backoff::new(target.clock.clone());
+    let established = loop {
+        let res = match &target.unix {
+            Some(path) => connect_unix(path, target.server),
+            None => connect_tcp(target),
+        };
+        match res {
+            Ok(established) => break established,
+            // trying again won't make the certificate any better
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied && !target.server => {
+                set_attempt(None);
+                let _ = tx.send(Err(e));
+                return;
+            }
+            Err(e) => {
+                failed += 1;
+                warn!("Failed to connect, attempt {failed}: {e}");
+                tasks::set_state(format!("retrying in {}s, {e}", backoff.delay().as_secs()));
+                let next = backoff.deadline();
+                set_attempt(Some(Attempt::Waiting {
+                    failed,
+                    error: e.to_string(),
+                    next,
+                }));
+                // a second at a time, for the countdown in the status line
+                backoff.wait(next, || REDRAW.store(true, Ordering::Release));
+            }
+        }
+    };
+    set_attempt(None);
+    // app might have quit in the mean time, nothing to do then
+    let _ = tx.send(Ok(established));
+}
+
+/// Connects to the address, or waits for the peer there as the server, and sets up TLS.
+fn connect_tcp(target: &Target) -> io::Result<Established> {
+    let (address, port) = (target.address.as_deref(), target.port);
+    loop {
+        let res = if target.server {
+            listen(address, port).and_then(|listeners| accept(&listeners, &target.access))
+        } else {
+            let address = address.expect("since server is necessary if the address is not given");
+            let shown = match &target.proxy {
+                Some(proxy) => format!("{address}:{port} via {proxy}"),
+                None => format!("{address}:{port}"),
+            };
+            tasks::set_state(format!("connecting to {shown}"));
+            set_attempt(Some(Attempt::Connecting(shown)));
+            match &target.proxy {
+                Some(proxy) => proxy.connect(&target.source, address, port),
+                None => target.source.connect(address, port),
+            }
+        };
+        let res = res.map(|stream| {
+            if let Err(e) = target.socket.apply(&stream) {
+                warn!("Failed to tune the connection: {e}");
+            }
+            let Some(tls) = &target.tls else {
+                return Ok(Established {
+                    stream: Box::new(stream),
+                    tls: None,
+                });
+            };
+            tasks::set_state("TLS handshake");
+            let host = address.filter(|_| !target.server);
+            tls.wrap(stream, host).map(|(stream, session)| Established {
+                stream: Box::new(stream),
+                tls: Some(session),
+            })
+        });
+        match res {
+            // the next client may well get it right
+            Ok(Err(e)) if target.server => warn!("TLS handshake with a client failed: {e}"),
+            Ok(res) => return res,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Connects to the socket at `path`, or waits for the peer there as the server.
+fn connect_unix(path: &std::path::Path, server: bool) -> io::Result<Established> {
+    let shown = path.display().to_string();
+    let stream = if server {
+        let listener = transport::bind_unix(path)?;
+        warn!("Waiting for client on {shown}");
+        tasks::set_state(format!("waiting for a client on {shown}"));
+        *LISTENING.lock().expect("listening lock is poisoned") = vec![shown];
+        REDRAW.store(true, Ordering::Release);
+        let (stream, _) = listener.accept()?;
+        LISTENING
+            .lock()
+            .expect("listening lock is poisoned")
+            .clear();
+        stream
+    } else {
+        tasks::set_state(format!("connecting to {shown}"));
+        set_attempt(Some(Attempt::Connecting(shown)));
+        std::os::unix::net::UnixStream::connect(path)?
+    };
+    Ok(Established {
+        stream: Box::new(stream),
+        tls: None,
+    })
+}
+
+fn set_attempt(attempt: Option<Attempt>) {
+    *ATTEMPT.lock().expect("attempt lock is poisoned") = attempt;
+    REDRAW.store(true, Ordering::Release);
+}
+
+/// Binds the given address, or both stacks when there is none.
+pub fn listen(address: Option<&str>, port: u16) -> io::Result<Vec<std::net::TcpListener>> {
+    let listeners = match address {
+        Some(address) => vec![std::net::TcpListener::bind((address, port))?],
+        None => {
+            let v6 = std::net::TcpListener::bind(("::", port));
+            let v4 = std::net::TcpListener::bind(("0.0.0.0", port));
+            match (v6, v4) {
+                (Ok(v6), Ok(v4)) => vec![v6, v4],
+                (Ok(v6), Err(e)) => {
+                    debug!("Not listening on IPv4 separately: {e}");
+                    vec![v6]
+                }
+                (Err(e), Ok(v4)) => {
+                    debug!("Not listening on IPv6: {e}");
+                    vec![v4]
+                }
+                (Err(_), Err(e)) => return Err(e),
+            }
+        }
+    };
+    let mut addresses: Vec<String> = listeners
+        .iter()
+        .filter_map(|l| l.local_addr().ok())
+        .map(|a| a.to_string())
+        .collect();
+    if address.is_none() && listeners.len() == 1 && addresses[0].starts_with('[') {
+        // a dual-stack socket takes the v4 connections as well
+        addresses.push(format!("0.0.0.0:{port}"));
+    }
+    let shown = addresses.join(" and ");
+    warn!("Waiting for client on {shown}");
+    tasks::set_state(format!("waiting for a client on {shown}"));
+    *LISTENING.lock().expect("listening lock is poisoned") = addresses;
+    REDRAW.store(true, Ordering::Release);
+    Ok(listeners)
+}
+
+/// Address of the peer, without the IPv6 dress of a v4 client of a dual-stack socket.
+pub fn peer_address(stream: &TcpStream) -> io::Result<std::net::SocketAddr> {
+    let address = stream.peer_addr()?;
+    Ok((address.ip().to_canonical(), address.port()).into())
+}
+
+/// First client to connect to any of the listeners who gets past `gate`.
+fn accept(listeners: &[std::net::TcpListener], gate: &access::Gate) -> io::Result<TcpStream> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+    for listener in listeners {
+        listener.set_nonblocking(true)?;
+    }
+    let stream = loop {
+        let accepted = listeners
+            .iter()
+            .find_map(|listener| match listener.accept() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+                res => Some(res),
+            });
+        match accepted {
+            Some(res) => {
+                let stream = res?.0;
+                match access::admit(gate, &stream) {
+                    Ok(()) => break stream,
+                    Err(reason) => info!("turned away: {reason}"),
+                }
+            }
+            None => std::thread::sleep(POLL_INTERVAL),
+        }
+    };
+    LISTENING
+        .lock()
+        .expect("listening lock is poisoned")
+        .clear();
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::clock::Manual;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let clock = Arc::new(Manual::new(0));
+        let mut backoff = Backoff::new(SharedClock(clock.clone()));
+        let mut ticks = 0;
+        let mut waited = Vec::new();
+        for _ in 0..8 {
+            let before = clock.elapsed();
+            backoff.wait(backoff.deadline(), || ticks += 1);
+            waited.push((clock.elapsed() - before).as_secs());
+        }
+        assert_eq!(waited, [1, 2, 4, 8, 16, 32, 60, 60]);
+        // one for every second of the countdown
+        assert_eq!(ticks, waited.iter().sum::<u64>());
+    }
+
+    #[test]
+    fn backoff_returns_at_a_passed_deadline() {
+        let clock = Arc::new(Manual::new(0));
+        let mut backoff = Backoff::new(SharedClock(clock.clone()));
+        let deadline = backoff.deadline();
+        clock.advance(Duration::from_secs(5));
+        backoff.wait(deadline, || panic!("nothing left to count down"));
+        assert_eq!(backoff.delay(), FIRST_RETRY * 2);
+    }
+
+    #[test]
+    fn servers_without_an_address_take_both_stacks() {
+        let port = std::net::TcpListener::bind(("0.0.0.0", 0))
+            .and_then(|l| l.local_addr())
+            .unwrap()
+            .port();
+        let listeners = listen(None, port).unwrap();
+        assert_eq!(
+            *LISTENING.lock().unwrap(),
+            [format!("[::]:{port}"), format!("0.0.0.0:{port}")]
+        );
+
+        for address in ["127.0.0.1", "::1"] {
+            let client = TcpStream::connect((address, port)).unwrap();
+            let stream = accept(&listeners, &access::Gate::default()).unwrap();
+            assert_eq!(peer_address(&stream).unwrap(), client.local_addr().unwrap());
+            assert!(LISTENING.lock().unwrap().is_empty());
+        }
+    }
+}
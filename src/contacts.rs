@@ -0,0 +1,408 @@
+//! Address book, peers known by an alias instead of their address.
+//!
+//! Kept in the data directory, one contact per line: the alias followed by tab separated
+//! `field=value` pairs, escaped like messages on the wire. Notes are stored as `note.<key>` and
+//! every tag as a `tag` field.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::Write,
+    path::PathBuf,
+};
+
+use tracing::{error, instrument, warn};
+
+use crate::protocol::{escape, unescape};
+
+/// Fields which can be set with `/contacts set`.
+pub const FIELDS: [&str; 4] = ["address", "port", "fingerprint", "nick"];
+/// Key of a note written without one
+pub const NOTE: &str = "note";
+
+/// Change made with `/note`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum NoteChange {
+    Set { key: String, value: String },
+    Remove(String),
+    Tag(String),
+    Untag(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Contact {
+    pub alias: String,
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    /// Fingerprint the peer is expected to show, to compare out of band
+    pub fingerprint: Option<String>,
+    /// Name the peer goes by
+    pub nick: Option<String>,
+    /// Free-form notes by key, like `timezone=CET`
+    pub notes: BTreeMap<String, String>,
+    pub tags: BTreeSet<String>,
+}
+
+impl Contact {
+    /// Sets one of [`FIELDS`], an empty value clears it.
+    pub fn set(&mut self, field: &str, value: &str) -> Result<(), String> {
+        let value = Some(value.to_string()).filter(|v| !v.is_empty());
+        match field {
+            "address" => self.address = value,
+            "port" => {
+                self.port = value
+                    .map(|v| v.parse().map_err(|_| format!("invalid port {v}")))
+                    .transpose()?
+            }
+            "fingerprint" => self.fingerprint = value,
+            "nick" => self.nick = value,
+            _ => {
+                return Err(format!(
+                    "unknown field {field}, one of {}",
+                    FIELDS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        fields.extend(self.address.clone().map(|v| ("address", v)));
+        fields.extend(self.port.map(|v| ("port", v.to_string())));
+        fields.extend(self.fingerprint.clone().map(|v| ("fingerprint", v)));
+        fields.extend(self.nick.clone().map(|v| ("nick", v)));
+        fields
+    }
+
+    pub fn note(&mut self, change: NoteChange) {
+        match change {
+            NoteChange::Set { key, value } => {
+                self.notes.insert(key, value);
+            }
+            NoteChange::Remove(key) => {
+                self.notes.remove(&key);
+            }
+            NoteChange::Tag(tag) => {
+                self.tags.insert(tag);
+            }
+            NoteChange::Untag(tag) => {
+                self.tags.remove(&tag);
+            }
+        }
+    }
+
+    /// Everything known about the contact, a line each.
+    pub fn info(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .fields()
+            .into_iter()
+            .map(|(field, value)| format!("{field}: {value}"))
+            .collect();
+        if !self.tags.is_empty() {
+            let tags: Vec<&str> = self.tags.iter().map(String::as_str).collect();
+            lines.push(format!("tags: {}", tags.join(", ")));
+        }
+        lines.extend(
+            self.notes
+                .iter()
+                .map(|(key, value)| format!("{key} = {value}")),
+        );
+        lines
+    }
+
+    /// Whether `query` appears anywhere in the contact, ignoring case.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        std::iter::once(self.alias.clone())
+            .chain(self.info())
+            .any(|text| text.to_lowercase().contains(&query))
+    }
+}
+
+impl std::fmt::Display for Contact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.alias)?;
+        for (field, value) in self.fields() {
+            write!(f, " {field}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Contacts {
+    /// Backing file, `None` keeps the contacts in memory only.
+    path: Option<PathBuf>,
+    list: Vec<Contact>,
+}
+
+impl Contacts {
+    #[instrument]
+    pub fn load() -> Self {
+        let Some(path) = crate::dirs::data_dir().map(|d| d.join("contacts")) else {
+            warn!("Couldn't determine data directory, contacts won't be persisted");
+            return Self::default();
+        };
+        let list = match fs::read_to_string(&path) {
+            Ok(content) => content.lines().filter_map(parse_line).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                error!("Failed to read contacts {}: {e}", path.display());
+                Vec::new()
+            }
+        };
+        Self {
+            path: Some(path),
+            list,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Contact> {
+        self.list.iter()
+    }
+
+    pub fn get(&self, alias: &str) -> Option<&Contact> {
+        self.list.iter().find(|c| c.alias == alias)
+    }
+
+    pub fn find_by_fingerprint(&self, fingerprint: &str) -> Option<&Contact> {
+        self.list
+            .iter()
+            .find(|c| c.fingerprint.as_deref() == Some(fingerprint))
+    }
+
+    /// Contact reached at `address`, preferring one with a matching port.
+    pub fn find_by_address(&self, address: &str, port: u16) -> Option<&Contact> {
+        let mut matching = self
+            .list
+            .iter()
+            .filter(|c| c.address.as_deref() == Some(address));
+        let first = matching.clone().next();
+        matching.find(|c| c.port == Some(port)).or(first)
+    }
+
+    /// Adds a contact or replaces the one with the same alias.
+    pub fn insert(&mut self, contact: Contact) {
+        match self.list.iter_mut().find(|c| c.alias == contact.alias) {
+            Some(existing) => *existing = contact,
+            None => self.list.push(contact),
+        }
+        self.persist();
+    }
+
+    pub fn search<'a>(&'a self, query: &'a str) -> impl Iterator<Item = &'a Contact> {
+        self.list.iter().filter(move |c| c.matches(query))
+    }
+
+    pub fn note(&mut self, alias: &str, change: NoteChange) -> Result<(), String> {
+        let contact = self
+            .list
+            .iter_mut()
+            .find(|c| c.alias == alias)
+            .ok_or_else(|| format!("no contact named {alias}"))?;
+        contact.note(change);
+        self.persist();
+        Ok(())
+    }
+
+    /// Changes a field of an existing contact.
+    pub fn set(&mut self, alias: &str, field: &str, value: &str) -> Result<(), String> {
+        let contact = self
+            .list
+            .iter_mut()
+            .find(|c| c.alias == alias)
+            .ok_or_else(|| format!("no contact named {alias}"))?;
+        contact.set(field, value)?;
+        self.persist();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, alias: &str) -> bool {
+        let before = self.list.len();
+        self.list.retain(|c| c.alias != alias);
+        let removed = self.list.len() != before;
+        if removed {
+            self.persist();
+        }
+        removed
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let res = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::File::create(path))
+            .and_then(|mut file| {
+                self.list.iter().try_for_each(|c| {
+                    let fields: String = c
+                        .fields()
+                        .iter()
+                        .map(|(field, value)| format!("\t{field}={}", escape(value)))
+                        .chain(c.tags.iter().map(|tag| format!("\ttag={}", escape(tag))))
+                        .chain(c.notes.iter().map(|(key, value)| {
+                            format!("\tnote.{}={}", escape(key), escape(value))
+                        }))
+                        .collect();
+                    writeln!(file, "{}{fields}", escape(&c.alias))
+                })
+            });
+        if let Err(e) = res {
+            error!("Failed to save contacts {}: {e}", path.display());
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<Contact> {
+    let mut parts = line.split('\t');
+    let mut contact = Contact {
+        alias: unescape(parts.next().filter(|a| !a.is_empty())?),
+        ..Contact::default()
+    };
+    for part in parts {
+        let Some((field, value)) = part.split_once('=') else {
+            continue;
+        };
+        if field == "tag" {
+            contact.tags.insert(unescape(value));
+            continue;
+        }
+        if let Some(key) = field.strip_prefix("note.") {
+            contact.notes.insert(unescape(key), unescape(value));
+            continue;
+        }
+        if let Err(e) = contact.set(field, &unescape(value)) {
+            warn!("Ignoring contact field of {}: {e}", contact.alias);
+        }
+    }
+    Some(contact)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(alias: &str, address: &str, port: Option<u16>) -> Contact {
+        Contact {
+            alias: alias.to_string(),
+            address: Some(address.to_string()),
+            port,
+            ..Contact::default()
+        }
+    }
+
+    #[test]
+    fn contacts_are_read_a_line_each() {
+        let ada = parse_line("ada\taddress=10.0.0.2\tport=7000\tnick=Ada\\sL.\tcolor=red").unwrap();
+        assert_eq!(ada.address.as_deref(), Some("10.0.0.2"));
+        assert_eq!(ada.port, Some(7000));
+        assert_eq!(ada.nick.as_deref(), Some("Ada L."));
+        assert_eq!(
+            ada.to_string(),
+            "ada address=10.0.0.2 port=7000 nick=Ada L."
+        );
+        // a bad port is skipped, the rest is kept
+        let bob = parse_line("bob\tport=99999\taddress=bob.lan").unwrap();
+        assert_eq!((bob.port, bob.address.as_deref()), (None, Some("bob.lan")));
+        assert_eq!(parse_line("\taddress=nobody"), None);
+    }
+
+    #[test]
+    fn fields_are_set_and_cleared() {
+        let mut ada = contact("ada", "10.0.0.2", Some(7000));
+        ada.set("port", "").unwrap();
+        ada.set("fingerprint", "0123abcd").unwrap();
+        assert_eq!(ada.port, None);
+        assert_eq!(ada.fingerprint.as_deref(), Some("0123abcd"));
+        assert_eq!(
+            ada.set("port", "seven"),
+            Err("invalid port seven".to_string())
+        );
+        assert!(ada.set("email", "ada@example.org").is_err());
+    }
+
+    #[test]
+    fn contacts_are_found_by_address_and_port() {
+        let mut contacts = Contacts::default();
+        contacts.insert(contact("home", "10.0.0.2", Some(7000)));
+        contacts.insert(contact("work", "10.0.0.2", Some(8000)));
+        contacts.insert(contact("any", "10.0.0.3", None));
+        let alias = |c: Option<&Contact>| c.map(|c| c.alias.clone());
+        assert_eq!(
+            alias(contacts.find_by_address("10.0.0.2", 8000)),
+            Some("work".into())
+        );
+        assert_eq!(
+            alias(contacts.find_by_address("10.0.0.2", 9000)),
+            Some("home".into())
+        );
+        assert_eq!(
+            alias(contacts.find_by_address("10.0.0.3", 1)),
+            Some("any".into())
+        );
+        assert_eq!(contacts.find_by_address("10.0.0.4", 7000), None);
+
+        // the same alias replaces the contact
+        contacts.insert(contact("home", "10.0.0.9", None));
+        assert_eq!(contacts.iter().count(), 3);
+        assert_eq!(
+            contacts.get("home").unwrap().address.as_deref(),
+            Some("10.0.0.9")
+        );
+        assert!(contacts.remove("home"));
+        assert!(!contacts.remove("home"));
+    }
+
+    #[test]
+    fn notes_and_tags_are_shown_and_searched() {
+        let mut ada =
+            parse_line("ada\taddress=10.0.0.2\ttag=work\tnote.tz=CET\tnote.note=met at\\tFOSDEM")
+                .unwrap();
+        ada.note(NoteChange::Tag("friend".to_string()));
+        ada.note(NoteChange::Untag("work".to_string()));
+        ada.note(NoteChange::Set {
+            key: "lang".to_string(),
+            value: "fr".to_string(),
+        });
+        ada.note(NoteChange::Remove("tz".to_string()));
+        assert_eq!(
+            ada.info(),
+            [
+                "address: 10.0.0.2",
+                "tags: friend",
+                "lang = fr",
+                "note = met at\tFOSDEM"
+            ]
+        );
+        assert!(ada.matches("fosdem"));
+        assert!(ada.matches("ADA"));
+        assert!(!ada.matches("CET"));
+
+        let mut contacts = Contacts::default();
+        contacts.insert(ada);
+        assert_eq!(contacts.search("friend").count(), 1);
+        assert_eq!(
+            contacts.note("bob", NoteChange::Tag("x".to_string())),
+            Err("no contact named bob".to_string())
+        );
+    }
+
+    #[test]
+    fn deniable_peers_are_found_by_fingerprint() {
+        let mut contacts = Contacts::default();
+        let mut ada = contact("ada", "10.0.0.2", None);
+        ada.set("fingerprint", "0123abcd").unwrap();
+        contacts.insert(ada);
+        contacts.insert(contact("bob", "10.0.0.3", None));
+        assert_eq!(
+            contacts
+                .find_by_fingerprint("0123abcd")
+                .map(|c| c.alias.as_str()),
+            Some("ada")
+        );
+        assert_eq!(contacts.find_by_fingerprint("0123"), None);
+    }
+}
@@ -0,0 +1,118 @@
+//! What a panic leaves behind: the terminal back in order and a report with the backtrace, the
+//! recent logs and the version in the state directory, its path printed on the way out.
+//!
+//! Threads other than the main one are left to their owner, which carries on without them, so
+//! only the report is written and the screen stays as it is.
+
+use std::{
+    backtrace::Backtrace,
+    fmt, fs,
+    io::{self, Write},
+    panic,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use tracing::error;
+
+use crate::{
+    diag::LogRing,
+    dirs,
+    timestamp::{now_millis, DateTime},
+};
+
+/// Replaces the panic hook, `logs` go into the reports.
+pub fn install(logs: LogRing) {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let main = thread::current().name() == Some("main");
+        if main {
+            restore_terminal();
+        }
+        default(info);
+        let dir =
+            dirs::state_dir().ok_or_else(|| io::Error::other("couldn't determine state directory"));
+        match dir.and_then(|dir| write(&dir, &report(info, &logs))) {
+            Ok(path) if main => eprintln!(
+                "chatterbox crashed, the report is in {}, please attach it to a bug report",
+                path.display()
+            ),
+            Ok(path) => error!("The report is in {}", path.display()),
+            Err(e) => eprintln!("Failed to write the crash report: {e}"),
+        }
+    }));
+}
+
+/// Undoes what the UI did to the terminal, if it is in raw mode.
+fn restore_terminal() {
+    if !crossterm::terminal::is_raw_mode_enabled().unwrap_or(false) {
+        return;
+    }
+    let _ = crossterm::terminal::disable_raw_mode();
+    let _ = crossterm::execute!(
+        io::stdout(),
+        crossterm::terminal::LeaveAlternateScreen,
+        crossterm::event::DisableMouseCapture,
+        crossterm::event::DisableFocusChange,
+        crossterm::event::DisableBracketedPaste,
+        crossterm::cursor::Show
+    );
+}
+
+fn report(info: &dyn fmt::Display, logs: &LogRing) -> String {
+    let thread = thread::current();
+    let mut out = format!(
+        "chatterbox {} crashed, {} UTC\n\n== panic ==\nthread {} {info}\n\n== backtrace ==\n{}\n== recent logs ==\n",
+        env!("CARGO_PKG_VERSION"),
+        DateTime::from_millis(now_millis()),
+        thread.name().unwrap_or("<unnamed>"),
+        Backtrace::force_capture(),
+    );
+    for line in logs.lines() {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+fn write(dir: &Path, report: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("crash-{}.txt", now_millis()));
+    let mut file = fs::File::create(&path)?;
+    file.write_all(report.as_bytes())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diag::LogWriter;
+
+    #[test]
+    fn the_report_has_the_panic_and_the_logs() {
+        let logs = LogRing::default();
+        let mut writer = LogWriter::new(logs.clone(), None);
+        writer
+            .write_all(b"connected to peer\nreceived a file\n")
+            .unwrap();
+        let report = report(&"panicked at src/app.rs:1:1:\noops", &logs);
+        assert!(report.starts_with(&format!("chatterbox {} crashed", env!("CARGO_PKG_VERSION"))));
+        assert!(report.contains("\noops\n\n== backtrace ==\n"));
+        assert!(report.ends_with("== recent logs ==\nconnected to peer\nreceived a file\n"));
+    }
+
+    #[test]
+    fn reports_go_into_the_directory() {
+        let dir = std::env::temp_dir().join(format!("chatterbox-crash-{}", std::process::id()));
+        let path = write(&dir.join("state"), "report").unwrap();
+        assert!(path.starts_with(dir.join("state")));
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("crash-"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "report");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
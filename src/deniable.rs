@@ -0,0 +1,252 @@
+//! OTR-like deniable authentication.
+//!
+//! Both peers derive a per-session MAC key from the shared secret and a pair of fresh nonces.
+//! Since both ends hold the same key a valid tag convinces the receiver that the peer wrote the
+//! message, but it proves nothing to a third party. When the session ends the key is published
+//! on the wire, after which anybody could have forged the whole transcript.
+
+use std::io::{self, BufRead, Write};
+
+use sha1::{Digest, Sha1};
+use tracing::{debug, instrument, warn};
+
+use crate::{rng::Rng, secret};
+
+const HELLO: &str = "\u{1}HELLO ";
+const MAC: &str = "\u{1}MAC ";
+const REVEAL: &str = "\u{1}REVEAL ";
+
+const NONCE_LEN: usize = 16;
+const BLOCK_LEN: usize = 64;
+
+type Nonce = [u8; NONCE_LEN];
+type Key = [u8; 20];
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Key {
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..20].copy_from_slice(&Sha1::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha1::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    let mut outer = Sha1::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn tag(key: &Key, sender: &Nonce, counter: u64, msg: &str) -> Key {
+    hmac(key, &[sender, &counter.to_be_bytes(), msg.as_bytes()])
+}
+
+/// Identifies the holder of `secret`, the same on both ends and for every session, so the
+/// conversation can be told apart from the address it comes from.
+pub fn fingerprint(secret: &str) -> String {
+    hex::encode(&hmac(secret.as_bytes(), &[b"chatterbox-fingerprint"])[..8])
+}
+
+/// Exchanges nonces with the peer and derives the session key.
+///
+/// Must be called before anything else is read from or written to the connection.
+#[instrument(skip_all)]
+pub fn handshake<R: BufRead, W: Write>(
+    secret: &str,
+    rng: &dyn Rng,
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<(Signer, Verifier)> {
+    let mut own: Nonce = [0; NONCE_LEN];
+    rng.fill(&mut own);
+    writer.write_all(format!("{HELLO}{}\n", hex::encode(own)).as_bytes())?;
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let peer: Nonce = line
+        .trim_end()
+        .strip_prefix(HELLO)
+        .and_then(|n| hex::decode(n).ok())
+        .and_then(|n| n.try_into().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer did not start a deniable session",
+            )
+        })?;
+    let (first, second) = if own < peer { (own, peer) } else { (peer, own) };
+    let key = hmac(
+        secret.as_bytes(),
+        &[b"chatterbox-deniable", &first, &second],
+    );
+    debug!("deniable session established");
+    Ok((
+        Signer {
+            key,
+            nonce: own,
+            counter: 0,
+        },
+        Verifier {
+            key,
+            nonce: peer,
+            counter: 0,
+            revealed: false,
+        },
+    ))
+}
+
+/// Outgoing half of a deniable session.
+#[derive(Debug)]
+pub struct Signer {
+    key: Key,
+    nonce: Nonce,
+    counter: u64,
+}
+
+impl Signer {
+    /// Wire representation of `msg`, including the trailing newline.
+    pub fn sign(&mut self, msg: &str) -> String {
+        let tag = tag(&self.key, &self.nonce, self.counter, msg);
+        self.counter += 1;
+        format!("{MAC}{} {msg}\n", hex::encode(tag))
+    }
+
+    /// Publishes the session key, after this the transcript can no longer be attributed to us.
+    pub fn reveal(&self) -> String {
+        format!("{REVEAL}{}\n", hex::encode(self.key))
+    }
+}
+
+/// What [`Verifier::verify`] made of an incoming line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming<'a> {
+    Authentic(&'a str),
+    Unauthenticated(&'a str),
+    /// Peer ended the session and published the key.
+    Revealed,
+}
+
+/// Incoming half of a deniable session.
+#[derive(Debug)]
+pub struct Verifier {
+    key: Key,
+    nonce: Nonce,
+    counter: u64,
+    revealed: bool,
+}
+
+impl Verifier {
+    pub fn verify<'a>(&mut self, line: &'a str) -> Incoming<'a> {
+        if let Some(key) = line.strip_prefix(REVEAL) {
+            if !hex::decode(key).is_ok_and(|key| secret::eq(&key, &self.key)) {
+                warn!("peer revealed a key which doesn't belong to this session");
+            }
+            self.revealed = true;
+            return Incoming::Revealed;
+        }
+        let Some((tag_hex, msg)) = line.strip_prefix(MAC).and_then(|l| l.split_once(' ')) else {
+            warn!("untagged message in deniable session");
+            return Incoming::Unauthenticated(line);
+        };
+        let expected = tag(&self.key, &self.nonce, self.counter, msg);
+        // once the key is public anybody can produce valid tags
+        if !self.revealed && hex::decode(tag_hex).is_ok_and(|tag| secret::eq(&tag, &expected)) {
+            self.counter += 1;
+            Incoming::Authentic(msg)
+        } else {
+            warn!("message failed authentication");
+            Incoming::Unauthenticated(msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::BufReader, os::unix::net::UnixStream};
+
+    use super::*;
+    use crate::rng::System;
+
+    /// Sessions of two peers, knowing `secret` and `other` respectively.
+    fn session(secret: &str, other: &str) -> ((Signer, Verifier), (Signer, Verifier)) {
+        let (mut ada, mut bob) = UnixStream::pair().unwrap();
+        let other = other.to_string();
+        let peer = std::thread::spawn(move || {
+            let mut reader = BufReader::new(bob.try_clone().unwrap());
+            handshake(&other, &System, &mut reader, &mut bob).unwrap()
+        });
+        let mut reader = BufReader::new(ada.try_clone().unwrap());
+        let own = handshake(secret, &System, &mut reader, &mut ada).unwrap();
+        (own, peer.join().unwrap())
+    }
+
+    #[test]
+    fn both_ends_agree_on_the_session() {
+        let ((mut ada, mut from_bob), (mut bob, mut from_ada)) = session("secret", "secret");
+        let (hi, hello) = (ada.sign("hi"), bob.sign("hello"));
+        assert_eq!(from_ada.verify(hi.trim_end()), Incoming::Authentic("hi"));
+        assert_eq!(
+            from_bob.verify(hello.trim_end()),
+            Incoming::Authentic("hello")
+        );
+
+        let mut reader = BufReader::new(&b"hi\n"[..]);
+        let err = handshake("secret", &System, &mut reader, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn signed_messages_verify_once_and_in_order() {
+        let ((mut ada, _), (_, mut bob)) = session("secret", "secret");
+        let first = ada.sign("first");
+        let second = ada.sign("second");
+        assert_eq!(
+            bob.verify(second.trim_end()),
+            Incoming::Unauthenticated("second")
+        );
+        assert_eq!(bob.verify(first.trim_end()), Incoming::Authentic("first"));
+        assert_eq!(
+            bob.verify(first.trim_end()),
+            Incoming::Unauthenticated("first")
+        );
+        assert_eq!(bob.verify(second.trim_end()), Incoming::Authentic("second"));
+
+        let signed = ada.sign("pay 10");
+        let changed = format!("{}99", signed.trim_end().strip_suffix("10").unwrap());
+        assert_eq!(
+            bob.verify(changed.trim_end()),
+            Incoming::Unauthenticated("pay 99")
+        );
+        assert_eq!(
+            bob.verify("untagged"),
+            Incoming::Unauthenticated("untagged")
+        );
+
+        let ((mut ada, _), (_, mut bob)) = session("secret", "guess");
+        let line = ada.sign("hi");
+        assert_eq!(bob.verify(line.trim_end()), Incoming::Unauthenticated("hi"));
+    }
+
+    #[test]
+    fn nothing_is_authentic_once_the_key_is_revealed() {
+        let ((mut ada, _), (_, mut bob)) = session("secret", "secret");
+        let later = ada.sign("later");
+        assert_eq!(bob.verify(ada.reveal().trim_end()), Incoming::Revealed);
+        assert_eq!(
+            bob.verify(later.trim_end()),
+            Incoming::Unauthenticated("later")
+        );
+    }
+
+    #[test]
+    fn fingerprints_only_depend_on_the_secret() {
+        let ours = fingerprint("correct horse");
+        assert_eq!(ours.len(), 16);
+        assert!(ours.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(fingerprint("correct horse"), ours);
+        assert_ne!(fingerprint("battery staple"), ours);
+    }
+}
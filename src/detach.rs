@@ -0,0 +1,749 @@
+//! Sessions which outlive the terminal, like `tmux`.
+//!
+//! `--detach` starts a daemon holding the interface in a pseudo terminal of its own, together with
+//! the connection, then attaches to it. Ctrl-\ detaches, `chatterbox attach` gets back to it and
+//! `chatterbox sessions` lists the running ones with their unread messages.
+//!
+//! Attached clients talk to the daemon through a unix socket in the runtime directory, the daemon
+//! sends them what the interface draws, they send it frames: a type byte, the length of the
+//! payload as two bytes and the payload. [`INPUT`] carries keys, [`RESIZE`] the size of the
+//! terminal as columns and rows of two bytes each. Any number of them may be attached at once,
+//! each written to by a thread of its own so a stalled terminal doesn't hold up the session.
+//!
+//! Frontends without a terminal, like `chatterbox attach --send`, use the control socket of the
+//! interface next to it instead, a line for every request:
+//!
+//! - `SEND <text>`, as if typed and entered, answered with `OK` or `ERROR <reason>`
+//! - `STATUS`, answered with `STATUS <connected|disconnected> <unread>`
+//! - `FOLLOW`, answered with `MESSAGE <in|out|sys> <text>` for every message from then on,
+//!   followed by `\t<data>` if it was annotated for bots
+//!
+//! Texts are escaped like messages on the wire.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Shutdown,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{
+            fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+            process::CommandExt,
+        },
+    },
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    json,
+    message::{Kind, Message},
+    protocol,
+    stateful_list::StatefulList,
+    tasks,
+};
+
+/// Set for the daemon, to the name of its session.
+pub const DAEMON: &str = "CHATTERBOX_DAEMON";
+/// Set for the interface run by the daemon, to the name of its session.
+const SESSION: &str = "CHATTERBOX_SESSION";
+
+const INPUT: u8 = 0;
+const RESIZE: u8 = 1;
+/// Ctrl-\
+const DETACH_KEY: u8 = 0x1c;
+/// Reads of the interface waiting for a client, one which falls further behind is dropped.
+const QUEUE: usize = 256;
+/// Time a write to a client may take before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Focus reports as sent by the terminal, the interface asked for them.
+const FOCUS_IN: &[u8] = b"\x1b[I";
+const FOCUS_OUT: &[u8] = b"\x1b[O";
+/// How often an attached client checks the size of its terminal.
+const RESIZE_INTERVAL: Duration = Duration::from_millis(250);
+/// How often followers are sent the new messages.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// `$XDG_RUNTIME_DIR/chatterbox`, falling back to a directory of the user in `/tmp`.
+fn dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("chatterbox"),
+        // SAFETY: getuid can't fail
+        _ => std::env::temp_dir().join(format!("chatterbox-{}", unsafe { libc::getuid() })),
+    }
+}
+
+/// [`dir`] after making sure only we can use it, someone else may have made it in `/tmp` first.
+fn own_dir() -> io::Result<PathBuf> {
+    let dir = dir();
+    let meta = fs::symlink_metadata(&dir)?;
+    // SAFETY: getuid can't fail
+    let uid = unsafe { libc::getuid() };
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o777 != 0o700 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} isn't a directory only we may use, remove it and try again",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(dir)
+}
+
+fn socket_path(session: &str) -> PathBuf {
+    dir().join(format!("{session}.sock"))
+}
+
+fn control_path(session: &str) -> PathBuf {
+    dir().join(format!("{session}.ctl"))
+}
+
+fn unread_path(session: &str) -> PathBuf {
+    dir().join(format!("{session}.unread"))
+}
+
+/// Whether a daemon answers for `session`, a socket left behind by a crashed one is removed.
+fn is_running(session: &str) -> bool {
+    let path = socket_path(session);
+    if UnixStream::connect(&path).is_ok() {
+        return true;
+    }
+    let _ = fs::remove_file(path);
+    false
+}
+
+/// Starts the daemon for `session` with the arguments of this run, then attaches to it.
+pub fn start(session: &str) -> io::Result<()> {
+    if is_running(session) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("session {session} is running, chatterbox attach {session} gets to it"),
+        ));
+    }
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir())?;
+    own_dir()?;
+    let args = std::env::args_os().skip(1).filter(|a| a != "--detach");
+    let mut daemon = Command::new(std::env::current_exe()?);
+    daemon
+        .args(args)
+        .env(DAEMON, session)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: setsid is async signal safe
+    unsafe {
+        // away from the terminal, so closing it doesn't hang the daemon up
+        daemon.pre_exec(|| match libc::setsid() {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    daemon.spawn()?;
+    for _ in 0..50 {
+        if socket_path(session).exists() {
+            return attach(session);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("session {session} didn't come up"),
+    ))
+}
+
+/// Runs the interface in a pseudo terminal and serves it to the attached clients until it quits.
+pub fn daemon(session: &str) -> io::Result<()> {
+    let (mut master, slave) = openpty()?;
+    let mut interface = Command::new(std::env::current_exe()?);
+    interface
+        .args(std::env::args_os().skip(1))
+        .env_remove(DAEMON)
+        .env(SESSION, session)
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    // SAFETY: setsid and ioctl are async signal safe
+    unsafe {
+        // the pseudo terminal becomes the controlling terminal of the interface
+        interface.pre_exec(|| {
+            if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = interface.spawn()?;
+    // holds on to the slave, which keeps the end of the interface from showing on the master
+    drop(interface);
+    let path = socket_path(session);
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    let clients: Arc<Mutex<Vec<Attached>>> = Arc::default();
+    let keys = master.try_clone()?;
+    let attached = Arc::clone(&clients);
+    tasks::spawn("attach listener", move || {
+        for client in listener.incoming() {
+            match client {
+                Ok(client) => serve(client, &keys, &attached),
+                Err(e) => warn!("Failed to accept client: {e}"),
+            }
+        }
+    });
+    let mut buf = [0; 4096];
+    loop {
+        // fails once the interface is gone
+        let len = match master.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        fan_out(
+            &mut clients.lock().expect("clients lock is poisoned"),
+            &buf[..len],
+        );
+    }
+    let _ = child.wait();
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(control_path(session));
+    let _ = fs::remove_file(unread_path(session));
+    Ok(())
+}
+
+/// Attached client, the interface is drawn on it by a thread of its own.
+struct Attached {
+    output: SyncSender<Vec<u8>>,
+}
+
+impl Attached {
+    /// Starts writing to `stream`, which is shut down once the client is dropped or a write
+    /// fails, ending the reading side as well.
+    fn spawn(mut stream: UnixStream) -> io::Result<Self> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let (output, queue) = mpsc::sync_channel::<Vec<u8>>(QUEUE);
+        tasks::spawn("attached output", move || {
+            for bytes in queue {
+                if let Err(e) = stream.write_all(&bytes) {
+                    debug!("Failed to write to a client: {e}");
+                    break;
+                }
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+        Ok(Self { output })
+    }
+}
+
+/// Queues what the interface drew for every client, dropping those which don't keep up.
+fn fan_out(clients: &mut Vec<Attached>, bytes: &[u8]) {
+    clients.retain(|client| match client.output.try_send(bytes.to_vec()) {
+        Ok(()) => true,
+        Err(mpsc::TrySendError::Full(_)) => {
+            warn!("Dropping a client, it doesn't keep up");
+            false
+        }
+        Err(mpsc::TrySendError::Disconnected(_)) => false,
+    });
+}
+
+/// Passes the frames of a freshly attached client on to the interface.
+fn serve(client: UnixStream, keys: &fs::File, clients: &Arc<Mutex<Vec<Attached>>>) {
+    let (Ok(mut reader), Ok(mut keys)) = (client.try_clone(), keys.try_clone()) else {
+        return;
+    };
+    let attached = match Attached::spawn(client) {
+        Ok(attached) => attached,
+        Err(e) => return warn!("Failed to attach client: {e}"),
+    };
+    debug!("client attached");
+    clients
+        .lock()
+        .expect("clients lock is poisoned")
+        .push(attached);
+    tasks::spawn("attached client", move || loop {
+        let mut header = [0; 3];
+        if reader.read_exact(&mut header).is_err() {
+            debug!("client detached");
+            return;
+        }
+        let mut payload = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+        if reader.read_exact(&mut payload).is_err() {
+            return;
+        }
+        match (header[0], payload.as_slice()) {
+            (INPUT, _) => {
+                if keys.write_all(&payload).is_err() {
+                    return;
+                }
+            }
+            (RESIZE, [c1, c2, r1, r2]) => {
+                let (cols, rows) = (
+                    u16::from_be_bytes([*c1, *c2]),
+                    u16::from_be_bytes([*r1, *r2]),
+                );
+                // a size change redraws everything, which the new client hasn't seen yet
+                set_size(&keys, cols, rows.saturating_sub(1));
+                set_size(&keys, cols, rows);
+            }
+            _ => warn!("Ignoring unknown frame {}", header[0]),
+        }
+    });
+}
+
+fn openpty() -> io::Result<(fs::File, OwnedFd)> {
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: the out pointers are valid, name, termios and size may be null
+    let res = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: sets a flag of the descriptor just opened, the interface has no business with it
+    unsafe { libc::fcntl(master, libc::F_SETFD, libc::FD_CLOEXEC) };
+    // SAFETY: both were just opened and aren't owned by anything else
+    unsafe { Ok((fs::File::from_raw_fd(master), OwnedFd::from_raw_fd(slave))) }
+}
+
+fn set_size(pty: &fs::File, cols: u16, rows: u16) {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: the size outlives the call
+    if unsafe { libc::ioctl(pty.as_raw_fd(), libc::TIOCSWINSZ, &size) } == -1 {
+        warn!("Failed to resize: {}", io::Error::last_os_error());
+    }
+}
+
+fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![kind];
+    frame.extend((payload.len() as u16).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+/// Shows the session in this terminal until it is detached with Ctrl-\ or the interface quits.
+pub fn attach(session: &str) -> io::Result<()> {
+    own_dir()?;
+    let mut daemon = UnixStream::connect(socket_path(session))
+        .map_err(|e| io::Error::new(e.kind(), format!("no session {session}: {e}")))?;
+    crossterm::terminal::enable_raw_mode()?;
+    // the interface set these up when it started, maybe in another terminal
+    crossterm::execute!(
+        io::stdout(),
+        crossterm::terminal::EnterAlternateScreen,
+        crossterm::event::EnableMouseCapture,
+        crossterm::event::EnableFocusChange,
+        crossterm::event::EnableBracketedPaste
+    )?;
+    let mut keys = daemon.try_clone()?;
+    let resizes = daemon.try_clone()?;
+    // whatever arrived while detached has been seen now
+    keys.write_all(&frame(INPUT, FOCUS_IN))?;
+    std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok(len @ 1..) = io::stdin().read(&mut buf) {
+            let input = &buf[..len];
+            let (input, detach) = match input.iter().position(|&b| b == DETACH_KEY) {
+                Some(i) => (&input[..i], true),
+                None => (input, false),
+            };
+            if keys.write_all(&frame(INPUT, input)).is_err() || detach {
+                break;
+            }
+        }
+        // counts what arrives from now on as unread
+        let _ = keys.write_all(&frame(INPUT, FOCUS_OUT));
+        // wakes up the main thread, which is waiting for output
+        let _ = keys.shutdown(std::net::Shutdown::Both);
+    });
+    std::thread::spawn(move || {
+        let mut resizes = resizes;
+        let mut last = None;
+        loop {
+            let size = crossterm::terminal::size().ok();
+            if let Some((cols, rows)) = size.filter(|_| size != last) {
+                let payload: Vec<u8> = [cols.to_be_bytes(), rows.to_be_bytes()].concat();
+                if resizes.write_all(&frame(RESIZE, &payload)).is_err() {
+                    return;
+                }
+            }
+            last = size;
+            std::thread::sleep(RESIZE_INTERVAL);
+        }
+    });
+    let mut buf = [0; 4096];
+    let mut stdout = io::stdout().lock();
+    // flushed right away, stdout would wait for the end of the line
+    let res = loop {
+        match daemon.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(len) => {
+                if let Err(e) = stdout.write_all(&buf[..len]).and_then(|()| stdout.flush()) {
+                    break Err(e);
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    drop(stdout);
+    crossterm::execute!(
+        io::stdout(),
+        crossterm::terminal::LeaveAlternateScreen,
+        crossterm::event::DisableMouseCapture,
+        crossterm::event::DisableFocusChange,
+        crossterm::event::DisableBracketedPaste,
+        crossterm::cursor::Show
+    )?;
+    crossterm::terminal::disable_raw_mode()?;
+    if is_running(session) {
+        println!("detached from {session}, chatterbox attach {session} gets back to it");
+    } else {
+        println!("session {session} ended");
+    }
+    res
+}
+
+/// Running sessions with their unread messages.
+pub fn list() -> Vec<(String, usize)> {
+    let Ok(entries) = own_dir().and_then(fs::read_dir) else {
+        return Vec::new();
+    };
+    let mut sessions: Vec<String> = entries
+        .filter_map(|e| {
+            let name = e.ok()?.file_name().into_string().ok()?;
+            Some(name.strip_suffix(".sock")?.to_string())
+        })
+        .filter(|session| is_running(session))
+        .collect();
+    sessions.sort();
+    sessions
+        .into_iter()
+        .map(|session| {
+            let unread = fs::read_to_string(unread_path(&session))
+                .ok()
+                .and_then(|count| count.trim().parse().ok())
+                .unwrap_or(0);
+            (session, unread)
+        })
+        .collect()
+}
+
+/// Tells `chatterbox sessions` how many messages are unread, if running in a session.
+pub fn note_unread(count: usize) {
+    let Ok(session) = std::env::var(SESSION) else {
+        return;
+    };
+    if let Err(e) = fs::write(unread_path(&session), count.to_string()) {
+        warn!("Failed to note the unread messages: {e}");
+    }
+}
+
+/// What a frontend on the control socket asks the interface to do.
+#[derive(Debug)]
+pub enum Request {
+    Send(String),
+    Status,
+}
+
+/// A request along with where its answer goes.
+#[derive(Debug)]
+pub struct Control {
+    pub request: Request,
+    pub reply: mpsc::Sender<String>,
+}
+
+/// Serves the control socket in the background, if running in a session.
+pub fn serve_control(
+    messages: Arc<Mutex<StatefulList<Message>>>,
+    requests: mpsc::Sender<Control>,
+) -> io::Result<()> {
+    let Ok(session) = std::env::var(SESSION) else {
+        return Ok(());
+    };
+    own_dir()?;
+    let path = control_path(&session);
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    tasks::spawn("control listener", move || {
+        for client in listener.incoming() {
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("Failed to accept control client: {e}");
+                    continue;
+                }
+            };
+            let (messages, requests) = (Arc::clone(&messages), requests.clone());
+            tasks::spawn("control client", move || {
+                if let Err(e) = answer(client, &messages, &requests) {
+                    debug!("control client gone: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn answer(
+    mut client: UnixStream,
+    messages: &Mutex<StatefulList<Message>>,
+    requests: &mpsc::Sender<Control>,
+) -> io::Result<()> {
+    let reader = BufReader::new(client.try_clone()?);
+    for line in reader.lines() {
+        let line = line?;
+        let request = match line.split_once(' ').unwrap_or((&line, "")) {
+            ("SEND", text) => Request::Send(protocol::unescape(text)),
+            ("STATUS", _) => Request::Status,
+            ("FOLLOW", _) => return follow(client, messages),
+            (other, _) => {
+                client.write_all(format!("ERROR unknown request {other}\n").as_bytes())?;
+                continue;
+            }
+        };
+        let (reply, answer) = mpsc::channel();
+        let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "interface is gone");
+        requests
+            .send(Control { request, reply })
+            .map_err(|_| gone())?;
+        let answer = answer.recv().map_err(|_| gone())?;
+        client.write_all(format!("{answer}\n").as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Writes every message recorded from now on until the client is gone.
+fn follow(mut client: UnixStream, messages: &Mutex<StatefulList<Message>>) -> io::Result<()> {
+    let mut sent = messages.lock().expect("messages lock is poisoned").len();
+    loop {
+        let fresh: Vec<String> = {
+            let lock = messages.lock().expect("messages lock is poisoned");
+            let fresh = (sent..lock.len()).filter_map(|i| lock.get(i)).map(|m| {
+                let kind = match m.kind {
+                    Kind::Incoming => "in",
+                    Kind::Outgoing => "out",
+                    Kind::System => "sys",
+                };
+                let data = m
+                    .data
+                    .as_ref()
+                    .map(|d| format!("\t{}", protocol::escape(&d.to_string())));
+                format!(
+                    "MESSAGE {kind} {}{}\n",
+                    protocol::escape(&m.line()),
+                    data.unwrap_or_default()
+                )
+            });
+            let fresh = fresh.collect();
+            sent = lock.len();
+            fresh
+        };
+        for line in fresh {
+            client.write_all(line.as_bytes())?;
+        }
+        std::thread::sleep(FOLLOW_INTERVAL);
+    }
+}
+
+/// Answer of the interface on the control socket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Answer {
+    Ok,
+    Status {
+        connected: bool,
+        unread: usize,
+    },
+    /// Message recorded while following, of kind `in`, `out` or `sys`
+    Message {
+        kind: String,
+        text: String,
+        data: Option<json::Value>,
+    },
+}
+
+impl Answer {
+    fn parse(line: &str) -> Option<Self> {
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match kind {
+            "OK" => Some(Self::Ok),
+            "STATUS" => {
+                let (connected, unread) = rest.split_once(' ')?;
+                Some(Self::Status {
+                    connected: connected == "connected",
+                    unread: unread.parse().ok()?,
+                })
+            }
+            "MESSAGE" => {
+                let (kind, text) = rest.split_once(' ').unwrap_or((rest, ""));
+                let (text, data) = text
+                    .split_once('\t')
+                    .map_or((text, None), |(t, d)| (t, Some(d)));
+                Some(Self::Message {
+                    kind: kind.to_string(),
+                    text: crate::ansi::sanitize(&protocol::unescape(text)).into_owned(),
+                    data: data.and_then(|d| json::parse(&protocol::unescape(d)).ok()),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Sends a request to the control socket of `session`, handing the answers to `answered`.
+pub fn request(session: &str, line: &str, mut answered: impl FnMut(Answer)) -> io::Result<()> {
+    own_dir()?;
+    let mut control = UnixStream::connect(control_path(session))
+        .map_err(|e| io::Error::new(e.kind(), format!("no session {session}: {e}")))?;
+    control.write_all(format!("{line}\n").as_bytes())?;
+    let following = line == "FOLLOW";
+    for answer in BufReader::new(control).lines() {
+        let answer = answer?;
+        if let Some(reason) = answer.strip_prefix("ERROR ") {
+            return Err(io::Error::other(reason.to_string()));
+        }
+        let answer = Answer::parse(&answer).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected answer {answer}"),
+            )
+        })?;
+        answered(answer);
+        if !following {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_live_in_a_directory_only_we_may_use() {
+        let runtime =
+            std::env::temp_dir().join(format!("chatterbox-runtime-{}", std::process::id()));
+        // the only test reading it
+        std::env::set_var("XDG_RUNTIME_DIR", &runtime);
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(dir())
+            .unwrap();
+        assert_eq!(
+            own_dir().unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert!(list().is_empty());
+
+        fs::set_permissions(dir(), fs::Permissions::from_mode(0o700)).unwrap();
+        let _running = UnixListener::bind(socket_path("work")).unwrap();
+        fs::write(unread_path("work"), "3\n").unwrap();
+        drop(UnixListener::bind(socket_path("crashed")).unwrap());
+        assert_eq!(list(), [("work".to_string(), 3)]);
+        assert!(!socket_path("crashed").exists());
+
+        assert_eq!(frame(RESIZE, &[0, 80, 0, 24]), [RESIZE, 0, 4, 0, 80, 0, 24]);
+        fs::remove_dir_all(runtime).unwrap();
+    }
+
+    #[test]
+    fn frontends_send_and_follow_through_the_control_socket() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let messages = Arc::new(Mutex::new(StatefulList::default()));
+        let (requests, controls) = mpsc::channel();
+        let served = Arc::clone(&messages);
+        std::thread::spawn(move || answer(theirs, &served, &requests));
+        std::thread::spawn(move || {
+            for control in controls {
+                let answer = match control.request {
+                    Request::Send(text) if text == "hi\nthere" => "OK",
+                    Request::Send(_) => "ERROR not that",
+                    Request::Status => "STATUS connected 2",
+                };
+                control.reply.send(answer.to_string()).unwrap();
+            }
+        });
+        let mut reader = BufReader::new(ours.try_clone().unwrap());
+        let mut ask = |line: &str| {
+            (&ours).write_all(format!("{line}\n").as_bytes()).unwrap();
+            let mut answer = String::new();
+            reader.read_line(&mut answer).unwrap();
+            answer.trim_end().to_string()
+        };
+        assert_eq!(ask("SEND hi\\nthere"), "OK");
+        assert_eq!(ask("SEND bye"), "ERROR not that");
+        assert_eq!(ask("HELLO"), "ERROR unknown request HELLO");
+        assert_eq!(
+            Answer::parse(&ask("STATUS")),
+            Some(Answer::Status {
+                connected: true,
+                unread: 2,
+            })
+        );
+
+        (&ours).write_all(b"FOLLOW\n").unwrap();
+        std::thread::sleep(FOLLOW_INTERVAL);
+        let mut message = Message::incoming("a\u{1b}[2J".to_string());
+        message.data = json::parse("{\"n\":1}").ok();
+        messages.lock().unwrap().push(message);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(
+            Answer::parse(line.trim_end()),
+            Some(Answer::Message {
+                kind: "in".to_string(),
+                text: "a␛[2J".to_string(),
+                data: json::parse("{\"n\":1}").ok(),
+            })
+        );
+        assert_eq!(Answer::parse("NOPE"), None);
+    }
+
+    #[test]
+    fn stalled_clients_are_dropped() {
+        let (reading, mut read_end) = UnixStream::pair().unwrap();
+        let (stalled, _never_read) = UnixStream::pair().unwrap();
+        let mut clients = vec![
+            Attached::spawn(reading).unwrap(),
+            Attached::spawn(stalled).unwrap(),
+        ];
+        let reader = std::thread::spawn(move || {
+            let mut bytes = Vec::new();
+            read_end.read_to_end(&mut bytes).unwrap();
+            bytes.len()
+        });
+        let chunk = [b'x'; 4096];
+        let mut sent = 0;
+        while clients.len() == 2 {
+            fan_out(&mut clients, &chunk);
+            sent += chunk.len();
+            // slow enough for the reading one to keep up
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(clients.len(), 1);
+        drop(clients);
+        assert_eq!(reader.join().unwrap(), sent);
+    }
+}
@@ -0,0 +1,276 @@
+//! Diagnostics: recent log lines kept in memory, connection counters, the `/diag` bundle and the
+//! counters of the HUD.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::VecDeque,
+    fs::File,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::timestamp::{now_millis, DateTime};
+
+/// Number of log lines kept for the bundle.
+const RING_LEN: usize = 500;
+
+/// Most recent log lines.
+#[derive(Debug, Clone, Default)]
+pub struct LogRing(Arc<Mutex<VecDeque<String>>>);
+
+impl LogRing {
+    pub fn lines(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .map(|ring| ring.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn push(&self, text: &str) {
+        if let Ok(mut ring) = self.0.lock() {
+            for line in text.lines() {
+                if ring.len() == RING_LEN {
+                    ring.pop_front();
+                }
+                ring.push_back(line.to_string());
+            }
+        }
+    }
+}
+
+/// Writer for the tracing subscriber, keeps the recent lines and forwards to the log file if any.
+#[derive(Debug, Clone)]
+pub struct LogWriter {
+    ring: LogRing,
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl LogWriter {
+    pub fn new(ring: LogRing, file: Option<File>) -> Self {
+        Self {
+            ring,
+            file: file.map(|f| Arc::new(Mutex::new(f))),
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.ring.push(&String::from_utf8_lossy(buf));
+        if let Some(file) = &self.file {
+            file.lock()
+                .map_err(|_| io::Error::other("log file lock is poisoned"))?
+                .write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file
+                .lock()
+                .map_err(|_| io::Error::other("log file lock is poisoned"))?
+                .flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = LogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Counters shared between the UI and the reciever.
+#[derive(Debug, Default)]
+pub struct Stats {
+    connections: AtomicU64,
+    /// Start of the current connection in milliseconds, 0 if disconnected
+    connected_at: AtomicU64,
+    sent: AtomicU64,
+    sent_bytes: AtomicU64,
+    received: AtomicU64,
+    received_bytes: AtomicU64,
+}
+
+impl Stats {
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connected_at.store(now_millis(), Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.connected_at.store(0, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        self.received_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> String {
+        let connected_at = self.connected_at.load(Ordering::Relaxed);
+        let since = match connected_at {
+            0 => "not connected".to_string(),
+            at => format!("connected since {} UTC", DateTime::from_millis(at)),
+        };
+        format!(
+            "{since}\nconnections: {}\nsent: {} messages, {} bytes\nreceived: {} messages, {} bytes\n",
+            self.connections.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed),
+            self.sent_bytes.load(Ordering::Relaxed),
+            self.received.load(Ordering::Relaxed),
+            self.received_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Bytes allocated through [`CountingAlloc`] and not freed yet.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocator of the system, keeping count of the bytes in use for the HUD. The binary installs
+/// it with `#[global_allocator]`.
+pub struct CountingAlloc;
+
+// SAFETY: everything is left to the system allocator, only the sizes are counted
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+/// Bytes on the heap, `None` unless [`CountingAlloc`] is the global allocator.
+pub fn allocated() -> Option<usize> {
+    Some(ALLOCATED.load(Ordering::Relaxed)).filter(|&bytes| bytes > 0)
+}
+
+/// Redraws counted while the HUD is shown.
+#[derive(Debug)]
+pub struct Hud {
+    since: Instant,
+    redraws: u32,
+    /// Redraws during the last full second
+    pub per_second: u32,
+}
+
+impl Default for Hud {
+    fn default() -> Self {
+        Self {
+            since: Instant::now(),
+            redraws: 0,
+            per_second: 0,
+        }
+    }
+}
+
+impl Hud {
+    pub fn redrawn(&mut self) {
+        self.redraws += 1;
+    }
+
+    /// Starts counting the next second once this one is over, returns whether it did. The HUD
+    /// is redrawn then, which counts as well.
+    pub fn tick(&mut self) -> bool {
+        if self.since.elapsed() < Duration::from_secs(1) {
+            return false;
+        }
+        self.per_second = std::mem::take(&mut self.redraws);
+        self.since = Instant::now();
+        true
+    }
+}
+
+/// Everything worth attaching to a bug report.
+pub fn bundle(logs: &LogRing, config: &str, stats: &Stats, connection: &str) -> String {
+    let mut out = format!(
+        "chatterbox {} diagnostics, {} UTC\n\n== connection ==\n{connection}\n{}\n== config ==\n{config}\n\n== recent logs ==\n",
+        env!("CARGO_PKG_VERSION"),
+        DateTime::from_millis(now_millis()),
+        stats.report(),
+    );
+    for line in logs.lines() {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_recent_lines_are_kept() {
+        let ring = LogRing::default();
+        let mut writer = LogWriter::new(ring.clone(), None);
+        // tracing hands over every event in a single write
+        for i in 0..RING_LEN {
+            writer.write_all(format!("line {i}\n").as_bytes()).unwrap();
+        }
+        writer.write_all(b"two\nmore\n").unwrap();
+        let lines = ring.lines();
+        assert_eq!(lines.len(), RING_LEN);
+        assert_eq!(lines[0], "line 2");
+        assert_eq!(lines[RING_LEN - 2..], ["two", "more"]);
+    }
+
+    #[test]
+    fn the_bundle_has_every_section() {
+        let stats = Stats::default();
+        stats.connected();
+        stats.record_sent(5);
+        stats.record_sent(7);
+        stats.record_received(3);
+        stats.disconnected();
+        let ring = LogRing::default();
+        ring.push("INFO started");
+        let config = "# in memory only\n[history]\nbackend = \"memory\"\n";
+        let bundle = bundle(&ring, config, &stats, "disconnected");
+        let connection = "== connection ==\ndisconnected\nnot connected\nconnections: 1\n\
+                          sent: 2 messages, 12 bytes\nreceived: 1 messages, 3 bytes\n";
+        assert!(bundle.contains(connection), "{bundle}");
+        assert!(bundle.contains(&format!("== config ==\n{config}")));
+        assert!(bundle.ends_with("== recent logs ==\nINFO started\n"));
+    }
+}
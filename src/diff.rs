@@ -0,0 +1,87 @@
+//! Word level difference between two versions of a message.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Words and the whitespace between them, so that joining them gives back the text.
+fn tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut space = None;
+    for (i, c) in text.char_indices() {
+        if space != Some(c.is_whitespace()) {
+            if i > start {
+                tokens.push(&text[start..i]);
+            }
+            start = i;
+            space = Some(c.is_whitespace());
+        }
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+    tokens
+}
+
+/// Changes turning `old` into `new`, removals come before the additions replacing them.
+pub fn words<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    let (old, new) = (tokens(old), tokens(new));
+    // longest common subsequence, lengths of the suffixes
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut changes = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            changes.push(Change::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            changes.push(Change::Removed(old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(new[j]));
+            j += 1;
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_replaced_in_place() {
+        assert_eq!(
+            words("see you at 5", "see you at 6 then"),
+            [
+                Change::Same("see"),
+                Change::Same(" "),
+                Change::Same("you"),
+                Change::Same(" "),
+                Change::Same("at"),
+                Change::Same(" "),
+                Change::Removed("5"),
+                Change::Added("6"),
+                Change::Added(" "),
+                Change::Added("then"),
+            ]
+        );
+        assert_eq!(tokens(" two  words\n"), [" ", "two", "  ", "words", "\n"]);
+        assert_eq!(words("", "new"), [Change::Added("new")]);
+        assert_eq!(words("same", "same"), [Change::Same("same")]);
+    }
+}
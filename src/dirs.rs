@@ -0,0 +1,38 @@
+//! Locations of the files chatterbox keeps between runs.
+
+use std::path::PathBuf;
+
+/// `$XDG_DATA_HOME/chatterbox`, falling back to `~/.local/share/chatterbox`.
+pub fn data_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(base.join("chatterbox"))
+}
+
+/// `$XDG_CONFIG_HOME/chatterbox`, falling back to `~/.config/chatterbox`.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("chatterbox"))
+}
+
+/// `$XDG_STATE_HOME/chatterbox`, falling back to `~/.local/state/chatterbox`.
+pub fn state_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(base.join("chatterbox"))
+}
+
+/// `$XDG_DOWNLOAD_DIR`, falling back to `~/Downloads`, where received files go.
+pub fn download_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_DOWNLOAD_DIR") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => Some(PathBuf::from(std::env::var_os("HOME")?).join("Downloads")),
+    }
+}
@@ -0,0 +1,230 @@
+//! Finding the others on the local network, `/who`.
+//!
+//! The ping is `\u{1}WHO` sent to a multicast group. Everybody started with `--discoverable`
+//! answers the sender with `\u{1}HERE <name>\t<port>\t<capabilities>`, the port being the one it
+//! listens on as a server, 0 if it doesn't, and the capabilities separated by commas.
+
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    os::fd::FromRawFd,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    protocol::{escape, unescape},
+    tasks,
+};
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 89, 89);
+const PORT: u16 = 8990;
+const WHO: &str = "\u{1}WHO";
+const HERE: &str = "\u{1}HERE ";
+/// How long answers are waited for.
+const WAIT: Duration = Duration::from_secs(1);
+
+/// What we answer a ping with.
+#[derive(Debug, Clone, Default)]
+pub struct Announcement {
+    pub name: String,
+    /// Port we listen on as a server
+    pub port: Option<u16>,
+    pub capabilities: Vec<String>,
+}
+
+/// Somebody who answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub name: String,
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+    pub capabilities: Vec<String>,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{} at {}", self.name, SocketAddr::new(self.ip, port))?,
+            None => write!(f, "{} at {}, not listening", self.name, self.ip)?,
+        }
+        if !self.capabilities.is_empty() {
+            write!(f, " ({})", self.capabilities.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Announcement {
+    fn to_line(&self) -> String {
+        format!(
+            "{HERE}{}\t{}\t{}",
+            escape(&self.name),
+            self.port.unwrap_or(0),
+            self.capabilities.join(",")
+        )
+    }
+}
+
+fn parse_answer(line: &str, ip: IpAddr) -> Option<Peer> {
+    let mut fields = line.strip_prefix(HERE)?.trim_end().splitn(3, '\t');
+    let name = unescape(fields.next()?);
+    let port = fields.next()?.parse().ok().filter(|&port| port != 0);
+    let capabilities = fields
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .filter(|c| !c.is_empty())
+        .map(str::to_string)
+        .collect();
+    Some(Peer {
+        name,
+        ip,
+        port,
+        capabilities,
+    })
+}
+
+/// Answers the pings of the local network with what `announcement` holds at the time.
+pub fn answer(announcement: Arc<Mutex<Announcement>>) -> io::Result<()> {
+    let socket = shared_socket(PORT)?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    tasks::spawn("discovery", move || {
+        let mut buf = [0; 512];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Failed to receive pings: {e}");
+                    return;
+                }
+            };
+            if &buf[..len] != WHO.as_bytes() {
+                continue;
+            }
+            debug!("pinged by {from}");
+            let line = announcement
+                .lock()
+                .expect("announcement lock is poisoned")
+                .to_line();
+            if let Err(e) = socket.send_to(line.as_bytes(), from) {
+                warn!("Failed to answer {from}: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Pings the local network, handing whoever answered to `answers`.
+pub fn ping(answers: mpsc::Sender<io::Result<Vec<Peer>>>) {
+    tasks::spawn("who", move || {
+        let _ = answers.send(collect());
+    });
+}
+
+fn collect() -> io::Result<Vec<Peer>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    // the local network only, and the others on this machine too
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.send_to(WHO.as_bytes(), (GROUP, PORT))?;
+    let until = Instant::now() + WAIT;
+    // the others on one machine all answer from the discovery port
+    let mut peers = Vec::new();
+    let mut buf = [0; 512];
+    while let Some(left) = until.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                let line = String::from_utf8_lossy(&buf[..len]);
+                match parse_answer(&line, from.ip()) {
+                    // over several interfaces
+                    Some(peer) if peers.contains(&peer) => {}
+                    Some(peer) => peers.push(peer),
+                    None => {}
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(peers)
+}
+
+/// Socket bound to `port`, which every chatterbox on the machine can bind as well.
+pub(crate) fn shared_socket(port: u16) -> io::Result<UdpSocket> {
+    // SAFETY: a fresh socket, checked for failure below
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd was just created and is owned by the socket from here on
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let on: libc::c_int = 1;
+    // SAFETY: the option value is a c_int as the kernel expects
+    let reused = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            (&on as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if reused == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: port.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: the address is a sockaddr_in of the size passed along
+    let bound = unsafe {
+        libc::bind(
+            fd,
+            (&address as *const libc::sockaddr_in).cast(),
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if bound == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_tell_the_name_port_and_capabilities() {
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut announcement = Announcement {
+            name: "ada\tl".to_string(),
+            port: Some(7000),
+            capabilities: vec!["tls".to_string(), "files".to_string()],
+        };
+        let peer = parse_answer(&announcement.to_line(), ip).unwrap();
+        assert_eq!(peer.name, "ada\tl");
+        assert_eq!(peer.to_string(), "ada\tl at 10.0.0.2:7000 (tls, files)");
+
+        announcement.port = None;
+        announcement.capabilities.clear();
+        let peer = parse_answer(&announcement.to_line(), ip).unwrap();
+        assert_eq!(peer.to_string(), "ada\tl at 10.0.0.2, not listening");
+        assert_eq!(parse_answer("\u{1}HERE ada", ip), None);
+        assert_eq!(parse_answer(WHO, ip), None);
+    }
+}
@@ -0,0 +1,150 @@
+//! The draft in the input box, saved every few seconds while it changes so that a crash or a
+//! killed terminal doesn't take a long message with it. The next run offers it back, quitting
+//! normally drops it. Every conversation has its own, readable only by the user. Queued
+//! messages are kept by the [`outbox`](crate::outbox) already.
+
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{dirs, store};
+
+/// Time between two saves of a draft which keeps changing.
+const SAVE_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Where the draft of the conversation with `conversation` is kept.
+pub fn path(conversation: &str) -> Option<PathBuf> {
+    dirs::data_dir().map(|d| d.join("drafts").join(store::file_name(conversation)))
+}
+
+/// Draft left at `path` by a run which didn't quit normally.
+pub fn recover(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().filter(|d| !d.is_empty())
+}
+
+/// Forgets the draft saved at `path`.
+pub fn discard(path: &Path) {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            warn!("Failed to remove {}: {e}", path.display())
+        }
+        _ => {}
+    }
+}
+
+/// Keeps the draft at its path, saving nothing without one.
+#[derive(Debug, Default)]
+pub struct Autosave {
+    path: Option<PathBuf>,
+    /// Draft as it is on disk
+    saved: String,
+    last: Option<Instant>,
+}
+
+impl Autosave {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            ..Self::default()
+        }
+    }
+
+    /// Forgets the saved draft.
+    pub fn discard(&mut self) {
+        if let Some(path) = &self.path {
+            discard(path);
+        }
+        self.saved.clear();
+    }
+
+    /// Saves `draft` if it changed, unless the last save was only just now.
+    pub fn tick(&mut self, draft: &str) {
+        if draft == self.saved || self.last.is_some_and(|t| t.elapsed() < SAVE_INTERVAL) {
+            return;
+        }
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        self.last = Some(Instant::now());
+        if draft.is_empty() {
+            self.discard();
+            return;
+        }
+        // a crash half way through writing leaves the previous draft
+        let partial = path.with_extension("partial");
+        let res = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&partial)?
+                    .write_all(draft.as_bytes())
+            })
+            .and_then(|()| fs::rename(&partial, &path));
+        match res {
+            Ok(()) => self.saved = draft.to_string(),
+            Err(e) => warn!("Failed to save the draft: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn conversations_keep_their_own_drafts() {
+        if let (Some(ada), Some(bob)) = (path("ada"), path("bob:4000")) {
+            assert!(ada.ends_with("drafts/ada"));
+            assert_eq!(ada.parent(), bob.parent());
+            assert_ne!(ada, bob);
+        }
+        let dir = std::env::temp_dir().join(format!("chatterbox-draft-{}", std::process::id()));
+        let (ada, bob) = (dir.join("ada"), dir.join("bob"));
+        let mut to_ada = Autosave::new(Some(ada.clone()));
+        let mut to_bob = Autosave::new(Some(bob.clone()));
+        to_ada.tick("dear ada");
+        to_bob.tick("hi bob");
+        assert_eq!(recover(&ada).as_deref(), Some("dear ada"));
+        assert_eq!(recover(&bob).as_deref(), Some("hi bob"));
+        let mode = fs::metadata(&ada).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        to_ada.discard();
+        assert_eq!(recover(&ada), None);
+        assert_eq!(recover(&bob).as_deref(), Some("hi bob"));
+        discard(&bob);
+        assert_eq!(recover(&bob), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saves_wait_for_the_interval() {
+        let dir = std::env::temp_dir().join(format!("chatterbox-autosave-{}", std::process::id()));
+        let path = dir.join("ada");
+        let mut autosave = Autosave::new(Some(path.clone()));
+        autosave.tick("one");
+        autosave.tick("one two");
+        assert_eq!(recover(&path).as_deref(), Some("one"));
+        autosave.last = None;
+        autosave.tick("");
+        assert!(!path.exists());
+        // nothing to save to without a path
+        let mut nowhere = Autosave::default();
+        nowhere.tick("lost");
+        assert_eq!(nowhere.saved, "");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+//! How the conversation is encrypted, shown behind a lock in the title of the messages, and
+//! which conversations were encrypted before, one per line in
+//! `$XDG_DATA_HOME/chatterbox/encrypted`.
+//!
+//! One of those connecting in plaintext, or the peer of a sealed conversation sending lines in
+//! the clear, is a downgrade: someone in the middle may have stripped the encryption, and the
+//! user is warned loudly.
+
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use tracing::{error, warn};
+
+/// Cipher of the key phrase.
+const SEAL_CIPHER: &str = "ChaCha20-Poly1305";
+
+/// Encryption of the current connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// Version and cipher of TLS
+    pub tls: Option<String>,
+    /// Whether lines are sealed with the key phrase
+    pub sealed: bool,
+}
+
+impl Status {
+    pub fn is_encrypted(&self) -> bool {
+        self.sealed || self.tls.is_some()
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.sealed, &self.tls) {
+            (true, Some(tls)) => write!(f, "{SEAL_CIPHER} over {tls}"),
+            (true, None) => f.write_str(SEAL_CIPHER),
+            (false, Some(tls)) => f.write_str(tls),
+            (false, None) => f.write_str("plaintext"),
+        }
+    }
+}
+
+/// Conversations which were encrypted.
+#[derive(Debug, Default)]
+pub struct Known {
+    /// Backing file, `None` keeps them in memory only
+    path: Option<PathBuf>,
+    conversations: BTreeSet<String>,
+}
+
+impl Known {
+    pub fn load() -> Self {
+        let Some(path) = crate::dirs::data_dir().map(|d| d.join("encrypted")) else {
+            warn!("Couldn't determine data directory, encrypted conversations won't be persisted");
+            return Self::default();
+        };
+        let conversations = match fs::read_to_string(&path) {
+            Ok(content) => content.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => {
+                error!("Failed to read {}: {e}", path.display());
+                BTreeSet::new()
+            }
+        };
+        Self {
+            path: Some(path),
+            conversations,
+        }
+    }
+
+    /// Notes how `conversation` is encrypted now. Returns whether it was encrypted before and
+    /// isn't anymore.
+    pub fn note(&mut self, conversation: &str, status: &Status) -> bool {
+        if !status.is_encrypted() {
+            return self.conversations.contains(conversation);
+        }
+        if self.conversations.insert(conversation.to_string()) {
+            if let Err(e) = self.append(conversation) {
+                error!("Failed to remember {conversation} as encrypted: {e}");
+            }
+        }
+        false
+    }
+
+    fn append(&self, conversation: &str) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{conversation}")
+    }
+}
@@ -0,0 +1,107 @@
+//! Commands the peer may run on this machine, for debugging together.
+//!
+//! Nothing runs unless it is listed under `[exec]` in the config, where the peer can only pick
+//! a command by its name, and the user accepts the request. Output is streamed back line by
+//! line.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead},
+    process::{Command, Stdio},
+    sync::mpsc,
+};
+
+use tracing::{debug, error};
+
+use crate::{config::Config, protocol};
+
+/// Config table holding the allowed commands.
+pub const TABLE: &str = "exec";
+/// Output beyond this is cut off and the command killed.
+const MAX_LINES: usize = 1000;
+
+#[derive(Debug, Default)]
+pub struct Exec {
+    allowed: BTreeMap<String, String>,
+    /// Name of the command the peer is waiting on an answer for
+    pub pending: Option<String>,
+}
+
+impl Exec {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            allowed: config.strings(TABLE).into_iter().collect(),
+            pending: None,
+        }
+    }
+
+    pub fn command(&self, name: &str) -> Option<&str> {
+        self.allowed.get(name).map(String::as_str)
+    }
+}
+
+/// Runs `cmd` in a shell, its output and then how it ended are sent to `lines` as wire lines.
+pub fn spawn(cmd: &str, lines: mpsc::Sender<String>) -> io::Result<()> {
+    debug!("running {cmd:?} for the peer");
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("exec 2>&1\n{cmd}"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    crate::tasks::spawn("exec", move || {
+        let mut count = 0;
+        for line in io::BufReader::new(stdout).lines().map_while(Result::ok) {
+            count += 1;
+            if count > MAX_LINES {
+                let _ = child.kill();
+                break;
+            }
+            if lines.send(protocol::exec_output(&line)).is_err() {
+                let _ = child.kill();
+                break;
+            }
+        }
+        let status = match child.wait() {
+            Ok(_) if count > MAX_LINES => format!("killed after {MAX_LINES} lines"),
+            Ok(status) => status.to_string(),
+            Err(e) => {
+                error!("Failed to wait for exec command: {e}");
+                e.to_string()
+            }
+        };
+        let _ = lines.send(protocol::exec_done(&status));
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Payload;
+
+    #[test]
+    fn only_configured_commands_are_known() {
+        let config: Config = "[exec]\nuptime = \"uptime -p\"\n".parse().unwrap();
+        let exec = Exec::from_config(&config);
+        assert_eq!(exec.command("uptime"), Some("uptime -p"));
+        assert_eq!(exec.command("uptime -p"), None);
+        assert_eq!(exec.command("rm"), None);
+    }
+
+    #[test]
+    fn output_and_status_are_sent_back() {
+        let (tx, rx) = mpsc::channel();
+        spawn("echo out; echo err >&2; exit 3", tx).unwrap();
+        let payloads: Vec<_> = rx.iter().map(|line| protocol::decode(&line)).collect();
+        assert_eq!(
+            payloads,
+            [
+                Payload::ExecOutput("out".to_string()),
+                Payload::ExecOutput("err".to_string()),
+                Payload::ExecDone("exit status: 3".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Standalone HTML rendering of a conversation history.
+
+use std::fmt::Write;
+
+use crate::{message::Kind, store::Record, timestamp::DateTime};
+
+const STYLE: &str = "\
+body { font-family: sans-serif; max-width: 50em; margin: 2em auto; color: #222; }
+h1 { font-size: 1.3em; }
+.msg { margin: 0.4em 0; }
+.time { color: #888; font-size: 0.8em; margin-right: 0.5em; }
+.author { font-weight: bold; margin-right: 0.5em; }
+.in .author { color: #1565c0; }
+.out .author { color: #2e7d32; }
+.sys { color: #888; font-style: italic; }
+.text { white-space: pre-wrap; }
+pre, code { background: #f3f3f3; font-family: monospace; }
+pre { padding: 0.5em; overflow-x: auto; white-space: pre; }
+";
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Text with `` `inline code` `` spans.
+fn inline(text: &str) -> String {
+    text.split('`')
+        .enumerate()
+        .map(|(i, part)| match i % 2 {
+            0 => escape(part),
+            _ => format!("<code>{}</code>", escape(part)),
+        })
+        .collect()
+}
+
+/// Message text with ```` ``` ```` fenced code blocks and inline code.
+fn render_text(text: &str) -> String {
+    text.split("```")
+        .enumerate()
+        .map(|(i, part)| match i % 2 {
+            0 => inline(part),
+            _ => {
+                // first line of a fence names the language
+                let code = match part.split_once('\n') {
+                    Some((lang, code)) if !lang.contains(' ') => code,
+                    _ => part,
+                };
+                format!("<pre><code>{}</code></pre>", escape(code))
+            }
+        })
+        .collect()
+}
+
+pub fn html(peer: &str, records: &[Record]) -> String {
+    let peer = escape(peer);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Chatterbox conversation with {peer}</title>\n<style>\n{STYLE}</style>\n</head>\n\
+         <body>\n<h1>Conversation with {peer}</h1>\n"
+    );
+    for record in records {
+        let (class, author) = match record.kind {
+            Kind::Incoming => ("in", peer.clone()),
+            Kind::Outgoing => ("out", "me".to_string()),
+            Kind::System => ("sys", String::new()),
+        };
+        let author = record.author.as_deref().map_or(author, escape);
+        let _ = writeln!(
+            out,
+            "<div class=\"msg {class}\"><span class=\"time\">{} UTC</span>\
+             <span class=\"author\">{author}</span><span class=\"text\">{}</span></div>",
+            DateTime::from_millis(record.at),
+            render_text(&record.text)
+        );
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_in_messages_is_escaped() {
+        assert_eq!(
+            render_text("<b>`a<b`</b>"),
+            "&lt;b&gt;<code>a&lt;b</code>&lt;/b&gt;"
+        );
+        assert_eq!(
+            render_text("see\n```rust\nfn main() {}\n```"),
+            "see\n<pre><code>fn main() {}\n</code></pre>"
+        );
+
+        let records = [Record {
+            at: 86_400_000,
+            kind: Kind::Incoming,
+            author: None,
+            text: "hi".to_string(),
+        }];
+        let page = html("<ada>", &records);
+        assert!(page.contains("<title>Chatterbox conversation with &lt;ada&gt;</title>"));
+        assert!(page.contains(
+            "<div class=\"msg in\"><span class=\"time\">1970-01-02 00:00:00 UTC</span>\
+             <span class=\"author\">&lt;ada&gt;</span><span class=\"text\">hi</span></div>"
+        ));
+    }
+}
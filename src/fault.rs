@@ -0,0 +1,93 @@
+//! What goes wrong with what the peer sends. The reader of a connection reports it over a
+//! channel, the interface shows it as system messages, a burst of the same fault as one.
+
+use std::io;
+
+/// Faulty lines in a row after which the peer is given up on and the connection closed.
+pub const HOPELESS: usize = 20;
+
+#[derive(Debug)]
+pub enum Fault {
+    /// A line which isn't UTF-8, dropped
+    InvalidUtf8,
+    /// A sealed message which can't be opened with our key, dropped
+    Undecryptable,
+    /// Reading failed, the connection is closed
+    Io(io::Error),
+    /// Too many faulty lines in a row, the connection was closed
+    Hopeless,
+}
+
+impl Fault {
+    /// Whether the connection can't go on after it.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Fault::Io(_) | Fault::Hopeless)
+    }
+
+    fn describe(&self, count: usize) -> String {
+        let dropped = match count {
+            1 => "a message dropped".to_string(),
+            n => format!("{n} messages dropped"),
+        };
+        match self {
+            Fault::InvalidUtf8 => format!("invalid UTF-8 from peer, {dropped}"),
+            Fault::Undecryptable => {
+                format!("can't decrypt, the peer may use another key phrase, {dropped}")
+            }
+            Fault::Io(e) => format!("failed to read from peer, {e}"),
+            Fault::Hopeless => {
+                format!("closed the connection after {HOPELESS} faulty lines in a row")
+            }
+        }
+    }
+}
+
+/// Lines to show for `faults`, one for every run of the same one.
+pub fn summarize(faults: impl IntoIterator<Item = Fault>) -> Vec<String> {
+    let mut runs: Vec<(Fault, usize)> = Vec::new();
+    for fault in faults {
+        match runs.last_mut() {
+            Some((last, count))
+                if std::mem::discriminant(last) == std::mem::discriminant(&fault)
+                    && !fault.is_fatal() =>
+            {
+                *count += 1
+            }
+            _ => runs.push((fault, 1)),
+        }
+    }
+    runs.iter()
+        .map(|(fault, count)| fault.describe(*count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_of_the_same_fault_are_one_line() {
+        let faults = [
+            Fault::InvalidUtf8,
+            Fault::InvalidUtf8,
+            Fault::InvalidUtf8,
+            Fault::Undecryptable,
+            Fault::InvalidUtf8,
+            Fault::Hopeless,
+            Fault::Hopeless,
+        ];
+        assert_eq!(
+            summarize(faults),
+            [
+                "invalid UTF-8 from peer, 3 messages dropped".to_string(),
+                "can't decrypt, the peer may use another key phrase, a message dropped".to_string(),
+                "invalid UTF-8 from peer, a message dropped".to_string(),
+                format!("closed the connection after {HOPELESS} faulty lines in a row"),
+                format!("closed the connection after {HOPELESS} faulty lines in a row"),
+            ]
+        );
+        assert!(Fault::Io(io::ErrorKind::UnexpectedEof.into()).is_fatal());
+        assert!(!Fault::Undecryptable.is_fatal());
+        assert!(summarize([]).is_empty());
+    }
+}
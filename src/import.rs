@@ -0,0 +1,376 @@
+//! Importers turning chat exports of other applications into history records.
+
+use crate::{
+    json,
+    message::Kind,
+    store::Record,
+    timestamp::{now_millis, DateTime},
+};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    /// "Export chat" text file of WhatsApp, Android or iOS flavour
+    Whatsapp,
+    /// irssi or weechat log file
+    IrcLog,
+    /// array of objects, or one object per line, with time, author and text
+    Json,
+}
+
+pub fn parse(format: Format, content: &str, me: &str) -> Result<Vec<Record>, String> {
+    let records = match format {
+        Format::Whatsapp => whatsapp(content, me),
+        Format::IrcLog => irc_log(content, me),
+        Format::Json => json_export(content, me)?,
+    };
+    if records.is_empty() {
+        return Err("no messages found, is it the right format?".to_string());
+    }
+    Ok(records)
+}
+
+fn record(at: u64, author: Option<&str>, me: &str, text: String) -> Record {
+    let (kind, author) = match author {
+        Some(author) if author.eq_ignore_ascii_case(me) => (Kind::Outgoing, None),
+        Some(author) => (Kind::Incoming, Some(author.to_string())),
+        None => (Kind::System, None),
+    };
+    Record {
+        at,
+        kind,
+        author,
+        text,
+    }
+}
+
+/// `9:41 PM`, `21:41` or `21:41:05`, returns hour, minute, second and whether it was 12h.
+fn parse_time(time: &str) -> Option<(u32, u32, u32, bool)> {
+    let time = time.replace('\u{202f}', " ");
+    let (time, meridiem) = match time.trim().rsplit_once(' ') {
+        Some((time, m)) if m.eq_ignore_ascii_case("am") => (time.to_string(), Some(0)),
+        Some((time, m)) if m.eq_ignore_ascii_case("pm") => (time.to_string(), Some(12)),
+        _ => (time.trim().to_string(), None),
+    };
+    let mut parts = time.splitn(3, ':').map(str::parse::<u32>);
+    let mut hour = parts.next()?.ok()?;
+    let minute = parts.next()?.ok()?;
+    let second = parts.next().transpose().ok()?.unwrap_or(0);
+    if let Some(offset) = meridiem {
+        hour = hour % 12 + offset;
+    }
+    (hour < 24 && minute < 60 && second < 60).then_some((hour, minute, second, meridiem.is_some()))
+}
+
+/// Header of a WhatsApp message line, returns the time and the rest of the line.
+fn whatsapp_header(line: &str) -> Option<(u64, &str)> {
+    let (stamp, rest) = match line.strip_prefix('[') {
+        // iOS: [31/12/2020, 21:41:05] Alice: hi
+        Some(line) => line.split_once("] ")?,
+        // Android: 12/31/20, 9:41 PM - Alice: hi
+        None => line.split_once(" - ")?,
+    };
+    let (date, time) = stamp.split_once(", ")?;
+    let (hour, minute, second, twelve_hour) = parse_time(time)?;
+    let mut date = date.splitn(3, ['/', '.', '-']).map(str::parse::<u32>);
+    let (a, b, year) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    // the order depends on the locale of the phone, a 12 hour clock hints at the US one
+    let (month, day) = if a > 12 || (b <= 12 && !twelve_hour) {
+        (b, a)
+    } else {
+        (a, b)
+    };
+    let year = if year < 100 { year + 2000 } else { year };
+    let datetime = DateTime {
+        year: i64::from(year),
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    };
+    ((1..=12).contains(&month) && (1..=31).contains(&day)).then(|| (datetime.to_millis(), rest))
+}
+
+fn whatsapp(content: &str, me: &str) -> Vec<Record> {
+    let mut records: Vec<Record> = Vec::new();
+    for line in content.lines() {
+        let line = line.trim_start_matches('\u{feff}');
+        match whatsapp_header(line) {
+            Some((at, rest)) => {
+                let (author, text) = match rest.split_once(": ") {
+                    Some((author, text)) => (Some(author), text),
+                    None => (None, rest),
+                };
+                records.push(record(at, author, me, text.to_string()));
+            }
+            // messages spanning multiple lines
+            None => match records.last_mut() {
+                Some(last) => {
+                    last.text.push('\n');
+                    last.text.push_str(line);
+                }
+                None => continue,
+            },
+        }
+    }
+    records
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Date out of irssi's `Tue Dec 31 2020` or `Tue Dec 31 21:41:05 2020`.
+fn irssi_date(s: &str) -> Option<(i64, u32, u32)> {
+    let words: Vec<_> = s.split_whitespace().collect();
+    let month = MONTHS.iter().position(|m| Some(m) == words.get(1))? as u32 + 1;
+    let day = words.get(2)?.parse().ok()?;
+    let year = words.last()?.parse().ok()?;
+    Some((year, month, day))
+}
+
+fn irc_log(content: &str, me: &str) -> Vec<Record> {
+    let today = DateTime::from_millis(now_millis());
+    let mut date = (today.year, today.month, today.day);
+    let mut records = Vec::new();
+    for line in content.lines() {
+        // irssi keeps track of the date with these
+        if let Some(rest) = line
+            .strip_prefix("--- Log opened ")
+            .or_else(|| line.strip_prefix("--- Day changed "))
+        {
+            date = irssi_date(rest).unwrap_or(date);
+            continue;
+        }
+        // weechat: 2020-12-31 21:41:05\tnick\ttext
+        let mut fields = line.splitn(3, '\t');
+        if let (Some(stamp), Some(nick), Some(text)) = (fields.next(), fields.next(), fields.next())
+        {
+            if let Some(datetime) = DateTime::parse_iso(stamp) {
+                let nick = nick.trim_start_matches(['@', '+']);
+                // weechat marks joins, parts and other events with arrows and dashes
+                let author =
+                    (!matches!(nick, "-->" | "<--" | "--" | "=!=" | " *" | "*")).then_some(nick);
+                records.push(record(datetime.to_millis(), author, me, text.to_string()));
+                continue;
+            }
+        }
+        // irssi: [21:41:05] <nick> text, the brackets are optional
+        let Some((time, rest)) = line.trim_start_matches('[').split_once([']', ' ']) else {
+            continue;
+        };
+        let Some((hour, minute, second, _)) = parse_time(time) else {
+            continue;
+        };
+        let at = DateTime {
+            year: date.0,
+            month: date.1,
+            day: date.2,
+            hour,
+            minute,
+            second,
+        }
+        .to_millis();
+        let rest = rest.trim_start();
+        if let Some((nick, text)) = rest.strip_prefix('<').and_then(|r| r.split_once("> ")) {
+            let nick = nick.trim_start_matches([' ', '@', '+']);
+            records.push(record(at, Some(nick), me, text.to_string()));
+        } else if let Some(action) = rest.strip_prefix("* ") {
+            let nick = action.split(' ').next().unwrap_or_default();
+            records.push(record(at, Some(nick), me, format!("* {action}")));
+        } else if let Some(event) = rest.strip_prefix("-!- ") {
+            records.push(record(at, None, me, event.to_string()));
+        }
+    }
+    records
+}
+
+fn json_record(value: &json::Value, me: &str) -> Option<Record> {
+    let field = |keys: &[&str]| keys.iter().find_map(|k| value.get(k));
+    let at = match field(&["timestamp", "time", "at"])? {
+        json::Value::Number(millis) => *millis as u64,
+        json::Value::String(s) => DateTime::parse_iso(s)?.to_millis(),
+        _ => return None,
+    };
+    let author = field(&["author", "from", "sender"]).and_then(json::Value::as_str);
+    let text = field(&["text", "message", "body"])?.as_str()?.to_string();
+    let mut record = record(at, author, me, text);
+    match field(&["kind"]).and_then(json::Value::as_str) {
+        Some("in" | "incoming") => record.kind = Kind::Incoming,
+        Some("out" | "outgoing") => record.kind = Kind::Outgoing,
+        Some("system") => record.kind = Kind::System,
+        _ => {}
+    }
+    Some(record)
+}
+
+fn json_export(content: &str, me: &str) -> Result<Vec<Record>, String> {
+    let values = match json::parse(content) {
+        Ok(json::Value::Array(values)) => values,
+        Ok(value) => vec![value],
+        // json lines
+        Err(_) => content
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .enumerate()
+            .map(|(i, l)| json::parse(l).map_err(|e| format!("line {}: {e}", i + 1)))
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(values.iter().filter_map(|v| json_record(v, me)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time, kind, author and text of each record.
+    fn summary(records: &[Record]) -> Vec<(String, Kind, Option<&str>, &str)> {
+        records
+            .iter()
+            .map(|r| {
+                let at = DateTime::from_millis(r.at).to_string();
+                (at, r.kind, r.author.as_deref(), r.text.as_str())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn whatsapp_exports_of_both_flavours() {
+        let ios = "\u{feff}[31/12/2020, 21:41:05] Messages are end-to-end encrypted.\n\
+                   [31/12/2020, 21:42:00] Alice: happy new year\n\
+                   and all the best\n\
+                   [01/01/2021, 00:00:10] Sam: you too";
+        let records = parse(Format::Whatsapp, ios, "sam").unwrap();
+        assert_eq!(
+            summary(&records),
+            [
+                (
+                    "2020-12-31 21:41:05".to_string(),
+                    Kind::System,
+                    None,
+                    "Messages are end-to-end encrypted."
+                ),
+                (
+                    "2020-12-31 21:42:00".to_string(),
+                    Kind::Incoming,
+                    Some("Alice"),
+                    "happy new year\nand all the best"
+                ),
+                (
+                    "2021-01-01 00:00:10".to_string(),
+                    Kind::Outgoing,
+                    None,
+                    "you too"
+                ),
+            ]
+        );
+
+        // a 12 hour clock puts the month first
+        let android = "1/2/21, 9:41\u{202f}PM - Alice: hi";
+        let records = parse(Format::Whatsapp, android, "sam").unwrap();
+        assert_eq!(summary(&records)[0].0, "2021-01-02 21:41:00");
+        assert!(parse(Format::Whatsapp, "just some text", "sam").is_err());
+    }
+
+    #[test]
+    fn irssi_and_weechat_logs() {
+        let irssi = "--- Log opened Thu Dec 31 21:00:00 2020\n\
+                     21:41 <@alice> hi sam\n\
+                     21:42 * alice waves\n\
+                     --- Day changed Fri Jan 01 2021\n\
+                     [00:00:05] -!- bob has joined #chat\n\
+                     [00:00:10] < sam> hi";
+        let records = parse(Format::IrcLog, irssi, "sam").unwrap();
+        assert_eq!(
+            summary(&records),
+            [
+                (
+                    "2020-12-31 21:41:00".to_string(),
+                    Kind::Incoming,
+                    Some("alice"),
+                    "hi sam"
+                ),
+                (
+                    "2020-12-31 21:42:00".to_string(),
+                    Kind::Incoming,
+                    Some("alice"),
+                    "* alice waves"
+                ),
+                (
+                    "2021-01-01 00:00:05".to_string(),
+                    Kind::System,
+                    None,
+                    "bob has joined #chat"
+                ),
+                (
+                    "2021-01-01 00:00:10".to_string(),
+                    Kind::Outgoing,
+                    None,
+                    "hi"
+                ),
+            ]
+        );
+
+        let weechat = "2020-12-31 21:41:05\t-->\talice has joined\n\
+                       2020-12-31 21:41:06\t+alice\thi\tthere";
+        let records = parse(Format::IrcLog, weechat, "sam").unwrap();
+        assert_eq!(
+            summary(&records),
+            [
+                (
+                    "2020-12-31 21:41:05".to_string(),
+                    Kind::System,
+                    None,
+                    "alice has joined"
+                ),
+                (
+                    "2020-12-31 21:41:06".to_string(),
+                    Kind::Incoming,
+                    Some("alice"),
+                    "hi\tthere"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_arrays_and_lines() {
+        let array = r#"[
+            {"time": "2020-12-31T21:41:05.123Z", "from": "alice", "message": "hi"},
+            {"timestamp": 1609451000000, "author": "SAM", "text": "hello"},
+            {"at": 1609451000000, "author": "alice", "body": "left", "kind": "system"},
+            {"text": "no time"}
+        ]"#;
+        let records = parse(Format::Json, array, "sam").unwrap();
+        assert_eq!(
+            summary(&records),
+            [
+                (
+                    "2020-12-31 21:41:05".to_string(),
+                    Kind::Incoming,
+                    Some("alice"),
+                    "hi"
+                ),
+                (
+                    "2020-12-31 21:43:20".to_string(),
+                    Kind::Outgoing,
+                    None,
+                    "hello"
+                ),
+                (
+                    "2020-12-31 21:43:20".to_string(),
+                    Kind::System,
+                    Some("alice"),
+                    "left"
+                ),
+            ]
+        );
+
+        let lines = "{\"at\": 0, \"text\": \"a\"}\n\n{\"at\": 0, \"text\": \"b\"}\n";
+        assert_eq!(parse(Format::Json, lines, "sam").unwrap().len(), 2);
+        let broken = "{\"at\": 0, \"text\": \"a\"}\n{\"at\": 0,";
+        assert!(parse(Format::Json, broken, "sam")
+            .unwrap_err()
+            .starts_with("line 2: "));
+    }
+}
@@ -0,0 +1,339 @@
+//! IRC bridge, the reference implementation of [`Bridge`].
+//!
+//! Plain text connection to a single channel, just the commands needed to take part in it.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::bridge::{Bridge, Event};
+
+/// How long `receive` waits for a line.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long the server has to accept us.
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Irc {
+    host: String,
+    port: u16,
+    nick: String,
+    /// Channel with its leading `#`
+    channel: String,
+    reader: Option<BufReader<TcpStream>>,
+    writer: Option<TcpStream>,
+    /// Partial line left by a timed out read
+    buf: String,
+    members: BTreeSet<String>,
+}
+
+/// A line sent by the server, `:prefix COMMAND params :trailing`.
+struct Line<'a> {
+    /// Nick of the sender, if any
+    from: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+fn parse(line: &str) -> Line<'_> {
+    let (from, rest) = match line.strip_prefix(':') {
+        Some(rest) => {
+            let (prefix, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            (Some(prefix.split('!').next().unwrap_or(prefix)), rest)
+        }
+        None => (None, line),
+    };
+    let (middle, trailing) = match rest.split_once(" :") {
+        Some((middle, trailing)) => (middle, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = middle.split(' ').filter(|w| !w.is_empty());
+    let command = words.next().unwrap_or("");
+    let mut params: Vec<&str> = words.collect();
+    params.extend(trailing);
+    Line {
+        from,
+        command,
+        params,
+    }
+}
+
+impl Irc {
+    /// From the part of `irc://nick@host:port/channel` after the scheme.
+    pub fn from_url(url: &str) -> Result<Self, String> {
+        const USAGE: &str = "expected irc://nick@host[:port]/channel";
+        let (nick, rest) = url.split_once('@').ok_or(USAGE)?;
+        let (server, channel) = rest.split_once('/').ok_or(USAGE)?;
+        let (host, port) = match server.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| USAGE)?),
+            None => (server, 6667),
+        };
+        if nick.is_empty() || host.is_empty() || channel.is_empty() {
+            return Err(USAGE.to_string());
+        }
+        let channel = channel.trim_start_matches('#');
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            nick: nick.to_string(),
+            channel: format!("#{channel}"),
+            reader: None,
+            writer: None,
+            buf: String::new(),
+            members: BTreeSet::new(),
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not connected"))?;
+        debug!("irc > {line}");
+        writer.write_all(format!("{line}\r\n").as_bytes())
+    }
+
+    /// Next complete line from the server, `None` if there is none yet.
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let reader = self
+            .reader
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not connected"))?;
+        match reader.read_line(&mut self.buf) {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {
+                let line = self.buf.trim_end_matches(['\r', '\n']).to_string();
+                self.buf.clear();
+                debug!("irc < {line}");
+                Ok(Some(line))
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Takes care of the line, returns what the user should know about it.
+    fn handle(&mut self, line: &str) -> io::Result<Option<Event>> {
+        let line = parse(line);
+        let from = line.from.unwrap_or("").to_string();
+        let in_channel = line.params.first() == Some(&self.channel.as_str());
+        Ok(match (line.command, line.params.as_slice()) {
+            ("PING", params) => {
+                self.write_line(&format!("PONG :{}", params.first().unwrap_or(&"")))?;
+                None
+            }
+            ("PRIVMSG", [_, text]) if in_channel => {
+                // CTCP ACTION, sent by /me
+                let text = match text.strip_prefix("\u{1}ACTION ") {
+                    Some(action) => format!("* {}", action.trim_end_matches('\u{1}')),
+                    None => text.to_string(),
+                };
+                Some(Event::Message { from, text })
+            }
+            ("JOIN", _) if from == self.nick => None,
+            ("JOIN", _) => {
+                self.members.insert(from.clone());
+                Some(Event::Joined(from))
+            }
+            ("PART", _) if in_channel => {
+                self.members.remove(&from);
+                Some(Event::Left(from))
+            }
+            ("QUIT", _) if self.members.remove(&from) => Some(Event::Left(from)),
+            ("NICK", [nick]) if self.members.remove(&from) => {
+                self.members.insert(nick.to_string());
+                None
+            }
+            // reply to NAMES, sent after joining
+            ("353", [.., channel, names]) if *channel == self.channel => {
+                self.members.extend(
+                    names
+                        .split(' ')
+                        .map(|n| n.trim_start_matches(['@', '+', '%', '&', '~']))
+                        .filter(|n| !n.is_empty() && *n != self.nick)
+                        .map(str::to_string),
+                );
+                None
+            }
+            _ => None,
+        })
+    }
+}
+
+impl Bridge for Irc {
+    fn name(&self) -> &str {
+        "irc"
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        self.writer = Some(stream.try_clone()?);
+        self.reader = Some(BufReader::new(stream));
+        self.buf.clear();
+        self.members.clear();
+        self.write_line(&format!("NICK {}", self.nick))?;
+        self.write_line(&format!("USER {} 0 * :chatterbox", self.nick))?;
+        let started = Instant::now();
+        // the channel can only be joined once the server welcomed us
+        loop {
+            if started.elapsed() > REGISTRATION_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server didn't accept the nick",
+                ));
+            }
+            let Some(line) = self.read_line()? else {
+                continue;
+            };
+            let parsed = parse(&line);
+            match parsed.command {
+                "001" => break,
+                // nickname in use or invalid
+                "432" | "433" => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("nick {} is not available", self.nick),
+                    ))
+                }
+                _ => {
+                    self.handle(&line)?;
+                }
+            }
+        }
+        self.write_line(&format!("JOIN {}", self.channel))
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Event>> {
+        match self.read_line()? {
+            Some(line) => self.handle(&line),
+            None => Ok(None),
+        }
+    }
+
+    fn send(&mut self, text: &str) -> io::Result<()> {
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            self.write_line(&format!("PRIVMSG {} :{line}", self.channel))?;
+        }
+        Ok(())
+    }
+
+    fn roster(&self) -> Vec<String> {
+        self.members.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn urls_name_the_nick_server_and_channel() {
+        let irc = Irc::from_url("sam@irc.example.org/#chat").unwrap();
+        assert_eq!(
+            (
+                irc.nick.as_str(),
+                irc.host.as_str(),
+                irc.port,
+                irc.channel.as_str()
+            ),
+            ("sam", "irc.example.org", 6667, "#chat")
+        );
+        let irc = Irc::from_url("sam@[::1]:6697/chat").unwrap();
+        assert_eq!((irc.host.as_str(), irc.port), ("[::1]", 6697));
+        for url in [
+            "irc.example.org/chat",
+            "sam@irc.example.org",
+            "@host/chat",
+            "sam@host:x/c",
+        ] {
+            assert!(Irc::from_url(url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn lines_have_a_sender_command_and_parameters() {
+        let line = parse(":ada!ada@host PRIVMSG #chat :hi: there");
+        assert_eq!(line.from, Some("ada"));
+        assert_eq!(
+            (line.command, line.params),
+            ("PRIVMSG", vec!["#chat", "hi: there"])
+        );
+        let line = parse("PING :irc.example.org");
+        assert_eq!(line.from, None);
+        assert_eq!(
+            (line.command, line.params),
+            ("PING", vec!["irc.example.org"])
+        );
+    }
+
+    #[test]
+    fn the_channel_is_joined_and_followed() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+            let mut stream = stream;
+            let mut next = || lines.next().unwrap().unwrap();
+            let registration = [next(), next()];
+            stream
+                .write_all(
+                    b":srv NOTICE * :hello\r\n:srv 001 sam :welcome\r\n\
+                      PING :srv\r\n\
+                      :srv 353 sam = #chat :sam @ada +bob\r\n\
+                      :ada!a@h PRIVMSG #chat :hi sam\r\n\
+                      :bob!b@h PRIVMSG #chat :\x01ACTION waves\x01\r\n\
+                      :ada!a@h PRIVMSG sam :psst\r\n\
+                      :cleo!c@h JOIN #chat\r\n\
+                      :bob!b@h PART #chat :bye\r\n",
+                )
+                .unwrap();
+            let rest = [next(), next(), next()];
+            (registration, rest)
+        });
+
+        let mut irc = Irc::from_url(&format!("sam@127.0.0.1:{port}/chat")).unwrap();
+        irc.connect().unwrap();
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while events.len() < 4 && Instant::now() < deadline {
+            events.extend(irc.receive().unwrap());
+        }
+        assert_eq!(
+            events,
+            [
+                Event::Message {
+                    from: "ada".to_string(),
+                    text: "hi sam".to_string(),
+                },
+                Event::Message {
+                    from: "bob".to_string(),
+                    text: "* waves".to_string(),
+                },
+                Event::Joined("cleo".to_string()),
+                Event::Left("bob".to_string()),
+            ]
+        );
+        assert_eq!(irc.roster(), ["ada", "cleo"]);
+        irc.send("two\n\nlines").unwrap();
+
+        let (registration, rest) = server.join().unwrap();
+        assert_eq!(registration, ["NICK sam", "USER sam 0 * :chatterbox"]);
+        assert_eq!(rest, ["JOIN #chat", "PONG :srv", "PRIVMSG #chat :two"]);
+    }
+}
@@ -0,0 +1,306 @@
+//! Just enough JSON to read the interchange formats and write our own, objects keep their key
+//! order.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// Compact JSON, on a single line.
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Number(n) if n.is_finite() => write!(f, "{n}"),
+            Value::Number(_) => f.write_str("null"),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(entries) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("{what} at byte {}", self.pos))
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, lit: &str) -> bool {
+        if self.src[self.pos..].starts_with(lit) {
+            self.pos += lit.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_ws();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Value::String),
+            Some('t') if self.eat("true") => Ok(Value::Bool(true)),
+            Some('f') if self.eat("false") => Ok(Value::Bool(false)),
+            Some('n') if self.eat("null") => Ok(Value::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(_) => self.error("unexpected character"),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let len = self.src[self.pos..]
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(self.src.len() - self.pos);
+        match self.src[self.pos..self.pos + len].parse() {
+            Ok(n) => {
+                self.pos += len;
+                Ok(Value::Number(n))
+            }
+            Err(_) => self.error("invalid number"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut out = String::new();
+        let mut chars = self.src[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let mut code = 0u32;
+                        for _ in 0..4 {
+                            let digit = chars.next().and_then(|(_, c)| c.to_digit(16));
+                            code = code * 16 + digit.ok_or("invalid unicode escape")?;
+                        }
+                        // surrogate pairs are rare enough in chat exports to not bother
+                        out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    Some(c) => out.push(c),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        self.error("unterminated string")
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_ws();
+        if self.eat("]") {
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_ws();
+            if self.eat("]") {
+                return Ok(Value::Array(items));
+            }
+            if !self.eat(",") {
+                return self.error("expected , or ]");
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.pos += 1;
+        let mut entries = Vec::new();
+        self.skip_ws();
+        if self.eat("}") {
+            return Ok(Value::Object(entries));
+        }
+        loop {
+            self.skip_ws();
+            if self.peek() != Some('"') {
+                return self.error("expected key");
+            }
+            let key = self.string()?;
+            self.skip_ws();
+            if !self.eat(":") {
+                return self.error("expected :");
+            }
+            entries.push((key, self.value()?));
+            self.skip_ws();
+            if self.eat("}") {
+                return Ok(Value::Object(entries));
+            }
+            if !self.eat(",") {
+                return self.error("expected , or }");
+            }
+        }
+    }
+}
+
+pub fn parse(src: &str) -> Result<Value, String> {
+    let mut parser = Parser { src, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_ws();
+    if parser.pos != src.len() {
+        return parser.error("trailing characters");
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_nest_and_keep_their_key_order() {
+        let value = parse(r#" {"b": [1, -2.5e1, true, null], "a": {"s": "q\"\\\né"}} "#).unwrap();
+        assert_eq!(
+            value,
+            Value::Object(vec![
+                (
+                    "b".to_string(),
+                    Value::Array(vec![
+                        Value::Number(1.0),
+                        Value::Number(-25.0),
+                        Value::Bool(true),
+                        Value::Null,
+                    ])
+                ),
+                (
+                    "a".to_string(),
+                    Value::Object(vec![(
+                        "s".to_string(),
+                        Value::String("q\"\\\né".to_string())
+                    )])
+                ),
+            ])
+        );
+        assert_eq!(
+            value
+                .get("a")
+                .and_then(|a| a.get("s"))
+                .and_then(Value::as_str),
+            Some("q\"\\\né")
+        );
+        assert_eq!(value.get("c"), None);
+    }
+
+    #[test]
+    fn errors_tell_where() {
+        assert_eq!(parse("[1, 2"), Err("expected , or ] at byte 5".to_string()));
+        assert_eq!(
+            parse("[1, "),
+            Err("unexpected end of input at byte 4".to_string())
+        );
+        assert_eq!(parse("{1: 2}"), Err("expected key at byte 1".to_string()));
+        assert_eq!(
+            parse("\"open"),
+            Err("unterminated string at byte 1".to_string())
+        );
+        assert_eq!(
+            parse("[] []"),
+            Err("trailing characters at byte 3".to_string())
+        );
+        assert!(parse("--1").is_err());
+    }
+
+    #[test]
+    fn values_are_written_on_a_single_line() {
+        let value = Value::Object(vec![
+            (
+                "text".to_string(),
+                Value::String("a \"b\"\\\n\t\u{7}".to_string()),
+            ),
+            (
+                "n".to_string(),
+                Value::Array(vec![Value::Number(1.5), Value::Number(f64::NAN)]),
+            ),
+            ("ok".to_string(), Value::Bool(false)),
+            ("none".to_string(), Value::Null),
+        ]);
+        let line = value.to_string();
+        assert_eq!(
+            line,
+            r#"{"text":"a \"b\"\\\n\t\u0007","n":[1.5,null],"ok":false,"none":null}"#
+        );
+        assert_eq!(parse(&line).unwrap().get("text"), value.get("text"));
+    }
+}
@@ -0,0 +1,358 @@
+//! Keys of the normal mode, like in vim, rebound in the `[keys]` table of the config.
+//!
+//! Every entry binds a key, or a sequence of them, to an action:
+//!
+//! ```toml
+//! [keys]
+//! J = "next"
+//! K = "previous"
+//! "ctrl-n" = "search-next"
+//! gg = "none"
+//! ```
+//!
+//! Keys are characters or one of `space`, `up`, `down`, `left`, `right`, `esc`, `enter`, `tab`,
+//! `backspace`, `home` and `end`, with `ctrl-` in front when held with Ctrl. A sequence is
+//! written without anything between the characters, or with spaces between the names. `none`
+//! takes the binding away.
+//!
+//! `ZZ` quits and `q` records a macro, `q = "quit"` brings back the old binding.
+
+use std::collections::HashMap;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use tracing::warn;
+
+use crate::config::Config;
+
+/// Config table holding the bindings.
+pub const TABLE: &str = "keys";
+
+const NAMED: [(&str, KeyCode); 11] = [
+    ("space", KeyCode::Char(' ')),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("esc", KeyCode::Esc),
+    ("enter", KeyCode::Enter),
+    ("tab", KeyCode::Tab),
+    ("backspace", KeyCode::Backspace),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Starts editing where the cursor was left
+    Insert,
+    /// Starts editing at the end of the input
+    Append,
+    /// Starts editing on a new line below the input
+    OpenLine,
+    Quit,
+    /// Starts recording a macro into the register typed next, or stops recording
+    Record,
+    /// Replays the macro of the register typed next
+    Replay,
+    Next,
+    Previous,
+    First,
+    Last,
+    Unselect,
+    Search,
+    /// Selects the next match of the last search, further up
+    SearchNext,
+    /// Selects the previous match of the last search, back down
+    SearchPrevious,
+    ToggleSystem,
+    ToggleTimestamps,
+    ToggleRaw,
+    ToggleDiff,
+    Contact,
+    ClosePane,
+    ScrollLeft,
+    ScrollRight,
+    /// Talks in the next room joined on a `--multi` server
+    NextRoom,
+    /// Shows or hides the counters for diagnosing a slow interface
+    ToggleHud,
+    /// Shows or hides the sidebar listing the clients of a `--multi` server
+    ToggleUsers,
+    /// Copies the selected message to the system clipboard
+    Yank,
+    /// Quotes the selected message in the input box
+    Quote,
+    /// Shows everything known about the selected message
+    Details,
+}
+
+const ACTIONS: [(&str, Action); 28] = [
+    ("insert", Action::Insert),
+    ("append", Action::Append),
+    ("open-line", Action::OpenLine),
+    ("quit", Action::Quit),
+    ("record", Action::Record),
+    ("replay", Action::Replay),
+    ("next", Action::Next),
+    ("previous", Action::Previous),
+    ("first", Action::First),
+    ("last", Action::Last),
+    ("unselect", Action::Unselect),
+    ("search", Action::Search),
+    ("search-next", Action::SearchNext),
+    ("search-previous", Action::SearchPrevious),
+    ("toggle-system", Action::ToggleSystem),
+    ("toggle-timestamps", Action::ToggleTimestamps),
+    ("toggle-raw", Action::ToggleRaw),
+    ("toggle-diff", Action::ToggleDiff),
+    ("contact", Action::Contact),
+    ("close-pane", Action::ClosePane),
+    ("scroll-left", Action::ScrollLeft),
+    ("scroll-right", Action::ScrollRight),
+    ("next-room", Action::NextRoom),
+    ("toggle-hud", Action::ToggleHud),
+    ("toggle-users", Action::ToggleUsers),
+    ("yank", Action::Yank),
+    ("quote", Action::Quote),
+    ("details", Action::Details),
+];
+
+const DEFAULTS: [(&str, Action); 31] = [
+    ("i", Action::Insert),
+    ("a", Action::Append),
+    ("o", Action::OpenLine),
+    ("ZZ", Action::Quit),
+    ("q", Action::Record),
+    ("@", Action::Replay),
+    ("j", Action::Next),
+    ("down", Action::Next),
+    ("k", Action::Previous),
+    ("up", Action::Previous),
+    ("gg", Action::First),
+    ("G", Action::Last),
+    ("esc", Action::Unselect),
+    ("/", Action::Search),
+    ("ctrl-f", Action::Search),
+    ("n", Action::SearchNext),
+    ("N", Action::SearchPrevious),
+    ("s", Action::ToggleSystem),
+    ("t", Action::ToggleTimestamps),
+    ("r", Action::ToggleRaw),
+    ("d", Action::ToggleDiff),
+    ("c", Action::Contact),
+    ("p", Action::ClosePane),
+    ("left", Action::ScrollLeft),
+    ("right", Action::ScrollRight),
+    ("tab", Action::NextRoom),
+    ("H", Action::ToggleHud),
+    ("u", Action::ToggleUsers),
+    ("y", Action::Yank),
+    (">", Action::Quote),
+    ("enter", Action::Details),
+];
+
+/// Name of a single key as written in the config.
+pub(crate) fn name(key: &KeyEvent) -> Option<String> {
+    let base = match NAMED.iter().find(|(_, c)| *c == key.code) {
+        Some((name, _)) => name.to_string(),
+        None => match key.code {
+            KeyCode::Char(c) => c.to_string(),
+            _ => return None,
+        },
+    };
+    match key.modifiers.contains(KeyModifiers::CONTROL) {
+        true => Some(format!("ctrl-{base}")),
+        false => Some(base),
+    }
+}
+
+/// Key named as in the config, the opposite of [`name`].
+pub(crate) fn key(name: &str) -> Option<KeyEvent> {
+    let (base, modifiers) = match name.strip_prefix("ctrl-") {
+        Some(base) => (base, KeyModifiers::CONTROL),
+        None => (name, KeyModifiers::NONE),
+    };
+    let code = match NAMED.iter().find(|(n, _)| *n == base) {
+        Some((_, code)) => *code,
+        None => {
+            let mut chars = base.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c),
+                _ => return None,
+            }
+        }
+    };
+    Some(KeyEvent::new(code, modifiers))
+}
+
+/// Keys of a binding as written in the config, `None` if one of them isn't a key.
+pub(crate) fn parse_sequence(keys: &str) -> Option<Vec<String>> {
+    let mut sequence = Vec::new();
+    for word in keys.split_whitespace() {
+        let key = word.strip_prefix("ctrl-").unwrap_or(word);
+        let is_named = NAMED.iter().any(|(name, _)| *name == key);
+        if is_named || key.chars().count() == 1 {
+            sequence.push(word.to_string());
+        } else if word.starts_with("ctrl-") {
+            return None;
+        } else {
+            sequence.extend(word.chars().map(String::from));
+        }
+    }
+    (!sequence.is_empty()).then_some(sequence)
+}
+
+#[derive(Debug, Clone)]
+pub struct Bindings {
+    table: HashMap<Vec<String>, Action>,
+    /// Keys pressed so far of a longer sequence
+    pending: Vec<String>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let table = DEFAULTS
+            .iter()
+            .map(|(keys, action)| (parse_sequence(keys).expect("defaults are keys"), *action))
+            .collect();
+        Self {
+            table,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Bindings {
+    /// The defaults, changed by what the config says.
+    pub fn from_config(config: &Config) -> Self {
+        let mut bindings = Self::default();
+        for (keys, action) in config.strings(TABLE) {
+            let Some(sequence) = parse_sequence(&keys) else {
+                warn!("Ignoring keys.{keys}, not a key");
+                continue;
+            };
+            if action == "none" {
+                bindings.table.remove(&sequence);
+                continue;
+            }
+            match ACTIONS.iter().find(|(name, _)| *name == action) {
+                Some((_, action)) => {
+                    bindings.table.insert(sequence, *action);
+                }
+                None => warn!("Ignoring keys.{keys}, there is no action {action:?}"),
+            }
+        }
+        bindings
+    }
+
+    /// Action of the sequence `key` completes, if any. Keys which may go on to become one are
+    /// kept until the next.
+    pub fn press(&mut self, key: &KeyEvent) -> Option<Action> {
+        let Some(name) = name(key) else {
+            self.pending.clear();
+            return None;
+        };
+        self.pending.push(name);
+        loop {
+            if let Some(&action) = self.table.get(&self.pending) {
+                self.pending.clear();
+                return Some(action);
+            }
+            if self
+                .table
+                .keys()
+                .any(|keys| keys.starts_with(&self.pending))
+            {
+                return None;
+            }
+            // what was pending goes nowhere, the last key may start something else
+            match self.pending.len() {
+                1 => {
+                    self.pending.clear();
+                    return None;
+                }
+                len => {
+                    self.pending.drain(..len - 1);
+                }
+            }
+        }
+    }
+
+    /// Keys pressed of a sequence not complete yet.
+    pub fn pending(&self) -> String {
+        self.pending.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(bindings: &mut Bindings, keys: &str) -> Vec<Option<Action>> {
+        parse_sequence(keys)
+            .unwrap()
+            .iter()
+            .map(|name| bindings.press(&key(name).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn sequences_are_parsed() {
+        assert_eq!(parse_sequence("gg").unwrap(), ["g", "g"]);
+        assert_eq!(
+            parse_sequence("ctrl-x ctrl-s").unwrap(),
+            ["ctrl-x", "ctrl-s"]
+        );
+        assert_eq!(parse_sequence("g space").unwrap(), ["g", "space"]);
+        assert_eq!(parse_sequence("space").unwrap(), ["space"]);
+        assert_eq!(parse_sequence("ctrl-gg"), None);
+        assert_eq!(parse_sequence(" "), None);
+        assert_eq!(name(&key("ctrl-up").unwrap()).unwrap(), "ctrl-up");
+        assert_eq!(key("up up"), None);
+    }
+
+    #[test]
+    fn sequences_wait_for_their_last_key() {
+        let mut bindings = Bindings::default();
+        assert_eq!(press(&mut bindings, "G"), [Some(Action::Last)]);
+        assert_eq!(bindings.press(&key("g").unwrap()), None);
+        assert_eq!(bindings.pending(), "g");
+        assert_eq!(bindings.press(&key("g").unwrap()), Some(Action::First));
+        assert_eq!(bindings.pending(), "");
+        assert_eq!(press(&mut bindings, "ZZ"), [None, Some(Action::Quit)]);
+        assert_eq!(press(&mut bindings, "q"), [Some(Action::Record)]);
+    }
+
+    #[test]
+    fn aborted_sequences_leave_the_last_key() {
+        let mut bindings = Bindings::default();
+        // j doesn't go on from g but is bound on its own
+        assert_eq!(press(&mut bindings, "gj"), [None, Some(Action::Next)]);
+        assert_eq!(press(&mut bindings, "gZ"), [None, None]);
+        assert_eq!(bindings.pending(), "Z");
+        assert_eq!(press(&mut bindings, "Z"), [Some(Action::Quit)]);
+        assert_eq!(press(&mut bindings, "gx"), [None, None]);
+        assert_eq!(bindings.pending(), "");
+        // a key without a name drops what is pending
+        bindings.press(&key("g").unwrap());
+        bindings.press(&KeyEvent::new(KeyCode::F(1), KeyModifiers::NONE));
+        assert_eq!(bindings.pending(), "");
+    }
+
+    #[test]
+    fn the_config_changes_the_defaults() {
+        let config: Config = "[keys]\nq = \"quit\"\ngg = \"none\"\nJ = \"next\"\n\"ctrl-x ctrl-n\" = \"search-next\"\nx = \"fly\"\n\"ctrl-zz\" = \"quit\"\n"
+            .parse()
+            .unwrap();
+        let mut bindings = Bindings::from_config(&config);
+        assert_eq!(press(&mut bindings, "q"), [Some(Action::Quit)]);
+        assert_eq!(press(&mut bindings, "gg"), [None, None]);
+        assert_eq!(press(&mut bindings, "J"), [Some(Action::Next)]);
+        assert_eq!(press(&mut bindings, "j"), [Some(Action::Next)]);
+        assert_eq!(
+            press(&mut bindings, "ctrl-x ctrl-n"),
+            [None, Some(Action::SearchNext)]
+        );
+        assert_eq!(press(&mut bindings, "x"), [None]);
+    }
+}
@@ -0,0 +1,79 @@
+//! Chat engine of chatterbox, to embed it in an interface of your own.
+//!
+//! [`App`] holds the state of a conversation and turns typed input into messages, a
+//! [`Connection`] keeps the link to the peer up and hands what happens on it to the app, and
+//! [`protocol`] is how messages look on the wire. The terminal interface draws the app with
+//! [`ui::draw`].
+
+pub mod access;
+pub mod acme;
+pub mod alerts;
+pub mod aliases;
+pub mod ansi;
+pub mod app;
+pub mod base64;
+pub mod beep;
+pub mod bidi;
+pub mod bridge;
+pub mod broadcast;
+pub mod cbor;
+pub mod clipboard;
+pub mod clock;
+pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod contacts;
+pub mod crash;
+pub mod deniable;
+pub mod detach;
+pub mod diag;
+pub mod diff;
+pub mod dirs;
+pub mod discovery;
+pub mod draft;
+pub mod encryption;
+pub mod exec;
+pub mod export;
+pub mod fault;
+pub mod import;
+pub mod irc;
+pub mod json;
+pub mod keys;
+pub mod live;
+pub mod location;
+pub mod macros;
+pub mod math;
+pub mod mdns;
+pub mod message;
+pub mod outbox;
+pub mod pane;
+pub mod protocol;
+pub mod proxy;
+pub mod relay;
+pub mod reminders;
+pub mod report;
+pub mod rng;
+pub mod sas;
+pub mod seal;
+pub mod secret;
+pub mod simulate;
+pub mod skew;
+pub mod snippets;
+pub mod socket;
+pub mod source;
+pub mod stateful_list;
+pub mod store;
+pub mod table;
+pub mod talk;
+pub mod tasks;
+pub mod theme;
+pub mod timestamp;
+pub mod tls;
+pub mod transfer;
+pub mod transport;
+pub mod triggers;
+pub mod ui;
+pub mod webhook;
+
+pub use app::App;
+pub use connection::Connection;
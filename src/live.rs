@@ -0,0 +1,133 @@
+//! Live typing, like talk(1): the draft in the input box is shown to the peer while it is typed.
+//!
+//! Both sides have to turn it on with `/live`, which tells the peer with a `LIVE on` line. Until
+//! the peer did the same, nothing of the draft leaves the input box.
+//!
+//! Without it the peer only learns that a message is being typed, from `TYPING` lines.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Typing stops being told this long after the last key.
+pub const TYPING_IDLE: Duration = Duration::from_secs(5);
+/// While typing goes on, the peer is told again this often.
+pub const TYPING_REFRESH: Duration = Duration::from_secs(3);
+/// The peer is taken to have stopped typing without hearing from it for this long, its `off`
+/// may have been lost with the connection.
+const TYPING_EXPIRY: Duration = Duration::from_secs(8);
+
+/// State shared with the reciever.
+#[derive(Debug, Default)]
+pub struct Live {
+    /// We opted in
+    enabled: AtomicBool,
+    /// The peer opted in
+    peer: AtomicBool,
+    /// Draft of the peer as it last sent it
+    preview: Mutex<String>,
+    /// When the peer last told it is typing, `None` once it stopped
+    peer_typing: Mutex<Option<Instant>>,
+}
+
+impl Live {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Release);
+        if !enabled {
+            self.clear_preview();
+        }
+    }
+
+    /// Whether drafts are exchanged, which takes both sides.
+    pub fn is_active(&self) -> bool {
+        self.is_enabled() && self.peer.load(Ordering::Acquire)
+    }
+
+    /// Records whether the peer opted in, returns whether it changed.
+    pub fn set_peer(&self, enabled: bool) -> bool {
+        if !enabled {
+            self.clear_preview();
+        }
+        self.peer.swap(enabled, Ordering::AcqRel) != enabled
+    }
+
+    pub fn preview(&self) -> String {
+        self.preview
+            .lock()
+            .expect("preview lock is poisoned")
+            .clone()
+    }
+
+    pub fn set_preview(&self, draft: String) {
+        *self.preview.lock().expect("preview lock is poisoned") = draft;
+    }
+
+    pub fn clear_preview(&self) {
+        self.preview
+            .lock()
+            .expect("preview lock is poisoned")
+            .clear();
+    }
+
+    pub fn set_peer_typing(&self, typing: bool) {
+        *self.peer_typing.lock().expect("typing lock is poisoned") = typing.then(Instant::now);
+    }
+
+    pub fn is_peer_typing(&self) -> bool {
+        self.peer_typing
+            .lock()
+            .expect("typing lock is poisoned")
+            .is_some_and(|at| at.elapsed() < TYPING_EXPIRY)
+    }
+
+    /// Forgets the peer typing once it hasn't been heard of for too long, returns whether it did.
+    pub fn expire_peer_typing(&self) -> bool {
+        let mut typing = self.peer_typing.lock().expect("typing lock is poisoned");
+        let expired = typing.is_some_and(|at| at.elapsed() >= TYPING_EXPIRY);
+        if expired {
+            *typing = None;
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drafts_are_only_shown_once_both_opted_in() {
+        let live = Live::default();
+        assert!(live.set_peer(true));
+        assert!(!live.set_peer(true));
+        assert!(!live.is_active());
+        live.set_enabled(true);
+        assert!(live.is_active());
+
+        live.set_preview("hel".to_string());
+        assert_eq!(live.preview(), "hel");
+        // a peer opting out takes its draft along
+        assert!(live.set_peer(false));
+        assert_eq!(live.preview(), "");
+        assert!(!live.is_active());
+    }
+
+    #[test]
+    fn the_peer_types_until_told_otherwise() {
+        let live = Live::default();
+        assert!(!live.is_peer_typing());
+        live.set_peer_typing(true);
+        assert!(live.is_peer_typing());
+        assert!(!live.expire_peer_typing());
+        live.set_peer_typing(false);
+        assert!(!live.is_peer_typing());
+    }
+}
@@ -0,0 +1,155 @@
+//! Sharing where you are, typed in or asked from a provider command set in the config.
+//!
+//! The provider is anything printing `<latitude> <longitude>` or `<latitude>,<longitude>`, like
+//! `termux-location` piped through jq or `curl -s ipinfo.io/loc`.
+
+use std::{process::Command, sync::mpsc};
+
+use tracing::debug;
+
+use crate::config::Config;
+
+/// Config table holding the provider.
+pub const TABLE: &str = "location";
+
+/// Land of an equirectangular world map, a character per 7.5° of longitude and 15° of latitude.
+const WORLD: [&str; 12] = [
+    "..............########...###........###.........",
+    "..####################..########################",
+    "......###########.....#####################.##..",
+    ".......#######........#####################.....",
+    "........######........##########.#######........",
+    "............######....#########...#..##.#.......",
+    ".............#######.....#####.......######.....",
+    "..............#####.......###.#........######...",
+    "..............###.........##...........#####..##",
+    "..............##................................",
+    "...............##...###########################.",
+    "################################################",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl std::str::FromStr for Point {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|p| !p.is_empty());
+        let (Some(lat), Some(lon), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("expected a latitude and a longitude".to_string());
+        };
+        let lat: f64 = lat.parse().map_err(|_| format!("invalid latitude {lat}"))?;
+        let lon: f64 = lon
+            .parse()
+            .map_err(|_| format!("invalid longitude {lon}"))?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err("latitude goes up to 90°, longitude up to 180°".to_string());
+        }
+        Ok(Self { lat, lon })
+    }
+}
+
+impl std::fmt::Display for Point {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.5}, {:.5}", self.lat, self.lon)
+    }
+}
+
+impl Point {
+    pub fn link(&self) -> String {
+        format!(
+            "https://www.openstreetmap.org/?mlat={:.5}&mlon={:.5}#map=15/{:.5}/{:.5}",
+            self.lat, self.lon, self.lat, self.lon
+        )
+    }
+
+    /// World map with the point marked as `@`.
+    pub fn map(&self) -> String {
+        let row = (((90.0 - self.lat) / 15.0) as usize).min(WORLD.len() - 1);
+        let column = (((self.lon + 180.0) / 7.5) as usize).min(WORLD[0].len() - 1);
+        let lines: Vec<String> = WORLD
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let mut line = line.to_string();
+                if i == row {
+                    line.replace_range(column..column + 1, "@");
+                }
+                line
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Text of the message showing the point.
+    pub fn describe(&self) -> String {
+        format!("location {self}\n{}\n{}", self.link(), self.map())
+    }
+}
+
+pub fn provider(config: &Config) -> Option<String> {
+    config
+        .strings(TABLE)
+        .into_iter()
+        .find(|(key, _)| key == "provider")
+        .map(|(_, cmd)| cmd)
+}
+
+/// Runs the provider in the background, hands over where it says we are.
+pub fn locate(cmd: String, tx: mpsc::Sender<Result<Point, String>>) {
+    crate::tasks::spawn("location", move || {
+        debug!("asking {cmd:?} for the location");
+        let result = match Command::new("sh").arg("-c").arg(&cmd).output() {
+            Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout)
+                .trim()
+                .parse()
+                .map_err(|e| format!("location provider: {e}")),
+            Ok(out) => Err(format!("location provider failed: {}", out.status)),
+            Err(e) => Err(format!("failed to run the location provider: {e}")),
+        };
+        let _ = tx.send(result);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_are_two_numbers_in_range() {
+        let paris = Point {
+            lat: 48.8566,
+            lon: 2.3522,
+        };
+        assert_eq!("48.8566 2.3522".parse(), Ok(paris));
+        assert_eq!(" 48.8566,2.3522\n".parse(), Ok(paris));
+        assert_eq!("48.8566, 2.3522".parse(), Ok(paris));
+        for bad in ["48.8566", "1 2 3", "north 2", "91 0", "0 -181"] {
+            assert!(bad.parse::<Point>().is_err(), "{bad}");
+        }
+        assert_eq!(paris.to_string(), "48.85660, 2.35220");
+        assert_eq!(
+            paris.link(),
+            "https://www.openstreetmap.org/?mlat=48.85660&mlon=2.35220#map=15/48.85660/2.35220"
+        );
+    }
+
+    #[test]
+    fn the_map_marks_the_point() {
+        let map = |lat, lon| Point { lat, lon }.map();
+        let corners = [(90.0, -180.0), (-90.0, 180.0)];
+        let [north_west, south_east] = corners.map(|(lat, lon)| map(lat, lon));
+        assert!(north_west.starts_with('@'));
+        assert!(south_east.ends_with('@'));
+        assert_eq!(north_west.lines().count(), WORLD.len());
+        // in the third row, a bit east of the middle
+        let paris = map(48.8566, 2.3522);
+        assert_eq!(paris.lines().nth(2).and_then(|l| l.find('@')), Some(24));
+    }
+}
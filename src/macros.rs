@@ -0,0 +1,180 @@
+//! Keys recorded with `q<register>` in the normal mode until the next `q`, replayed with
+//! `@<register>`, `@@` replaying the last one again. Registers are lowercase letters and digits,
+//! every macro is kept in the `[macros]` table of the config, its keys named like in
+//! [`keys`](crate::keys), words which aren't the name of a key typing their characters:
+//!
+//! ```toml
+//! [macros]
+//! g = "i hello space there enter esc"
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crossterm::event::KeyEvent;
+use tracing::warn;
+
+use crate::{config::Config, keys};
+
+/// Config table holding the macros.
+pub const TABLE: &str = "macros";
+/// Keys replayed at most in a row, so a macro replaying itself ends.
+const MAX_REPLAYED: usize = 10_000;
+
+/// What the register typed next is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Awaiting {
+    Record,
+    Replay,
+}
+
+#[derive(Debug, Default)]
+pub struct Macros {
+    saved: BTreeMap<char, Vec<KeyEvent>>,
+    /// Register and the keys pressed since recording started
+    recording: Option<(char, Vec<KeyEvent>)>,
+    /// Set after `q` or `@` until a register is typed
+    pub awaiting: Option<Awaiting>,
+    queue: VecDeque<KeyEvent>,
+    /// Keys taken off the queue since it was last empty
+    replayed: usize,
+    last: Option<char>,
+}
+
+pub fn is_register(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit()
+}
+
+/// Keys as written in the config.
+pub fn encode(keys: &[KeyEvent]) -> String {
+    let names: Vec<String> = keys.iter().filter_map(keys::name).collect();
+    names.join(" ")
+}
+
+/// Keys written in the config, `None` if one of them isn't a key.
+pub fn decode(text: &str) -> Option<Vec<KeyEvent>> {
+    keys::parse_sequence(text)?
+        .iter()
+        .map(|name| keys::key(name))
+        .collect()
+}
+
+impl Macros {
+    pub fn from_config(config: &Config) -> Self {
+        let mut macros = Self::default();
+        for (register, text) in config.strings(TABLE) {
+            let mut chars = register.chars();
+            let register = match (chars.next(), chars.next()) {
+                (Some(c), None) if is_register(c) => c,
+                _ => {
+                    warn!("Ignoring macros.{register}, registers are a letter or a digit");
+                    continue;
+                }
+            };
+            match decode(&text) {
+                Some(keys) => {
+                    macros.saved.insert(register, keys);
+                }
+                None => warn!("Ignoring macros.{register}, {text:?} aren't keys"),
+            }
+        }
+        macros
+    }
+
+    /// Register being recorded into.
+    pub fn recording(&self) -> Option<char> {
+        self.recording.as_ref().map(|(register, _)| *register)
+    }
+
+    pub fn start(&mut self, register: char) {
+        self.recording = Some((register, Vec::new()));
+    }
+
+    /// Keeps `key` if recording.
+    pub fn record(&mut self, key: KeyEvent) {
+        if let Some((_, keys)) = &mut self.recording {
+            keys.push(key);
+        }
+    }
+
+    /// Ends the recording, returns the register and what it holds now.
+    pub fn stop(&mut self) -> Option<(char, &[KeyEvent])> {
+        let (register, keys) = self.recording.take()?;
+        self.saved.insert(register, keys);
+        Some((register, &self.saved[&register]))
+    }
+
+    /// Queues the keys of `register` to be handled before the next ones typed, `@` being the
+    /// register replayed last. Returns the register, `None` if there is nothing in it.
+    pub fn replay(&mut self, register: char) -> Option<char> {
+        let register = match register {
+            '@' => self.last?,
+            register => register,
+        };
+        let keys = self.saved.get(&register).filter(|k| !k.is_empty())?;
+        // in place of the key which replayed it, before the rest of an outer macro
+        for key in keys.iter().rev() {
+            self.queue.push_front(*key);
+        }
+        self.last = Some(register);
+        Some(register)
+    }
+
+    /// Next key of the macros being replayed. Replaying stops with an error once it took too
+    /// many keys.
+    pub fn next_key(&mut self) -> Option<Result<KeyEvent, String>> {
+        let Some(key) = self.queue.pop_front() else {
+            self.replayed = 0;
+            return None;
+        };
+        self.replayed += 1;
+        if self.replayed > MAX_REPLAYED {
+            self.queue.clear();
+            return Some(Err(format!(
+                "stopped replaying after {MAX_REPLAYED} keys, a macro replays itself"
+            )));
+        }
+        Some(Ok(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyModifiers};
+
+    use super::*;
+
+    #[test]
+    fn recorded_keys_read_back_from_the_config() {
+        let keys = [
+            KeyEvent::new(KeyCode::Char('i'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char(' '), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        ];
+        let text = encode(&keys);
+        assert_eq!(text, "i space ctrl-w enter");
+        assert_eq!(decode(&text).unwrap(), keys);
+        assert_eq!(decode("hi esc").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn a_macro_replaying_itself_is_stopped() {
+        let mut macros = Macros::default();
+        macros.start('a');
+        macros.record(KeyEvent::new(KeyCode::Char('@'), KeyModifiers::NONE));
+        macros.record(KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE));
+        macros.stop();
+        assert_eq!(macros.replay('a'), Some('a'));
+        let mut replayed = 0;
+        while let Some(key) = macros.next_key() {
+            let Ok(key) = key else { break };
+            replayed += 1;
+            // what the normal mode does with it
+            if key.code == KeyCode::Char('a') {
+                macros.replay('a');
+            }
+        }
+        assert_eq!(replayed, MAX_REPLAYED);
+        assert!(macros.next_key().is_none());
+    }
+}
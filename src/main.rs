@@ -1,24 +1,45 @@
-use notify_rust::Notification;
 use std::{
     io::{self, BufRead},
-    sync::{atomic::Ordering, Arc, Mutex},
+    sync::{atomic::Ordering, mpsc, Arc, Mutex},
 };
 
 use clap::Parser;
-use tracing::{debug, error, instrument, warn};
+use tracing::{debug, instrument};
 
-static RESET: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
-static TERMINATE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
-static REDRAW: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
-static NOTIFY: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+use chatterbox::{
+    access, acme, ansi,
+    app::{self, InputMode, Quit, NOTIFY, REDRAW},
+    bridge, broadcast, cbor,
+    config::Config,
+    connection::{self, listen},
+    contacts::Contacts,
+    crash, detach, diag, discovery, draft, encryption, export, import, json,
+    keys::Action,
+    macros, mdns, message,
+    message::Message,
+    outbox::Outbox,
+    protocol, proxy, relay,
+    reminders::Reminders,
+    report::{self, Report},
+    seal, secret, simulate, socket, source,
+    store::{self, Store},
+    talk, tasks, timestamp, tls, ui, webhook, App, Connection,
+};
+
+/// Counts the bytes in use for the HUD.
+#[global_allocator]
+static ALLOCATOR: diag::CountingAlloc = diag::CountingAlloc;
 
 #[derive(Debug, Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Subcommand>,
     #[arg(
         short,
         long,
         help = "remote address",
-        required_unless_present("server")
+        required_unless_present_any(["server", "to", "simulate", "discover", "unix", "inetd"])
     )]
     address: Option<String>,
     #[arg(short, long, help = "remote port", default_value_t = 8989)]
@@ -29,59 +50,415 @@ struct Args {
         short,
         long,
         help = "run as server",
-        required_unless_present("address")
+        required_unless_present_any(["address", "to", "simulate", "discover", "unix", "inetd"])
     )]
     server: bool,
+    /// let any number of clients in, passing the lines of each on to all others
+    #[arg(long, requires = "server", conflicts_with = "deniable")]
+    multi: bool,
+    /// talk over the unix socket at this path instead of TCP, listening on it with --server
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "relay",
+            "multi",
+            "tls",
+            "discover",
+            "discoverable",
+            "simulate",
+            "bind_interface",
+            "source_address",
+        ]
+    )]
+    unix: Option<std::path::PathBuf>,
+    /// serve the connection inetd or systemd started us with, on stdin or fd 3, without an
+    /// interface until it ends. Triggers and auto replies do the talking
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "address",
+            "server",
+            "to",
+            "relay",
+            "multi",
+            "unix",
+            "tls",
+            "discover",
+            "discoverable",
+            "simulate",
+            "follow",
+            "no_tui",
+            "detach",
+        ]
+    )]
+    inetd: bool,
     #[arg(short, long, help = "sets the logging level", action=clap::ArgAction::Count)]
     verbose: u8,
     /// write the logs to given file
     #[arg(short, long)]
     output: Option<String>,
+    /// authenticate messages with a shared secret, without making them provable to others. It
+    /// is asked for, or read from --deniable-file or $CHATTERBOX_DENIABLE
+    #[arg(long, value_name = "SECRET", num_args = 0..=1, default_missing_value = "")]
+    deniable: Option<String>,
+    /// file holding the secret of --deniable
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["deniable", "multi", "relay"])]
+    deniable_file: Option<std::path::PathBuf>,
+    /// encrypt every message with a key derived from this passphrase, asked for without one
+    #[arg(long, value_name = "PHRASE", num_args = 0..=1, default_missing_value = "")]
+    key_phrase: Option<String>,
+    /// the address is a relay, which holds messages until the recipient connects
+    #[arg(long, requires = "name", conflicts_with_all = ["server", "deniable"])]
+    relay: bool,
+    /// your name, sent along with your messages and the one on the relay
+    #[arg(long)]
+    name: Option<String>,
+    /// name of the peer on the relay, or the alias of a contact to connect to. Without one the
+    /// relay puts you in its lobby
+    #[arg(long)]
+    to: Option<String>,
+    /// code to get into an invitation only relay room
+    #[arg(long, value_name = "CODE", requires = "relay")]
+    join_code: Option<String>,
+    /// make the relay room invitation only, when you are its op
+    #[arg(long, requires = "relay")]
+    private: bool,
+    /// keep the relay room out of its directory, when you are its op
+    #[arg(long, requires = "relay")]
+    unlisted: bool,
+    /// key the name is registered with on the relay, the one in relay-key of the data directory
+    /// of the device which connected first. It is asked for, or read from --relay-key-file or
+    /// $CHATTERBOX_RELAY_KEY, one is made up there without it
+    #[arg(
+        long,
+        value_name = "KEY",
+        num_args = 0..=1,
+        default_missing_value = "",
+        requires = "relay"
+    )]
+    relay_key: Option<String>,
+    /// file holding the key of --relay-key
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "relay",
+        conflicts_with = "relay_key"
+    )]
+    relay_key_file: Option<std::path::PathBuf>,
+    /// print incoming messages instead of running the interface, lines read from stdin are sent
+    /// or run as commands
+    #[arg(long)]
+    follow: bool,
+    /// like --follow, but quit once stdin ends and everything read from it is sent, for shell
+    /// pipelines
+    #[arg(long, conflicts_with = "follow")]
+    no_tui: bool,
+    /// carry the conversation over to another network, like irc://nick@host:6667/channel
+    #[arg(long, value_name = "URL")]
+    bridge: Option<String>,
+    #[command(flatten)]
+    webhook: webhook::Options,
+    #[command(flatten)]
+    source: source::Options,
+    /// reach the peer through this proxy, socks5://[user[:password]@]host:port, socks5h:// to
+    /// have the proxy resolve the name, or http:// to tunnel with CONNECT
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["server", "unix", "inetd", "simulate"]
+    )]
+    proxy: Option<proxy::Proxy>,
+    #[command(flatten)]
+    tls: tls::Options,
+    /// print JSON, an object per message with --follow and --no-tui, or per result of a
+    /// subcommand. Lines like {"text": ..., "data": ...} on their stdin send the text annotated
+    /// with the data
+    #[arg(long, global = true)]
+    json: bool,
+    /// show colors sent as ANSI escape codes in incoming messages
+    #[arg(long)]
+    ansi: bool,
+    /// offer the peer CBOR frames, a third smaller for files, used once it offers them too
+    #[arg(long)]
+    cbor: bool,
+    /// play the peer of a scripted conversation instead of connecting, for demos
+    #[arg(
+        long,
+        value_name = "SCRIPT",
+        conflicts_with_all = ["address", "server", "relay", "tls_options"]
+    )]
+    simulate: Option<std::path::PathBuf>,
+    /// keep no history of the conversation, and show none of the earlier ones
+    #[arg(long)]
+    no_history: bool,
+    /// strftime format of the time shown in front of the messages, t hides and shows it
+    #[arg(long, value_name = "FORMAT", default_value = app::DEFAULT_TIMESTAMP_FORMAT)]
+    timestamp_format: String,
+    /// answer /who of the others on the local network with your name and how to reach you
+    #[arg(long)]
+    discoverable: bool,
+    /// advertise the server over mDNS, or pick one advertised there instead of giving the address
+    #[arg(long, conflicts_with_all = ["relay", "simulate"])]
+    discover: bool,
+    /// how the conversation is shown
+    #[arg(long, value_enum, default_value_t)]
+    mode: talk::Mode,
+    /// keep running in the background, Ctrl-\ detaches and `chatterbox attach` gets back
+    #[arg(long, conflicts_with_all = ["follow", "no_tui"])]
+    detach: bool,
+    /// name of the detached session
+    #[arg(long, default_value = "default", requires = "detach")]
+    session: String,
 }
 
-#[instrument()]
-fn notify(msg: &str) {
-    if NOTIFY.load(Ordering::Acquire) {
-        if let Err(e) = Notification::new()
-            .summary("Chatterbox")
-            .body(msg)
-            .appname("ChatterBox")
-            .show()
-        {
-            warn!("Failed to send notification {e}")
-        }
+#[derive(Debug, clap::Subcommand)]
+enum Subcommand {
+    /// write the conversation with a peer as a standalone HTML page
+    ExportHtml {
+        /// address of the peer, as shown when connected
+        peer: String,
+        /// file to write the page to
+        out: std::path::PathBuf,
+    },
+    /// load the export of another chat application into the history
+    Import {
+        #[arg(long, value_enum)]
+        format: import::Format,
+        /// address of the peer the conversation belongs to
+        #[arg(long)]
+        peer: String,
+        /// your own name in the export, these messages are shown as sent
+        #[arg(long)]
+        me: String,
+        file: std::path::PathBuf,
+    },
+    /// get back to a session started with --detach
+    Attach {
+        #[arg(default_value = "default")]
+        session: String,
+        /// send a message, or run a command, without showing the session
+        #[arg(long, value_name = "TEXT", conflicts_with_all = ["status", "follow"])]
+        send: Option<String>,
+        /// print whether the session is connected and how much is unread
+        #[arg(long, conflicts_with = "follow")]
+        status: bool,
+        /// print the messages of the session as they come
+        #[arg(long)]
+        follow: bool,
+    },
+    /// list the detached sessions with their unread messages
+    Sessions,
+    /// pass messages between named clients, holding them for whoever isn't connected
+    Relay {
+        #[arg(long, default_value = "0.0.0.0")]
+        listen: String,
+        #[arg(short, long, default_value_t = 8989)]
+        port: u16,
+        /// how long messages are held for a recipient, e.g. 12h or 7d
+        #[arg(long, default_value = "7d", value_parser = duration_arg)]
+        ttl: std::time::Duration,
+        /// messages held per recipient
+        #[arg(long, default_value_t = 1000)]
+        max_held: usize,
+        /// largest message accepted, in bytes
+        #[arg(long, default_value_t = 64 * 1024)]
+        max_size: usize,
+        /// room every client is put in when connecting, may be given several times
+        #[arg(long, value_name = "#ROOM")]
+        lobby: Vec<String>,
+        #[command(flatten)]
+        webhook: webhook::Options,
+    },
+}
+
+/// Names the conversation of this run, which the outbox belongs to. Whoever connects to a
+/// server is taken for the same peer.
+fn conversation(args: &Args) -> String {
+    let address = args.address.as_deref().unwrap_or("localhost");
+    if let Some(path) = &args.unix {
+        return format!("unix-{}", path.display());
+    }
+    if args.relay {
+        let to = args.to.as_deref().unwrap_or("lobby");
+        return format!("relay-{address}-{}-{to}", args.port);
+    }
+    match args.server {
+        true => format!("server-{}", args.port),
+        // the hub of --multi is a client of its own
+        false => format!("{address}-{}", args.port),
     }
 }
 
-#[instrument(skip(reader))]
-fn reciever<T: std::io::Read>(mut reader: std::io::BufReader<T>, dest: Arc<Mutex<Vec<String>>>) {
-    let mut buf = String::new();
+fn duration_arg(s: &str) -> Result<std::time::Duration, String> {
+    timestamp::parse_duration(s)
+        .ok_or_else(|| "expected a duration like 90s, 15m or 1h30m".to_string())
+}
 
-    const PREFIX: &str = "<-- ";
-    'read: while !RESET.load(std::sync::atomic::Ordering::Acquire) {
-        buf.push_str(PREFIX);
-        match reader.read_line(&mut buf) {
-            Ok(size) => {
-                if size == 0 {
-                    warn!("May be other end is closed!");
-                    RESET.store(true, Ordering::Release);
-                    break 'read;
-                };
-                debug!("recieved data: {:?}", buf.as_bytes());
-                let msg = buf.trim();
-                // no point in printing empty message
-                if msg.len() > PREFIX.len() {
-                    let owned = msg.to_string();
-                    if let Ok(mut lock) = dest.lock() {
-                        lock.push(owned);
-                        REDRAW.store(true, Ordering::Release);
-                    }
-                    notify(&msg[PREFIX.len()..]);
+/// Runs a non interactive subcommand, reporting failures in JSON as well with `json`.
+fn run_subcommand(command: &Subcommand, json: bool) -> anyhow::Result<()> {
+    let res = run_reporting(command, json);
+    if let (Err(e), true) = (&res, json) {
+        report::print(&report::Failed {
+            error: format!("{e:#}"),
+        });
+        std::process::exit(1);
+    }
+    res
+}
+
+/// Prints the result in JSON with `json`, for people otherwise.
+fn show(json: bool, result: impl Report, human: impl FnOnce()) {
+    match json {
+        true => report::print(&result),
+        false => human(),
+    }
+}
+
+#[instrument]
+fn run_reporting(command: &Subcommand, json: bool) -> anyhow::Result<()> {
+    match command {
+        Subcommand::ExportHtml { peer, out } => {
+            let store = Store::for_peer(peer)
+                .ok_or_else(|| anyhow::anyhow!("couldn't determine data directory"))?;
+            let mut records = store
+                .read()
+                .map_err(|e| anyhow::anyhow!("no history for {peer}: {e}"))?;
+            // earlier versions appended imported messages, whenever they were written
+            records.sort_by_key(|r| r.at);
+            std::fs::write(out, export::html(peer, &records))?;
+            let result = report::Exported {
+                peer: peer.clone(),
+                file: out.clone(),
+                messages: records.len(),
+            };
+            show(json, result, || {});
+            Ok(())
+        }
+        Subcommand::Import {
+            format,
+            peer,
+            me,
+            file,
+        } => {
+            let content = std::fs::read_to_string(file)?;
+            let records = import::parse(*format, &content, me).map_err(|e| anyhow::anyhow!(e))?;
+            let store = Store::for_peer(peer)
+                .ok_or_else(|| anyhow::anyhow!("couldn't determine data directory"))?;
+            // importing the same file twice shouldn't duplicate everything
+            let imported = store.import(records)?;
+            let result = report::Imported {
+                peer: peer.clone(),
+                messages: imported,
+            };
+            show(json, result, || println!("imported {imported} messages"));
+            Ok(())
+        }
+        Subcommand::Attach {
+            session,
+            send,
+            status,
+            follow,
+        } => {
+            let answered = |answer| match answer {
+                detach::Answer::Ok => show(
+                    json,
+                    report::Sent {
+                        session: session.clone(),
+                    },
+                    || {},
+                ),
+                detach::Answer::Status { connected, unread } => {
+                    let result = report::Status {
+                        session: session.clone(),
+                        connected,
+                        unread,
+                    };
+                    show(json, result, || {
+                        let state = if connected {
+                            "connected"
+                        } else {
+                            "disconnected"
+                        };
+                        println!("STATUS {state} {unread}")
+                    })
                 }
+                detach::Answer::Message { kind, text, data } => {
+                    let human = format!("{kind}: {text}");
+                    let result = report::Followed {
+                        session: session.clone(),
+                        kind,
+                        text,
+                        data,
+                    };
+                    show(json, result, || println!("{human}"))
+                }
+            };
+            match (send, status, follow) {
+                (Some(text), _, _) => detach::request(
+                    session,
+                    &format!("SEND {}", protocol::escape(text)),
+                    answered,
+                )?,
+                (None, true, _) => detach::request(session, "STATUS", answered)?,
+                (None, false, true) => detach::request(session, "FOLLOW", answered)?,
+                (None, false, false) => detach::attach(session)?,
+            }
+            Ok(())
+        }
+        Subcommand::Sessions => {
+            let sessions = detach::list();
+            if sessions.is_empty() && !json {
+                println!("no sessions, start one with --detach");
+            }
+            for (name, unread) in sessions {
+                let human = match unread {
+                    0 => format!("{name}: nothing unread"),
+                    n => format!("{name}: {n} unread"),
+                };
+                show(json, report::Session { name, unread }, || {
+                    println!("{human}")
+                });
             }
-            Err(e) => warn!("Failed to read data: {e}"),
+            Ok(())
+        }
+        Subcommand::Relay {
+            listen,
+            port,
+            ttl,
+            max_held,
+            max_size,
+            lobby,
+            webhook,
+        } => {
+            let limits = relay::Limits {
+                ttl: *ttl,
+                max_held: *max_held,
+                max_size: *max_size,
+            };
+            let result = report::Relaying {
+                listen: listen.clone(),
+                port: *port,
+            };
+            let mut webhook = webhook.clone();
+            webhook.resolve_token()?;
+            // on the interface the relay listens on, unless told otherwise
+            webhook.webhook_listen.get_or_insert_with(|| listen.clone());
+            show(json, result, || println!("relaying on {listen}:{port}"));
+            let config = Config::load();
+            relay::run(
+                listen,
+                *port,
+                limits,
+                &webhook,
+                webhook::Hooks::from_config(&config),
+                socket::Tuning::from_config(&config),
+                access::Gate::from_config(&config).map_err(anyhow::Error::msg)?,
+                acme::Options::from_config(&config),
+                lobby.clone(),
+            )?;
+            Ok(())
         }
-        buf.clear();
     }
 }
 
@@ -95,7 +472,8 @@ fn init_terminal() -> Result<LocalTerminal, std::io::Error> {
         stdout,
         crossterm::terminal::EnterAlternateScreen,
         crossterm::event::EnableMouseCapture,
-        crossterm::event::EnableFocusChange
+        crossterm::event::EnableFocusChange,
+        crossterm::event::EnableBracketedPaste
     )?;
     let backend = ratatui::backend::CrosstermBackend::new(stdout);
     ratatui::Terminal::new(backend)
@@ -106,266 +484,729 @@ fn reset_terminal(mut terminal: LocalTerminal) -> Result<(), std::io::Error> {
     crossterm::execute!(
         terminal.backend_mut(),
         crossterm::terminal::LeaveAlternateScreen,
-        crossterm::event::DisableMouseCapture
+        crossterm::event::DisableMouseCapture,
+        crossterm::event::DisableBracketedPaste
     )?;
     terminal.show_cursor()?;
     Ok(())
 }
 #[instrument]
+/// Fills in the secrets given in files or the environment, asking for those given without value.
+fn resolve_secrets(args: &mut Args) -> anyhow::Result<()> {
+    args.deniable = secret::resolve(
+        "deniable secret",
+        args.deniable.take(),
+        args.deniable_file.as_deref(),
+        "CHATTERBOX_DENIABLE",
+    )?;
+    if args.deniable.is_some() && (args.relay || args.multi) {
+        anyhow::bail!("--deniable can't be used with --relay or --multi");
+    }
+    if args.relay {
+        args.relay_key = secret::resolve(
+            "relay key",
+            args.relay_key.take(),
+            args.relay_key_file.as_deref(),
+            "CHATTERBOX_RELAY_KEY",
+        )?;
+    }
+    args.webhook.resolve_token()?;
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
     let level = match args.verbose {
         0 => tracing::Level::WARN,
         1 => tracing::Level::INFO,
         2 => tracing::Level::DEBUG,
         _ => tracing::Level::TRACE,
     };
-    if let Some(op_file_name) = args.output {
-        let fd = std::fs::OpenOptions::new()
+    let fd = args.output.as_ref().map(|op_file_name| {
+        std::fs::OpenOptions::new()
             .write(true)
-            .open(&op_file_name)
-            .unwrap_or_else(|e| panic!("Failed to open file {op_file_name}: {e}"));
-        tracing_subscriber::fmt()
-            .with_max_level(level)
-            .with_writer(fd)
-            .init()
-    }
+            .open(op_file_name)
+            .unwrap_or_else(|e| panic!("Failed to open file {op_file_name}: {e}"))
+    });
+    // recent logs are always kept around for the diagnostics bundle
+    let logs = diag::LogRing::default();
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_writer(diag::LogWriter::new(logs.clone(), fd))
+        .init();
+    crash::install(logs.clone());
     debug!("setting log level to {level}");
-    while !TERMINATE.load(std::sync::atomic::Ordering::Acquire) {
-        RESET.store(false, Ordering::Release);
-        let stream = {
-            let address = args.address.as_ref().map_or("0.0.0.0", |s| s.as_str());
-            warn!("Waiting for client on {address}:{}", args.port);
-            if args.server {
-                std::net::TcpListener::bind((address, args.port))?
-                    .accept()?
-                    .0
-            } else {
-                std::net::TcpStream::connect((
-                    args.address
-                        .as_ref()
-                        .expect("since server is necessary if the address is not given")
-                        .as_str(),
-                    args.port,
-                ))?
-            }
+    if let Some(command) = &args.command {
+        return run_subcommand(command, args.json);
+    }
+    if let Ok(session) = std::env::var(detach::DAEMON) {
+        return Ok(detach::daemon(&session)?);
+    }
+    if args.detach {
+        return Ok(detach::start(&args.session)?);
+    }
+    resolve_secrets(&mut args)?;
+    // create app and run it
+    let mut app = App {
+        logs,
+        ..App::default()
+    };
+    app.load_config(Config::load());
+    app.contacts = Contacts::load();
+    app.ansi = args.ansi;
+    app.cbor.set_offered(args.cbor);
+    app.timestamp_format = Arc::from(args.timestamp_format.as_str());
+    if let Some(name) = &args.name {
+        app.set_name(name.clone());
+    }
+    if args.mode == talk::Mode::Talk {
+        app.talking = Some(talk::Window::default());
+        app.input_mode = InputMode::Editing;
+    }
+    args.source.validate().map_err(anyhow::Error::msg)?;
+    app.source = args.source.describe();
+    if let (false, Some(alias)) = (args.relay, &args.to) {
+        if args.server {
+            anyhow::bail!("--to connects to a contact, it can't be used with --server");
+        }
+        let contact = app.contacts.get(alias).ok_or_else(|| {
+            anyhow::anyhow!("no contact named {alias}, add one with /contacts add")
+        })?;
+        args.address = Some(
+            contact
+                .address
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("contact {alias} has no address"))?,
+        );
+        args.port = contact.port.unwrap_or(args.port);
+        app.dialing = Some(alias.clone());
+    }
+    app.encrypted = encryption::Known::load();
+    app.reminders = Reminders::load(app.clock.clone());
+    if let (true, Some(name)) = (args.relay, &args.name) {
+        let to = args.to.clone().unwrap_or_default();
+        let mut route = relay::Route::new(name.clone(), to, &*app.clock);
+        route.join_code = args.join_code.clone();
+        route.private = args.private;
+        route.unlisted = args.unlisted;
+        route.key = match &args.relay_key {
+            Some(key) => key.clone(),
+            None => relay::identity_key(&*app.rng)?,
+        };
+        app.relay = Some(route);
+    }
+    if let Some(url) = &args.bridge {
+        let bridge = bridge::open(url).map_err(anyhow::Error::msg)?;
+        app.bridge = Some(bridge::spawn(bridge, app.bridge_event_sender.clone()));
+    }
+    let serving = args.server || args.multi;
+    if args.multi {
+        // the interface takes part like any other client
+        let host = broadcast::spawn(
+            listen(args.address.as_deref(), args.port)?,
+            access::Gate::from_config(&app.config).map_err(anyhow::Error::msg)?,
+        )?;
+        args.address = Some(host.ip().to_string());
+        args.port = host.port();
+        args.server = false;
+    }
+    if let Some(path) = &args.simulate {
+        let script =
+            simulate::Script::parse(&std::fs::read_to_string(path)?).map_err(anyhow::Error::msg)?;
+        // a made up conversation has no place in the history
+        app.storage = Some(Arc::new(store::Memory::default()));
+        let peer = simulate::spawn(script, app.session_sender.clone())?;
+        args.address = Some(peer.ip().to_string());
+        args.port = peer.port();
+    }
+    if let Some(phrase) = &args.key_phrase {
+        let phrase = match phrase.as_str() {
+            "" => seal::prompt("key phrase")?,
+            phrase => phrase.to_string(),
         };
+        app.seal = Some(Arc::new(seal::Seal::new(&phrase, app.rng.clone())?));
+    }
+    let mut capabilities = Vec::new();
+    if args.mode == talk::Mode::Talk {
+        capabilities.push("talk".to_string());
+    }
+    if args.key_phrase.is_some() {
+        capabilities.push("key-phrase".to_string());
+    }
+    if args.deniable.is_some() {
+        capabilities.push("deniable".to_string());
+    }
+    if args.tls.tls {
+        capabilities.push("tls".to_string());
+    }
+    if args.multi {
+        capabilities.push("multi".to_string());
+    }
+    if args.cbor {
+        capabilities.push(cbor::CAPABILITY.to_string());
+    }
+    if args.discover && serving {
+        mdns::advertise(mdns::Advertisement {
+            name: app.name.clone(),
+            port: args.port,
+            capabilities: capabilities.clone(),
+        })?;
+    } else if args.discover && args.address.is_none() {
+        if args.follow || args.no_tui {
+            anyhow::bail!("--follow and --no-tui can't pick a server, give its --address instead");
+        }
         let mut terminal = init_terminal()?;
-        // create app and run it
-        let app = App::default();
-        let res = run_app(&mut terminal, app, stream);
+        let picked = pick_server(&mut terminal);
         reset_terminal(terminal)?;
-        res?;
+        let Some(service) = picked? else {
+            return Ok(());
+        };
+        args.address = Some(service.address.ip().to_string());
+        args.port = service.address.port();
     }
-    Ok(())
-}
-
-use crossterm::event::{self, Event, KeyCode, KeyEventKind};
-use ratatui::{prelude::*, widgets::*};
-
-enum InputMode {
-    Normal,
-    Editing,
-}
-
-/// App holds the state of the application
-struct App {
-    /// Current value of the input box
-    input: String,
-    /// Position of cursor in the editor area.
-    cursor_position: usize,
-    /// Current input mode
-    input_mode: InputMode,
-    /// History of recorded messages
-    messages: Arc<Mutex<Vec<String>>>,
-}
-
-impl Default for App {
-    fn default() -> App {
-        App {
-            input: String::new(),
-            input_mode: InputMode::Normal,
-            messages: Arc::new(Mutex::new(Vec::new())),
-            cursor_position: 0,
-        }
+    if args.discoverable {
+        let announcement = Arc::new(Mutex::new(discovery::Announcement {
+            name: app.name.clone(),
+            port: args.server.then_some(args.port),
+            capabilities,
+        }));
+        discovery::answer(Arc::clone(&announcement))?;
+        app.announcement = Some(announcement);
     }
-}
-
-impl App {
-    fn move_cursor_left(&mut self) {
-        let cursor_moved_left = self.cursor_position.saturating_sub(1);
-        self.cursor_position = self.clamp_cursor(cursor_moved_left);
+    if args.no_history {
+        app.storage = None;
     }
-
-    fn move_cursor_right(&mut self) {
-        let cursor_moved_right = self.cursor_position.saturating_add(1);
-        self.cursor_position = self.clamp_cursor(cursor_moved_right);
+    let posts = app.webhook_sender.clone();
+    webhook::spawn(
+        &args.webhook,
+        Arc::new(move |_, text| posts.send(text).map_err(|e| e.to_string())),
+    )?;
+    // pins saved with /pin-cert
+    let wants_pin = args.tls.tls && !args.server && args.tls.pin_cert.is_none();
+    if let Some(address) = args.address.as_ref().filter(|_| wants_pin) {
+        let server = format!("{address}:{}", args.port);
+        args.tls.pin_cert = app
+            .config
+            .strings(tls::PINS_TABLE)
+            .into_iter()
+            .find(|(key, _)| *key == server)
+            .map(|(_, pin)| pin);
     }
-
-    fn enter_char(&mut self, new_char: char) {
-        self.input.insert(self.cursor_position, new_char);
-
-        self.move_cursor_right();
+    if let Some(proxy) = args
+        .proxy
+        .as_mut()
+        .filter(|p| p.user.is_some() && p.password.is_none())
+    {
+        proxy.password = Some(seal::prompt("proxy password")?);
     }
-
-    fn delete_char(&mut self) {
-        let is_not_cursor_leftmost = self.cursor_position != 0;
-        if is_not_cursor_leftmost {
-            // Method "remove" is not used on the saved text for deleting the selected char.
-            // Reason: Using remove on String works on bytes instead of the chars.
-            // Using remove would require special care because of char boundaries.
-
-            let current_index = self.cursor_position;
-            let from_left_to_current_index = current_index - 1;
-
-            // Getting all characters before the selected character.
-            let before_char_to_delete = self.input.chars().take(from_left_to_current_index);
-            // Getting all characters after selected character.
-            let after_char_to_delete = self.input.chars().skip(current_index);
-
-            // Put all characters together except the selected one.
-            // By leaving the selected one out, it is forgotten and therefore deleted.
-            self.input = before_char_to_delete.chain(after_char_to_delete).collect();
-            self.move_cursor_left();
-        }
+    // made up conversations and inetd connections, from whoever connected this time, queue in
+    // memory, what waits for a known peer stays on disk
+    if args.simulate.is_none() && !args.inetd {
+        app.restore_outbox(Outbox::load(&conversation(&args)));
     }
-
-    fn clamp_cursor(&self, new_cursor_pos: usize) -> usize {
-        new_cursor_pos.clamp(0, self.input.len())
+    let target = connection::Target {
+        address: args.address.clone(),
+        port: args.port,
+        server: args.server,
+        deniable: args.deniable.clone(),
+        source: args.source.clone(),
+        proxy: args.proxy.clone(),
+        socket: app.socket,
+        tls: tls::Context::new(&args.tls, args.server)?,
+        access: Arc::new(access::Gate::from_config(&app.config).map_err(anyhow::Error::msg)?),
+        clock: app.clock.clone(),
+        unix: args.unix.clone(),
+        inherited: args.inetd,
+    };
+    if args.inetd {
+        run_headless(app, target);
+        return Ok(());
     }
-    fn reset_cursor(&mut self) {
-        self.cursor_position = 0;
+    if args.follow || args.no_tui {
+        return Ok(run_follow(app, target, args.json, args.no_tui)?);
     }
+    detach::serve_control(Arc::clone(&app.messages), app.session_sender.clone())?;
+    let draft_path = draft::path(&conversation(&args));
+    if let Some(recovered) = draft_path.as_deref().and_then(draft::recover) {
+        app.offer_draft(recovered);
+    }
+    app.autosave = draft::Autosave::new(draft_path.clone());
+    let mut terminal = init_terminal()?;
+    let res = run_app(&mut terminal, app, target);
+    reset_terminal(terminal)?;
+    res?;
+    // quitting asked about the draft already
+    if let Some(path) = &draft_path {
+        draft::discard(path);
+    }
+    Ok(())
+}
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers, MouseEventKind};
+use ratatui::prelude::*;
 
-    fn submit_message(&mut self, writer: &mut impl std::io::Write) {
-        const PREFIX: &str = "--> ";
-        let usr_str = self.input.trim();
-        if !usr_str.is_empty() {
-            if let Ok(mut lock) = self.messages.lock() {
-                let mut msg = PREFIX.to_string();
-                msg.push_str(usr_str);
-                lock.push(msg);
-            } else {
-                error!("Failed to lock messages, may be poisoned");
+/// Lines a turn of the mouse wheel scrolls.
+const WHEEL_LINES: usize = 3;
+
+/// Lets the user pick one of the servers advertised on the local network, `None` if they quit.
+fn pick_server<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<Option<mdns::Service>> {
+    let (tx, found) = mpsc::channel();
+    mdns::browse(tx);
+    let mut services = Vec::new();
+    let mut state = ratatui::widgets::ListState::default();
+    let mut error = None;
+    loop {
+        while let Ok(res) = found.try_recv() {
+            match res {
+                Ok(found) => services = found,
+                Err(e) => error = Some(e.to_string()),
             }
-            self.input.push('\n');
-            if let Err(e) = writer.write_all(self.input.as_bytes()) {
-                error!("Failed to send message {e}");
+        }
+        if state.selected().is_none() && !services.is_empty() {
+            state.select(Some(0));
+        }
+        terminal.draw(|f| ui::draw_picker(f, &services, &mut state, error.as_deref()))?;
+        if !event::poll(std::time::Duration::from_millis(200))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let selected = state.selected().unwrap_or(0);
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => state.select(Some(selected.saturating_sub(1))),
+            KeyCode::Down | KeyCode::Char('j') if selected + 1 < services.len() => {
+                state.select(Some(selected + 1))
             }
+            KeyCode::Enter if !services.is_empty() => return Ok(services.get(selected).cloned()),
+            KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(None),
+            _ => {}
         }
-        self.input.clear();
-        self.reset_cursor();
     }
 }
 
 fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
     mut app: App,
-    mut stream: std::net::TcpStream,
+    target: connection::Target,
 ) -> io::Result<()> {
-    let reader = std::io::BufReader::new(stream.try_clone()?);
-    let reciever_buffer = Arc::clone(&app.messages);
-    std::thread::spawn(move || reciever(reader, reciever_buffer));
-    REDRAW.store(true, Ordering::Release);
-    while !RESET.load(std::sync::atomic::Ordering::Acquire) {
+    let mut connection = Connection::new(target);
+    let mut unread = 0;
+    loop {
+        connection.poll(&mut app);
+        app.note_typing(connection.stream.as_mut());
+        app.fire_reminders();
+        app.escalate();
+        if app.unread.load(Ordering::Relaxed) != unread {
+            unread = app.unread.load(Ordering::Relaxed);
+            detach::note_unread(unread);
+        }
         if let Ok(true) = REDRAW.compare_exchange(
             true,
             false,
             std::sync::atomic::Ordering::AcqRel,
             std::sync::atomic::Ordering::Relaxed,
         ) {
-            terminal.draw(|f| ui(f, &app))?;
+            terminal.draw(|f| ui::draw(f, &app))?;
+            if let Some(hud) = &mut app.hud {
+                hud.redrawn();
+            }
+        }
+        if app.hud.as_mut().is_some_and(diag::Hud::tick) {
+            REDRAW.store(true, Ordering::Release);
+        }
+        app.autosave.tick(&app.input);
+        if app.ready_to_quit() {
+            if let Some(stream) = connection.stream.as_mut() {
+                app.end_session(stream.as_mut());
+            }
+            return Ok(());
         }
 
-        if crossterm::event::poll(std::time::Duration::from_millis(200))? {
-            match event::read()? {
-                Event::Key(key) => {
-                    REDRAW.store(true, Ordering::Release);
-                    match app.input_mode {
-                        InputMode::Normal => match key.code {
-                            KeyCode::Char('i') => {
-                                app.input_mode = InputMode::Editing;
-                            }
-                            KeyCode::Char('q') => {
-                                TERMINATE.store(true, Ordering::Release);
-                                return Ok(());
+        // keys of a macro come first, they are not recorded again
+        let (event, replayed) = match app.macros.next_key() {
+            Some(Ok(key)) => (Event::Key(key), true),
+            Some(Err(e)) => {
+                app.notice = Some(e);
+                REDRAW.store(true, Ordering::Release);
+                continue;
+            }
+            None if crossterm::event::poll(std::time::Duration::from_millis(200))? => {
+                (event::read()?, false)
+            }
+            None => continue,
+        };
+        match event {
+            Event::Key(key) if app.recovered_draft.is_some() && key.kind == KeyEventKind::Press => {
+                match key.code {
+                    KeyCode::Char('y') => app.answer_draft(true),
+                    KeyCode::Char('n') | KeyCode::Esc => app.answer_draft(false),
+                    // the draft waits for an answer
+                    _ => continue,
+                }
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::Key(key)
+                if app.quit == Some(Quit::Asking) && key.kind == KeyEventKind::Press =>
+            {
+                app.popup = None;
+                app.quit = None;
+                match key.code {
+                    KeyCode::Char('f') => {
+                        if let Some(stream) = connection.stream.as_mut() {
+                            app.end_session(stream.as_mut());
+                        }
+                        return Ok(());
+                    }
+                    KeyCode::Char('w') if !app.outbox.is_empty() => {
+                        app.quit = Some(Quit::WhenSent);
+                        app.notice = Some("quitting once the queued messages are sent".to_string());
+                    }
+                    // anything else stays
+                    _ => {}
+                }
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::Key(key)
+                if key.code == KeyCode::Char('c')
+                    && key.modifiers.contains(KeyModifiers::CONTROL)
+                    && key.kind == KeyEventKind::Press =>
+            {
+                if app.request_quit() {
+                    if let Some(stream) = connection.stream.as_mut() {
+                        app.end_session(stream.as_mut());
+                    }
+                    return Ok(());
+                }
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::Key(key) if key.code == KeyCode::Esc && app.popup.is_some() => {
+                app.popup = None;
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::Key(key)
+                if matches!(key.code, KeyCode::PageUp | KeyCode::PageDown)
+                    && key.kind == KeyEventKind::Press =>
+            {
+                // a page of the messages pane, keeping two lines in view
+                let page = terminal.size()?.height.saturating_sub(8).max(1) as usize;
+                match key.code {
+                    KeyCode::PageUp => app.scroll_back(page),
+                    _ => app.scroll_forward(page),
+                }
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::Key(key) => {
+                REDRAW.store(true, Ordering::Release);
+                // neither the key starting a recording nor the one stopping it is part of it
+                let recording = app.macros.recording().is_some();
+                match app.input_mode {
+                    InputMode::Normal if key.kind == KeyEventKind::Press => {
+                        if let Some(awaiting) = app.macros.awaiting.take() {
+                            app.macro_register(awaiting, &key);
+                        } else if let Some(action) = app.keys.press(&key) {
+                            match action {
+                                Action::Insert => app.input_mode = InputMode::Editing,
+                                Action::Append => app.append(),
+                                Action::OpenLine => app.open_line(),
+                                Action::Quit if app.request_quit() => {
+                                    if let Some(stream) = connection.stream.as_mut() {
+                                        app.end_session(stream.as_mut());
+                                    }
+                                    return Ok(());
+                                }
+                                Action::Quit => {}
+                                Action::Record if recording => app.stop_recording(),
+                                Action::Record => {
+                                    app.macros.awaiting = Some(macros::Awaiting::Record)
+                                }
+                                Action::Replay => {
+                                    app.macros.awaiting = Some(macros::Awaiting::Replay)
+                                }
+                                Action::Next => app.select_next_message(),
+                                Action::Previous => app.select_previous_message(),
+                                Action::First => app.select_first_message(),
+                                Action::Last => app.select_last_message(),
+                                Action::Unselect => app.unselect_message(),
+                                Action::Search if app.talking.is_some() => {
+                                    app.notice = Some("nothing to search in talk mode".to_string())
+                                }
+                                Action::Search => app.start_search(),
+                                Action::SearchNext => app.find(true),
+                                Action::SearchPrevious => app.find(false),
+                                Action::ToggleSystem => app.toggle_system_messages(),
+                                Action::ToggleTimestamps => app.toggle_timestamps(),
+                                Action::ToggleRaw => app.raw = !app.raw,
+                                Action::ToggleDiff => app.toggle_diff(),
+                                Action::Contact => match app.peer_alias.clone() {
+                                    Some(alias) => app.show_contact(&alias),
+                                    None => {
+                                        app.notice = Some("the peer isn't a contact".to_string())
+                                    }
+                                },
+                                Action::ClosePane => *app.pane.lock().unwrap() = None,
+                                Action::ScrollLeft => {
+                                    app.table_scroll = app.table_scroll.saturating_sub(8)
+                                }
+                                Action::ScrollRight => app.table_scroll += 8,
+                                Action::NextRoom => app.next_room(),
+                                Action::ToggleUsers => app.toggle_users(),
+                                Action::Yank => app.yank(),
+                                Action::Quote => app.quote(),
+                                Action::Details => app.show_details(),
+                                Action::ToggleHud => {
+                                    app.hud = match app.hud {
+                                        Some(_) => None,
+                                        None => Some(diag::Hud::default()),
+                                    }
+                                }
                             }
+                        }
+                    }
+                    InputMode::Editing
+                        if app.searching.is_some() && key.kind == KeyEventKind::Press =>
+                    {
+                        match key.code {
+                            KeyCode::Enter => app.finish_search(true),
+                            KeyCode::Esc => app.finish_search(false),
+                            KeyCode::Char(c) => app.enter_char(c),
+                            KeyCode::Backspace => app.delete_char(),
+                            KeyCode::Left => app.move_cursor_left(),
+                            KeyCode::Right => app.move_cursor_right(),
                             _ => {}
-                        },
-                        InputMode::Editing if key.kind == KeyEventKind::Press => match key.code {
-                            KeyCode::Enter => app.submit_message(&mut stream),
-                            KeyCode::Char(to_insert) => {
-                                app.enter_char(to_insert);
-                            }
-                            KeyCode::Backspace => {
-                                app.delete_char();
-                            }
-                            KeyCode::Left => {
-                                app.move_cursor_left();
-                            }
-                            KeyCode::Right => {
-                                app.move_cursor_right();
+                        }
+                    }
+                    InputMode::Editing
+                        if app.talking.is_some() && key.kind == KeyEventKind::Press =>
+                    {
+                        let typed = match key.code {
+                            KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                match c {
+                                    'h' => Some(talk::ERASE),
+                                    'w' => Some(talk::WORD_ERASE),
+                                    'u' => Some(talk::KILL),
+                                    'g' => Some(talk::BELL),
+                                    'l' => {
+                                        terminal.clear()?;
+                                        None
+                                    }
+                                    _ => None,
+                                }
                             }
+                            KeyCode::Char(c) => Some(c),
+                            KeyCode::Backspace => Some(talk::ERASE),
+                            KeyCode::Enter => Some('\n'),
                             KeyCode::Esc => {
                                 app.input_mode = InputMode::Normal;
+                                None
                             }
-                            _ => {}
-                        },
+                            _ => None,
+                        };
+                        if let Some(c) = typed {
+                            app.talk(connection.stream.as_mut(), &c.to_string());
+                        }
+                    }
+                    InputMode::Editing if key.kind == KeyEventKind::Press => match key.code {
+                        // Shift+Enter only where the terminal tells it apart
+                        KeyCode::Enter
+                            if key
+                                .modifiers
+                                .intersects(KeyModifiers::ALT | KeyModifiers::SHIFT) =>
+                        {
+                            app.enter_char('\n')
+                        }
+                        KeyCode::Char('j') if key.modifiers == KeyModifiers::CONTROL => {
+                            app.enter_char('\n')
+                        }
+                        KeyCode::Char('f') if key.modifiers == KeyModifiers::CONTROL => {
+                            app.start_search()
+                        }
+                        KeyCode::Char('v') if key.modifiers == KeyModifiers::CONTROL => {
+                            app.paste_clipboard()
+                        }
+                        KeyCode::Enter => app.submit_message(connection.stream.as_mut()),
+                        KeyCode::Tab => app.expand_snippet(),
+                        KeyCode::Char(to_insert) => {
+                            app.enter_char(to_insert);
+                        }
+                        KeyCode::Backspace => {
+                            app.delete_char();
+                        }
+                        KeyCode::Left => {
+                            app.move_cursor_left();
+                        }
+                        KeyCode::Right => {
+                            app.move_cursor_right();
+                        }
+                        KeyCode::Up => app.move_cursor_up(),
+                        KeyCode::Down => app.move_cursor_down(),
+                        KeyCode::Esc => {
+                            app.input_mode = InputMode::Normal;
+                        }
                         _ => {}
+                    },
+                    _ => {}
+                }
+                let still = app.macros.recording().is_some();
+                if recording && still && !replayed && key.kind == KeyEventKind::Press {
+                    app.macros.record(key);
+                }
+                app.share_draft(connection.stream.as_mut());
+            }
+            Event::FocusGained => {
+                NOTIFY.store(false, Ordering::Release);
+                app.mark_read(connection.stream.as_mut());
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::FocusLost => NOTIFY.store(true, Ordering::Release),
+            Event::Resize(_, _) => {
+                // everything, a detached session has a new terminal behind the same size
+                terminal.clear()?;
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::Paste(text)
+                if app.talking.is_some() && matches!(app.input_mode, InputMode::Editing) =>
+            {
+                app.talk(connection.stream.as_mut(), &text.replace("\r\n", "\n"));
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::Paste(text) if matches!(app.input_mode, InputMode::Editing) => {
+                app.paste(&text);
+                app.share_draft(connection.stream.as_mut());
+                REDRAW.store(true, Ordering::Release);
+            }
+            Event::Mouse(mouse) => match mouse.kind {
+                MouseEventKind::ScrollUp => {
+                    app.scroll_back(WHEEL_LINES);
+                    REDRAW.store(true, Ordering::Release);
+                }
+                MouseEventKind::ScrollDown => {
+                    app.scroll_forward(WHEEL_LINES);
+                    REDRAW.store(true, Ordering::Release);
+                }
+                _ => {}
+            },
+            Event::Paste(_) => (),
+        }
+    }
+}
+
+/// Prints messages as they arrive, like `tail -f`, and sends every line of stdin. With `until_eof`
+/// it returns once stdin ends and the outbox is empty.
+fn run_follow(
+    mut app: App,
+    target: connection::Target,
+    json: bool,
+    until_eof: bool,
+) -> io::Result<()> {
+    let (lines_tx, lines) = mpsc::channel();
+    tasks::spawn("stdin", move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let mut connection = Connection::new(target);
+    let mut printed = app.messages.lock().expect("poisoned lock").len();
+    let mut reading = true;
+    loop {
+        connection.poll(&mut app);
+        app.fire_reminders();
+        let interval = std::time::Duration::from_millis(200);
+        match lines.recv_timeout(interval) {
+            Ok(line) => {
+                match annotated(&line).filter(|_| json) {
+                    Some((text, data)) => {
+                        app.send_annotated(connection.stream.as_mut(), text, data)
                     }
+                    None => app.submit(connection.stream.as_mut(), &line),
+                }
+                if let Some(notice) = app.notice.take() {
+                    eprintln!("*** {}", ansi::sanitize(&notice));
                 }
-                Event::FocusGained => NOTIFY.store(false, Ordering::Release),
-                Event::FocusLost => NOTIFY.store(true, Ordering::Release),
-                Event::Resize(_, _) => REDRAW.store(true, Ordering::Release),
-                Event::Mouse(_) | Event::Paste(_) => (),
             }
+            // stdin closing doesn't stop following, just like tail
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                reading = false;
+                std::thread::sleep(interval);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+        let fresh: Vec<Message> = {
+            let lock = app.messages.lock().expect("poisoned lock");
+            let fresh = (printed..lock.len()).filter_map(|i| lock.get(i).cloned());
+            let fresh = fresh.collect();
+            printed = lock.len();
+            fresh
+        };
+        for msg in fresh {
+            print_message(&msg, json)?;
+        }
+        if until_eof && !reading && app.queued.is_empty() {
+            return Ok(());
         }
     }
-    Ok(())
 }
 
-fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([Constraint::Min(1), Constraint::Length(3)].as_ref())
-        .split(f.size());
+/// Serves the connection without showing anything, stdin and stdout may well be the connection.
+fn run_headless(mut app: App, target: connection::Target) {
+    let mut connection = Connection::new(target);
+    while !connection.is_finished() {
+        connection.poll(&mut app);
+        app.fire_reminders();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    }
+}
 
-    let input = Paragraph::new(app.input.as_str())
-        .style(match app.input_mode {
-            InputMode::Normal => Style::default(),
-            InputMode::Editing => Style::default().fg(Color::Yellow),
-        })
-        .block(Block::default().borders(Borders::ALL).title("Input"));
-    f.render_widget(input, chunks[1]);
-    match app.input_mode {
-        InputMode::Normal =>
-            // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
-            {}
+/// Text and data of a line like `{"text": "hi", "data": {...}}`, how bots send annotations.
+fn annotated(line: &str) -> Option<(String, Option<json::Value>)> {
+    let value = json::parse(line).ok()?;
+    let text = value.get("text")?.as_str()?.to_string();
+    Some((text, value.get("data").cloned()))
+}
 
-        InputMode::Editing => {
-            // Make the cursor visible and ask ratatui to put it at the specified coordinates after
-            // rendering
-            f.set_cursor(
-                // Draw the cursor at the current position in the input field.
-                // This position is can be controlled via the left and right arrow key
-                chunks[1].x + app.cursor_position as u16 + 1,
-                // Move one line down, from the border to the input line
-                chunks[1].y + 1,
-            )
+/// Incoming messages go to stdout, the system ones to stderr unless printing JSON.
+fn print_message(msg: &Message, json: bool) -> io::Result<()> {
+    use std::io::{IsTerminal, Write};
+    let kind = match msg.kind {
+        message::Kind::Incoming => "incoming",
+        message::Kind::System => "system",
+        message::Kind::Outgoing => return Ok(()),
+    };
+    let mut stdout = io::stdout().lock();
+    if json {
+        let mut fields = vec![
+            (
+                "at".to_string(),
+                json::Value::Number(timestamp::now_millis() as f64),
+            ),
+            ("kind".to_string(), json::Value::String(kind.to_string())),
+        ];
+        if let Some(author) = &msg.author {
+            fields.push(("author".to_string(), json::Value::String(author.clone())));
+        }
+        fields.push(("text".to_string(), json::Value::String(msg.text.clone())));
+        if let Some(data) = &msg.data {
+            fields.push(("data".to_string(), data.clone()));
         }
+        if let Some(room) = &msg.room {
+            fields.push(("room".to_string(), json::Value::String(room.clone())));
+        }
+        let value = json::Value::Object(fields);
+        writeln!(stdout, "{value}")?;
+    } else if msg.kind == message::Kind::System {
+        eprintln!("*** {}", ansi::sanitize(&msg.text));
+    } else if stdout.is_terminal() {
+        writeln!(stdout, "{}", ansi::sanitize(&msg.line()))?;
+    } else {
+        // left alone for whatever reads it
+        writeln!(stdout, "{}", msg.line())?;
     }
-    let messages: Vec<ListItem> = {
-        let lock = app.messages.lock().unwrap();
-        // ignore borders
-        lock[lock.len().saturating_sub(chunks[0].height as usize - 2)..lock.len()]
-            .iter()
-            .map(|m| {
-                let content = Line::from(Span::raw(m.clone()));
-                ListItem::new(content)
-            })
-            .collect()
-    };
-    let messages =
-        List::new(messages).block(Block::default().borders(Borders::ALL).title("Messages"));
-    f.render_widget(messages, chunks[0]);
+    stdout.flush()
 }
@@ -0,0 +1,440 @@
+//! Unicode approximation of inline LaTeX, `$x^2$` is shown as `x²`.
+//!
+//! Covers what people tend to type in chat: greek letters, common operators and arrows,
+//! scripts, `\frac`, `\sqrt` and `\mathbb`. Anything unknown is left as written.
+
+/// Part of a message, either plain text or the rendering of a math span.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Math(String),
+}
+
+/// Splits `text` at `$...$` and `$$...$$` spans.
+///
+/// Like pandoc an inline span must not start or end with a space and the closing `$` must not
+/// be followed by a digit, so that prices like `$5 or $10` stay text.
+pub fn segments(text: &str) -> Vec<Segment<'_>> {
+    let mut out = Vec::new();
+    let mut plain = 0;
+    let mut pos = 0;
+    while let Some(found) = text[pos..].find('$') {
+        let start = pos + found;
+        pos = start + 1;
+        if text[..start].ends_with('\\') {
+            continue;
+        }
+        let (body, end) = if text[start..].starts_with("$$") {
+            match text[start + 2..].find("$$") {
+                Some(len) => (&text[start + 2..start + 2 + len], start + 4 + len),
+                None => continue,
+            }
+        } else {
+            match inline_end(&text[start + 1..]) {
+                Some(len) => (&text[start + 1..start + 1 + len], start + 2 + len),
+                None => continue,
+            }
+        };
+        if body.trim().is_empty() {
+            continue;
+        }
+        if plain < start {
+            out.push(Segment::Text(&text[plain..start]));
+        }
+        out.push(Segment::Math(to_unicode(body.trim())));
+        plain = end;
+        pos = end;
+    }
+    if plain < text.len() {
+        out.push(Segment::Text(&text[plain..]));
+    }
+    out
+}
+
+/// Length of the inline span at the start of `rest`, up to the closing `$`.
+fn inline_end(rest: &str) -> Option<usize> {
+    if rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let mut from = 0;
+    loop {
+        let end = from + rest[from..].find('$')?;
+        if rest[..end].ends_with('\\') {
+            from = end + 1;
+            continue;
+        }
+        let closes = !rest[..end].ends_with(char::is_whitespace)
+            && !rest[end + 1..].starts_with(|c: char| c.is_ascii_digit());
+        return closes.then_some(end);
+    }
+}
+
+pub fn to_unicode(src: &str) -> String {
+    Parser { rest: src }.sequence(false)
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.rest.chars().next()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        Some(c)
+    }
+
+    /// Everything up to the end, or the closing brace of a group.
+    fn sequence(&mut self, group: bool) -> String {
+        let mut out = String::new();
+        while let Some(c) = self.next() {
+            match c {
+                '}' if group => break,
+                '{' => out.push_str(&self.sequence(true)),
+                '^' => {
+                    let arg = self.argument();
+                    out.push_str(&script(&arg, superscript, '^'));
+                }
+                '_' => {
+                    let arg = self.argument();
+                    out.push_str(&script(&arg, subscript, '_'));
+                }
+                '\\' => out.push_str(&self.command()),
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    /// Single character, command or group following a command or script.
+    fn argument(&mut self) -> String {
+        self.rest = self.rest.trim_start();
+        match self.next() {
+            Some('{') => self.sequence(true),
+            Some('\\') => self.command(),
+            Some(c) => c.to_string(),
+            None => String::new(),
+        }
+    }
+
+    fn command(&mut self) -> String {
+        let len = self
+            .rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(self.rest.len());
+        let name = &self.rest[..len];
+        self.rest = &self.rest[len..];
+        if name.is_empty() {
+            return match self.next() {
+                Some(',' | ';' | ':' | ' ') => " ".to_string(),
+                Some('!') => String::new(),
+                Some('\\') => "  ".to_string(),
+                Some(c) => c.to_string(),
+                None => "\\".to_string(),
+            };
+        }
+        match name {
+            "frac" | "dfrac" | "tfrac" => {
+                let (num, den) = (self.argument(), self.argument());
+                format!("{}/{}", parenthesize(&num), parenthesize(&den))
+            }
+            "sqrt" => {
+                let root = match self.rest.strip_prefix('[') {
+                    Some(rest) => {
+                        let end = rest.find(']').unwrap_or(rest.len());
+                        let index = &rest[..end];
+                        self.rest = rest.get(end + 1..).unwrap_or("");
+                        match index.trim() {
+                            "3" => "∛",
+                            "4" => "∜",
+                            _ => "√",
+                        }
+                    }
+                    None => "√",
+                };
+                format!("{root}{}", parenthesize(&self.argument()))
+            }
+            "text" | "textrm" | "mathrm" | "mathit" | "mathbf" | "mathsf" | "operatorname" => {
+                self.argument()
+            }
+            "mathbb" => self.argument().chars().map(double_struck).collect(),
+            // delimiters are drawn as they come
+            "left" | "right" | "big" | "Big" | "bigg" | "Bigg" => String::new(),
+            name => match symbol(name) {
+                Some(symbol) => symbol.to_string(),
+                None => format!("\\{name}"),
+            },
+        }
+    }
+}
+
+/// Wraps anything longer than a single term in parentheses.
+fn parenthesize(s: &str) -> String {
+    if s.chars().count() <= 1 || s.chars().all(char::is_alphanumeric) {
+        s.to_string()
+    } else {
+        format!("({s})")
+    }
+}
+
+/// Raised or lowered `arg`, falls back to `x^(...)` if not every character has a form.
+fn script(arg: &str, map: fn(char) -> Option<char>, marker: char) -> String {
+    match arg.chars().map(map).collect::<Option<String>>() {
+        Some(s) if !s.is_empty() => s,
+        _ if arg.chars().count() == 1 => format!("{marker}{arg}"),
+        _ => format!("{marker}({arg})"),
+    }
+}
+
+fn superscript(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '⁰',
+        '1' => '¹',
+        '2' => '²',
+        '3' => '³',
+        '4' => '⁴',
+        '5' => '⁵',
+        '6' => '⁶',
+        '7' => '⁷',
+        '8' => '⁸',
+        '9' => '⁹',
+        '+' => '⁺',
+        '-' | '−' => '⁻',
+        '=' => '⁼',
+        '(' => '⁽',
+        ')' => '⁾',
+        'a' => 'ᵃ',
+        'b' => 'ᵇ',
+        'c' => 'ᶜ',
+        'd' => 'ᵈ',
+        'e' => 'ᵉ',
+        'f' => 'ᶠ',
+        'g' => 'ᵍ',
+        'h' => 'ʰ',
+        'i' => 'ⁱ',
+        'j' => 'ʲ',
+        'k' => 'ᵏ',
+        'l' => 'ˡ',
+        'm' => 'ᵐ',
+        'n' => 'ⁿ',
+        'o' => 'ᵒ',
+        'p' => 'ᵖ',
+        'r' => 'ʳ',
+        's' => 'ˢ',
+        't' => 'ᵗ',
+        'u' => 'ᵘ',
+        'v' => 'ᵛ',
+        'w' => 'ʷ',
+        'x' => 'ˣ',
+        'y' => 'ʸ',
+        'z' => 'ᶻ',
+        'T' => 'ᵀ',
+        '′' | '\'' => '′',
+        _ => return None,
+    })
+}
+
+fn subscript(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '₀',
+        '1' => '₁',
+        '2' => '₂',
+        '3' => '₃',
+        '4' => '₄',
+        '5' => '₅',
+        '6' => '₆',
+        '7' => '₇',
+        '8' => '₈',
+        '9' => '₉',
+        '+' => '₊',
+        '-' | '−' => '₋',
+        '=' => '₌',
+        '(' => '₍',
+        ')' => '₎',
+        'a' => 'ₐ',
+        'e' => 'ₑ',
+        'h' => 'ₕ',
+        'i' => 'ᵢ',
+        'j' => 'ⱼ',
+        'k' => 'ₖ',
+        'l' => 'ₗ',
+        'm' => 'ₘ',
+        'n' => 'ₙ',
+        'o' => 'ₒ',
+        'p' => 'ₚ',
+        'r' => 'ᵣ',
+        's' => 'ₛ',
+        't' => 'ₜ',
+        'u' => 'ᵤ',
+        'v' => 'ᵥ',
+        'x' => 'ₓ',
+        _ => return None,
+    })
+}
+
+fn double_struck(c: char) -> char {
+    match c {
+        'C' => 'ℂ',
+        'H' => 'ℍ',
+        'N' => 'ℕ',
+        'P' => 'ℙ',
+        'Q' => 'ℚ',
+        'R' => 'ℝ',
+        'Z' => 'ℤ',
+        c => c,
+    }
+}
+
+fn symbol(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" | "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" | "vartheta" => "θ",
+        "iota" => "ι",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "pi" => "π",
+        "rho" => "ρ",
+        "sigma" => "σ",
+        "tau" => "τ",
+        "upsilon" => "υ",
+        "phi" | "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Xi" => "Ξ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        "infty" => "∞",
+        "pm" => "±",
+        "mp" => "∓",
+        "times" => "×",
+        "cdot" => "·",
+        "div" => "÷",
+        "le" | "leq" => "≤",
+        "ge" | "geq" => "≥",
+        "ne" | "neq" => "≠",
+        "ll" => "≪",
+        "gg" => "≫",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "simeq" => "≃",
+        "cong" => "≅",
+        "propto" => "∝",
+        "to" | "rightarrow" => "→",
+        "leftarrow" | "gets" => "←",
+        "leftrightarrow" => "↔",
+        "Rightarrow" => "⇒",
+        "Leftarrow" => "⇐",
+        "Leftrightarrow" | "iff" => "⇔",
+        "implies" => "⟹",
+        "mapsto" => "↦",
+        "in" => "∈",
+        "notin" => "∉",
+        "ni" => "∋",
+        "subset" => "⊂",
+        "subseteq" => "⊆",
+        "supset" => "⊃",
+        "supseteq" => "⊇",
+        "cup" => "∪",
+        "cap" => "∩",
+        "setminus" => "∖",
+        "emptyset" | "varnothing" => "∅",
+        "forall" => "∀",
+        "exists" => "∃",
+        "neg" | "lnot" => "¬",
+        "land" | "wedge" => "∧",
+        "lor" | "vee" => "∨",
+        "oplus" => "⊕",
+        "otimes" => "⊗",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "sum" => "∑",
+        "prod" => "∏",
+        "int" => "∫",
+        "iint" => "∬",
+        "oint" => "∮",
+        "ldots" | "dots" => "…",
+        "cdots" => "⋯",
+        "circ" => "∘",
+        "bullet" => "•",
+        "star" => "⋆",
+        "degree" => "°",
+        "prime" => "′",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "lfloor" => "⌊",
+        "rfloor" => "⌋",
+        "lceil" => "⌈",
+        "rceil" => "⌉",
+        "hbar" => "ℏ",
+        "ell" => "ℓ",
+        "aleph" => "ℵ",
+        "quad" => "  ",
+        "qquad" => "    ",
+        // operator names are just upright text
+        "sin" => "sin",
+        "cos" => "cos",
+        "tan" => "tan",
+        "log" => "log",
+        "ln" => "ln",
+        "exp" => "exp",
+        "lim" => "lim",
+        "max" => "max",
+        "min" => "min",
+        "det" => "det",
+        "mod" => "mod",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_need_tight_dollars() {
+        assert_eq!(
+            segments("area $\\pi r^2$ and $$x_1$$."),
+            [
+                Segment::Text("area "),
+                Segment::Math("π r²".to_string()),
+                Segment::Text(" and "),
+                Segment::Math("x₁".to_string()),
+                Segment::Text("."),
+            ]
+        );
+        // prices, spaces inside and escaped dollars stay text
+        for text in ["$5 or $10", "from $ 5 to 10 $", "\\$x$ and", "$$ $$", "$x"] {
+            assert_eq!(segments(text), [Segment::Text(text)], "{text}");
+        }
+    }
+
+    #[test]
+    fn commands_and_scripts_are_approximated() {
+        assert_eq!(to_unicode("\\frac{a+b}{2}"), "(a+b)/2");
+        assert_eq!(to_unicode("\\frac12"), "1/2");
+        assert_eq!(to_unicode("\\sqrt[3]{x} \\cdot \\sqrt{2}"), "∛x · √2");
+        assert_eq!(to_unicode("\\sum_{i=1}^n i \\leq \\infty"), "∑ᵢ₌₁ⁿ i ≤ ∞");
+        assert_eq!(to_unicode("\\mathbb{R} \\to \\mathbb R"), "ℝ → ℝ");
+        // scripts without a Unicode form keep their marker
+        assert_eq!(to_unicode("x^q y_{qz}"), "x^q y_(qz)");
+        assert_eq!(to_unicode("\\text{if } \\unknown"), "if  \\unknown");
+    }
+}
@@ -0,0 +1,631 @@
+//! Finding servers on the local network with multicast DNS, `--discover`.
+//!
+//! A server started with `--discover` answers the DNS-SD queries for `_chatterbox._tcp.local`
+//! with a PTR record naming its instance, the SRV record with its port, a TXT record with its
+//! `name=` and the `caps=` it was started with, and the addresses of the host. Clients started
+//! with `--discover` instead of an address ask for the service and let the user pick one.
+//!
+//! Unlike with [`discovery`](crate::discovery), other mDNS browsers such as `avahi-browse` see
+//! the servers as well.
+
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use crate::{discovery, tasks};
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+const SERVICE: [&str; 3] = ["_chatterbox", "_tcp", "local"];
+
+const A: u16 = 1;
+const PTR: u16 = 12;
+const TXT: u16 = 16;
+const SRV: u16 = 33;
+const ANY: u16 = 255;
+const IN: u16 = 1;
+/// In the class of a record: it's ours alone. In the class of a question: answer by unicast.
+const UNIQUE: u16 = 0x8000;
+/// Flags of an authoritative answer.
+const RESPONSE: u16 = 0x8400;
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// How long the answers to a query are waited for, before asking again.
+const ROUND: Duration = Duration::from_secs(2);
+
+/// What the server tells those looking for it.
+#[derive(Debug, Clone, Default)]
+pub struct Advertisement {
+    pub name: String,
+    pub port: u16,
+    pub capabilities: Vec<String>,
+}
+
+/// Server which answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// Instance name, unique on the network
+    pub instance: String,
+    pub name: String,
+    pub address: SocketAddr,
+    pub capabilities: Vec<String>,
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.name, self.address)?;
+        if !self.capabilities.is_empty() {
+            write!(f, " ({})", self.capabilities.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+type Name = Vec<String>;
+
+fn service() -> Name {
+    SERVICE.map(String::from).to_vec()
+}
+
+fn same(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn put_name(buf: &mut Vec<u8>, name: &[String]) {
+    for label in name {
+        // labels are 63 bytes at most
+        let mut end = label.len().min(63);
+        while !label.is_char_boundary(end) {
+            end -= 1;
+        }
+        buf.push(end as u8);
+        buf.extend_from_slice(&label.as_bytes()[..end]);
+    }
+    buf.push(0);
+}
+
+fn put_record(buf: &mut Vec<u8>, name: &[String], kind: u16, class: u16, ttl: u32, data: &[u8]) {
+    put_name(buf, name);
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&class.to_be_bytes());
+    buf.extend_from_slice(&ttl.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+struct Question {
+    name: Name,
+    kind: u16,
+    class: u16,
+}
+
+enum Data {
+    Ptr(Name),
+    Srv { port: u16 },
+    Txt(Vec<String>),
+    Other,
+}
+
+struct Record {
+    name: Name,
+    data: Data,
+}
+
+struct Message {
+    id: u16,
+    flags: u16,
+    questions: Vec<Question>,
+    /// Answers and additional records alike
+    records: Vec<Record>,
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.packet.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn name(&mut self) -> Option<Name> {
+        let mut name = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // pointers could loop forever
+        for _ in 0..128 {
+            let len = *self.packet.get(pos)? as usize;
+            match len {
+                0 => {
+                    if !jumped {
+                        self.pos = pos + 1;
+                    }
+                    return Some(name);
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *self.packet.get(pos + 1)? as usize;
+                    if !jumped {
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+                    pos = (len & 0x3f) << 8 | low;
+                }
+                len => {
+                    let label = self.packet.get(pos + 1..pos + 1 + len)?;
+                    name.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+            }
+        }
+        None
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = self.name()?;
+        let kind = self.u16()?;
+        // class and ttl
+        self.bytes(6)?;
+        let len = self.u16()? as usize;
+        let end = self.pos + len;
+        let data = match kind {
+            PTR => Data::Ptr(self.name()?),
+            SRV => {
+                // priority and weight
+                self.bytes(4)?;
+                Data::Srv { port: self.u16()? }
+            }
+            TXT => {
+                let mut strings = Vec::new();
+                while self.pos < end {
+                    let len = *self.bytes(1)?.first()? as usize;
+                    strings.push(String::from_utf8_lossy(self.bytes(len)?).into_owned());
+                }
+                Data::Txt(strings)
+            }
+            _ => Data::Other,
+        };
+        if end > self.packet.len() {
+            return None;
+        }
+        self.pos = end;
+        Some(Record { name, data })
+    }
+}
+
+fn parse(packet: &[u8]) -> Option<Message> {
+    let mut reader = Reader { packet, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let questions = reader.u16()?;
+    let records = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+    let questions = (0..questions)
+        .map(|_| {
+            Some(Question {
+                name: reader.name()?,
+                kind: reader.u16()?,
+                class: reader.u16()?,
+            })
+        })
+        .collect::<Option<_>>()?;
+    // whatever can't be read of the records is left out
+    let records = (0..records).map_while(|_| reader.record()).collect();
+    Some(Message {
+        id,
+        flags,
+        questions,
+        records,
+    })
+}
+
+fn query(id: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for field in [id, 0, 1, 0, 0, 0] {
+        buf.extend_from_slice(&field.to_be_bytes());
+    }
+    put_name(&mut buf, &service());
+    buf.extend_from_slice(&PTR.to_be_bytes());
+    buf.extend_from_slice(&IN.to_be_bytes());
+    buf
+}
+
+/// Names and records of the advertised server.
+struct Responder {
+    advertisement: Advertisement,
+    instance: Name,
+    host: Name,
+}
+
+impl Responder {
+    /// Whether anything in `question` is about us.
+    fn is_asked(&self, question: &Question) -> bool {
+        (same(&question.name, &service()) && matches!(question.kind, PTR | ANY))
+            || same(&question.name, &self.instance)
+            || (same(&question.name, &self.host) && matches!(question.kind, A | ANY))
+    }
+
+    /// Everything about us, repeating `questions` for those which asked the old fashioned way.
+    fn response(&self, id: u16, questions: &[Question]) -> Vec<u8> {
+        let addresses = local_addresses();
+        // nor do they know about records being ours alone
+        let unique = if questions.is_empty() { UNIQUE } else { 0 };
+        let mut buf = Vec::new();
+        let counts = [questions.len(), 1, 0, 2 + addresses.len()];
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&RESPONSE.to_be_bytes());
+        for count in counts {
+            buf.extend_from_slice(&(count as u16).to_be_bytes());
+        }
+        for question in questions {
+            put_name(&mut buf, &question.name);
+            buf.extend_from_slice(&question.kind.to_be_bytes());
+            buf.extend_from_slice(&(question.class & !UNIQUE).to_be_bytes());
+        }
+
+        let mut instance = Vec::new();
+        put_name(&mut instance, &self.instance);
+        put_record(&mut buf, &service(), PTR, IN, SERVICE_TTL, &instance);
+
+        let mut srv = vec![0; 4];
+        srv.extend_from_slice(&self.advertisement.port.to_be_bytes());
+        put_name(&mut srv, &self.host);
+        put_record(&mut buf, &self.instance, SRV, IN | unique, HOST_TTL, &srv);
+
+        let ad = &self.advertisement;
+        let mut txt = Vec::new();
+        for entry in [
+            format!("name={}", ad.name),
+            format!("caps={}", ad.capabilities.join(",")),
+        ] {
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            txt.push(entry.len() as u8);
+            txt.extend_from_slice(entry);
+        }
+        put_record(
+            &mut buf,
+            &self.instance,
+            TXT,
+            IN | unique,
+            SERVICE_TTL,
+            &txt,
+        );
+
+        for address in addresses {
+            put_record(
+                &mut buf,
+                &self.host,
+                A,
+                IN | unique,
+                HOST_TTL,
+                &address.octets(),
+            );
+        }
+        buf
+    }
+}
+
+/// Answers the queries for the service in the background, after announcing it once.
+pub fn advertise(advertisement: Advertisement) -> io::Result<()> {
+    let socket = discovery::shared_socket(PORT)?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    let host = hostname();
+    let mut instance = service();
+    instance.insert(0, format!("{} on {host}", advertisement.name));
+    let responder = Responder {
+        advertisement,
+        instance,
+        host: vec![host, "local".to_string()],
+    };
+    socket.send_to(&responder.response(0, &[]), (GROUP, PORT))?;
+    tasks::spawn("mdns", move || {
+        let mut buf = [0; 9000];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Failed to receive mDNS queries: {e}");
+                    return;
+                }
+            };
+            let Some(message) = parse(&buf[..len]) else {
+                continue;
+            };
+            // answers of others
+            if message.flags & 0x8000 != 0 {
+                continue;
+            }
+            let asked: Vec<_> = message
+                .questions
+                .into_iter()
+                .filter(|q| responder.is_asked(q))
+                .collect();
+            if asked.is_empty() {
+                continue;
+            }
+            debug!("asked by {from}");
+            // queries from other ports don't speak multicast DNS, they get a plain DNS answer
+            let legacy = from.port() != PORT;
+            let res = if legacy || asked.iter().any(|q| q.class & UNIQUE != 0) {
+                let questions = if legacy { &asked[..] } else { &[] };
+                socket.send_to(&responder.response(message.id, questions), from)
+            } else {
+                socket.send_to(&responder.response(0, &[]), (GROUP, PORT))
+            };
+            if let Err(e) = res {
+                warn!("Failed to answer {from}: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Servers described by `message`, which came from `ip`.
+fn services_in(message: &Message, ip: IpAddr) -> Vec<Service> {
+    let instances = message.records.iter().filter_map(|r| match &r.data {
+        Data::Ptr(instance) if same(&r.name, &service()) => Some(instance),
+        _ => None,
+    });
+    instances
+        .filter_map(|instance| {
+            let about = || message.records.iter().filter(|r| same(&r.name, instance));
+            let port = about().find_map(|r| match r.data {
+                Data::Srv { port } => Some(port),
+                _ => None,
+            })?;
+            let txt = about()
+                .find_map(|r| match &r.data {
+                    Data::Txt(strings) => Some(strings.as_slice()),
+                    _ => None,
+                })
+                .unwrap_or_default();
+            let field = |key: &str| {
+                txt.iter()
+                    .find_map(|s| s.strip_prefix(key)?.strip_prefix('='))
+                    .unwrap_or_default()
+            };
+            let label = instance.first().cloned().unwrap_or_default();
+            Some(Service {
+                name: Some(field("name"))
+                    .filter(|n| !n.is_empty())
+                    .map_or_else(|| label.clone(), str::to_string),
+                instance: label,
+                address: SocketAddr::new(ip, port),
+                capabilities: field("caps")
+                    .split(',')
+                    .filter(|c| !c.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Asks for the servers on the local network again and again, handing all that answered so far
+/// to `found` after every round. Stops once nobody listens on `found` anymore.
+pub fn browse(found: mpsc::Sender<io::Result<Vec<Service>>>) {
+    tasks::spawn("mdns browser", move || {
+        let mut services = Vec::new();
+        let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+            Ok(socket) => socket,
+            Err(e) => return drop(found.send(Err(e))),
+        };
+        loop {
+            let res = round(&socket, &mut services).map(|_| services.clone());
+            let failed = res.is_err();
+            if found.send(res).is_err() || failed {
+                return;
+            }
+        }
+    });
+}
+
+/// Sends a query and collects the answers to it.
+fn round(socket: &UdpSocket, services: &mut Vec<Service>) -> io::Result<()> {
+    // sent from a port of our own, the answers come straight back to it
+    let id = std::process::id() as u16;
+    socket.send_to(&query(id), (GROUP, PORT))?;
+    let until = Instant::now() + ROUND;
+    let mut buf = [0; 9000];
+    while let Some(left) = until.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(message) = parse(&buf[..len]) else {
+            continue;
+        };
+        for found in services_in(&message, from.ip()) {
+            match services
+                .iter_mut()
+                .find(|s| s.instance == found.instance && s.address.ip() == found.address.ip())
+            {
+                Some(known) => *known = found,
+                None => services.push(found),
+            }
+        }
+    }
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(())
+}
+
+/// Name of this machine, without any domain.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its length
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "chatterbox".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]);
+    name.split('.').next().unwrap_or_default().to_string()
+}
+
+/// IPv4 addresses of the network interfaces, but for loopback.
+fn local_addresses() -> Vec<Ipv4Addr> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: the list is freed below
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Vec::new();
+    }
+    let mut addresses = Vec::new();
+    let mut entry = list;
+    while !entry.is_null() {
+        // SAFETY: the entries stay valid until the list is freed
+        let ifa = unsafe { &*entry };
+        // SAFETY: an address of the AF_INET family is a sockaddr_in
+        let ip = (!ifa.ifa_addr.is_null()
+            && i32::from(unsafe { (*ifa.ifa_addr).sa_family }) == libc::AF_INET)
+            .then(|| unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in>() })
+            .map(|sin| Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)));
+        if let Some(ip) = ip.filter(|ip| !ip.is_loopback()) {
+            addresses.push(ip);
+        }
+        entry = ifa.ifa_next;
+    }
+    // SAFETY: allocated by getifaddrs above, not used anymore
+    unsafe { libc::freeifaddrs(list) };
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn responder() -> Responder {
+        let mut instance = service();
+        instance.insert(0, "ada on box".to_string());
+        Responder {
+            advertisement: Advertisement {
+                name: "ada".to_string(),
+                port: 8989,
+                capabilities: vec!["tls".to_string(), "talk".to_string()],
+            },
+            instance,
+            host: vec!["box".to_string(), "local".to_string()],
+        }
+    }
+
+    fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for field in [0, flags, questions, answers, 0, 0] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn queries_ask_for_the_service() {
+        let message = parse(&query(7)).unwrap();
+        assert_eq!((message.id, message.flags), (7, 0));
+        let question = &message.questions[0];
+        assert!(same(
+            &question.name,
+            &["_CHATTERBOX", "_tcp", "local"].map(String::from)
+        ));
+        assert_eq!((question.kind, question.class), (PTR, IN));
+        assert!(message.records.is_empty());
+
+        let responder = responder();
+        assert!(responder.is_asked(question));
+        let about_host = |kind| Question {
+            name: responder.host.clone(),
+            kind,
+            class: IN,
+        };
+        assert!(responder.is_asked(&about_host(A)));
+        assert!(!responder.is_asked(&about_host(TXT)));
+    }
+
+    #[test]
+    fn responses_describe_the_server() {
+        let responder = responder();
+        let message = parse(&responder.response(0, &[])).unwrap();
+        assert_eq!(message.flags, RESPONSE);
+        let ip = IpAddr::from([192, 168, 1, 2]);
+        assert_eq!(
+            services_in(&message, ip),
+            [Service {
+                instance: "ada on box".to_string(),
+                name: "ada".to_string(),
+                address: SocketAddr::new(ip, 8989),
+                capabilities: vec!["tls".to_string(), "talk".to_string()],
+            }]
+        );
+
+        // plain DNS gets its questions back, without the unicast bit
+        let question = Question {
+            name: service(),
+            kind: PTR,
+            class: IN | UNIQUE,
+        };
+        let message = parse(&responder.response(9, &[question])).unwrap();
+        assert_eq!(message.id, 9);
+        assert_eq!(message.questions[0].class, IN);
+    }
+
+    #[test]
+    fn names_follow_compression_pointers() {
+        let mut packet = header(RESPONSE, 0, 1);
+        put_name(&mut packet, &service());
+        packet.extend_from_slice(&[0, PTR as u8, 0, 1, 0, 0, 0, 120, 0, 4]);
+        // "x" then the rest of the name at offset 12
+        packet.extend_from_slice(&[1, b'x', 0xc0, 12]);
+        let message = parse(&packet).unwrap();
+        let Data::Ptr(name) = &message.records[0].data else {
+            panic!("not a PTR record");
+        };
+        assert_eq!(name, &["x", "_chatterbox", "_tcp", "local"]);
+
+        // a pointer to itself is no name
+        let mut looping = header(0, 1, 0);
+        looping.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+        assert!(parse(&looping).is_none());
+        let mut looping = header(RESPONSE, 0, 1);
+        looping.extend_from_slice(&[1, b'a', 0xc0, 12]);
+        assert!(parse(&looping).unwrap().records.is_empty());
+    }
+
+    #[test]
+    fn broken_packets_are_left_out() {
+        let query = query(1);
+        assert!(parse(&query[..query.len() - 1]).is_none());
+        assert!(parse(&[0; 5]).is_none());
+        // a label running past the end
+        let mut packet = header(0, 1, 0);
+        packet.extend_from_slice(&[10, b'a']);
+        assert!(parse(&packet).is_none());
+
+        // only the records read whole are kept
+        let response = responder().response(0, &[]);
+        let message = parse(&response[..response.len() - 3]).unwrap();
+        let whole = parse(&response).unwrap();
+        assert_eq!(message.records.len(), whole.records.len() - 1);
+        // the data claiming more than there is
+        let mut packet = header(RESPONSE, 0, 1);
+        put_name(&mut packet, &service());
+        packet.extend_from_slice(&[0, 99, 0, 1, 0, 0, 0, 120, 0xff, 0xff, 1, 2]);
+        assert!(parse(&packet).unwrap().records.is_empty());
+    }
+}
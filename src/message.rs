@@ -0,0 +1,389 @@
+use std::{cell::RefCell, sync::Arc};
+
+use ratatui::prelude::*;
+
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    ansi, bidi, diff, json,
+    math::{self, Segment},
+    protocol::Envelope,
+    relay::Delivery,
+    table,
+    theme::Theme,
+    timestamp, ui,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Incoming,
+    Outgoing,
+    /// Generated locally, e.g. connection events or reminders
+    System,
+}
+
+/// How entries are drawn, the same for all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Render {
+    /// strftime format of the time shown in front of every entry, none without it
+    pub timestamps: Option<Arc<str>>,
+    /// Text as typed, without rendering math, tables or right to left lines
+    pub raw: bool,
+    /// Columns wide tables are scrolled to the left
+    pub table_scroll: usize,
+    /// Width of the pane, right to left lines are aligned to its right edge
+    pub width: usize,
+    /// Interpret ANSI colors in incoming messages
+    pub ansi: bool,
+    pub theme: Arc<Theme>,
+}
+
+/// Entry of the messages pane.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub kind: Kind,
+    pub text: String,
+    /// Milliseconds since the unix epoch it was sent or received at
+    pub at: u64,
+    /// Outgoing message waiting in the outbox
+    pub queued: bool,
+    /// Incoming message which failed authentication
+    pub unauthenticated: bool,
+    /// Matched a highlight trigger
+    pub highlighted: bool,
+    /// Id the relay acknowledges an outgoing message with
+    pub relay_id: Option<u64>,
+    /// Last state reported by the relay, or by the peer itself
+    pub delivery: Option<Delivery>,
+    /// Number the peer acknowledges an outgoing message with
+    pub id: Option<u64>,
+    /// Who sent an incoming message and when, if the peer told
+    pub envelope: Option<Envelope>,
+    /// Milliseconds the clock of the sender was ahead of ours when it arrived
+    pub skew: i64,
+    /// Name shown in front of the text instead of an arrow
+    pub author: Option<String>,
+    /// Annotation for bots, never shown
+    pub data: Option<json::Value>,
+    /// Room of a `--multi` server it was written in
+    pub room: Option<String>,
+    /// Text before the last edit
+    pub edited_from: Option<String>,
+    /// Whether the changes of the last edit are shown
+    pub show_diff: bool,
+    /// Last rendering, reused until the pane is resized or the message changes
+    cache: RefCell<Option<(CacheKey, Text<'static>)>>,
+}
+
+/// Everything the rendering depends on besides the text, which only changes through
+/// [`Message::edit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CacheKey {
+    render: Render,
+    queued: bool,
+    unauthenticated: bool,
+    highlighted: bool,
+    delivery: Option<Delivery>,
+    show_diff: bool,
+}
+
+impl Message {
+    pub fn incoming(text: String) -> Self {
+        Self::new(Kind::Incoming, text)
+    }
+
+    pub fn outgoing(text: String) -> Self {
+        Self::new(Kind::Outgoing, text)
+    }
+
+    pub fn system(text: String) -> Self {
+        Self::new(Kind::System, text)
+    }
+
+    pub fn new(kind: Kind, text: String) -> Self {
+        Self {
+            kind,
+            text,
+            at: timestamp::now_millis(),
+            queued: false,
+            unauthenticated: false,
+            highlighted: false,
+            relay_id: None,
+            delivery: None,
+            id: None,
+            envelope: None,
+            skew: 0,
+            author: None,
+            data: None,
+            room: None,
+            edited_from: None,
+            show_diff: false,
+            cache: RefCell::default(),
+        }
+    }
+
+    /// Replaces the text, keeping the previous one to show what changed.
+    pub fn edit(&mut self, text: String) {
+        self.edited_from = Some(std::mem::replace(&mut self.text, text));
+        self.cache.take();
+    }
+
+    /// Replaces the text as if it had always been this, like the progress of a transfer.
+    pub fn set_text(&mut self, text: String) {
+        self.text = text;
+        self.cache.take();
+    }
+
+    fn cache_key(&self, render: &Render) -> CacheKey {
+        CacheKey {
+            render: render.clone(),
+            queued: self.queued,
+            unauthenticated: self.unauthenticated,
+            highlighted: self.highlighted,
+            delivery: self.delivery,
+            show_diff: self.show_diff,
+        }
+    }
+
+    /// Lines taken by the message.
+    pub fn height(&self, render: &Render) -> usize {
+        self.cached(render, |text| text.height())
+    }
+
+    pub fn to_text(&self, render: &Render) -> Text<'static> {
+        self.cached(render, Text::clone)
+    }
+
+    /// Runs `f` on the rendered message, rendering it again only if something changed.
+    fn cached<R>(&self, render: &Render, f: impl FnOnce(&Text<'static>) -> R) -> R {
+        let key = self.cache_key(render);
+        let mut cache = self.cache.borrow_mut();
+        match &*cache {
+            Some((cached, text)) if *cached == key => f(text),
+            _ => {
+                let text = self.render(render);
+                let result = f(&text);
+                *cache = Some((key, text));
+                result
+            }
+        }
+    }
+
+    /// Text as a single line of a log, after the name of the author if there is one.
+    pub fn line(&self) -> String {
+        match &self.author {
+            Some(author) => format!("{author}: {}", self.text),
+            None => self.text.clone(),
+        }
+    }
+
+    fn render(&self, render: &Render) -> Text<'static> {
+        let author = self
+            .author
+            .as_ref()
+            .map(|a| format!("{}: ", ansi::sanitize(a)));
+        let prefix = match (&author, self.kind) {
+            (Some(author), _) => author.as_str(),
+            (None, Kind::Incoming) => "<-- ",
+            (None, Kind::Outgoing) => "--> ",
+            (None, Kind::System) => "*** ",
+        };
+        let theme = &render.theme;
+        let stamp = render
+            .timestamps
+            .as_ref()
+            .map(|format| format!("[{}] ", timestamp::format_local(self.at, format)));
+        let mut spans: Vec<Span> = stamp
+            .iter()
+            .map(|stamp| Span::styled(stamp.clone(), theme.timestamp))
+            .collect();
+        spans.push(match (&author, self.kind) {
+            (_, Kind::System) => Span::styled(prefix.to_string(), theme.system),
+            (Some(_), Kind::Incoming) => {
+                let name = self.author.as_deref().unwrap_or_default();
+                Span::styled(prefix.to_string(), theme.sender(name))
+            }
+            (Some(_), Kind::Outgoing) => Span::styled(prefix.to_string(), theme.own),
+            (None, _) => Span::raw(prefix.to_string()),
+        });
+        if self.queued {
+            spans.push(Span::styled("[queued] ", theme.muted));
+        }
+        match self.delivery {
+            None => {}
+            Some(Delivery::Stored) => spans.push(Span::styled("[on relay] ", theme.muted)),
+            Some(Delivery::Delivered) => spans.push(Span::styled("✓ ", theme.success)),
+            Some(Delivery::Seen) => spans.push(Span::styled("✓✓ ", theme.success)),
+            Some(Delivery::Expired) => spans.push(Span::styled("[expired] ", theme.error)),
+            Some(Delivery::Rejected) => {
+                spans.push(Span::styled("[rejected by relay] ", theme.error))
+            }
+        }
+        if self.unauthenticated {
+            spans.push(Span::styled("[unauthenticated] ", theme.error));
+        }
+        let style = if self.highlighted {
+            theme.highlight
+        } else if self.kind == Kind::System {
+            theme.system
+        } else {
+            Style::default()
+        };
+        if self.edited_from.is_some() && !self.show_diff {
+            spans.push(Span::styled("[edited] ", theme.muted));
+        }
+        // continuation lines line up with the first one
+        let indent = " ".repeat(stamp.as_deref().map_or(0, str::width) + prefix.width());
+        if let (Some(old), true) = (&self.edited_from, self.show_diff) {
+            let mut lines = Vec::new();
+            for change in diff::words(&ansi::sanitize(old), &ansi::sanitize(&self.text)) {
+                let (text, style) = match change {
+                    diff::Change::Same(text) => (text, style),
+                    diff::Change::Removed(text) => (
+                        text,
+                        style.patch(theme.error).add_modifier(Modifier::CROSSED_OUT),
+                    ),
+                    diff::Change::Added(text) => (
+                        text,
+                        style.patch(theme.success).add_modifier(Modifier::BOLD),
+                    ),
+                };
+                for (i, part) in text.split('\n').enumerate() {
+                    if i > 0 {
+                        lines.push(Line::from(std::mem::replace(
+                            &mut spans,
+                            vec![Span::raw(indent.clone())],
+                        )));
+                    }
+                    spans.push(Span::styled(part.to_string(), style));
+                }
+            }
+            lines.push(Line::from(spans));
+            return wrapped(lines, render.width, indent.len());
+        }
+        let mut sgr = (render.ansi && self.kind == Kind::Incoming).then(ansi::Sgr::default);
+        // escape codes are left in for the colors, the rest of them is dropped on the way
+        let text = match sgr {
+            Some(_) => self.text.as_str().into(),
+            None => ansi::sanitize(&self.text),
+        };
+        if let Some(table) = (!render.raw).then(|| table::detect(&text)).flatten() {
+            let mut lines = vec![Line::from(spans)];
+            lines.extend(table.lines(&indent, render.table_scroll));
+            return Text::from(lines);
+        }
+        let mut lines = Vec::new();
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                lines.push(Line::from(std::mem::replace(
+                    &mut spans,
+                    vec![Span::raw(indent.clone())],
+                )));
+            }
+            if render.raw {
+                spans.push(Span::styled(line.to_string(), style));
+                continue;
+            }
+            if let Some(sgr) = &mut sgr {
+                spans.extend(sgr.spans(line, style));
+                continue;
+            }
+            if bidi::contains_rtl(line) {
+                let line = bidi::reorder(line);
+                if bidi::is_rtl_paragraph(&line) {
+                    let used: usize = spans.iter().map(Span::width).sum::<usize>() + line.width();
+                    spans.push(Span::raw(" ".repeat(render.width.saturating_sub(used))));
+                }
+                spans.push(Span::styled(line, style));
+                continue;
+            }
+            spans.extend(
+                math::segments(line)
+                    .into_iter()
+                    .map(|segment| match segment {
+                        Segment::Text(text) => Span::styled(text.to_string(), style),
+                        Segment::Math(math) => {
+                            Span::styled(math, style.add_modifier(Modifier::ITALIC))
+                        }
+                    }),
+            );
+        }
+        lines.push(Line::from(spans));
+        wrapped(lines, render.width, indent.len())
+    }
+}
+
+/// Lines wrapped to the width of the pane, continuing under the first one.
+fn wrapped(lines: Vec<Line<'static>>, width: usize, indent: usize) -> Text<'static> {
+    Text::from(
+        lines
+            .into_iter()
+            .flat_map(|line| ui::wrap(line, width, indent))
+            .collect::<Vec<_>>(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(width: usize) -> Render {
+        Render {
+            timestamps: None,
+            raw: false,
+            table_scroll: 0,
+            width,
+            ansi: false,
+            theme: Arc::default(),
+        }
+    }
+
+    fn shown(message: &Message, render: &Render) -> String {
+        message
+            .to_text(render)
+            .lines
+            .iter()
+            .flat_map(|line| line.spans.iter().map(|s| s.content.as_ref()))
+            .collect()
+    }
+
+    #[test]
+    fn renderings_are_reused_until_something_changes() {
+        let mut message = Message::incoming("hello".to_string());
+        assert_eq!(shown(&message, &render(80)), "<-- hello");
+
+        // the text only changes through edit and set_text
+        message.text = "stale".to_string();
+        assert_eq!(shown(&message, &render(80)), "<-- hello");
+        message.set_text("hello there".to_string());
+        assert_eq!(shown(&message, &render(80)), "<-- hello there");
+        assert_eq!(message.height(&render(80)), 1);
+        assert!(message.height(&render(8)) > 1);
+
+        message.edit("bye".to_string());
+        assert_eq!(message.edited_from.as_deref(), Some("hello there"));
+        assert!(shown(&message, &render(80)).contains("bye"));
+        message.highlighted = true;
+        assert_eq!(message.to_text(&render(80)), message.render(&render(80)));
+    }
+
+    #[test]
+    fn authors_are_shown_in_front_of_their_text() {
+        let mut message = Message::incoming("hi".to_string());
+        message.author = Some("ada\u{1b}[2J".to_string());
+        assert_eq!(shown(&message, &render(80)), "ada␛[2J: hi");
+        assert_eq!(message.line(), "ada\u{1b}[2J: hi");
+        assert_eq!(Message::outgoing("hi".to_string()).line(), "hi");
+    }
+
+    #[test]
+    fn timestamps_go_in_front() {
+        let mut message = Message::system("connected".to_string());
+        message.at = 1_709_210_096_000;
+        let render = Render {
+            timestamps: Some("%M:%S".into()),
+            ..render(80)
+        };
+        assert_eq!(shown(&message, &render), "[34:56] *** connected");
+    }
+}
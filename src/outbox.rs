@@ -0,0 +1,106 @@
+//! Messages composed while there was no connection, kept on disk until they are delivered.
+//!
+//! Every conversation has an outbox of its own, what was queued for one peer never goes out to
+//! another.
+
+use std::{collections::VecDeque, fs, io::Write, path::PathBuf};
+
+use tracing::{error, instrument, warn};
+
+use crate::{
+    protocol::{escape, unescape},
+    store,
+};
+
+#[derive(Debug, Default)]
+pub struct Outbox {
+    /// Backing file, one message per line. `None` keeps the outbox in memory only.
+    path: Option<PathBuf>,
+    pending: VecDeque<String>,
+}
+
+impl Outbox {
+    /// Loads the messages left over from a previous run in `conversation`.
+    #[instrument]
+    pub fn load(conversation: &str) -> Self {
+        let Some(data) = crate::dirs::data_dir() else {
+            warn!("Couldn't determine data directory, outbox won't be persisted");
+            return Self::default();
+        };
+        let legacy = data.join("outbox");
+        if legacy.exists() {
+            warn!(
+                "Ignoring {}, an outbox of an older version which doesn't say for whom it is",
+                legacy.display()
+            );
+        }
+        let path = data.join("outboxes").join(store::file_name(conversation));
+        let pending = match fs::read_to_string(&path) {
+            Ok(content) => content.lines().map(unescape).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => {
+                error!("Failed to read outbox {}: {e}", path.display());
+                VecDeque::new()
+            }
+        };
+        Self {
+            path: Some(path),
+            pending,
+        }
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &str> {
+        self.pending.iter().map(String::as_str)
+    }
+
+    pub fn front(&self) -> Option<&str> {
+        self.pending.front().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues a message and appends it to the backing file.
+    pub fn push(&mut self, msg: String) {
+        if let Some(path) = &self.path {
+            let res = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(path))
+                .and_then(|mut file| writeln!(file, "{}", escape(&msg)));
+            if let Err(e) = res {
+                error!("Failed to persist queued message: {e}");
+            }
+        }
+        self.pending.push_back(msg);
+    }
+
+    /// Drops the oldest message after it has been delivered.
+    pub fn pop_front(&mut self) {
+        self.pending.pop_front();
+    }
+
+    /// Rewrites the backing file with the messages still pending.
+    pub fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let res = if self.pending.is_empty() {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                res => res,
+            }
+        } else {
+            let content: String = self
+                .pending
+                .iter()
+                .map(|m| format!("{}\n", escape(m)))
+                .collect();
+            fs::write(path, content)
+        };
+        if let Err(e) = res {
+            error!("Failed to update outbox {}: {e}", path.display());
+        }
+    }
+}
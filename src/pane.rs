@@ -0,0 +1,345 @@
+//! Read-only sharing of a command's terminal with the peer.
+//!
+//! The command runs in a pseudo terminal of a fixed size, its output is drawn onto a screen
+//! understanding the usual vt100 cursor movement and erasing, and whenever that changes the
+//! whole screen is sent as a `\u{1}PANE` control line.
+
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::{fs::OpenOptionsExt, process::CommandExt},
+    },
+    process::{Command, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::{debug, error};
+
+use crate::{protocol, tasks};
+
+pub const ROWS: u16 = 24;
+pub const COLUMNS: u16 = 80;
+/// How often the screen is sent at most
+const FRAME_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// Escape sequence taking one more character, like a charset selection
+    EscapeArgument,
+    Csi,
+    /// Operating system command, e.g. the window title, ends with BEL or ST
+    Osc,
+}
+
+/// Characters on the terminal of the shared command, without colors.
+#[derive(Debug)]
+pub struct Screen {
+    cells: Vec<Vec<char>>,
+    row: usize,
+    column: usize,
+    state: State,
+    params: String,
+    /// Start of a character split between two reads
+    partial: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(rows: usize, columns: usize) -> Self {
+        Self {
+            cells: vec![vec![' '; columns]; rows],
+            row: 0,
+            column: 0,
+            state: State::Ground,
+            params: String::new(),
+            partial: Vec::new(),
+        }
+    }
+
+    fn columns(&self) -> usize {
+        self.cells[0].len()
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.partial.extend_from_slice(bytes);
+        let valid = match std::str::from_utf8(&self.partial) {
+            Ok(s) => s.len(),
+            // invalid bytes are dropped, an incomplete character waits for the next read
+            Err(e) if e.error_len().is_some() => e.valid_up_to() + e.error_len().unwrap_or(0),
+            Err(e) => e.valid_up_to(),
+        };
+        let rest = self.partial.split_off(valid);
+        let text = String::from_utf8_lossy(&self.partial).into_owned();
+        self.partial = rest;
+        for c in text.chars() {
+            self.put(c);
+        }
+    }
+
+    fn put(&mut self, c: char) {
+        match (self.state, c) {
+            (State::Ground, '\x1b') => self.state = State::Escape,
+            (State::Ground, '\r') => self.column = 0,
+            (State::Ground, '\n' | '\x0b' | '\x0c') => self.line_feed(),
+            (State::Ground, '\x08') => self.column = self.column.saturating_sub(1),
+            (State::Ground, '\t') => {
+                self.column = ((self.column / 8 + 1) * 8).min(self.columns() - 1)
+            }
+            (State::Ground, c) if c.is_control() => {}
+            (State::Ground, c) => {
+                if self.column >= self.columns() {
+                    self.column = 0;
+                    self.line_feed();
+                }
+                self.cells[self.row][self.column] = c;
+                self.column += 1;
+            }
+            (State::Escape, '[') => {
+                self.params.clear();
+                self.state = State::Csi;
+            }
+            (State::Escape, ']') => self.state = State::Osc,
+            (State::Escape, '(' | ')' | '*' | '+' | '#') => self.state = State::EscapeArgument,
+            (State::Escape, 'c') => *self = Self::new(self.cells.len(), self.columns()),
+            (State::Escape | State::EscapeArgument, _) => self.state = State::Ground,
+            (State::Csi, '\x40'..='\x7e') => {
+                self.state = State::Ground;
+                self.csi(c);
+            }
+            (State::Csi, c) => self.params.push(c),
+            (State::Osc, '\x07') => self.state = State::Ground,
+            (State::Osc, '\x1b') => self.state = State::Escape,
+            (State::Osc, _) => {}
+        }
+    }
+
+    fn line_feed(&mut self) {
+        if self.row + 1 < self.cells.len() {
+            self.row += 1;
+        } else {
+            let columns = self.columns();
+            self.cells.remove(0);
+            self.cells.push(vec![' '; columns]);
+        }
+    }
+
+    fn csi(&mut self, command: char) {
+        let params: Vec<usize> = self
+            .params
+            .trim_start_matches('?')
+            .split(';')
+            .map(|p| p.parse().unwrap_or(0))
+            .collect();
+        // a missing or zero count means one
+        let count = params[0].max(1);
+        let (rows, columns) = (self.cells.len(), self.columns());
+        match command {
+            'A' => self.row = self.row.saturating_sub(count),
+            'B' => self.row = (self.row + count).min(rows - 1),
+            'C' => self.column = (self.column + count).min(columns - 1),
+            'D' => self.column = self.column.saturating_sub(count),
+            'G' => self.column = (count - 1).min(columns - 1),
+            'd' => self.row = (count - 1).min(rows - 1),
+            'H' | 'f' => {
+                self.row = (count - 1).min(rows - 1);
+                self.column = (params.get(1).copied().unwrap_or(1).max(1) - 1).min(columns - 1);
+            }
+            'J' => {
+                let (row, column) = (self.row, self.column.min(columns));
+                match params[0] {
+                    0 => {
+                        self.cells[row][column..].fill(' ');
+                        self.cells[row + 1..].iter_mut().for_each(|r| r.fill(' '));
+                    }
+                    1 => {
+                        self.cells[..row].iter_mut().for_each(|r| r.fill(' '));
+                        self.cells[row][..(column + 1).min(columns)].fill(' ');
+                    }
+                    _ => self.cells.iter_mut().for_each(|r| r.fill(' ')),
+                }
+            }
+            'K' => {
+                let (row, column) = (self.row, self.column.min(columns));
+                match params[0] {
+                    0 => self.cells[row][column..].fill(' '),
+                    1 => self.cells[row][..(column + 1).min(columns)].fill(' '),
+                    _ => self.cells[row].fill(' '),
+                }
+            }
+            // colors, modes and scroll regions don't change what is shown
+            _ => {}
+        }
+    }
+
+    /// The screen as text, without trailing blanks.
+    pub fn text(&self) -> String {
+        let lines: Vec<String> = self
+            .cells
+            .iter()
+            .map(|row| row.iter().collect::<String>().trim_end().to_string())
+            .collect();
+        lines.join("\n").trim_end().to_string()
+    }
+}
+
+/// Shared terminal of the peer as last received.
+#[derive(Debug, Default)]
+pub struct View {
+    pub text: String,
+    /// How the command ended, once it did
+    pub ended: Option<String>,
+}
+
+/// Runs `cmd` in a pseudo terminal, its screen and then how it ended are sent to `lines` as
+/// wire lines. Returns the process id.
+pub fn share(cmd: &str, lines: mpsc::Sender<String>) -> io::Result<u32> {
+    let (mut master, slave) = open_pty()?;
+    let child = {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(cmd)
+            .env("TERM", "vt100")
+            .env("LINES", ROWS.to_string())
+            .env("COLUMNS", COLUMNS.to_string())
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        // SAFETY: only async signal safe calls between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        // dropping the command closes our copies of the slave, so reading ends with the child
+        command.spawn()?
+    };
+    let pid = child.id();
+    debug!("sharing {cmd:?} as {pid}");
+    let screen = Arc::new(Mutex::new((
+        Screen::new(ROWS as usize, COLUMNS as usize),
+        false,
+    )));
+    let reader_screen = Arc::clone(&screen);
+    let reader = tasks::spawn("pane reader", move || {
+        let mut buf = [0; 4096];
+        // the pseudo terminal reports an error instead of the end once the child is gone
+        while let Ok(size @ 1..) = master.read(&mut buf) {
+            let mut lock = reader_screen.lock().expect("screen lock is poisoned");
+            lock.0.feed(&buf[..size]);
+            lock.1 = true;
+        }
+    });
+    tasks::spawn("pane", move || {
+        let mut child = child;
+        loop {
+            std::thread::sleep(FRAME_INTERVAL);
+            let finished = reader.is_finished();
+            let frame = {
+                let mut lock = screen.lock().expect("screen lock is poisoned");
+                std::mem::take(&mut lock.1).then(|| lock.0.text())
+            };
+            if let Some(text) = frame {
+                if lines.send(protocol::pane(&text)).is_err() {
+                    let _ = child.kill();
+                }
+            }
+            if finished {
+                break;
+            }
+        }
+        let status = match child.wait() {
+            Ok(status) => status.to_string(),
+            Err(e) => {
+                error!("Failed to wait for shared command: {e}");
+                e.to_string()
+            }
+        };
+        let _ = lines.send(protocol::pane_end(&status));
+    });
+    Ok(pid)
+}
+
+/// Ends the shared command.
+pub fn stop(pid: u32) {
+    // SAFETY: plain syscall, at worst the process is gone already
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGHUP);
+    }
+}
+
+fn open_pty() -> io::Result<(File, File)> {
+    // SAFETY: the descriptor is checked and owned by the file from then on
+    let master = unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        File::from_raw_fd(fd)
+    };
+    let mut name = [0 as libc::c_char; 128];
+    let size = libc::winsize {
+        ws_row: ROWS,
+        ws_col: COLUMNS,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: the descriptor is valid and the buffers are large enough
+    let name = unsafe {
+        let fd = master.as_raw_fd();
+        if libc::grantpt(fd) < 0
+            || libc::unlockpt(fd) < 0
+            || libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0
+            || libc::ioctl(fd, libc::TIOCSWINSZ, &size) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        CStr::from_ptr(name.as_ptr())
+    };
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(name.to_string_lossy().as_ref())?;
+    Ok((master, slave))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(bytes: &[u8]) -> Screen {
+        let mut screen = Screen::new(3, 10);
+        screen.feed(bytes);
+        screen
+    }
+
+    #[test]
+    fn the_cursor_moves_and_erases() {
+        assert_eq!(screen(b"ab\r\ncd\x1b[1;5Hx").text(), "ab  x\ncd");
+        assert_eq!(screen(b"hello\x1b[3D\x1b[K!").text(), "he!");
+        assert_eq!(screen(b"1\n2\n3\x1b[2;1H\x1b[J").text(), "1");
+        assert_eq!(screen(b"a\tb\x08c").text(), "a       c");
+        // titles, charsets and colors don't show
+        assert_eq!(screen(b"\x1b]0;title\x07\x1b(B\x1b[31mred").text(), "red");
+    }
+
+    #[test]
+    fn long_lines_wrap_and_the_screen_scrolls() {
+        assert_eq!(screen(b"0123456789abc\r\nd\r\ne").text(), "abc\nd\ne");
+        // a character split between two reads
+        let mut split = screen("caf".as_bytes());
+        let e = "é".as_bytes();
+        split.feed(&e[..1]);
+        split.feed(&e[1..]);
+        assert_eq!(split.text(), "café");
+    }
+}
@@ -0,0 +1,480 @@
+//! Text of a message on the wire, where every message is a single line.
+//!
+//! Backslashes, tabs and newlines are escaped so that pasted multi-line text stays one message.
+//! A message is sent as `\u{1}MSG <sender>\t<sent>\t<text>`, `sent` in milliseconds since the
+//! unix epoch, while a line without a frame is taken as a message of unknown origin, like the
+//! relay's webhook posts or those of a peer typing into netcat. Bots may annotate a message with
+//! JSON for other programs, sent as a fourth field `\t<data>` that the chat itself doesn't show.
+//! An edit is sent as `\u{1}EDIT <old>\t<new>`, replacing the latest message with the old text.
+//! `\u{1}EXEC <name>` asks the peer to run a command, which answers with a `\u{1}EXEC-OUT <line>`
+//! per line of output and `\u{1}EXEC-END <status>` once it is done or refused.
+//! A shared pane is sent as `\u{1}PANE <screen>` whenever it changes, `\u{1}PANE-END <status>`
+//! when its command ended. A location is sent as `\u{1}LOCATION <latitude> <longitude>`.
+//! `\u{1}LIVE on` or `off` tells whether we show our drafts, each of which is then sent as
+//! `\u{1}PREVIEW <draft>` while it changes. Keys typed in the talk mode are sent as
+//! `\u{1}TALK <keys>`, with spaces escaped as `\s` as a lone space would be trimmed off the line.
+//! `\u{1}TYPING on` is sent while typing a message, again every few seconds as long as it goes
+//! on, and `\u{1}TYPING off` once it stopped.
+//! Files are sent with `\u{1}FILE` lines, described in [`transfer`](crate::transfer).
+//! `\u{1}CAPS <names>` tells what else the peer takes, like the [`cbor`](crate::cbor) frames.
+//! `\u{1}BYE` is the last line of a peer quitting, rather than just losing the connection.
+//! `\u{1}CLOCK` lines tell how far the clocks are apart, described in [`skew`](crate::skew).
+//! A message may be numbered, sent as `<sent>#<id>`. The peer answers `\u{1}RECEIVED <id>` when
+//! it arrives, `\u{1}SEEN <id>` once the user saw it and every message before.
+
+use crate::{cbor, json, location::Point};
+
+const EDIT: &str = "\u{1}EDIT ";
+const EXEC: &str = "\u{1}EXEC ";
+const EXEC_OUT: &str = "\u{1}EXEC-OUT ";
+const EXEC_END: &str = "\u{1}EXEC-END ";
+const PANE: &str = "\u{1}PANE ";
+const PANE_END: &str = "\u{1}PANE-END ";
+const LOCATION: &str = "\u{1}LOCATION ";
+const LIVE: &str = "\u{1}LIVE ";
+const PREVIEW: &str = "\u{1}PREVIEW ";
+const TALK: &str = "\u{1}TALK ";
+const TYPING: &str = "\u{1}TYPING ";
+const FILE: &str = "\u{1}FILE ";
+const FILE_ACCEPT: &str = "\u{1}FILE-ACCEPT ";
+const FILE_DECLINE: &str = "\u{1}FILE-DECLINE ";
+const FILE_CHUNK: &str = "\u{1}FILE-CHUNK ";
+const FILE_END: &str = "\u{1}FILE-END ";
+const FILE_ABORT: &str = "\u{1}FILE-ABORT ";
+const MESSAGE: &str = "\u{1}MSG ";
+const CAPS: &str = "\u{1}CAPS ";
+const GOODBYE: &str = "\u{1}BYE";
+const CLOCK: &str = "\u{1}CLOCK ";
+const CLOCK_ECHO: &str = "\u{1}CLOCK-ECHO ";
+const RECEIVED: &str = "\u{1}RECEIVED ";
+const SEEN: &str = "\u{1}SEEN ";
+
+/// Who sent a message and when, as the sender tells it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub sender: String,
+    /// Milliseconds since the unix epoch
+    pub sent: u64,
+    /// Number the sender counts its messages with, for the receipts
+    pub id: Option<u64>,
+}
+
+/// Content of a received line.
+#[derive(Debug, PartialEq)]
+pub enum Payload {
+    Text(String),
+    Message {
+        envelope: Envelope,
+        text: String,
+        /// Machine-readable annotation, shown to programs rather than people
+        data: Option<json::Value>,
+    },
+    /// Replaces an earlier message
+    Edit {
+        old: String,
+        new: String,
+    },
+    /// Asks to run the command of that name
+    Exec(String),
+    /// Line of output of the command the peer runs for us
+    ExecOutput(String),
+    /// How the command ended
+    ExecDone(String),
+    /// Whole screen of the pane the peer shares
+    Pane(String),
+    /// How the command of the shared pane ended
+    PaneEnd(String),
+    Location(Point),
+    /// Whether the peer opted in to live typing
+    Live(bool),
+    /// Draft in the input box of the peer
+    Preview(String),
+    /// Keys the peer typed in the talk mode
+    Talk(String),
+    /// Whether the peer is typing a message
+    Typing(bool),
+    /// The peer offers to send a file
+    FileOffer {
+        id: u64,
+        size: u64,
+        name: String,
+    },
+    /// Whether the peer wants the file we offered
+    FileAnswer {
+        id: u64,
+        accepted: bool,
+    },
+    /// Next piece of the file
+    FileChunk {
+        id: u64,
+        data: Vec<u8>,
+    },
+    /// All of the file was sent, with its SHA-1 in hex
+    FileEnd {
+        id: u64,
+        digest: String,
+    },
+    /// Sending the file stopped half way
+    FileAbort {
+        id: u64,
+        reason: String,
+    },
+    /// What the peer takes besides the text frames
+    Capabilities(Vec<String>),
+    /// The peer quits
+    Goodbye,
+    /// Time of the peer's clock, to be echoed
+    Clock(u64),
+    /// Answer to our [`clock`], with the time of the peer's clock when it got it
+    ClockEcho {
+        sent: u64,
+        peer: u64,
+    },
+    /// Our message of that id arrived
+    Received(u64),
+    /// The user of the peer saw our messages up to that id
+    Seen(u64),
+}
+
+pub fn decode(line: &str) -> Payload {
+    if let Some(payload) = line.strip_prefix(cbor::FRAME).and_then(cbor::decode_frame) {
+        return payload;
+    }
+    if line == GOODBYE {
+        return Payload::Goodbye;
+    }
+    if let Some(Ok(now)) = line.strip_prefix(CLOCK).map(str::parse) {
+        return Payload::Clock(now);
+    }
+    let echo = line
+        .strip_prefix(CLOCK_ECHO)
+        .and_then(|r| r.split_once(' '));
+    if let Some((Ok(sent), Ok(peer))) = echo.map(|(s, p)| (s.parse(), p.parse())) {
+        return Payload::ClockEcho { sent, peer };
+    }
+    if let Some(Ok(id)) = line.strip_prefix(RECEIVED).map(str::parse) {
+        return Payload::Received(id);
+    }
+    if let Some(Ok(id)) = line.strip_prefix(SEEN).map(str::parse) {
+        return Payload::Seen(id);
+    }
+    if let Some(names) = line.strip_prefix(CAPS) {
+        return Payload::Capabilities(names.split_whitespace().map(str::to_string).collect());
+    }
+    if let Some((old, new)) = line.strip_prefix(EDIT).and_then(|r| r.split_once('\t')) {
+        return Payload::Edit {
+            old: unescape(old),
+            new: unescape(new),
+        };
+    }
+    if let Some(name) = line.strip_prefix(EXEC) {
+        return Payload::Exec(unescape(name));
+    }
+    if let Some(output) = line.strip_prefix(EXEC_OUT) {
+        return Payload::ExecOutput(unescape(output));
+    }
+    if let Some(status) = line.strip_prefix(EXEC_END) {
+        return Payload::ExecDone(unescape(status));
+    }
+    if let Some(screen) = line.strip_prefix(PANE) {
+        return Payload::Pane(unescape(screen));
+    }
+    if let Some(status) = line.strip_prefix(PANE_END) {
+        return Payload::PaneEnd(unescape(status));
+    }
+    if let Some(Ok(point)) = line.strip_prefix(LOCATION).map(str::parse) {
+        return Payload::Location(point);
+    }
+    match line.strip_prefix(LIVE) {
+        Some("on") => return Payload::Live(true),
+        Some("off") => return Payload::Live(false),
+        _ => {}
+    }
+    if let Some(draft) = line.strip_prefix(PREVIEW) {
+        return Payload::Preview(unescape(draft));
+    }
+    if let Some(keys) = line.strip_prefix(TALK) {
+        return Payload::Talk(unescape(keys));
+    }
+    if let Some(file) = decode_file(line) {
+        return file;
+    }
+    match line.strip_prefix(TYPING) {
+        Some("on") => return Payload::Typing(true),
+        Some("off") => return Payload::Typing(false),
+        _ => {}
+    }
+    let message = line.strip_prefix(MESSAGE).and_then(|r| {
+        let (sender, r) = r.split_once('\t')?;
+        let (sent, r) = r.split_once('\t')?;
+        // the text is escaped, a tab can only start the annotation
+        let (text, data) = r.split_once('\t').map_or((r, None), |(t, d)| (t, Some(d)));
+        let (sent, id) = match sent.split_once('#') {
+            Some((sent, id)) => (sent, Some(id.parse().ok()?)),
+            None => (sent, None),
+        };
+        Some((sender, sent.parse().ok()?, id, text, data))
+    });
+    if let Some((sender, sent, id, text, data)) = message {
+        return Payload::Message {
+            envelope: Envelope {
+                sender: unescape(sender),
+                sent,
+                id,
+            },
+            text: unescape(text),
+            data: data.and_then(|d| json::parse(&unescape(d)).ok()),
+        };
+    }
+    Payload::Text(unescape(line))
+}
+
+fn decode_file(line: &str) -> Option<Payload> {
+    let id = |rest: &str| rest.split_once(' ').map_or(rest, |(id, _)| id).parse().ok();
+    let rest = |rest: &str| unescape(rest.split_once(' ').map_or("", |(_, r)| r));
+    if let Some(r) = line.strip_prefix(FILE) {
+        let (id, r) = r.split_once(' ')?;
+        let (size, name) = r.split_once(' ')?;
+        return Some(Payload::FileOffer {
+            id: id.parse().ok()?,
+            size: size.parse().ok()?,
+            name: unescape(name),
+        });
+    }
+    if let Some(r) = line.strip_prefix(FILE_ACCEPT) {
+        return Some(Payload::FileAnswer {
+            id: id(r)?,
+            accepted: true,
+        });
+    }
+    if let Some(r) = line.strip_prefix(FILE_DECLINE) {
+        return Some(Payload::FileAnswer {
+            id: id(r)?,
+            accepted: false,
+        });
+    }
+    if let Some(r) = line.strip_prefix(FILE_CHUNK) {
+        let (id, data) = r.split_once(' ')?;
+        return Some(Payload::FileChunk {
+            id: id.parse().ok()?,
+            data: hex::decode(data).ok()?,
+        });
+    }
+    if let Some(r) = line.strip_prefix(FILE_END) {
+        return Some(Payload::FileEnd {
+            id: id(r)?,
+            digest: rest(r),
+        });
+    }
+    let r = line.strip_prefix(FILE_ABORT)?;
+    Some(Payload::FileAbort {
+        id: id(r)?,
+        reason: rest(r),
+    })
+}
+
+pub fn message(envelope: &Envelope, text: &str, data: Option<&json::Value>) -> String {
+    let mut line = format!("{MESSAGE}{}\t{}", escape(&envelope.sender), envelope.sent);
+    if let Some(id) = envelope.id {
+        line.push_str(&format!("#{id}"));
+    }
+    line.push('\t');
+    line.push_str(&escape(text));
+    if let Some(data) = data {
+        line.push('\t');
+        line.push_str(&escape(&data.to_string()));
+    }
+    line
+}
+
+/// Line replacing the earlier message `old` with `new`.
+pub fn edit(old: &str, new: &str) -> String {
+    format!("{EDIT}{}\t{}", escape(old), escape(new))
+}
+
+pub fn exec(name: &str) -> String {
+    format!("{EXEC}{}", escape(name))
+}
+
+pub fn exec_output(line: &str) -> String {
+    format!("{EXEC_OUT}{}", escape(line))
+}
+
+pub fn exec_done(status: &str) -> String {
+    format!("{EXEC_END}{}", escape(status))
+}
+
+pub fn pane(screen: &str) -> String {
+    format!("{PANE}{}", escape(screen))
+}
+
+pub fn pane_end(status: &str) -> String {
+    format!("{PANE_END}{}", escape(status))
+}
+
+pub fn location(point: Point) -> String {
+    format!("{LOCATION}{} {}", point.lat, point.lon)
+}
+
+pub fn live(enabled: bool) -> String {
+    format!("{LIVE}{}", if enabled { "on" } else { "off" })
+}
+
+pub fn preview(draft: &str) -> String {
+    format!("{PREVIEW}{}", escape(draft))
+}
+
+pub fn typing(typing: bool) -> String {
+    format!("{TYPING}{}", if typing { "on" } else { "off" })
+}
+
+pub fn file_offer(id: u64, size: u64, name: &str) -> String {
+    format!("{FILE}{id} {size} {}", escape(name))
+}
+
+pub fn file_answer(id: u64, accepted: bool) -> String {
+    match accepted {
+        true => format!("{FILE_ACCEPT}{id}"),
+        false => format!("{FILE_DECLINE}{id}"),
+    }
+}
+
+/// Piece of a file, in hex as the line has to stay text.
+pub fn file_chunk(id: u64, data: &[u8]) -> String {
+    format!("{FILE_CHUNK}{id} {}", hex::encode(data))
+}
+
+pub fn file_end(id: u64, digest: &str) -> String {
+    format!("{FILE_END}{id} {digest}")
+}
+
+pub fn file_abort(id: u64, reason: &str) -> String {
+    format!("{FILE_ABORT}{id} {}", escape(reason))
+}
+
+pub fn capabilities(names: &[&str]) -> String {
+    format!("{CAPS}{}", names.join(" "))
+}
+
+pub fn goodbye() -> String {
+    GOODBYE.to_string()
+}
+
+pub fn received(id: u64) -> String {
+    format!("{RECEIVED}{id}")
+}
+
+pub fn seen(id: u64) -> String {
+    format!("{SEEN}{id}")
+}
+
+pub fn clock(now: u64) -> String {
+    format!("{CLOCK}{now}")
+}
+
+pub fn clock_echo(sent: u64, now: u64) -> String {
+    format!("{CLOCK_ECHO}{sent} {now}")
+}
+
+/// Lines only meaningful between two peers, which a `--multi` server doesn't pass on.
+pub fn is_peer_to_peer(line: &str) -> bool {
+    line == GOODBYE
+        || [CLOCK, CLOCK_ECHO, RECEIVED, SEEN]
+            .iter()
+            .any(|frame| line.starts_with(frame))
+}
+
+pub fn talk(keys: &str) -> String {
+    // a space in the escaped text is always a space of the keys
+    format!("{TALK}{}", escape(keys).replace(' ', "\\s"))
+}
+
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some('s') => out.push(' '),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            },
+            (c, false) => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drafts_are_sent_escaped() {
+        assert_eq!(decode(&live(true)), Payload::Live(true));
+        assert_eq!(decode(&live(false)), Payload::Live(false));
+        assert_eq!(
+            decode(&preview("two\nlines")),
+            Payload::Preview("two\nlines".to_string())
+        );
+        assert_eq!(
+            decode("\u{1}LIVE maybe"),
+            Payload::Text("\u{1}LIVE maybe".to_string())
+        );
+    }
+
+    #[test]
+    fn talk_keys_keep_their_spaces() {
+        let line = talk(" a\tb \u{8}");
+        assert!(!line.ends_with(' '));
+        assert_eq!(decode(&line), Payload::Talk(" a\tb \u{8}".to_string()));
+    }
+
+    #[test]
+    fn messages_carry_their_sender_and_time() {
+        let envelope = Envelope {
+            sender: "ada\tl".to_string(),
+            sent: 1_700_000_000_000,
+            id: None,
+        };
+        assert_eq!(
+            decode(&message(&envelope, "hi\nthere", None)),
+            Payload::Message {
+                envelope,
+                text: "hi\nthere".to_string(),
+                data: None,
+            }
+        );
+        // from netcat or a webhook
+        assert_eq!(decode("plain"), Payload::Text("plain".to_string()));
+        assert_eq!(
+            decode("\u{1}MSG ada\tsoon\thi"),
+            Payload::Text("\u{1}MSG ada\tsoon\thi".to_string())
+        );
+    }
+
+    #[test]
+    fn typing_is_on_or_off() {
+        assert_eq!(decode(&typing(true)), Payload::Typing(true));
+        assert_eq!(decode(&typing(false)), Payload::Typing(false));
+        assert_eq!(
+            decode("\u{1}TYPING maybe"),
+            Payload::Text("\u{1}TYPING maybe".to_string())
+        );
+    }
+}
@@ -0,0 +1,1708 @@
+//! Store-and-forward relay: clients identify with a name and address messages to each other,
+//! anything for somebody who isn't connected is held until they are.
+//!
+//! Everything is a line, control lines start with `\u{1}` like the deniable ones:
+//!
+//! - `IDENT <name> <key>` first line of a client, answered with `WELCOME [<#room>...]` or
+//!   `ERROR <reason>`. The rooms are the lobby, every client is put in them when connecting.
+//!   The first to connect with a name registers its key, the name is theirs from then on and
+//!   later sessions have to bring the same key.
+//! - `TO <name> <id> <payload>` from a client, `id` is picked by the sender
+//! - `FROM <name> <payload>` to the recipient
+//! - `ACK <id> <state>` to the sender, see [`Delivery`]
+//! - `SENT <name> <payload>` to the other sessions of the sender
+//! - `READ` from a client when the conversation was read, passed on to its other sessions
+//! - `JOIN <#room>` and `PART <#room>` from a client, the first to join a room is its owner
+//! - `ROOM <#room> <name> <payload>` to the other members, for `TO <#room>` from a member
+//! - `TOPIC <#room> <topic>` and `PIN <#room> <message>` from an op, passed on to the members as
+//!   `TOPIC <#room> <name> <topic>` and `PIN <#room> <name> <message>`, who also get them when
+//!   joining. An empty message clears the pins.
+//! - `KICK <#room> <name>` from an op, `ROLE <#room> <name> <role>` to change the [`Role`] of a
+//!   member, passed on like the topic. Only members with a lower role can be kicked or changed.
+//! - `PRIVATE <#room>` from an op makes the room invitation only: `JOIN <#room> <code>` with a
+//!   code is needed to get in, unless a member sent `INVITE <#room> <name>` for them before,
+//!   which takes a name registered already.
+//!   `CODE <#room>` from a member is answered with `CODE <#room> <code>`, every code works once.
+//!   The invited get `INVITE <#room> <name>` if connected.
+//! - `LIST rooms <page>` and `LIST users <page>` from a client ask for the directory, answered
+//!   with `LISTED <rooms|users> <page> <pages>` followed by a tab and an entry for everything on
+//!   the page, tab separated. A room is `<#room> <members> <open|private> [<topic>]`, a user just
+//!   the name of somebody connected. `UNLISTED <#room>` from an op keeps the room out of it.
+//!
+//! Nobody owns a lobby room, and it stays around when everybody left.
+//! A name may be connected from several devices at once, messages go to all of its sessions.
+//! Every session has a thread writing to it, one which stops reading is dropped rather than
+//! holding up the others.
+//! Names starting with `#` are rooms, whatever is sent to one goes to all of its members.
+//! Payloads are passed on untouched, the relay never looks into them.
+//!
+//! Registered names and held messages are kept in `relay` of the data directory, so restarting
+//! the relay neither frees up a name for somebody else nor loses what waits for its owner.
+//!
+//! Posts to the [webhook](crate::webhook) at `/hook/<name>` are held for `name` like messages
+//! from a client called `webhook`, those to `/hook/%23room` go to the members of `#room`, and
+//! messages are posted to the outgoing hooks they match.
+//! With an [`[acme]`](crate::acme) domain in the config, clients connect over TLS.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    access, acme,
+    clock::{Clock, SharedClock},
+    connection, dirs, protocol,
+    rng::{self, Rng, SharedRng},
+    secret, socket, webhook,
+};
+
+const IDENT: &str = "\u{1}IDENT ";
+const WELCOME: &str = "\u{1}WELCOME";
+const ERROR: &str = "\u{1}ERROR ";
+const TO: &str = "\u{1}TO ";
+const FROM: &str = "\u{1}FROM ";
+const ACK: &str = "\u{1}ACK ";
+const SENT: &str = "\u{1}SENT ";
+const READ: &str = "\u{1}READ";
+const JOIN: &str = "\u{1}JOIN ";
+const PART: &str = "\u{1}PART ";
+const ROOM: &str = "\u{1}ROOM ";
+const TOPIC: &str = "\u{1}TOPIC ";
+const PIN: &str = "\u{1}PIN ";
+const KICK: &str = "\u{1}KICK ";
+const ROLE: &str = "\u{1}ROLE ";
+const PRIVATE: &str = "\u{1}PRIVATE ";
+const CODE: &str = "\u{1}CODE ";
+const INVITE: &str = "\u{1}INVITE ";
+const LIST: &str = "\u{1}LIST ";
+const LISTED: &str = "\u{1}LISTED ";
+const UNLISTED: &str = "\u{1}UNLISTED ";
+/// Entries on a page of the directory.
+const PAGE: usize = 20;
+/// Characters in a join code.
+const CODE_LENGTH: usize = 10;
+/// Lines waiting for a client, one which falls further behind is dropped.
+const QUEUE: usize = 1024;
+/// Time a write to a client may take before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes a line may have besides its payload, for the command, the recipient and the id.
+const LINE_OVERHEAD: usize = 1024;
+/// Shortest key a name is registered with.
+const MIN_KEY_LENGTH: usize = 16;
+/// Length of the keys made up for us.
+const KEY_LENGTH: usize = 32;
+
+/// What happened to a message sent through the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Held until the recipient connects
+    Stored,
+    Delivered,
+    /// Recipient didn't show up before the time to live ran out
+    Expired,
+    /// Too large, or the recipient has too much waiting already
+    Rejected,
+    /// Seen by the peer, which tells so itself rather than the relay
+    Seen,
+}
+
+impl Delivery {
+    pub fn name(self) -> &'static str {
+        match self {
+            Delivery::Stored => "stored",
+            Delivery::Delivered => "delivered",
+            Delivery::Expired => "expired",
+            Delivery::Rejected => "rejected",
+            Delivery::Seen => "seen",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "stored" => Some(Delivery::Stored),
+            "delivered" => Some(Delivery::Delivered),
+            "expired" => Some(Delivery::Expired),
+            "rejected" => Some(Delivery::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// What a member may do in a room, every role can do what the ones below can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Member,
+    /// Sets the topic, pins messages, kicks members
+    Op,
+    /// Opened the room, makes others ops
+    Owner,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Op => "op",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "member" => Some(Role::Member),
+            "op" => Some(Role::Op),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+/// What we know of the room we talk in, from the relay.
+#[derive(Debug, Default)]
+pub struct RoomInfo {
+    pub topic: Option<String>,
+    /// Pinned messages with who pinned them, oldest first
+    pub pins: Vec<(String, String)>,
+}
+
+/// Line passing `payload` on from `sender`, as the relay does.
+pub fn forward(sender: &str, payload: &str) -> String {
+    format!("{FROM}{sender} {payload}\n")
+}
+
+/// Line passing `payload` on from `sender` to the other members of `room`.
+pub fn forward_in(room: &str, sender: &str, payload: &str) -> String {
+    format!("{ROOM}{room} {sender} {payload}\n")
+}
+
+/// Line telling a client what went wrong with its last one.
+pub fn error(reason: &str) -> String {
+    format!("{ERROR}{reason}\n")
+}
+
+/// Line of a client becoming a member of `room`.
+pub fn join(room: &str) -> String {
+    format!("{JOIN}{room}\n")
+}
+
+/// Line of a client leaving `room`.
+pub fn part(room: &str) -> String {
+    format!("{PART}{room}\n")
+}
+
+/// Line sending `payload` to the members of `room`, where nothing is acknowledged.
+pub fn post(room: &str, payload: &str) -> String {
+    format!("{TO}{room} 0 {payload}\n")
+}
+
+/// Page of the directory of the relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    /// Rooms rather than users
+    pub rooms: bool,
+    /// Counting from 1
+    pub page: usize,
+    pub pages: usize,
+    /// Users, or rooms as `<#room> <members> <open|private> [<topic>]`
+    pub entries: Vec<String>,
+}
+
+impl Listing {
+    fn line(&self) -> String {
+        let kind = if self.rooms { "rooms" } else { "users" };
+        let mut line = format!("{LISTED}{kind} {} {}", self.page, self.pages);
+        for entry in &self.entries {
+            line.push('\t');
+            line.push_str(entry);
+        }
+        line.push('\n');
+        line
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.strip_prefix(LISTED)?.split('\t');
+        let mut head = fields.next()?.split(' ');
+        let rooms = match head.next()? {
+            "rooms" => true,
+            "users" => false,
+            _ => return None,
+        };
+        Some(Self {
+            rooms,
+            page: head.next()?.parse().ok()?,
+            pages: head.next()?.parse().ok()?,
+            entries: fields.map(str::to_string).collect(),
+        })
+    }
+}
+
+/// Line a client sent about rooms.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    Join(&'a str),
+    Part(&'a str),
+    Post { room: &'a str, payload: &'a str },
+}
+
+/// Reads a line of [`join`], [`part`] or [`post`], `None` for anything else.
+pub fn parse_request(line: &str) -> Option<Request<'_>> {
+    if let Some(room) = line.strip_prefix(JOIN).filter(|r| r.starts_with('#')) {
+        return Some(Request::Join(room));
+    }
+    if let Some(room) = line.strip_prefix(PART).filter(|r| r.starts_with('#')) {
+        return Some(Request::Part(room));
+    }
+    let (room, rest) = line.strip_prefix(TO)?.split_once(' ')?;
+    let (_, payload) = rest.split_once(' ')?;
+    room.starts_with('#')
+        .then_some(Request::Post { room, payload })
+}
+
+/// Line received from the relay.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    From {
+        sender: &'a str,
+        payload: &'a str,
+    },
+    Ack {
+        id: u64,
+        state: Delivery,
+    },
+    /// Sent by another session of ours
+    Sent {
+        to: &'a str,
+        payload: &'a str,
+    },
+    /// Another session of ours read the conversation
+    Read,
+    /// Sent to a room we are in
+    Room {
+        room: &'a str,
+        sender: &'a str,
+        payload: &'a str,
+    },
+    /// Topic of a room we are in, set by `by`, escaped like a message
+    Topic {
+        room: &'a str,
+        by: &'a str,
+        topic: &'a str,
+    },
+    /// Message pinned in a room we are in, empty when the pins were cleared
+    Pin {
+        room: &'a str,
+        by: &'a str,
+        text: &'a str,
+    },
+    Kick {
+        room: &'a str,
+        by: &'a str,
+        name: &'a str,
+    },
+    Role {
+        room: &'a str,
+        by: &'a str,
+        name: &'a str,
+        role: Role,
+    },
+    /// Join code we asked for
+    Code {
+        room: &'a str,
+        code: &'a str,
+    },
+    Invite {
+        room: &'a str,
+        by: &'a str,
+    },
+    /// Page of the directory we asked for
+    Listing(Listing),
+    Error(&'a str),
+    /// Not addressed through the relay, e.g. when talking to a peer directly
+    Other(&'a str),
+}
+
+pub fn parse(line: &str) -> Frame<'_> {
+    if let Some((sender, payload)) = line.strip_prefix(FROM).and_then(|r| r.split_once(' ')) {
+        return Frame::From { sender, payload };
+    }
+    let ack = line
+        .strip_prefix(ACK)
+        .and_then(|r| r.split_once(' '))
+        .and_then(|(id, state)| Some((id.parse().ok()?, Delivery::parse(state)?)));
+    if let Some((id, state)) = ack {
+        return Frame::Ack { id, state };
+    }
+    if let Some((to, payload)) = line.strip_prefix(SENT).and_then(|r| r.split_once(' ')) {
+        return Frame::Sent { to, payload };
+    }
+    if line == READ {
+        return Frame::Read;
+    }
+    let room = line
+        .strip_prefix(ROOM)
+        .and_then(|r| r.split_once(' '))
+        .and_then(|(room, r)| Some((room, r.split_once(' ')?)));
+    if let Some((room, (sender, payload))) = room {
+        return Frame::Room {
+            room,
+            sender,
+            payload,
+        };
+    }
+    let topic = line
+        .strip_prefix(TOPIC)
+        .and_then(|r| r.split_once(' '))
+        .map(|(room, r)| (room, r.split_once(' ').unwrap_or((r, ""))));
+    if let Some((room, (by, topic))) = topic {
+        return Frame::Topic { room, by, topic };
+    }
+    let pin = line
+        .strip_prefix(PIN)
+        .and_then(|r| r.split_once(' '))
+        .map(|(room, r)| (room, r.split_once(' ').unwrap_or((r, ""))));
+    if let Some((room, (by, text))) = pin {
+        return Frame::Pin { room, by, text };
+    }
+    let kick = line
+        .strip_prefix(KICK)
+        .and_then(|r| r.split_once(' '))
+        .and_then(|(room, r)| Some((room, r.split_once(' ')?)));
+    if let Some((room, (by, name))) = kick {
+        return Frame::Kick { room, by, name };
+    }
+    let role = line
+        .strip_prefix(ROLE)
+        .and_then(|r| r.split_once(' '))
+        .and_then(|(room, r)| Some((room, r.split_once(' ')?)))
+        .and_then(|(room, (by, r))| Some((room, by, r.split_once(' ')?)))
+        .and_then(|(room, by, (name, role))| Some((room, by, name, Role::parse(role)?)));
+    if let Some((room, code)) = line.strip_prefix(CODE).and_then(|r| r.split_once(' ')) {
+        return Frame::Code { room, code };
+    }
+    if let Some((room, by)) = line.strip_prefix(INVITE).and_then(|r| r.split_once(' ')) {
+        return Frame::Invite { room, by };
+    }
+    if let Some(listing) = Listing::parse(line) {
+        return Frame::Listing(listing);
+    }
+    if let Some((room, by, name, role)) = role {
+        return Frame::Role {
+            room,
+            by,
+            name,
+            role,
+        };
+    }
+    match line.strip_prefix(ERROR) {
+        Some(reason) => Frame::Error(reason),
+        None => Frame::Other(line),
+    }
+}
+
+/// Where outgoing messages go when connected to a relay.
+#[derive(Debug)]
+pub struct Route {
+    /// Our own name on the relay
+    pub name: String,
+    /// Recipient of everything we send, empty until the relay names its lobby
+    pub to: String,
+    /// Lets us into an invitation only room
+    pub join_code: Option<String>,
+    /// Makes the room invitation only once we joined, if we are allowed to
+    pub private: bool,
+    /// Keeps the room out of the directory once we joined, if we are allowed to
+    pub unlisted: bool,
+    /// Proves the name is ours, see [`identity_key`]
+    pub key: String,
+    next_id: u64,
+}
+
+impl Route {
+    pub fn new(name: String, to: String, clock: &dyn Clock) -> Self {
+        Self {
+            name,
+            to,
+            join_code: None,
+            private: false,
+            unlisted: false,
+            key: String::new(),
+            // acks may arrive after a restart, they shouldn't match anything new
+            next_id: clock.millis(),
+        }
+    }
+
+    /// Id for the next message, the relay acknowledges it with that.
+    pub fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Addresses `payload` to the recipient.
+    pub fn wrap(&self, id: u64, payload: &str) -> String {
+        format!("{TO}{} {id} {payload}\n", self.to)
+    }
+
+    /// Whether the recipient is a room rather than a single peer.
+    pub fn is_room(&self) -> bool {
+        self.to.starts_with('#')
+    }
+
+    /// Becomes a member of the room we talk to.
+    pub fn join<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let line = match &self.join_code {
+            Some(code) => format!("{JOIN}{} {code}\n", self.to),
+            None => format!("{JOIN}{}\n", self.to),
+        };
+        writer.write_all(line.as_bytes())?;
+        if self.private {
+            writer.write_all(format!("{PRIVATE}{}\n", self.to).as_bytes())?;
+        }
+        if self.unlisted {
+            writer.write_all(format!("{UNLISTED}{}\n", self.to).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Asks for a page of the rooms, or of the users, in the directory.
+    pub fn list<W: Write>(&self, writer: &mut W, rooms: bool, page: usize) -> io::Result<()> {
+        let kind = if rooms { "rooms" } else { "users" };
+        writer.write_all(format!("{LIST}{kind} {page}\n").as_bytes())
+    }
+
+    /// Asks for a code to get into the room we talk to.
+    pub fn request_code<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(format!("{CODE}{}\n", self.to).as_bytes())
+    }
+
+    pub fn invite<W: Write>(&self, writer: &mut W, name: &str) -> io::Result<()> {
+        writer.write_all(format!("{INVITE}{} {name}\n", self.to).as_bytes())
+    }
+
+    /// Sets the topic of the room we talk to.
+    pub fn set_topic<W: Write>(&self, writer: &mut W, topic: &str) -> io::Result<()> {
+        writer.write_all(format!("{TOPIC}{} {}\n", self.to, protocol::escape(topic)).as_bytes())
+    }
+
+    /// Pins a message in the room we talk to, clears the pins without one.
+    pub fn pin<W: Write>(&self, writer: &mut W, text: &str) -> io::Result<()> {
+        writer.write_all(format!("{PIN}{} {}\n", self.to, protocol::escape(text)).as_bytes())
+    }
+
+    pub fn kick<W: Write>(&self, writer: &mut W, name: &str) -> io::Result<()> {
+        writer.write_all(format!("{KICK}{} {name}\n", self.to).as_bytes())
+    }
+
+    pub fn set_role<W: Write>(&self, writer: &mut W, name: &str, role: Role) -> io::Result<()> {
+        writer.write_all(format!("{ROLE}{} {name} {}\n", self.to, role.name()).as_bytes())
+    }
+
+    /// Lets our other sessions know that everything has been read.
+    pub fn mark_read<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(format!("{READ}\n").as_bytes())
+    }
+
+    /// Introduces us to the relay, must be the first thing on the connection.
+    ///
+    /// Returns the rooms of the lobby, which we are in from now on.
+    #[instrument(skip(reader, writer))]
+    pub fn identify<R: BufRead, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<Vec<String>> {
+        writer.write_all(format!("{IDENT}{} {}\n", self.name, self.key).as_bytes())?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        let lobby = line
+            .strip_prefix(WELCOME)
+            .filter(|rooms| rooms.is_empty() || rooms.starts_with(' '));
+        match lobby {
+            Some(rooms) => Ok(rooms.split_whitespace().map(str::to_string).collect()),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                line.strip_prefix(ERROR)
+                    .unwrap_or("not a relay")
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+/// Lines of a file, none if there isn't one.
+fn read_lines(path: &std::path::Path) -> io::Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces a file only we can read, written next to it first so it is never half there.
+fn write_private(path: &std::path::Path, content: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("new");
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary)?
+        .write_all(content)?;
+    fs::rename(temporary, path)
+}
+
+/// Key we register our names on relays with, made up and saved on first use. Other devices
+/// connecting with the same name need it too, `--relay-key`.
+pub fn identity_key(rng: &dyn Rng) -> io::Result<String> {
+    let path = dirs::data_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory"))?
+        .join("relay-key");
+    match std::fs::read_to_string(&path) {
+        Ok(key) => return Ok(key.trim().to_string()),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+    let key = rng::alphanumeric(rng, KEY_LENGTH);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(key.as_bytes())?;
+    info!("made up a relay key, it is in {}", path.display());
+    Ok(key)
+}
+
+/// Caps on what the relay holds for clients which aren't connected.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub ttl: Duration,
+    /// Messages held per recipient
+    pub max_held: usize,
+    /// Bytes per payload
+    pub max_size: usize,
+}
+
+struct Held {
+    line: String,
+    expires: Instant,
+    /// Sender and id to acknowledge once delivered
+    ack: Option<(String, u64)>,
+}
+
+/// Connection of a client, one name may have several.
+struct Session {
+    id: u64,
+    /// Lines for the thread writing them, the hub never waits for a client
+    lines: SyncSender<String>,
+}
+
+impl Session {
+    /// Starts writing to `stream`, which is shut down once the session is dropped or a write
+    /// fails, ending the reading side as well.
+    fn spawn(id: u64, mut stream: TcpStream) -> io::Result<Self> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let (lines, queue) = mpsc::sync_channel::<String>(QUEUE);
+        std::thread::spawn(move || {
+            for line in queue {
+                if let Err(e) = stream.write_all(line.as_bytes()) {
+                    warn!("Failed to write to session {id}: {e}");
+                    break;
+                }
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+        Ok(Self { id, lines })
+    }
+}
+
+#[derive(Default)]
+struct Room {
+    members: BTreeMap<String, Role>,
+    /// Who set it and the topic, escaped like a message
+    topic: Option<(String, String)>,
+    /// Who pinned them and the messages, escaped
+    pins: Vec<(String, String)>,
+    /// Only members, the invited and holders of a code may join
+    private: bool,
+    /// Left out of the directory
+    unlisted: bool,
+    /// Join codes not used yet, every one lets a single member in
+    codes: HashSet<String>,
+    invited: HashSet<String>,
+}
+
+/// Name of a client which brought the key the name is registered with, roles and memberships
+/// are only ever looked up for one.
+struct Identity(String);
+
+impl Identity {
+    fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Room {
+    /// Role of `who`, who has to have at least `needed` to do whatever it is.
+    fn require(&self, who: &Identity, room: &str, needed: Role) -> Result<Role, String> {
+        match self.members.get(who.name()) {
+            None => Err(format!("you aren't in {room}")),
+            Some(&role) if role < needed => {
+                Err(format!("you need to be {} in {room}", needed.name()))
+            }
+            Some(&role) => Ok(role),
+        }
+    }
+}
+
+struct Hub {
+    clients: HashMap<String, Vec<Session>>,
+    /// Digests of the keys names were registered with
+    keys: HashMap<String, [u8; 20]>,
+    held: HashMap<String, VecDeque<Held>>,
+    rooms: HashMap<String, Room>,
+    /// Rooms every client is put in
+    lobby: Vec<String>,
+    next_session: u64,
+    /// When held messages expire
+    clock: SharedClock,
+    /// Join codes
+    rng: SharedRng,
+    /// Where the registrations and held messages outlive the relay, nothing is kept without it
+    state: Option<PathBuf>,
+}
+
+impl Hub {
+    fn new(clock: SharedClock, rng: SharedRng) -> Self {
+        Self {
+            clients: HashMap::new(),
+            keys: HashMap::new(),
+            held: HashMap::new(),
+            rooms: HashMap::new(),
+            lobby: Vec::new(),
+            next_session: 0,
+            clock,
+            rng,
+            state: None,
+        }
+    }
+
+    /// Hub with the names registered and the messages held before the last restart, kept in
+    /// `dir` from now on.
+    fn load(dir: PathBuf, clock: SharedClock, rng: SharedRng) -> io::Result<Self> {
+        let mut hub = Self::new(clock, rng);
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+        for line in read_lines(&dir.join("keys"))? {
+            let digest = line
+                .split_once('\t')
+                .and_then(|(name, digest)| Some((name, hex::decode(digest).ok()?)))
+                .and_then(|(name, digest)| Some((name, <[u8; 20]>::try_from(digest).ok()?)));
+            match digest {
+                Some((name, digest)) => {
+                    hub.keys.insert(name.to_string(), digest);
+                }
+                None => warn!("Ignoring a broken registration in {}", dir.display()),
+            }
+        }
+        let (now, millis) = (hub.clock.now(), hub.clock.millis());
+        for line in read_lines(&dir.join("held"))? {
+            let fields: Vec<&str> = line.splitn(4, '\t').collect();
+            let [to, expires, ack, line] = fields[..] else {
+                warn!("Ignoring a broken held message in {}", dir.display());
+                continue;
+            };
+            let Ok(expires) = expires.parse::<u64>() else {
+                continue;
+            };
+            let ack = ack
+                .split_once(' ')
+                .and_then(|(sender, id)| Some((sender.to_string(), id.parse().ok()?)));
+            hub.held.entry(to.to_string()).or_default().push_back(Held {
+                line: format!("{}\n", protocol::unescape(line)),
+                expires: now + Duration::from_millis(expires.saturating_sub(millis)),
+                ack,
+            });
+        }
+        info!(
+            "{} registered names and messages for {} held",
+            hub.keys.len(),
+            hub.held.len()
+        );
+        hub.state = Some(dir);
+        Ok(hub)
+    }
+
+    /// Writes the held messages over the last ones written.
+    fn save_held(&self) {
+        let Some(dir) = &self.state else {
+            return;
+        };
+        let (now, millis) = (self.clock.now(), self.clock.millis());
+        let mut content = String::new();
+        for (to, held) in &self.held {
+            for msg in held {
+                let expires =
+                    millis + msg.expires.saturating_duration_since(now).as_millis() as u64;
+                let ack = msg
+                    .ack
+                    .as_ref()
+                    .map_or(String::new(), |(sender, id)| format!("{sender} {id}"));
+                let line = protocol::escape(msg.line.trim_end_matches('\n'));
+                content.push_str(&format!("{to}\t{expires}\t{ack}\t{line}\n"));
+            }
+        }
+        if let Err(e) = write_private(&dir.join("held"), content.as_bytes()) {
+            warn!("Failed to save the held messages: {e}");
+        }
+    }
+
+    /// Checks that `key` is the one `name` was registered with, registering it for a new name.
+    fn authenticate(&mut self, name: &str, key: &str) -> Result<Identity, String> {
+        let digest: [u8; 20] = Sha1::digest(key.as_bytes()).into();
+        match self.keys.get(name) {
+            Some(registered) if secret::eq(registered, &digest) => Ok(Identity(name.to_string())),
+            Some(_) => Err(format!(
+                "{name} is registered with another key, bring the one of your other device"
+            )),
+            None => {
+                info!("{name} registered");
+                if let Some(dir) = &self.state {
+                    let line = format!("{name}\t{}\n", hex::encode(digest));
+                    fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .mode(0o600)
+                        .open(dir.join("keys"))
+                        .and_then(|mut file| file.write_all(line.as_bytes()))
+                        .map_err(|e| {
+                            warn!("Failed to save the registration of {name}: {e}");
+                            "the relay failed to register the name".to_string()
+                        })?;
+                }
+                self.keys.insert(name.to_string(), digest);
+                Ok(Identity(name.to_string()))
+            }
+        }
+    }
+
+    /// Writes to every session of `name` but `except`, failing ones are dropped.
+    ///
+    /// Returns whether any session got it.
+    fn send_to_sessions(&mut self, name: &str, except: Option<u64>, line: &str) -> bool {
+        self.send_matching(name, |id| Some(id) != except, line)
+    }
+
+    /// Writes to session `id` of `name` only, an answer to something it sent.
+    fn reply(&mut self, name: &str, id: u64, line: &str) {
+        self.send_matching(name, |session| session == id, line);
+    }
+
+    /// Queues `line` for the sessions of `name` which `matches` picks, dropping those which are
+    /// gone or too far behind.
+    fn send_matching(&mut self, name: &str, matches: impl Fn(u64) -> bool, line: &str) -> bool {
+        let Some(sessions) = self.clients.get_mut(name) else {
+            return false;
+        };
+        let mut sent = false;
+        sessions.retain(|session| {
+            if !matches(session.id) {
+                return true;
+            }
+            match session.lines.try_send(line.to_string()) {
+                Ok(()) => {
+                    sent = true;
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    warn!("Dropping {name} session {}, it doesn't keep up", session.id);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        if sessions.is_empty() {
+            self.clients.remove(name);
+        }
+        sent
+    }
+
+    fn send_to(&mut self, name: &str, line: &str) -> bool {
+        self.send_to_sessions(name, None, line)
+    }
+
+    fn deliver(
+        &mut self,
+        to: &str,
+        line: String,
+        ack: Option<(String, u64)>,
+        limits: &Limits,
+    ) -> Delivery {
+        if self.send_to(to, &line) {
+            return Delivery::Delivered;
+        }
+        let held = self.held.entry(to.to_string()).or_default();
+        if held.len() >= limits.max_held {
+            return Delivery::Rejected;
+        }
+        held.push_back(Held {
+            line,
+            expires: self.clock.now() + limits.ttl,
+            ack,
+        });
+        self.save_held();
+        Delivery::Stored
+    }
+
+    /// Handles the room related lines from `who`, `None` if it isn't one of them.
+    fn room_command(&mut self, who: &Identity, line: &str) -> Option<Result<(), String>> {
+        let name = who.name();
+        let commands = [
+            JOIN, PART, TOPIC, PIN, KICK, ROLE, PRIVATE, UNLISTED, CODE, INVITE,
+        ];
+        let (command, rest) = commands
+            .iter()
+            .find_map(|c| Some((*c, line.strip_prefix(c)?)))?;
+        let (room, args) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command == JOIN {
+            return Some(self.join(who, room, args));
+        }
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return Some(Err(format!("there is no {room}")));
+        };
+        let result =
+            match command {
+                PART => {
+                    entry.members.remove(name);
+                    if entry.members.is_empty() && !self.lobby.iter().any(|r| r == room) {
+                        self.rooms.remove(room);
+                    }
+                    return Some(Ok(()));
+                }
+                PRIVATE => {
+                    return Some(entry.require(who, room, Role::Op).map(|_| {
+                        entry.private = true;
+                    }))
+                }
+                UNLISTED => {
+                    return Some(entry.require(who, room, Role::Op).map(|_| {
+                        entry.unlisted = true;
+                    }))
+                }
+                CODE => {
+                    let line = entry.require(who, room, Role::Member).map(|_| {
+                        let code = rng::alphanumeric(&*self.rng, CODE_LENGTH);
+                        entry.codes.insert(code.clone());
+                        format!("{CODE}{room} {code}\n")
+                    });
+                    return Some(line.map(|line| {
+                        self.send_to(name, &line);
+                    }));
+                }
+                INVITE => {
+                    let line = entry.require(who, room, Role::Member).and_then(|_| {
+                        if args.is_empty() || args.starts_with('#') {
+                            return Err(format!("{args} isn't a name"));
+                        }
+                        if entry.members.contains_key(args) {
+                            return Err(format!("{args} is in {room} already"));
+                        }
+                        // whoever registered the name first would get in
+                        if !self.keys.contains_key(args) {
+                            return Err(format!(
+                                "{args} has never been on the relay, give them a code instead"
+                            ));
+                        }
+                        entry.invited.insert(args.to_string());
+                        Ok(format!("{INVITE}{room} {name}\n"))
+                    });
+                    // whoever isn't connected can still join, they just don't know yet
+                    return Some(line.map(|line| {
+                        self.send_to(args, &line);
+                    }));
+                }
+                TOPIC => entry.require(who, room, Role::Op).map(|_| {
+                    entry.topic =
+                        Some((name.to_string(), args.to_string())).filter(|_| !args.is_empty());
+                    format!("{TOPIC}{room} {name} {args}\n")
+                }),
+                PIN => entry.require(who, room, Role::Op).map(|_| {
+                    if args.is_empty() {
+                        entry.pins.clear();
+                    } else {
+                        entry.pins.push((name.to_string(), args.to_string()));
+                    }
+                    format!("{PIN}{room} {name} {args}\n")
+                }),
+                KICK => entry.require(who, room, Role::Op).and_then(|role| {
+                    match entry.members.get(args) {
+                        None => Err(format!("{args} isn't in {room}")),
+                        Some(&target) if target >= role => {
+                            Err(format!("{args} ranks as high as you in {room}"))
+                        }
+                        Some(_) => Ok(format!("{KICK}{room} {name} {args}\n")),
+                    }
+                }),
+                _ => {
+                    let (target, new) = args.split_once(' ').unwrap_or((args, ""));
+                    entry.require(who, room, Role::Op).and_then(|role| {
+                        let new = Role::parse(new).ok_or_else(|| format!("no role {new}"))?;
+                        match entry.members.get(target) {
+                            None => Err(format!("{target} isn't in {room}")),
+                            Some(&current) if current >= role || new >= role => Err(format!(
+                                "can't make {target} {} as {}",
+                                new.name(),
+                                role.name()
+                            )),
+                            Some(_) => {
+                                entry.members.insert(target.to_string(), new);
+                                Ok(format!("{ROLE}{room} {name} {target} {}\n", new.name()))
+                            }
+                        }
+                    })
+                }
+            };
+        Some(result.map(|line| {
+            // the one kicked learns about it too, before being dropped
+            self.broadcast(room, &line);
+            if command == KICK {
+                if let Some(entry) = self.rooms.get_mut(room) {
+                    entry.members.remove(args);
+                }
+            }
+        }))
+    }
+
+    fn join(&mut self, who: &Identity, room: &str, code: &str) -> Result<(), String> {
+        let name = who.name();
+        if !room.starts_with('#') || room.len() < 2 {
+            return Err(format!("{room} isn't a room, they start with #"));
+        }
+        let lobby = self.lobby.iter().any(|r| r == room);
+        let entry = self.rooms.entry(room.to_string()).or_insert_with(|| {
+            if lobby {
+                return Room::default();
+            }
+            info!("{name} opened {room}");
+            Room {
+                members: BTreeMap::from([(name.to_string(), Role::Owner)]),
+                ..Room::default()
+            }
+        });
+        if entry.private
+            && !entry.members.contains_key(name)
+            && !entry.invited.remove(name)
+            && !entry.codes.remove(code)
+        {
+            return Err(format!(
+                "{room} is invitation only, ask a member for a code"
+            ));
+        }
+        entry
+            .members
+            .entry(name.to_string())
+            .or_insert(Role::Member);
+        let mut lines: Vec<String> = entry
+            .topic
+            .iter()
+            .map(|(by, topic)| format!("{TOPIC}{room} {by} {topic}\n"))
+            .collect();
+        lines.extend(
+            entry
+                .pins
+                .iter()
+                .map(|(by, text)| format!("{PIN}{room} {by} {text}\n")),
+        );
+        for line in lines {
+            self.send_to(name, &line);
+        }
+        Ok(())
+    }
+
+    /// Page `page` of the rooms, or of the users connected, for `LIST <kind> <page>`.
+    fn list(&self, request: &str) -> Result<Listing, String> {
+        let (kind, page) = request.split_once(' ').unwrap_or((request, "1"));
+        let page: usize = page
+            .parse()
+            .ok()
+            .filter(|&p| p > 0)
+            .ok_or_else(|| format!("{page} isn't a page"))?;
+        let mut entries: Vec<String> = match kind {
+            "rooms" => self
+                .rooms
+                .iter()
+                .filter(|(_, r)| !r.unlisted)
+                .map(|(name, r)| {
+                    let access = if r.private { "private" } else { "open" };
+                    let entry = format!("{name} {} {access}", r.members.len());
+                    match &r.topic {
+                        Some((_, topic)) => format!("{entry} {topic}"),
+                        None => entry,
+                    }
+                })
+                .collect(),
+            "users" => self.clients.keys().cloned().collect(),
+            _ => return Err(format!("can't list {kind}, only rooms or users")),
+        };
+        entries.sort();
+        let pages = entries.len().div_ceil(PAGE).max(1);
+        if page > pages {
+            return Err(format!("there are only {pages} pages of {kind}"));
+        }
+        Ok(Listing {
+            rooms: kind == "rooms",
+            page,
+            pages,
+            entries: entries.drain((page - 1) * PAGE..).take(PAGE).collect(),
+        })
+    }
+
+    /// Writes to every member of `room` who is connected.
+    fn broadcast(&mut self, room: &str, line: &str) {
+        let members: Vec<String> = self
+            .rooms
+            .get(room)
+            .map(|r| r.members.keys().cloned().collect())
+            .unwrap_or_default();
+        for member in members {
+            self.send_to(&member, line);
+        }
+    }
+
+    /// Hands a message over to every other member, delivered if any of them got it right away.
+    fn deliver_to_room(
+        &mut self,
+        name: &str,
+        room: &str,
+        payload: &str,
+        limits: &Limits,
+    ) -> Delivery {
+        if !self
+            .rooms
+            .get(room)
+            .is_some_and(|r| r.members.contains_key(name))
+        {
+            self.send_to(name, &format!("{ERROR}you aren't in {room}\n"));
+            return Delivery::Rejected;
+        }
+        self.hand_to_members(name, room, payload, limits)
+    }
+
+    /// Hands a message from `name` over to the members of `room` but `name`, which has to exist.
+    fn hand_to_members(
+        &mut self,
+        name: &str,
+        room: &str,
+        payload: &str,
+        limits: &Limits,
+    ) -> Delivery {
+        let others: Vec<String> = self.rooms[room]
+            .members
+            .keys()
+            .filter(|m| *m != name)
+            .cloned()
+            .collect();
+        let line = format!("{ROOM}{room} {name} {payload}\n");
+        let mut state = Delivery::Stored;
+        for member in others {
+            if self.deliver(&member, line.clone(), None, limits) == Delivery::Delivered {
+                state = Delivery::Delivered;
+            }
+        }
+        state
+    }
+
+    /// Tells `sender` what happened to message `id`, held as well if they are gone.
+    fn ack(&mut self, sender: &str, id: u64, state: Delivery, limits: &Limits) {
+        self.deliver(
+            sender,
+            format!("{ACK}{id} {}\n", state.name()),
+            None,
+            limits,
+        );
+    }
+
+    /// Hands everything held for `name` over, now that it is connected.
+    fn flush(&mut self, name: &str, limits: &Limits) {
+        let Some(mut held) = self.held.remove(name) else {
+            return;
+        };
+        while let Some(msg) = held.pop_front() {
+            if !self.send_to(name, &msg.line) {
+                held.push_front(msg);
+                self.held.insert(name.to_string(), held);
+                break;
+            }
+            if let Some((sender, id)) = msg.ack {
+                self.ack(&sender, id, Delivery::Delivered, limits);
+            }
+        }
+        self.save_held();
+    }
+
+    fn expire(&mut self, limits: &Limits) {
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        let mut removed = false;
+        for held in self.held.values_mut() {
+            held.retain_mut(|msg| {
+                let keep = msg.expires > now;
+                if !keep {
+                    removed = true;
+                    expired.extend(msg.ack.take());
+                }
+                keep
+            });
+        }
+        self.held.retain(|_, held| !held.is_empty());
+        if removed {
+            self.save_held();
+        }
+        for (sender, id) in expired {
+            self.ack(&sender, id, Delivery::Expired, limits);
+        }
+    }
+}
+
+/// Serves clients until the listener fails.
+#[allow(clippy::too_many_arguments)]
+#[instrument]
+pub fn run(
+    address: &str,
+    port: u16,
+    limits: Limits,
+    webhook: &webhook::Options,
+    hooks: webhook::Hooks,
+    tuning: socket::Tuning,
+    gate: access::Gate,
+    acme: Option<acme::Options>,
+    lobby: Vec<String>,
+) -> io::Result<()> {
+    if let Some(room) = lobby.iter().find(|r| !r.starts_with('#') || r.len() < 2) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{room} isn't a room, they start with #"),
+        ));
+    }
+    let listener = TcpListener::bind((address, port))?;
+    info!("relaying on {}", listener.local_addr()?);
+    let certificate = acme.map(acme::serve).transpose()?;
+    let mut hub = match dirs::data_dir() {
+        Some(dir) => Hub::load(
+            dir.join("relay"),
+            SharedClock::default(),
+            SharedRng::default(),
+        )?,
+        None => {
+            warn!("no data directory, registrations and held messages go with the relay");
+            Hub::new(SharedClock::default(), SharedRng::default())
+        }
+    };
+    hub.lobby = lobby;
+    let hub = Arc::new(Mutex::new(hub));
+    let inbox = Arc::clone(&hub);
+    // posts are held like any other message for whoever is named in the path, or its members
+    webhook::spawn(
+        webhook,
+        Arc::new(move |to, text| {
+            let to = to.ok_or("name the recipient, /hook/<name>")?;
+            let payload = protocol::escape(&text);
+            let mut hub = inbox.lock().expect("hub lock is poisoned");
+            if to.starts_with('#') {
+                if !hub.rooms.contains_key(to) {
+                    return Err(format!("there is no room {to}"));
+                }
+                hub.hand_to_members("webhook", to, &payload, &limits);
+                return Ok(());
+            }
+            let line = format!("{FROM}webhook {payload}\n");
+            match hub.deliver(to, line, None, &limits) {
+                Delivery::Rejected => Err(format!("too many messages waiting for {to}")),
+                _ => Ok(()),
+            }
+        }),
+    )?;
+    let hooks = Arc::new(hooks);
+    let sweeper = Arc::clone(&hub);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(60));
+        sweeper
+            .lock()
+            .expect("hub lock is poisoned")
+            .expire(&limits);
+    });
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(reason) = access::admit(&gate, &stream) {
+                    info!("turned away: {reason}");
+                    continue;
+                }
+                // dead clients are noticed through keepalive, their messages are held then
+                if let Err(e) = tuning.apply(&stream) {
+                    warn!("Failed to tune client connection: {e}");
+                }
+                let (hub, hooks) = (Arc::clone(&hub), Arc::clone(&hooks));
+                let tls = certificate
+                    .as_ref()
+                    .map(|c| Arc::clone(&c.read().expect("certificate lock is poisoned")));
+                std::thread::spawn(move || {
+                    let peer = match connection::peer_address(&stream) {
+                        Ok(peer) => peer,
+                        Err(e) => return warn!("Client failed: {e}"),
+                    };
+                    let stream = match tls {
+                        Some(tls) => match tls.wrap(stream, None) {
+                            Ok((stream, _)) => stream,
+                            // challenges of the authority end here as well
+                            Err(e) => return debug!("TLS handshake with {peer} failed: {e}"),
+                        },
+                        None => stream,
+                    };
+                    if let Err(e) = serve(stream, peer, &hub, &limits, &hooks) {
+                        warn!("Client failed: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept client: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a line of at most `max` bytes with its newline, a longer one is an error before it is
+/// all in memory.
+fn read_line(reader: &mut impl BufRead, line: &mut String, max: usize) -> io::Result<usize> {
+    let read = Read::take(&mut *reader, max as u64 + 1).read_line(line)?;
+    if read > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line longer than {max} bytes"),
+        ));
+    }
+    Ok(read)
+}
+
+fn serve(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    hub: &Mutex<Hub>,
+    limits: &Limits,
+    hooks: &webhook::Hooks,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let max_line = limits.max_size + LINE_OVERHEAD;
+    let mut line = String::new();
+    read_line(&mut reader, &mut line, max_line)?;
+    let ident = line
+        .trim_end()
+        .strip_prefix(IDENT)
+        .and_then(|rest| rest.split_once(' '));
+    let (name, key) = match ident {
+        Some((name, key))
+            if !name.is_empty()
+                && !name.starts_with('#')
+                && key.len() >= MIN_KEY_LENGTH
+                && !key.contains(char::is_whitespace) =>
+        {
+            (name.to_string(), key)
+        }
+        _ => {
+            stream.write_all(
+                format!(
+                    "{ERROR}expected a name without spaces, not starting with #, and a key of at \
+                     least {MIN_KEY_LENGTH} characters\n"
+                )
+                .as_bytes(),
+            )?;
+            return Ok(());
+        }
+    };
+    let writer = stream.try_clone()?;
+    let (session, who) = {
+        let mut hub = hub.lock().expect("hub lock is poisoned");
+        let who = match hub.authenticate(&name, key) {
+            Ok(who) => who,
+            Err(e) => {
+                drop(hub);
+                info!("turned away {name} from {peer}: {e}");
+                stream.write_all(format!("{ERROR}{e}\n").as_bytes())?;
+                return Ok(());
+            }
+        };
+        hub.next_session += 1;
+        let session = Session::spawn(hub.next_session, writer)?;
+        let rooms: String = hub.lobby.iter().map(|r| format!(" {r}")).collect();
+        // nothing else is queued yet
+        let _ = session.lines.try_send(format!("{WELCOME}{rooms}\n"));
+        let id = session.id;
+        let sessions = hub.clients.entry(name.clone()).or_default();
+        sessions.push(session);
+        info!(
+            "{name} connected from {peer}, {} session(s)",
+            sessions.len()
+        );
+        // after the session is known, so it gets the topic and the pins
+        for room in hub.lobby.clone() {
+            if let Err(e) = hub.join(&who, &room, "") {
+                warn!("Failed to put {name} in {room}: {e}");
+            }
+        }
+        hub.expire(limits);
+        hub.flush(&name, limits);
+        (id, who)
+    };
+    loop {
+        line.clear();
+        match read_line(&mut reader, &mut line, max_line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("dropping {name}: {e}");
+                break;
+            }
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line == READ {
+            let mut hub = hub.lock().expect("hub lock is poisoned");
+            hub.send_to_sessions(&name, Some(session), &format!("{READ}\n"));
+            continue;
+        }
+        if let Some(request) = line.strip_prefix(LIST) {
+            let mut hub = hub.lock().expect("hub lock is poisoned");
+            let answer = match hub.list(request) {
+                Ok(listing) => listing.line(),
+                Err(e) => format!("{ERROR}{e}\n"),
+            };
+            hub.reply(&name, session, &answer);
+            continue;
+        }
+        {
+            let mut hub = hub.lock().expect("hub lock is poisoned");
+            match hub.room_command(&who, line) {
+                Some(Ok(())) => continue,
+                Some(Err(e)) => {
+                    hub.reply(&name, session, &format!("{ERROR}{e}\n"));
+                    continue;
+                }
+                None => {}
+            }
+        }
+        let addressed = line
+            .strip_prefix(TO)
+            .and_then(|r| r.split_once(' '))
+            .and_then(|(to, r)| r.split_once(' ').map(|(id, payload)| (to, id, payload)))
+            .and_then(|(to, id, payload)| Some((to, id.parse::<u64>().ok()?, payload)));
+        let Some((to, id, payload)) = addressed else {
+            debug!("ignoring {line:?} from {name}");
+            continue;
+        };
+        let mut hub = hub.lock().expect("hub lock is poisoned");
+        let state = if payload.len() > limits.max_size {
+            Delivery::Rejected
+        } else {
+            // the other devices show it as sent
+            hub.send_to_sessions(&name, Some(session), &format!("{SENT}{to} {payload}\n"));
+            if to.starts_with('#') {
+                hub.deliver_to_room(&name, to, payload, limits)
+            } else {
+                let line = format!("{FROM}{name} {payload}\n");
+                hub.deliver(to, line, Some((name.clone(), id)), limits)
+            }
+        };
+        debug!("message {id} from {name} to {to}: {}", state.name());
+        hub.ack(&name, id, state, limits);
+        // control lines aren't messages anybody would want to be paged for
+        if state != Delivery::Rejected {
+            if let protocol::Payload::Text(text) | protocol::Payload::Message { text, .. } =
+                protocol::decode(payload)
+            {
+                hooks.fire(&name, to, &text);
+            }
+        }
+    }
+    info!("{name} disconnected");
+    let mut hub = hub.lock().expect("hub lock is poisoned");
+    if let Some(sessions) = hub.clients.get_mut(&name) {
+        sessions.retain(|s| s.id != session);
+        if sessions.is_empty() {
+            hub.clients.remove(&name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::Manual, rng::Seeded};
+
+    const LIMITS: Limits = Limits {
+        ttl: Duration::from_secs(600),
+        max_held: 10,
+        max_size: 1024,
+    };
+    const KEY: &str = "a key of a decent length";
+
+    #[test]
+    fn held_messages_expire_after_the_ttl() {
+        let clock = Arc::new(Manual::new(0));
+        let mut hub = Hub::new(
+            SharedClock(clock.clone()),
+            SharedRng(Arc::new(Seeded::new(1))),
+        );
+        let line = format!("{FROM}alice hi\n");
+        let delivery = hub.deliver("bob", line, Some(("alice".to_string(), 7)), &LIMITS);
+        assert_eq!(delivery, Delivery::Stored);
+
+        clock.advance(LIMITS.ttl - Duration::from_secs(1));
+        hub.expire(&LIMITS);
+        assert_eq!(hub.held["bob"].len(), 1);
+        assert!(!hub.held.contains_key("alice"));
+
+        clock.advance(Duration::from_secs(1));
+        hub.expire(&LIMITS);
+        assert!(!hub.held.contains_key("bob"));
+        // the sender learns about it once back
+        let ack = format!("{ACK}7 {}\n", Delivery::Expired.name());
+        assert_eq!(hub.held["alice"][0].line, ack);
+    }
+
+    #[test]
+    fn lobby_rooms_have_no_owner_and_outlast_their_members() {
+        let mut hub = Hub::new(SharedClock::default(), SharedRng(Arc::new(Seeded::new(1))));
+        hub.lobby = vec!["#lobby".to_string()];
+        let alice = hub.authenticate("alice", KEY).unwrap();
+        hub.join(&alice, "#lobby", "").unwrap();
+        assert_eq!(hub.rooms["#lobby"].members["alice"], Role::Member);
+        hub.room_command(&alice, &format!("{PART}#lobby"))
+            .unwrap()
+            .unwrap();
+        assert!(hub.rooms["#lobby"].members.is_empty());
+
+        hub.join(&alice, "#elsewhere", "").unwrap();
+        assert_eq!(hub.rooms["#elsewhere"].members["alice"], Role::Owner);
+    }
+
+    #[test]
+    fn only_ops_change_a_room_and_never_those_above_them() {
+        let mut hub = Hub::new(SharedClock::default(), SharedRng(Arc::new(Seeded::new(1))));
+        let alice = hub.authenticate("alice", KEY).unwrap();
+        let bob = hub
+            .authenticate("bob", "bob's key of a decent length")
+            .unwrap();
+        hub.join(&alice, "#room", "").unwrap();
+        hub.join(&bob, "#room", "").unwrap();
+        let topic = format!("{TOPIC}#room hi");
+        assert!(hub.room_command(&bob, &topic).unwrap().is_err());
+        assert!(hub
+            .room_command(&bob, &format!("{KICK}#room alice"))
+            .unwrap()
+            .is_err());
+
+        hub.room_command(&alice, &format!("{ROLE}#room bob op"))
+            .unwrap()
+            .unwrap();
+        hub.room_command(&bob, &topic).unwrap().unwrap();
+        assert!(hub
+            .room_command(&bob, &format!("{KICK}#room alice"))
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn private_rooms_take_an_invitation_or_a_code() {
+        let mut hub = Hub::new(SharedClock::default(), SharedRng(Arc::new(Seeded::new(1))));
+        let alice = hub.authenticate("alice", KEY).unwrap();
+        let eve = hub
+            .authenticate("eve", "eve's key of a decent length")
+            .unwrap();
+        hub.join(&alice, "#secret", "").unwrap();
+        hub.room_command(&alice, &format!("{PRIVATE}#secret"))
+            .unwrap()
+            .unwrap();
+        assert!(hub.join(&eve, "#secret", "").is_err());
+        assert!(hub
+            .room_command(&alice, &format!("{INVITE}#secret carol"))
+            .unwrap()
+            .is_err());
+
+        let carol = hub
+            .authenticate("carol", "carol's key of a decent length")
+            .unwrap();
+        hub.room_command(&alice, &format!("{INVITE}#secret carol"))
+            .unwrap()
+            .unwrap();
+        hub.join(&carol, "#secret", "").unwrap();
+        assert!(hub.join(&eve, "#secret", "").is_err());
+    }
+
+    #[test]
+    fn the_directory_leaves_out_unlisted_rooms_and_pages() {
+        let mut hub = Hub::new(SharedClock::default(), SharedRng(Arc::new(Seeded::new(1))));
+        let alice = hub.authenticate("alice", KEY).unwrap();
+        for i in 0..25 {
+            hub.join(&alice, &format!("#room{i:02}"), "").unwrap();
+        }
+        hub.room_command(&alice, &format!("{UNLISTED}#room00"))
+            .unwrap()
+            .unwrap();
+        hub.room_command(&alice, &format!("{PRIVATE}#room01"))
+            .unwrap()
+            .unwrap();
+
+        let first = hub.list("rooms 1").unwrap();
+        assert_eq!((first.page, first.pages), (1, 2));
+        assert_eq!(first.entries[0], "#room01 1 private");
+        assert_eq!(first.entries.len(), PAGE);
+        assert_eq!(hub.list("rooms").unwrap(), first);
+        let second = hub.list("rooms 2").unwrap();
+        assert_eq!(second.entries.len(), 24 - PAGE);
+        assert_eq!(Listing::parse(second.line().trim_end()), Some(second));
+        assert!(hub.list("rooms 3").is_err());
+        assert!(hub.list("users").unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn a_session_which_stops_reading_is_dropped() {
+        let mut hub = Hub::new(SharedClock::default(), SharedRng(Arc::new(Seeded::new(1))));
+        let (stuck, _stuck_lines) = mpsc::sync_channel(1);
+        let (reading, reading_lines) = mpsc::sync_channel(QUEUE);
+        hub.clients.insert(
+            "alice".to_string(),
+            vec![
+                Session {
+                    id: 1,
+                    lines: stuck,
+                },
+                Session {
+                    id: 2,
+                    lines: reading,
+                },
+            ],
+        );
+        let line = format!("{FROM}bob hi\n");
+        assert!(hub.send_to("alice", &line));
+        assert!(hub.send_to("alice", &line));
+        let left: Vec<u64> = hub.clients["alice"].iter().map(|s| s.id).collect();
+        assert_eq!(left, [2]);
+        assert_eq!(reading_lines.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn a_name_belongs_to_whoever_registered_it() {
+        let mut hub = Hub::new(SharedClock::default(), SharedRng(Arc::new(Seeded::new(1))));
+        hub.authenticate("alice", "alice's key of a decent length")
+            .unwrap();
+        hub.authenticate("alice", "alice's key of a decent length")
+            .unwrap();
+        assert!(hub.authenticate("alice", "somebody else's key").is_err());
+        hub.authenticate("bob", "somebody else's key").unwrap();
+    }
+
+    #[test]
+    fn message_ids_start_from_the_clock() {
+        let clock = Manual::new(1_000);
+        let mut route = Route::new("alice".to_string(), "bob".to_string(), &clock);
+        assert_eq!(route.next_id(), 1_001);
+        assert_eq!(route.next_id(), 1_002);
+    }
+
+    #[test]
+    fn room_messages_and_topics_are_parsed() {
+        assert_eq!(
+            parse(&format!("{ROOM}#rust alice hi there")),
+            Frame::Room {
+                room: "#rust",
+                sender: "alice",
+                payload: "hi there",
+            }
+        );
+        assert_eq!(
+            parse(&format!("{TOPIC}#rust alice no\\nspam")),
+            Frame::Topic {
+                room: "#rust",
+                by: "alice",
+                topic: "no\\nspam",
+            }
+        );
+        // a cleared topic
+        assert_eq!(
+            parse(&format!("{TOPIC}#rust alice")),
+            Frame::Topic {
+                room: "#rust",
+                by: "alice",
+                topic: "",
+            }
+        );
+        assert_eq!(
+            parse(&format!("{ROOM}#rust")),
+            Frame::Other(&format!("{ROOM}#rust"))
+        );
+    }
+
+    #[test]
+    fn endless_lines_are_cut_off() {
+        let mut reader = io::Cursor::new(b"short\nlonger than ten\n".to_vec());
+        let mut line = String::new();
+        assert_eq!(read_line(&mut reader, &mut line, 10).unwrap(), 6);
+        line.clear();
+        let e = read_line(&mut reader, &mut line, 10).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        // no more than the cap is read
+        assert_eq!(line.len(), 11);
+        assert_eq!(
+            read_line(&mut io::Cursor::new(b""), &mut line, 10).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn registrations_and_held_messages_outlive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("chatterbox-relay-{}", std::process::id()));
+        let rng = || SharedRng(Arc::new(Seeded::new(1)));
+        let mut hub = Hub::load(dir.clone(), SharedClock(Arc::new(Manual::new(0))), rng()).unwrap();
+        hub.authenticate("alice", KEY).unwrap();
+        let line = format!("{FROM}alice hi\tthere\n");
+        let delivery = hub.deliver("bob", line.clone(), Some(("alice".to_string(), 7)), &LIMITS);
+        assert_eq!(delivery, Delivery::Stored);
+        drop(hub);
+
+        let clock = Arc::new(Manual::new(0));
+        let mut hub = Hub::load(dir.clone(), SharedClock(clock.clone()), rng()).unwrap();
+        assert!(hub
+            .authenticate("alice", "another key of some length")
+            .is_err());
+        assert!(hub.authenticate("alice", KEY).is_ok());
+        assert_eq!(hub.held["bob"][0].line, line);
+        assert_eq!(hub.held["bob"][0].ack, Some(("alice".to_string(), 7)));
+        // the time to live goes on from where it was
+        clock.advance(LIMITS.ttl);
+        hub.expire(&LIMITS);
+        assert!(!hub.held.contains_key("bob"));
+        drop(hub);
+
+        let hub = Hub::load(dir.clone(), SharedClock(clock), rng()).unwrap();
+        assert!(!hub.held.contains_key("bob"));
+        assert!(hub.held.contains_key("alice"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
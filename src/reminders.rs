@@ -0,0 +1,155 @@
+//! Local reminders set with `/remind`, kept on disk until they are due.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use tracing::{error, instrument, warn};
+
+use crate::clock::SharedClock;
+
+#[derive(Debug, Clone)]
+pub struct Reminder {
+    /// Seconds since the unix epoch
+    pub due: u64,
+    pub text: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Reminders {
+    /// Backing file, one `<due> <text>` per line. `None` keeps them in memory only.
+    path: Option<PathBuf>,
+    pending: Vec<Reminder>,
+    /// Tells when they are due
+    clock: SharedClock,
+}
+
+impl Reminders {
+    /// Reminders kept in memory only, due by `clock`.
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            path: None,
+            pending: Vec::new(),
+            clock,
+        }
+    }
+
+    #[instrument]
+    pub fn load(clock: SharedClock) -> Self {
+        let Some(path) = crate::dirs::data_dir().map(|d| d.join("reminders")) else {
+            warn!("Couldn't determine data directory, reminders won't be persisted");
+            return Self::new(clock);
+        };
+        let pending = match fs::read_to_string(&path) {
+            Ok(content) => content
+                .lines()
+                .filter_map(|line| {
+                    let (due, text) = line.split_once(' ')?;
+                    Some(Reminder {
+                        due: due.parse().ok()?,
+                        text: text.to_string(),
+                    })
+                })
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                error!("Failed to read reminders {}: {e}", path.display());
+                Vec::new()
+            }
+        };
+        Self {
+            path: Some(path),
+            pending,
+            clock,
+        }
+    }
+
+    /// Seconds since the unix epoch.
+    fn now(&self) -> u64 {
+        self.clock.millis() / 1000
+    }
+
+    pub fn add(&mut self, after: Duration, text: String) {
+        self.pending.push(Reminder {
+            due: self.now() + after.as_secs(),
+            text,
+        });
+        self.persist();
+    }
+
+    /// Pending reminders with the time left until each is due.
+    pub fn pending(&self) -> impl Iterator<Item = (Duration, &str)> {
+        let now = self.now();
+        self.pending.iter().map(move |r| {
+            (
+                Duration::from_secs(r.due.saturating_sub(now)),
+                r.text.as_str(),
+            )
+        })
+    }
+
+    /// Removes and returns the reminders which are due, oldest first.
+    pub fn take_due(&mut self) -> Vec<Reminder> {
+        let now = self.now();
+        if !self.pending.iter().any(|r| r.due <= now) {
+            return Vec::new();
+        }
+        let (mut due, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|r| r.due <= now);
+        self.pending = pending;
+        self.persist();
+        due.sort_by_key(|r| r.due);
+        due
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let res = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::File::create(path))
+            .and_then(|mut file| {
+                self.pending
+                    .iter()
+                    .try_for_each(|r| writeln!(file, "{} {}", r.due, r.text))
+            });
+        if let Err(e) = res {
+            error!("Failed to save reminders {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::clock::Manual;
+
+    #[test]
+    fn reminders_are_due_in_order() {
+        let clock = Arc::new(Manual::new(1_700_000_000_000));
+        let mut reminders = Reminders::new(SharedClock(clock.clone()));
+        reminders.add(Duration::from_secs(120), "later".to_string());
+        reminders.add(Duration::from_secs(60), "sooner".to_string());
+
+        clock.advance(Duration::from_secs(59));
+        assert!(reminders.take_due().is_empty());
+        let left: Vec<_> = reminders
+            .pending()
+            .map(|(left, _)| left.as_secs())
+            .collect();
+        assert_eq!(left, [61, 1]);
+
+        clock.advance(Duration::from_secs(61));
+        let due: Vec<_> = reminders.take_due().into_iter().map(|r| r.text).collect();
+        assert_eq!(due, ["sooner", "later"]);
+        assert_eq!(reminders.pending().count(), 0);
+    }
+}
@@ -0,0 +1,224 @@
+//! What the subcommands print with `--json`, one object per line so scripts can read them as
+//! they come. Every object names its `type`:
+//!
+//! - `exported` by `export-html`: `peer`, `file` and the number of `messages` written
+//! - `imported` by `import`: `peer` and the number of new `messages`
+//! - `sent` by `attach --send`: `session`
+//! - `status` by `attach --status`: `session`, whether `connected` and how many are `unread`
+//! - `message` by `attach --follow`, for every message: `session`, `kind` being `in`, `out` or
+//!   `sys`, `text`, and the `data` a bot annotated it with if there is any
+//! - `session` by `sessions`, for every running one: `name` and how many are `unread`
+//! - `relaying` by `relay` when it starts: `listen` and `port`
+//! - `error` when a subcommand fails, with the `error` itself, the exit status is 1 then
+//!
+//! Later versions may add fields, but the ones listed keep their names and meaning.
+
+use std::path::PathBuf;
+
+use crate::json::Value;
+
+/// Result of a subcommand, printed as one line of JSON.
+pub trait Report {
+    fn to_json(&self) -> Value;
+}
+
+/// Prints the result, on a line of its own.
+pub fn print(report: &impl Report) {
+    println!("{}", report.to_json());
+}
+
+fn object(kind: &str, fields: Vec<(&str, Value)>) -> Value {
+    let kind = ("type".to_string(), Value::String(kind.to_string()));
+    Value::Object(
+        std::iter::once(kind)
+            .chain(fields.into_iter().map(|(k, v)| (k.to_string(), v)))
+            .collect(),
+    )
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn count(n: usize) -> Value {
+    Value::Number(n as f64)
+}
+
+#[derive(Debug, Clone)]
+pub struct Exported {
+    pub peer: String,
+    pub file: PathBuf,
+    pub messages: usize,
+}
+
+impl Report for Exported {
+    fn to_json(&self) -> Value {
+        object(
+            "exported",
+            vec![
+                ("peer", string(&self.peer)),
+                ("file", string(&self.file.to_string_lossy())),
+                ("messages", count(self.messages)),
+            ],
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Imported {
+    pub peer: String,
+    /// Not counting those already in the history
+    pub messages: usize,
+}
+
+impl Report for Imported {
+    fn to_json(&self) -> Value {
+        object(
+            "imported",
+            vec![
+                ("peer", string(&self.peer)),
+                ("messages", count(self.messages)),
+            ],
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sent {
+    pub session: String,
+}
+
+impl Report for Sent {
+    fn to_json(&self) -> Value {
+        object("sent", vec![("session", string(&self.session))])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub session: String,
+    pub connected: bool,
+    pub unread: usize,
+}
+
+impl Report for Status {
+    fn to_json(&self) -> Value {
+        object(
+            "status",
+            vec![
+                ("session", string(&self.session)),
+                ("connected", Value::Bool(self.connected)),
+                ("unread", count(self.unread)),
+            ],
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Followed {
+    pub session: String,
+    /// `in`, `out` or `sys`
+    pub kind: String,
+    pub text: String,
+    pub data: Option<Value>,
+}
+
+impl Report for Followed {
+    fn to_json(&self) -> Value {
+        let mut fields = vec![
+            ("session", string(&self.session)),
+            ("kind", string(&self.kind)),
+            ("text", string(&self.text)),
+        ];
+        if let Some(data) = &self.data {
+            fields.push(("data", data.clone()));
+        }
+        object("message", fields)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub name: String,
+    pub unread: usize,
+}
+
+impl Report for Session {
+    fn to_json(&self) -> Value {
+        object(
+            "session",
+            vec![("name", string(&self.name)), ("unread", count(self.unread))],
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Relaying {
+    pub listen: String,
+    pub port: u16,
+}
+
+impl Report for Relaying {
+    fn to_json(&self) -> Value {
+        object(
+            "relaying",
+            vec![
+                ("listen", string(&self.listen)),
+                ("port", count(self.port.into())),
+            ],
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Failed {
+    pub error: String,
+}
+
+impl Report for Failed {
+    fn to_json(&self) -> Value {
+        object("error", vec![("error", string(&self.error))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn objects_start_with_their_type() {
+        let status = Status {
+            session: "main".to_string(),
+            connected: true,
+            unread: 3,
+        };
+        assert_eq!(
+            status.to_json().to_string(),
+            r#"{"type":"status","session":"main","connected":true,"unread":3}"#
+        );
+        let relaying = Relaying {
+            listen: "::".to_string(),
+            port: 4000,
+        };
+        assert_eq!(
+            relaying.to_json().to_string(),
+            r#"{"type":"relaying","listen":"::","port":4000}"#
+        );
+    }
+
+    #[test]
+    fn messages_only_carry_data_when_annotated() {
+        let mut message = Followed {
+            session: "main".to_string(),
+            kind: "in".to_string(),
+            text: "say \"hi\"".to_string(),
+            data: None,
+        };
+        assert_eq!(
+            message.to_json().to_string(),
+            r#"{"type":"message","session":"main","kind":"in","text":"say \"hi\""}"#
+        );
+        message.data = Some(Value::Array(vec![Value::Null]));
+        assert_eq!(message.to_json().get("data"), message.data.as_ref());
+    }
+}
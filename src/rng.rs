@@ -0,0 +1,86 @@
+//! Randomness for keys, nonces and codes, seeded in tests to get the same ones every run.
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+pub trait Rng: fmt::Debug + Send + Sync {
+    fn fill(&self, buf: &mut [u8]);
+}
+
+/// Randomness of the operating system, what keys are made from.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct System;
+
+impl Rng for System {
+    fn fill(&self, buf: &mut [u8]) {
+        rand::thread_rng().fill_bytes(buf);
+    }
+}
+
+/// Same sequence for the same seed, never for anything real.
+#[derive(Debug)]
+pub struct Seeded(Mutex<StdRng>);
+
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        Self(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+impl Rng for Seeded {
+    fn fill(&self, buf: &mut [u8]) {
+        self.0.lock().expect("rng lock is poisoned").fill_bytes(buf);
+    }
+}
+
+/// `len` letters and digits.
+pub fn alphanumeric(rng: &dyn Rng, len: usize) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut code = String::with_capacity(len);
+    let mut byte = [0];
+    while code.len() < len {
+        rng.fill(&mut byte);
+        // 64 fits 256 evenly, the two past the alphabet are drawn again
+        if let Some(&c) = CHARS.get(usize::from(byte[0]) % 64) {
+            code.push(char::from(c));
+        }
+    }
+    code
+}
+
+/// Randomness shared by whoever needs some, the system's unless given another.
+#[derive(Debug, Clone)]
+pub struct SharedRng(pub Arc<dyn Rng>);
+
+impl Default for SharedRng {
+    fn default() -> Self {
+        Self(Arc::new(System))
+    }
+}
+
+impl Deref for SharedRng {
+    type Target = dyn Rng;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_seed_gives_the_same_codes() {
+        let code = alphanumeric(&Seeded::new(7), 32);
+        assert_eq!(code.len(), 32);
+        assert!(code.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_eq!(alphanumeric(&Seeded::new(7), 32), code);
+        assert_ne!(alphanumeric(&Seeded::new(8), 32), code);
+    }
+}
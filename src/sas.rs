@@ -0,0 +1,109 @@
+//! Short authentication string, shown on both ends so the users can compare them out of band.
+
+/// Each symbol carries 6 bits of the session key.
+const SYMBOLS: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐎", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("☁️", "cloud"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("❤️", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("☂️", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("✏️", "pencil"),
+    ("📎", "paperclip"),
+    ("✂️", "scissors"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("☎️", "telephone"),
+    ("🏁", "flag"),
+    ("🚂", "train"),
+    ("🚲", "bicycle"),
+    ("✈️", "aeroplane"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+/// Number of symbols shown, 42 bits are plenty for a live comparison.
+const LEN: usize = 7;
+
+/// Renders the first `LEN * 6` bits of `material` as emoji with their names.
+pub fn derive(material: &[u8]) -> String {
+    let mut bits = material
+        .iter()
+        .flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1));
+    (0..LEN)
+        .map(|_| {
+            let index = bits
+                .by_ref()
+                .take(6)
+                .fold(0usize, |acc, bit| (acc << 1) | bit as usize);
+            let (emoji, name) = SYMBOLS[index];
+            format!("{emoji} {name}")
+        })
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_six_bits_pick_a_symbol() {
+        assert_eq!(derive(&[0; 6]), ["🐶 dog"; LEN].join("  "));
+        assert_eq!(derive(&[0xff; 6]), ["📌 pin"; LEN].join("  "));
+        // 000001 000010 000011 …
+        let sas = derive(&[0x04, 0x20, 0xc4, 0x14, 0x61, 0xc0]);
+        assert_eq!(
+            sas,
+            "🐱 cat  🦁 lion  🐎 horse  🦄 unicorn  🐷 pig  🐘 elephant  🐰 rabbit"
+        );
+        // bits past the first 42 don't matter
+        assert_eq!(derive(&[0x04, 0x20, 0xc4, 0x14, 0x61, 0xc3]), sas);
+    }
+}
@@ -0,0 +1,412 @@
+//! End-to-end encryption with a passphrase both peers know, `--key-phrase`.
+//!
+//! The key is derived from the passphrase with PBKDF2, so the same phrase gives the same key on
+//! both ends without any handshake. Every payload goes out as `\u{1}SEAL <hex>`, a fresh random
+//! nonce followed by the ChaCha20-Poly1305 ciphertext and its tag. Relays and the hub of
+//! `--multi` only ever see the sealed lines.
+//!
+//! Sealed with the payload are the session of the sender, random and new for every connection,
+//! and the number of the payload in it. Only the next number of a session is opened, so lines
+//! can't be replayed, dropped or reordered on the way, and our own sessions never come back from
+//! the peer.
+//!
+//! It goes through the system's OpenSSL, like TLS.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_int, c_void},
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::fd::AsRawFd,
+    sync::Mutex,
+};
+
+use crate::rng::SharedRng;
+
+const SEAL: &str = "\u{1}SEAL ";
+/// Salt of the key derivation, the passphrase is all that differs between conversations.
+const SALT: &[u8] = b"chatterbox key phrase";
+const ITERATIONS: c_int = 200_000;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const SESSION_LEN: usize = 8;
+/// Session and number in front of the payload.
+const HEADER_LEN: usize = SESSION_LEN + 8;
+
+type Session = [u8; SESSION_LEN];
+
+/// Key derived from the passphrase.
+pub struct Seal {
+    key: [u8; KEY_LEN],
+    /// Nonces and sessions
+    rng: SharedRng,
+    /// Our session and the number of the next payload in it
+    sending: Mutex<(Session, u64)>,
+    /// Number of the next payload of every session seen, none for ours
+    expected: Mutex<HashMap<Session, u64>>,
+}
+
+impl std::fmt::Debug for Seal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Seal")
+    }
+}
+
+/// What [`Seal::open`] made of an incoming line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Opened {
+    Plain(String),
+    /// Sent without encryption, anybody on the way could have read or changed it
+    Unsealed(String),
+    /// Sealed with another key, or changed, replayed, dropped or reordered on the way
+    Failed,
+}
+
+impl Seal {
+    pub fn new(passphrase: &str, rng: SharedRng) -> io::Result<Self> {
+        let mut key = [0; KEY_LEN];
+        // SAFETY: all buffers are valid for the lengths passed along
+        let ok = unsafe {
+            ffi::PKCS5_PBKDF2_HMAC(
+                passphrase.as_ptr().cast(),
+                passphrase.len() as c_int,
+                SALT.as_ptr(),
+                SALT.len() as c_int,
+                ITERATIONS,
+                ffi::EVP_sha256(),
+                KEY_LEN as c_int,
+                key.as_mut_ptr(),
+            )
+        };
+        if ok != 1 {
+            return Err(io::Error::other("failed to derive the key"));
+        }
+        let seal = Self {
+            key,
+            rng,
+            sending: Mutex::new(([0; SESSION_LEN], 0)),
+            expected: Mutex::default(),
+        };
+        seal.restart();
+        Ok(seal)
+    }
+
+    /// Starts a new session, for a new connection.
+    pub fn restart(&self) {
+        let mut session = [0; SESSION_LEN];
+        self.rng.fill(&mut session);
+        // anything sealed in it is the peer's to open, not ours
+        self.expected
+            .lock()
+            .expect("seal lock is poisoned")
+            .insert(session, u64::MAX);
+        *self.sending.lock().expect("seal lock is poisoned") = (session, 0);
+    }
+
+    /// Wire representation of `payload`.
+    pub fn seal(&self, payload: &str) -> io::Result<String> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce);
+        let payload = {
+            let mut sending = self.sending.lock().expect("seal lock is poisoned");
+            let (session, number) = &mut *sending;
+            let mut numbered = Vec::with_capacity(HEADER_LEN + payload.len());
+            numbered.extend_from_slice(session);
+            numbered.extend_from_slice(&number.to_be_bytes());
+            numbered.extend_from_slice(payload.as_bytes());
+            *number += 1;
+            numbered
+        };
+        let cipher = Cipher::new()?;
+        let mut out = vec![0; payload.len()];
+        let mut tag = [0; TAG_LEN];
+        let mut len = 0;
+        // SAFETY: the output has room for the input, the stream cipher adds nothing and the tag
+        // buffer is as long as the tag asked for
+        let ok = unsafe {
+            ffi::EVP_EncryptInit_ex(
+                cipher.0,
+                ffi::EVP_chacha20_poly1305(),
+                std::ptr::null_mut(),
+                self.key.as_ptr(),
+                nonce.as_ptr(),
+            ) == 1
+                && ffi::EVP_EncryptUpdate(
+                    cipher.0,
+                    out.as_mut_ptr(),
+                    &mut len,
+                    payload.as_ptr(),
+                    payload.len() as c_int,
+                ) == 1
+                && ffi::EVP_EncryptFinal_ex(cipher.0, out.as_mut_ptr(), &mut 0) == 1
+                && ffi::EVP_CIPHER_CTX_ctrl(
+                    cipher.0,
+                    ffi::EVP_CTRL_AEAD_GET_TAG,
+                    TAG_LEN as c_int,
+                    tag.as_mut_ptr().cast(),
+                ) == 1
+        };
+        if !ok {
+            return Err(io::Error::other("failed to encrypt"));
+        }
+        Ok(format!(
+            "{SEAL}{}{}{}",
+            hex::encode(nonce),
+            hex::encode(&out[..len as usize]),
+            hex::encode(tag)
+        ))
+    }
+
+    pub fn open(&self, line: &str) -> Opened {
+        let Some(sealed) = line.strip_prefix(SEAL) else {
+            return Opened::Unsealed(line.to_string());
+        };
+        let Ok(bytes) = hex::decode(sealed.trim()) else {
+            return Opened::Failed;
+        };
+        if bytes.len() < NONCE_LEN + TAG_LEN {
+            return Opened::Failed;
+        }
+        let (nonce, rest) = bytes.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let Ok(cipher) = Cipher::new() else {
+            return Opened::Failed;
+        };
+        let mut out = vec![0; ciphertext.len()];
+        let mut len = 0;
+        // SAFETY: as when sealing, the tag is only read
+        let ok = unsafe {
+            ffi::EVP_DecryptInit_ex(
+                cipher.0,
+                ffi::EVP_chacha20_poly1305(),
+                std::ptr::null_mut(),
+                self.key.as_ptr(),
+                nonce.as_ptr(),
+            ) == 1
+                && ffi::EVP_DecryptUpdate(
+                    cipher.0,
+                    out.as_mut_ptr(),
+                    &mut len,
+                    ciphertext.as_ptr(),
+                    ciphertext.len() as c_int,
+                ) == 1
+                && ffi::EVP_CIPHER_CTX_ctrl(
+                    cipher.0,
+                    ffi::EVP_CTRL_AEAD_SET_TAG,
+                    TAG_LEN as c_int,
+                    tag.as_ptr() as *mut c_void,
+                ) == 1
+                // checks the tag
+                && ffi::EVP_DecryptFinal_ex(cipher.0, out.as_mut_ptr(), &mut 0) == 1
+        };
+        out.truncate(len as usize);
+        if !ok || out.len() < HEADER_LEN {
+            return Opened::Failed;
+        }
+        let payload = out.split_off(HEADER_LEN);
+        let (session, number) = out.split_at(SESSION_LEN);
+        let session: Session = session.try_into().expect("split at its length");
+        let number = u64::from_be_bytes(number.try_into().expect("the rest of the header"));
+        let mut expected = self.expected.lock().expect("seal lock is poisoned");
+        let next = expected.entry(session).or_insert(0);
+        if number != *next {
+            return Opened::Failed;
+        }
+        match String::from_utf8(payload) {
+            Ok(payload) => {
+                *next += 1;
+                Opened::Plain(payload)
+            }
+            Err(_) => Opened::Failed,
+        }
+    }
+}
+
+/// Asks for the passphrase, or another secret named by `label`, on the terminal without showing
+/// it.
+pub fn prompt(label: &str) -> io::Result<String> {
+    let mut tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")?;
+    tty.write_all(format!("{label}: ").as_bytes())?;
+    let fd = tty.as_raw_fd();
+    // SAFETY: termios is plain data, filled in by tcgetattr before it is used
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    // SAFETY: fd is open for as long as tty lives
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let echoing = termios;
+    termios.c_lflag &= !libc::ECHO;
+    // SAFETY: as above
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    let mut phrase = String::new();
+    let read = BufReader::new(&tty).read_line(&mut phrase);
+    // SAFETY: as above
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &echoing) };
+    tty.write_all(b"\n")?;
+    read?;
+    Ok(phrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Cipher context, freed when dropped.
+struct Cipher(*mut ffi::EVP_CIPHER_CTX);
+
+impl Cipher {
+    fn new() -> io::Result<Self> {
+        // SAFETY: checked for failure below
+        let ctx = unsafe { ffi::EVP_CIPHER_CTX_new() };
+        if ctx.is_null() {
+            return Err(io::Error::other("failed to create a cipher context"));
+        }
+        Ok(Self(ctx))
+    }
+}
+
+impl Drop for Cipher {
+    fn drop(&mut self) {
+        // SAFETY: created in `new` and not used after this
+        unsafe { ffi::EVP_CIPHER_CTX_free(self.0) }
+    }
+}
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+mod ffi {
+    use super::*;
+    pub use crate::tls::ffi::{EVP_sha256, ENGINE, EVP_MD};
+
+    pub enum EVP_CIPHER_CTX {}
+    pub enum EVP_CIPHER {}
+
+    pub const EVP_CTRL_AEAD_GET_TAG: c_int = 0x10;
+    pub const EVP_CTRL_AEAD_SET_TAG: c_int = 0x11;
+
+    #[link(name = "crypto")]
+    extern "C" {
+        pub fn PKCS5_PBKDF2_HMAC(
+            pass: *const std::ffi::c_char,
+            passlen: c_int,
+            salt: *const u8,
+            saltlen: c_int,
+            iter: c_int,
+            digest: *const EVP_MD,
+            keylen: c_int,
+            out: *mut u8,
+        ) -> c_int;
+        pub fn EVP_chacha20_poly1305() -> *const EVP_CIPHER;
+        pub fn EVP_CIPHER_CTX_new() -> *mut EVP_CIPHER_CTX;
+        pub fn EVP_CIPHER_CTX_free(ctx: *mut EVP_CIPHER_CTX);
+        pub fn EVP_CIPHER_CTX_ctrl(
+            ctx: *mut EVP_CIPHER_CTX,
+            kind: c_int,
+            arg: c_int,
+            ptr: *mut c_void,
+        ) -> c_int;
+        pub fn EVP_EncryptInit_ex(
+            ctx: *mut EVP_CIPHER_CTX,
+            cipher: *const EVP_CIPHER,
+            engine: *mut ENGINE,
+            key: *const u8,
+            iv: *const u8,
+        ) -> c_int;
+        pub fn EVP_EncryptUpdate(
+            ctx: *mut EVP_CIPHER_CTX,
+            out: *mut u8,
+            outl: *mut c_int,
+            input: *const u8,
+            inl: c_int,
+        ) -> c_int;
+        pub fn EVP_EncryptFinal_ex(
+            ctx: *mut EVP_CIPHER_CTX,
+            out: *mut u8,
+            outl: *mut c_int,
+        ) -> c_int;
+        pub fn EVP_DecryptInit_ex(
+            ctx: *mut EVP_CIPHER_CTX,
+            cipher: *const EVP_CIPHER,
+            engine: *mut ENGINE,
+            key: *const u8,
+            iv: *const u8,
+        ) -> c_int;
+        pub fn EVP_DecryptUpdate(
+            ctx: *mut EVP_CIPHER_CTX,
+            out: *mut u8,
+            outl: *mut c_int,
+            input: *const u8,
+            inl: c_int,
+        ) -> c_int;
+        pub fn EVP_DecryptFinal_ex(
+            ctx: *mut EVP_CIPHER_CTX,
+            out: *mut u8,
+            outl: *mut c_int,
+        ) -> c_int;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Seal, Seal) {
+        let ada = Seal::new("correct horse", SharedRng::default()).unwrap();
+        let bob = Seal::new("correct horse", SharedRng::default()).unwrap();
+        (ada, bob)
+    }
+
+    #[test]
+    fn payloads_open_on_the_other_end_in_order() {
+        let (ada, bob) = pair();
+        let first = ada.seal("hi").unwrap();
+        let second = ada.seal("how are you").unwrap();
+        assert_eq!(bob.open(&first), Opened::Plain("hi".to_string()));
+        assert_eq!(bob.open(&second), Opened::Plain("how are you".to_string()));
+        assert_eq!(
+            ada.open(&bob.seal("").unwrap()),
+            Opened::Plain(String::new())
+        );
+        assert_eq!(bob.open("hi"), Opened::Unsealed("hi".to_string()));
+
+        let eve = Seal::new("wrong horse", SharedRng::default()).unwrap();
+        assert_eq!(eve.open(&ada.seal("secret").unwrap()), Opened::Failed);
+    }
+
+    #[test]
+    fn changed_lines_dont_open() {
+        let (ada, bob) = pair();
+        let line = ada.seal("pay 10").unwrap();
+        let flipped = |at: usize| {
+            let mut bytes = line.clone().into_bytes();
+            bytes[at] = if bytes[at] == b'0' { b'1' } else { b'0' };
+            String::from_utf8(bytes).unwrap()
+        };
+        // in the nonce, the ciphertext and the tag
+        for at in [SEAL.len(), SEAL.len() + 2 * NONCE_LEN + 1, line.len() - 1] {
+            assert_eq!(bob.open(&flipped(at)), Opened::Failed);
+        }
+        assert_eq!(bob.open(&line[..line.len() - 2]), Opened::Failed);
+        assert_eq!(bob.open(&line), Opened::Plain("pay 10".to_string()));
+    }
+
+    #[test]
+    fn only_the_next_payload_of_a_session_opens() {
+        let (ada, bob) = pair();
+        let first = ada.seal("first").unwrap();
+        let second = ada.seal("second").unwrap();
+        assert_eq!(bob.open(&second), Opened::Failed);
+        assert_eq!(bob.open(&first), Opened::Plain("first".to_string()));
+        assert_eq!(bob.open(&first), Opened::Failed);
+        assert_eq!(bob.open(&second), Opened::Plain("second".to_string()));
+        // reflected back at the sender
+        assert_eq!(ada.open(&ada.seal("mine").unwrap()), Opened::Failed);
+
+        // a lost line doesn't hold up the next connection
+        let _lost = ada.seal("lost").unwrap();
+        ada.restart();
+        assert_eq!(
+            bob.open(&ada.seal("again").unwrap()),
+            Opened::Plain("again".to_string())
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Scripted conversation for screenshots and demos, `--simulate <script.toml>`:
+//!
+//! ```toml
+//! peer = "alice"        # name the peer sends with
+//!
+//! [[message]]
+//! from = "peer"         # or "me"
+//! after = "2s"          # since the previous one, by default about as long as typing it takes
+//! text = "are you there?"
+//! ```
+//!
+//! The peer is played on a local port the app connects to like to any other, the messages of
+//! `me` go out as if typed into the input box.
+
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpListener},
+    sync::mpsc,
+    time::Duration,
+};
+
+use toml_edit::Document;
+use tracing::{debug, warn};
+
+use crate::{detach, protocol, tasks, timestamp};
+
+/// Pause before a message, and what typing every character of it adds.
+const THINKING: Duration = Duration::from_millis(800);
+const PER_CHARACTER: Duration = Duration::from_millis(60);
+
+#[derive(Debug)]
+pub struct Script {
+    peer: String,
+    messages: Vec<Line>,
+}
+
+#[derive(Debug)]
+struct Line {
+    mine: bool,
+    after: Duration,
+    text: String,
+}
+
+impl Script {
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let doc: Document = content
+            .parse()
+            .map_err(|e| format!("invalid script: {e}"))?;
+        let peer = doc
+            .get("peer")
+            .and_then(|p| p.as_str())
+            .unwrap_or("peer")
+            .to_string();
+        let Some(tables) = doc.get("message").and_then(|m| m.as_array_of_tables()) else {
+            return Err("the script has no [[message]]".to_string());
+        };
+        let mut messages = Vec::new();
+        for (i, table) in tables.iter().enumerate() {
+            let field = |key| table.get(key).and_then(|v| v.as_str());
+            let text = field("text").ok_or_else(|| format!("message {} has no text", i + 1))?;
+            let mine = match field("from") {
+                None | Some("peer") => false,
+                Some("me") => true,
+                Some(other) => {
+                    return Err(format!(
+                        "message {} is from {other:?}, not peer or me",
+                        i + 1
+                    ))
+                }
+            };
+            let after = match field("after") {
+                Some(after) => timestamp::parse_duration(after)
+                    .ok_or_else(|| format!("message {} waits for {after:?}", i + 1))?,
+                None => THINKING + PER_CHARACTER * text.chars().count() as u32,
+            };
+            messages.push(Line {
+                mine,
+                after,
+                text: text.to_string(),
+            });
+        }
+        Ok(Self { peer, messages })
+    }
+}
+
+/// Plays the peer for the first to connect to the returned address, sending our own lines
+/// through `requests`.
+pub fn spawn(script: Script, requests: mpsc::Sender<detach::Control>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let address = listener.local_addr()?;
+    tasks::spawn("simulated peer", move || {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept the app: {e}");
+                return;
+            }
+        };
+        for line in script.messages {
+            tasks::set_state(format!("waiting {:?}", line.after));
+            std::thread::sleep(line.after);
+            if line.mine {
+                let (reply, answer) = mpsc::channel();
+                let control = detach::Control {
+                    request: detach::Request::Send(line.text),
+                    reply,
+                };
+                if requests.send(control).is_err() {
+                    return;
+                }
+                debug!("sent: {:?}", answer.recv());
+                continue;
+            }
+            let envelope = protocol::Envelope {
+                sender: script.peer.clone(),
+                sent: timestamp::now_millis(),
+                id: None,
+            };
+            let frame = format!("{}\n", protocol::message(&envelope, &line.text, None));
+            if let Err(e) = stream.write_all(frame.as_bytes()) {
+                warn!("The app went away: {e}");
+                return;
+            }
+        }
+        tasks::set_state("done");
+        // the app would take a closed connection for the peer leaving
+        let _ = io::copy(&mut stream, &mut io::sink());
+    });
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    #[test]
+    fn scripts_tell_who_says_what_when() {
+        let script = Script::parse(
+            "peer = \"alice\"\n\
+             [[message]]\ntext = \"hey\"\n\
+             [[message]]\nfrom = \"me\"\nafter = \"2s\"\ntext = \"hi\"\n",
+        )
+        .unwrap();
+        assert_eq!(script.peer, "alice");
+        let lines: Vec<_> = script
+            .messages
+            .iter()
+            .map(|l| (l.mine, l.after, l.text.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (false, Duration::from_millis(980), "hey"),
+                (true, Duration::from_secs(2), "hi")
+            ]
+        );
+
+        for (script, error) in [
+            ("peer = \"alice\"", "the script has no [[message]]"),
+            ("[[message]]\nfrom = \"me\"", "message 1 has no text"),
+            (
+                "[[message]]\ntext = \"a\"\n[[message]]\ntext = \"b\"\nfrom = \"bob\"",
+                "message 2 is from \"bob\", not peer or me",
+            ),
+            (
+                "[[message]]\ntext = \"a\"\nafter = \"soon\"",
+                "message 1 waits for \"soon\"",
+            ),
+        ] {
+            assert_eq!(Script::parse(script).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn the_peer_plays_its_part_and_ours_is_typed() {
+        let line = |mine, text: &str| Line {
+            mine,
+            after: Duration::ZERO,
+            text: text.to_string(),
+        };
+        let script = Script {
+            peer: "alice".to_string(),
+            messages: vec![line(false, "hey"), line(true, "hi")],
+        };
+        let (requests, controls) = mpsc::channel();
+        let address = spawn(script, requests).unwrap();
+        let mut app = BufReader::new(TcpStream::connect(address).unwrap());
+        let mut line = String::new();
+        app.read_line(&mut line).unwrap();
+        match protocol::decode(line.trim_end()) {
+            protocol::Payload::Message { envelope, text, .. } => {
+                assert_eq!((envelope.sender.as_str(), text.as_str()), ("alice", "hey"));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        let control = controls.recv().unwrap();
+        assert!(matches!(control.request, detach::Request::Send(text) if text == "hi"));
+    }
+}
@@ -0,0 +1,65 @@
+//! How far the clock of the peer is off ours, so the times it tells line up with ours.
+//!
+//! Once connected, both sides send `\u{1}CLOCK <now>`, which the other answers right away with
+//! `\u{1}CLOCK-ECHO <that now> <its own now>`. Taking the answer to have been written half way
+//! through the round trip, the difference of the clocks follows, and the times in the envelopes
+//! of the peer are shown less that. Differences within [`TOLERANCE`] are taken for none.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Difference of the clocks below which they are taken to agree, the round trip blurs it anyway.
+const TOLERANCE: i64 = 2_000;
+/// Difference worth telling the user about.
+pub const NOTABLE: i64 = 60_000;
+
+/// Shared with the reciever, which makes the estimate.
+#[derive(Debug, Default)]
+pub struct Skew {
+    /// Milliseconds the clock of the peer is ahead of ours
+    offset: AtomicI64,
+}
+
+impl Skew {
+    /// Milliseconds the clock of the peer is ahead of ours, 0 if they agree or it isn't known.
+    pub fn offset(&self) -> i64 {
+        self.offset.load(Ordering::Acquire)
+    }
+
+    /// Records the estimate of a round trip started at `sent`, answered when the peer's clock
+    /// said `peer` and back by `received`. Returns the offset.
+    pub fn estimate(&self, sent: u64, peer: u64, received: u64) -> i64 {
+        let midway = sent as i64 + (received.saturating_sub(sent) / 2) as i64;
+        let offset = match peer as i64 - midway {
+            offset if offset.abs() < TOLERANCE => 0,
+            offset => offset,
+        };
+        self.offset.store(offset, Ordering::Release);
+        offset
+    }
+
+    pub fn reset(&self) {
+        self.offset.store(0, Ordering::Release);
+    }
+}
+
+/// Time of ours `millis` of the peer's clock was at.
+pub fn adjust(millis: u64, offset: i64) -> u64 {
+    (millis as i64 - offset).max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_round_trip_is_split_evenly() {
+        let skew = Skew::default();
+        let hours = 3 * 3_600_000;
+        // 400ms there and back, the peer answered 3h ahead of us half way through
+        assert_eq!(skew.estimate(10_000, 10_200 + hours, 10_400), hours as i64);
+        assert_eq!(adjust(50_000 + hours, skew.offset()), 50_000);
+        // a slow network isn't a wrong clock
+        assert_eq!(skew.estimate(10_000, 10_900, 10_400), 0);
+        assert_eq!(skew.estimate(10_000, 6_000, 10_000), -4_000);
+    }
+}
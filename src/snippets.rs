@@ -0,0 +1,73 @@
+//! Canned responses, typed as `!name` and expanded before sending.
+
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+
+/// Config table holding the snippets.
+pub const TABLE: &str = "snippets";
+pub const MARKER: char = '!';
+
+#[derive(Debug, Default)]
+pub struct Snippets(BTreeMap<String, String>);
+
+impl Snippets {
+    pub fn from_config(config: &Config) -> Self {
+        Self(config.strings(TABLE).into_iter().collect())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, name: String, text: String) {
+        self.0.insert(name, text);
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.0.remove(name).is_some()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    /// Snippet for `word` if it is a `!name` reference to a known snippet.
+    pub fn lookup(&self, word: &str) -> Option<&str> {
+        word.strip_prefix(MARKER).and_then(|name| self.get(name))
+    }
+
+    /// Replaces every `!name` word of `text` with its snippet, unknown names are left alone.
+    pub fn expand(&self, text: &str) -> String {
+        text.split_inclusive(char::is_whitespace)
+            .map(|piece| {
+                let word = piece.trim_end();
+                match self.lookup(word) {
+                    Some(snippet) => format!("{snippet}{}", &piece[word.len()..]),
+                    None => piece.to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_names_are_expanded() {
+        let mut snippets = Snippets::default();
+        snippets.insert("sig".to_string(), "-- sam".to_string());
+        snippets.insert("ty".to_string(), "thank you".to_string());
+        assert_eq!(
+            snippets.expand("!ty for !this\n!sig"),
+            "thank you for !this\n-- sam"
+        );
+        // only whole words are names
+        assert_eq!(snippets.expand("wow!ty !ty!"), "wow!ty !ty!");
+        assert!(snippets.remove("ty"));
+        assert!(!snippets.remove("ty"));
+        assert_eq!(snippets.names().collect::<Vec<_>>(), ["sig"]);
+    }
+}
@@ -0,0 +1,173 @@
+//! Options of the TCP connections, tuned in the config:
+//!
+//! ```toml
+//! [socket]
+//! nodelay = "off"             # on by default, off batches small writes
+//! keepalive = "60s"           # idle time before the peer is probed, off by default
+//! keepalive_interval = "10s"  # between probes
+//! keepalive_probes = "6"      # unanswered probes before the connection is given up
+//! ```
+
+use std::{io, net::TcpStream, os::fd::AsRawFd, time::Duration};
+
+use tracing::warn;
+
+use crate::{config::Config, timestamp};
+
+/// Config table holding the options.
+pub const TABLE: &str = "socket";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tuning {
+    nodelay: bool,
+    keepalive: Option<Keepalive>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Keepalive {
+    idle: Duration,
+    interval: Option<Duration>,
+    probes: Option<u8>,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            // a chat is all small writes, which shouldn't wait for each other
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl Tuning {
+    pub fn from_config(config: &Config) -> Self {
+        let mut tuning = Self::default();
+        let mut keepalive = Keepalive {
+            idle: Duration::ZERO,
+            interval: None,
+            probes: None,
+        };
+        for (key, value) in config.strings(TABLE) {
+            let valid = match (key.as_str(), value.as_str()) {
+                ("nodelay", "on" | "off") => {
+                    tuning.nodelay = value == "on";
+                    true
+                }
+                ("keepalive", "off") => true,
+                ("keepalive", _) => timestamp::parse_duration(&value)
+                    .map(|d| keepalive.idle = d)
+                    .is_some(),
+                ("keepalive_interval", _) => timestamp::parse_duration(&value)
+                    .map(|d| keepalive.interval = Some(d))
+                    .is_some(),
+                ("keepalive_probes", _) => {
+                    value.parse().map(|n| keepalive.probes = Some(n)).is_ok()
+                }
+                ("nodelay", _) => false,
+                _ => {
+                    warn!("Ignoring unknown socket option {key}");
+                    continue;
+                }
+            };
+            if !valid {
+                warn!("Ignoring invalid socket option {key} = {value:?}");
+            }
+        }
+        tuning.keepalive = Some(keepalive).filter(|k| !k.idle.is_zero());
+        tuning
+    }
+
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let Some(keepalive) = self.keepalive else {
+            return Ok(());
+        };
+        let fd = stream.as_raw_fd();
+        set(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        set(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPIDLE,
+            seconds(keepalive.idle),
+        )?;
+        if let Some(interval) = keepalive.interval {
+            set(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPINTVL,
+                seconds(interval),
+            )?;
+        }
+        if let Some(probes) = keepalive.probes {
+            set(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, i32::from(probes))?;
+        }
+        Ok(())
+    }
+}
+
+fn seconds(duration: Duration) -> i32 {
+    duration.as_secs().try_into().unwrap_or(i32::MAX)
+}
+
+fn set(fd: i32, level: i32, option: i32, value: i32) -> io::Result<()> {
+    // SAFETY: the value is an int, as all of the options set here expect
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            (&value as *const i32).cast(),
+            std::mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn get(stream: &TcpStream, level: i32, option: i32) -> i32 {
+        let mut value = 0i32;
+        let mut len = std::mem::size_of::<i32>() as libc::socklen_t;
+        // SAFETY: the value is an int of the given length
+        let res = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                level,
+                option,
+                (&mut value as *mut i32).cast(),
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0);
+        value
+    }
+
+    #[test]
+    fn keepalive_timers_are_set_on_the_connection() {
+        let config: Config = "[socket]\nkeepalive = \"1m\"\nkeepalive_interval = \"10s\"\n\
+                              keepalive_probes = \"lots\"\nnodelay = \"off\"\n"
+            .parse()
+            .unwrap();
+        let tuning = Tuning::from_config(&config);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        tuning.apply(&stream).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert_eq!(get(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 60);
+        assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 10);
+
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        Tuning::default().apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(get(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+    }
+}
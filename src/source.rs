@@ -0,0 +1,257 @@
+//! Local end of outgoing connections, for hosts with several networks like a VPN next to the LAN.
+//!
+//! With `--bind-interface` the connection leaves through the named interface, from its address of
+//! the family of the peer. With `--source-address` it comes from that address, which has to be
+//! assigned to one of the interfaces.
+
+use std::{
+    ffi::CStr,
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    os::fd::{FromRawFd, OwnedFd},
+};
+
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Default, clap::Args)]
+#[group(id = "source")]
+pub struct Options {
+    /// connect through this network interface, like wg0 or eth0
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["server", "source_address"])]
+    pub bind_interface: Option<String>,
+    /// connect from this local address
+    #[arg(long, value_name = "ADDRESS", conflicts_with = "server")]
+    pub source_address: Option<IpAddr>,
+}
+
+impl Options {
+    /// Makes sure the interface or the address exists, so a typo doesn't go unnoticed until the
+    /// first connection fails.
+    pub fn validate(&self) -> Result<(), String> {
+        let interfaces = interfaces().map_err(|e| format!("failed to list the interfaces: {e}"))?;
+        if let Some(name) = &self.bind_interface {
+            if !interfaces.iter().any(|(n, _)| n == name) {
+                return Err(format!("there is no interface {name}"));
+            }
+        }
+        if let Some(address) = self.source_address {
+            if !interfaces.iter().any(|(_, a)| *a == address) {
+                return Err(format!("{address} isn't an address of this host"));
+            }
+        }
+        Ok(())
+    }
+
+    /// What the connections are bound to, if anything.
+    pub fn describe(&self) -> Option<String> {
+        match (&self.bind_interface, self.source_address) {
+            (Some(name), _) => Some(format!("interface {name}")),
+            (None, Some(address)) => Some(format!("address {address}")),
+            (None, None) => None,
+        }
+    }
+
+    pub fn connect(&self, address: &str, port: u16) -> io::Result<TcpStream> {
+        if self.bind_interface.is_none() && self.source_address.is_none() {
+            return TcpStream::connect((address, port));
+        }
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "address didn't resolve");
+        for peer in (address, port).to_socket_addrs()? {
+            match self
+                .local(&peer)
+                .and_then(|local| connect_from(local, peer, self))
+            {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("Failed to connect to {peer}: {e}");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Address to connect to `peer` from.
+    fn local(&self, peer: &SocketAddr) -> io::Result<IpAddr> {
+        if let Some(address) = self.source_address {
+            return match address.is_ipv4() == peer.is_ipv4() {
+                true => Ok(address),
+                false => Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{address} can't reach {peer}"),
+                )),
+            };
+        }
+        let name = self.bind_interface.as_deref().unwrap_or_default();
+        interfaces()?
+            .into_iter()
+            .filter(|(n, _)| n == name)
+            .map(|(_, a)| a)
+            .find(|a| a.is_ipv4() == peer.is_ipv4())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{name} has no address to reach {peer}"),
+                )
+            })
+    }
+}
+
+/// Name and address of every address of the interfaces.
+fn interfaces() -> io::Result<Vec<(String, IpAddr)>> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: fills in `list`, freed below
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut interfaces = Vec::new();
+    let mut entry = list;
+    while !entry.is_null() {
+        // SAFETY: a node of the list returned by getifaddrs, which is still around
+        let ifaddr = unsafe { &*entry };
+        entry = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        // SAFETY: a nul terminated name, the address is of the size its family says
+        let (name, address) = unsafe {
+            let name = CStr::from_ptr(ifaddr.ifa_name)
+                .to_string_lossy()
+                .into_owned();
+            let address = match i32::from((*ifaddr.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let address = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let address = &*(ifaddr.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(address.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            };
+            (name, address)
+        };
+        interfaces.push((name, address));
+    }
+    // SAFETY: allocated by getifaddrs and no longer referenced
+    unsafe { libc::freeifaddrs(list) };
+    Ok(interfaces)
+}
+
+fn connect_from(local: IpAddr, peer: SocketAddr, options: &Options) -> io::Result<TcpStream> {
+    let family = match peer {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: plain socket creation, checked for failure below
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: a fresh socket nobody else owns, closed on every error from here on
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let raw = std::os::fd::AsRawFd::as_raw_fd(&fd);
+    if let Some(name) = &options.bind_interface {
+        // the address alone doesn't keep the routing table from picking another interface
+        // SAFETY: the option value is the name with its length
+        let res = unsafe {
+            libc::setsockopt(
+                raw,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                name.as_ptr().cast(),
+                name.len() as libc::socklen_t,
+            )
+        };
+        if res != 0 {
+            warn!(
+                "Failed to bind to {name}, only its address is used: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+    let (local, len) = sockaddr(SocketAddr::new(local, 0));
+    // SAFETY: the address is valid for `len` bytes
+    if unsafe { libc::bind(raw, (&local as *const libc::sockaddr_storage).cast(), len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (remote, len) = sockaddr(peer);
+    // SAFETY: as above
+    if unsafe { libc::connect(raw, (&remote as *const libc::sockaddr_storage).cast(), len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(TcpStream::from(fd))
+}
+
+fn sockaddr(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: all zeroes is a valid sockaddr_storage
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match address {
+        SocketAddr::V4(address) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for any address
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = address.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*address.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            // SAFETY: as above
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = address.port().to_be();
+            sin6.sin6_addr.s6_addr = address.ip().octets();
+            sin6.sin6_scope_id = address.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn options(bind_interface: Option<&str>, source_address: Option<&str>) -> Options {
+        Options {
+            bind_interface: bind_interface.map(str::to_string),
+            source_address: source_address.map(|a| a.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn unknown_interfaces_and_addresses_are_refused() {
+        assert_eq!(options(Some("lo"), None).validate(), Ok(()));
+        assert_eq!(options(None, Some("127.0.0.1")).validate(), Ok(()));
+        assert_eq!(
+            options(Some("nope0"), None).validate(),
+            Err("there is no interface nope0".to_string())
+        );
+        assert_eq!(
+            options(None, Some("192.0.2.1")).validate(),
+            Err("192.0.2.1 isn't an address of this host".to_string())
+        );
+        assert_eq!(
+            options(Some("lo"), None).describe().as_deref(),
+            Some("interface lo")
+        );
+        assert_eq!(options(None, None).describe(), None);
+    }
+
+    #[test]
+    fn connections_leave_from_the_chosen_end() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for options in [options(None, Some("127.0.0.1")), options(Some("lo"), None)] {
+            let stream = options.connect("127.0.0.1", port).unwrap();
+            let (_, from) = listener.accept().unwrap();
+            assert_eq!(from, stream.local_addr().unwrap());
+            assert_eq!(from.ip(), Ipv4Addr::LOCALHOST);
+        }
+        // not of the family of the peer
+        let err = options(None, Some("::1")).connect("127.0.0.1", port);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
@@ -0,0 +1,276 @@
+use std::cell::Cell;
+
+use ratatui::widgets::ListState;
+
+/// List of items together with the selection/scroll state used to render them.
+///
+/// The selection is an index into `items`, new messages are only ever appended so it keeps
+/// pointing at the same item. When nothing is selected the list follows the tail, or stays
+/// where it was scrolled back to.
+///
+/// Items can be hidden from view by passing a filter to the navigation and rendering methods.
+#[derive(Debug)]
+pub struct StatefulList<T> {
+    items: Vec<T>,
+    selected: Option<usize>,
+    /// First rendered item, as a position among the visible items
+    offset: usize,
+    /// Lines scrolled back from the tail, settled while rendering
+    scroll: Cell<usize>,
+    /// Items there were at the last rendering, the view stays put when more arrive
+    seen: Cell<usize>,
+}
+
+impl<T> Default for StatefulList<T> {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            selected: None,
+            offset: 0,
+            scroll: Cell::new(0),
+            seen: Cell::new(0),
+        }
+    }
+}
+
+impl<T> StatefulList<T> {
+    /// Appends an item at the end, selection is not affected.
+    pub fn push(&mut self, item: T) {
+        self.items.push(item);
+    }
+
+    /// Puts items in front of the others, the selection stays on the same item.
+    pub fn prepend(&mut self, items: Vec<T>) {
+        let count = items.len();
+        self.items.splice(0..0, items);
+        self.selected = self.selected.map(|i| i + count);
+        self.offset += count;
+        self.seen.set(self.seen.get() + count);
+    }
+
+    /// Removes every item, scrolling back to the tail.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.items.get(index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.items.get_mut(index)
+    }
+
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut T> {
+        self.items.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    /// Moves the selection to the next visible item, starts from the top if nothing is selected.
+    pub fn select_next(&mut self, visible: impl Fn(&T) -> bool) {
+        let start = self.selected.map_or(0, |i| i + 1);
+        if let Some(next) = (start..self.items.len()).find(|&i| visible(&self.items[i])) {
+            self.selected = Some(next);
+        }
+    }
+
+    /// Moves the selection to the previous visible item, starts from the bottom if nothing is
+    /// selected.
+    pub fn select_previous(&mut self, visible: impl Fn(&T) -> bool) {
+        let end = self.selected.unwrap_or(self.items.len());
+        if let Some(prev) = (0..end).rev().find(|&i| visible(&self.items[i])) {
+            self.selected = Some(prev);
+        }
+    }
+
+    /// Scrolls the selected item to the top of the view, `visible` telling which items are shown.
+    pub fn show_selected(&mut self, visible: impl Fn(&T) -> bool) {
+        if let Some(selected) = self.selected {
+            self.offset = self.items[..selected].iter().filter(|i| visible(i)).count();
+        }
+    }
+
+    pub fn unselect(&mut self) {
+        self.selected = None;
+    }
+
+    /// Lines scrolled back from the tail.
+    pub fn scrolled(&self) -> usize {
+        self.scroll.get()
+    }
+
+    /// Scrolls towards older items, as far as there are any.
+    pub fn scroll_back(&mut self, lines: usize) {
+        self.scroll.set(self.scroll.get().saturating_add(lines));
+    }
+
+    /// Scrolls towards the tail, following it again once there.
+    pub fn scroll_forward(&mut self, lines: usize) {
+        self.scroll.set(self.scroll.get().saturating_sub(lines));
+    }
+
+    /// Items passing `visible` and the state to render them with.
+    ///
+    /// When nothing is selected the offset is moved so that the last items filling `height`
+    /// lines are shown, above those scrolled past. `lines` tells how many each item takes.
+    pub fn view(
+        &self,
+        height: usize,
+        visible: impl Fn(&T) -> bool,
+        lines: impl Fn(&T) -> usize,
+    ) -> (Vec<&T>, ListState) {
+        let mut selected = None;
+        let items: Vec<&T> = self
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| visible(item))
+            .enumerate()
+            .map(|(position, (i, item))| {
+                if self.selected == Some(i) {
+                    selected = Some(position);
+                }
+                item
+            })
+            .collect();
+        let offset = match selected {
+            Some(_) => self.offset.min(items.len().saturating_sub(1)),
+            None => {
+                let mut scroll = self.scroll.get();
+                if scroll > 0 {
+                    let start = self.seen.get().min(self.items.len());
+                    let arrived: usize = self.items[start..]
+                        .iter()
+                        .filter(|item| visible(item))
+                        .map(&lines)
+                        .sum();
+                    let total: usize = items.iter().map(|item| lines(item)).sum();
+                    scroll = (scroll + arrived).min(total.saturating_sub(height));
+                    self.scroll.set(scroll);
+                }
+                let mut end = items.len();
+                let mut skipped = 0;
+                while skipped < scroll && end > 1 {
+                    end -= 1;
+                    skipped += lines(items[end]);
+                }
+                let mut offset = end;
+                let mut used = 0;
+                while let Some(item) = offset.checked_sub(1).map(|i| items[i]) {
+                    used += lines(item);
+                    // the newest item is shown even if it doesn't fit
+                    if used > height && offset < end {
+                        break;
+                    }
+                    offset -= 1;
+                }
+                offset
+            }
+        };
+        self.seen.set(self.items.len());
+        let state = ListState::default()
+            .with_selected(selected)
+            .with_offset(offset);
+        (items, state)
+    }
+
+    /// Keeps the scroll position the renderer settled on for the next frame.
+    pub fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(len: u32) -> StatefulList<u32> {
+        let mut list = StatefulList::default();
+        for i in 0..len {
+            list.push(i);
+        }
+        list
+    }
+
+    fn all(_: &u32) -> bool {
+        true
+    }
+
+    #[test]
+    fn the_selection_moves_between_items() {
+        let mut items = list(3);
+        items.select_previous(all);
+        assert_eq!(items.selected(), Some(2));
+        items.select_next(all);
+        assert_eq!(items.selected(), Some(2));
+        items.unselect();
+        items.select_next(all);
+        items.select_previous(all);
+        assert_eq!(items.selected(), Some(0));
+        items.push(3);
+        assert_eq!(items.selected(), Some(0));
+        let (shown, state) = items.view(10, all, |_| 1);
+        assert_eq!((shown.len(), state.selected()), (4, Some(0)));
+    }
+
+    #[test]
+    fn hidden_items_are_skipped() {
+        let mut items = list(6);
+        let even = |i: &u32| i.is_multiple_of(2);
+        items.select_next(even);
+        items.select_next(even);
+        assert_eq!(items.selected(), Some(2));
+        items.select_previous(even);
+        assert_eq!(items.selected(), Some(0));
+        items.select_previous(even);
+        assert_eq!(items.selected(), Some(0));
+
+        items.unselect();
+        let (shown, state) = items.view(2, even, |_| 1);
+        assert_eq!(shown, [&0, &2, &4]);
+        assert_eq!(state.offset(), 1);
+    }
+
+    #[test]
+    fn older_items_go_in_front_of_the_selection() {
+        let mut items = list(2);
+        items.select_next(all);
+        items.select_next(all);
+        items.prepend(vec![10, 11, 12]);
+        assert_eq!(items.selected(), Some(4));
+        assert_eq!(items.get(4), Some(&1));
+        assert_eq!(items.get(0), Some(&10));
+        assert_eq!(items.len(), 5);
+    }
+
+    #[test]
+    fn scrolling_back_stays_put_as_items_arrive() {
+        let mut items = list(10);
+        let offset = |items: &StatefulList<u32>| items.view(3, all, |_| 1).1.offset();
+        assert_eq!(offset(&items), 7);
+        items.scroll_back(2);
+        assert_eq!(offset(&items), 5);
+        items.push(10);
+        items.push(11);
+        assert_eq!(offset(&items), 5);
+        assert_eq!(items.scrolled(), 4);
+
+        // no further than the oldest item
+        items.scroll_back(100);
+        assert_eq!(offset(&items), 0);
+        assert_eq!(items.scrolled(), 9);
+        items.scroll_forward(100);
+        assert_eq!(offset(&items), 9);
+    }
+}
@@ -0,0 +1,629 @@
+//! Transcript of every conversation, by default one file per peer under
+//! `$XDG_DATA_HOME/chatterbox/history`. Other places to keep it implement [`Storage`].
+//! Messages are appended as they come, imports are merged in by time and the file rewritten, so
+//! it stays oldest first.
+//!
+//! Each line is `<millis>\t<kind>\t<text>`, or `<millis>\t<kind>\t<author>\t<text>` for
+//! imported messages, with backslashes, tabs and newlines escaped.
+//!
+//! Scrolling back reads through an [`Archive`]. For files that is the file read from the end a
+//! chunk at a time, only as far as the user goes, so even a huge history opens instantly.
+//!
+//! How far the user read is kept next to it, the millis of the last message read in
+//! `$XDG_DATA_HOME/chatterbox/read/<peer>`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use tracing::{error, warn};
+
+use crate::{
+    config::Config,
+    message::Kind,
+    protocol::{escape, unescape},
+};
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// Milliseconds since the unix epoch
+    pub at: u64,
+    pub kind: Kind,
+    /// Name of the sender, if it isn't just us or the peer
+    pub author: Option<String>,
+    pub text: String,
+}
+
+fn kind_tag(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Incoming => "in",
+        Kind::Outgoing => "out",
+        Kind::System => "sys",
+    }
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields: Vec<_> = line.split('\t').collect();
+        let text = unescape(fields.pop()?);
+        let author = (fields.len() == 3).then(|| unescape(fields[2]));
+        let kind = match *fields.get(1)? {
+            "in" => Kind::Incoming,
+            "out" => Kind::Outgoing,
+            "sys" => Kind::System,
+            _ => return None,
+        };
+        Some(Self {
+            at: fields[0].parse().ok()?,
+            kind,
+            author,
+            text,
+        })
+    }
+
+    fn to_line(&self) -> String {
+        match &self.author {
+            Some(author) => format!(
+                "{}\t{}\t{}\t{}\n",
+                self.at,
+                kind_tag(self.kind),
+                escape(author),
+                escape(&self.text)
+            ),
+            None => format!(
+                "{}\t{}\t{}\n",
+                self.at,
+                kind_tag(self.kind),
+                escape(&self.text)
+            ),
+        }
+    }
+}
+
+/// Where the histories are kept, one per peer.
+///
+/// Picked with `backend` in the `[history]` table of the config: `file`, the default, or
+/// `memory` to forget everything on exit.
+pub trait Storage: Send + Sync + std::fmt::Debug {
+    fn append(&self, peer: &str, records: &[Record]) -> io::Result<()>;
+
+    /// Whole history of `peer`, oldest first.
+    fn read(&self, peer: &str) -> io::Result<Vec<Record>>;
+
+    /// History of `peer` as it is now, for reading it backwards.
+    fn archive(&self, peer: &str) -> io::Result<Archive> {
+        Ok(Archive {
+            peer: peer.to_string(),
+            source: Source::Records(self.read(peer)?),
+        })
+    }
+
+    /// Millis up to which the history of `peer` was read, `None` if it never was.
+    fn read_marker(&self, peer: &str) -> io::Result<Option<u64>>;
+
+    fn set_read_marker(&self, peer: &str, at: u64) -> io::Result<()>;
+
+    /// Puts `records` in place of the whole history of `peer`.
+    fn replace(&self, peer: &str, records: &[Record]) -> io::Result<()>;
+
+    /// Deletes the history of `peer` and how far it was read.
+    fn purge(&self, peer: &str) -> io::Result<()>;
+}
+
+/// Config table choosing the storage.
+pub const TABLE: &str = "history";
+
+/// Storage chosen in the config, `None` if it can't be used.
+pub fn from_config(config: &Config) -> Option<Arc<dyn Storage>> {
+    let backend = config
+        .strings(TABLE)
+        .into_iter()
+        .find(|(key, _)| key == "backend")
+        .map(|(_, backend)| backend);
+    match backend.as_deref() {
+        None | Some("file") => Some(Arc::new(Files::new()?)),
+        Some("memory") => Some(Arc::new(Memory::default())),
+        Some(other) => {
+            warn!("Unknown history backend {other}, keeping the history in files");
+            Some(Arc::new(Files::new()?))
+        }
+    }
+}
+
+/// A file per peer under the data directory.
+#[derive(Debug)]
+pub struct Files {
+    dir: PathBuf,
+    read_dir: PathBuf,
+}
+
+impl Files {
+    /// `None` if there is no data directory.
+    pub fn new() -> Option<Self> {
+        let data = crate::dirs::data_dir()?;
+        Some(Self {
+            dir: data.join("history"),
+            read_dir: data.join("read"),
+        })
+    }
+
+    fn path(&self, peer: &str) -> PathBuf {
+        self.dir.join(file_name(peer))
+    }
+}
+
+impl Storage for Files {
+    fn append(&self, peer: &str, records: &[Record]) -> io::Result<()> {
+        let content: String = records.iter().map(Record::to_line).collect();
+        fs::create_dir_all(&self.dir)?;
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(peer))?
+            .write_all(content.as_bytes())
+    }
+
+    /// Lines which can't be parsed are skipped.
+    fn read(&self, peer: &str) -> io::Result<Vec<Record>> {
+        Ok(fs::read_to_string(self.path(peer))?
+            .lines()
+            .filter_map(Record::parse)
+            .collect())
+    }
+
+    /// The file kept open and read from its end, so even a huge history opens instantly.
+    fn archive(&self, peer: &str) -> io::Result<Archive> {
+        let source = match fs::File::open(self.path(peer)) {
+            Ok(file) => Source::File {
+                offset: file.metadata()?.len(),
+                file,
+                tail: Vec::new(),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Source::Records(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(Archive {
+            peer: peer.to_string(),
+            source,
+        })
+    }
+
+    fn read_marker(&self, peer: &str) -> io::Result<Option<u64>> {
+        match fs::read_to_string(self.read_dir.join(file_name(peer))) {
+            Ok(content) => Ok(content.trim().parse().ok()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn set_read_marker(&self, peer: &str, at: u64) -> io::Result<()> {
+        fs::create_dir_all(&self.read_dir)?;
+        fs::write(self.read_dir.join(file_name(peer)), format!("{at}\n"))
+    }
+
+    /// Written next to the history and renamed over it, a reader never sees half of it.
+    fn replace(&self, peer: &str, records: &[Record]) -> io::Result<()> {
+        let content: String = records.iter().map(Record::to_line).collect();
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(peer);
+        let temporary = self.dir.join(format!(".{}.new", file_name(peer)));
+        fs::write(&temporary, content)?;
+        fs::rename(temporary, path)
+    }
+
+    fn purge(&self, peer: &str) -> io::Result<()> {
+        for path in [self.path(peer), self.read_dir.join(file_name(peer))] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `peer` made usable as a file name.
+pub fn file_name(peer: &str) -> String {
+    peer.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' | ':' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Histories gone with the process.
+#[derive(Debug, Default)]
+pub struct Memory {
+    histories: Mutex<HashMap<String, Vec<Record>>>,
+    read_markers: Mutex<HashMap<String, u64>>,
+}
+
+impl Storage for Memory {
+    fn append(&self, peer: &str, records: &[Record]) -> io::Result<()> {
+        let mut histories = self.histories.lock().expect("history lock is poisoned");
+        histories
+            .entry(peer.to_string())
+            .or_default()
+            .extend_from_slice(records);
+        Ok(())
+    }
+
+    fn read(&self, peer: &str) -> io::Result<Vec<Record>> {
+        let histories = self.histories.lock().expect("history lock is poisoned");
+        Ok(histories.get(peer).cloned().unwrap_or_default())
+    }
+
+    fn read_marker(&self, peer: &str) -> io::Result<Option<u64>> {
+        let markers = self.read_markers.lock().expect("history lock is poisoned");
+        Ok(markers.get(peer).copied())
+    }
+
+    fn set_read_marker(&self, peer: &str, at: u64) -> io::Result<()> {
+        let mut markers = self.read_markers.lock().expect("history lock is poisoned");
+        markers.insert(peer.to_string(), at);
+        Ok(())
+    }
+
+    fn replace(&self, peer: &str, records: &[Record]) -> io::Result<()> {
+        let mut histories = self.histories.lock().expect("history lock is poisoned");
+        histories.insert(peer.to_string(), records.to_vec());
+        Ok(())
+    }
+
+    fn purge(&self, peer: &str) -> io::Result<()> {
+        let mut histories = self.histories.lock().expect("history lock is poisoned");
+        histories.remove(peer);
+        let mut markers = self.read_markers.lock().expect("history lock is poisoned");
+        markers.remove(peer);
+        Ok(())
+    }
+}
+
+/// History of a single peer.
+#[derive(Debug, Clone)]
+pub struct Store {
+    peer: String,
+    storage: Arc<dyn Storage>,
+}
+
+impl Store {
+    pub fn new(storage: Arc<dyn Storage>, peer: &str) -> Self {
+        Self {
+            peer: peer.to_string(),
+            storage,
+        }
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// File backed store for `peer`, `None` if there is no data directory.
+    pub fn for_peer(peer: &str) -> Option<Self> {
+        Some(Self::new(Arc::new(Files::new()?), peer))
+    }
+
+    /// Records a message sent or received just now.
+    pub fn append(&self, kind: Kind, text: &str) {
+        self.append_records(&[Record {
+            at: crate::timestamp::now_millis(),
+            kind,
+            author: None,
+            text: text.to_string(),
+        }]);
+    }
+
+    pub fn append_records(&self, records: &[Record]) {
+        if let Err(e) = self.storage.append(&self.peer, records) {
+            error!("Failed to write history of {}: {e}", self.peer);
+        }
+    }
+
+    /// Whole history, oldest first.
+    pub fn read(&self) -> io::Result<Vec<Record>> {
+        self.storage.read(&self.peer)
+    }
+
+    /// Merges messages written elsewhere into the history by their time, leaving out those it
+    /// has already. Returns how many were new.
+    pub fn import(&self, mut records: Vec<Record>) -> io::Result<usize> {
+        let mut history = match self.read() {
+            Ok(history) => history,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let known: HashSet<_> = history
+            .iter()
+            .map(|r| (r.at, kind_tag(r.kind), r.author.as_deref(), r.text.as_str()))
+            .collect();
+        records.retain(|r| {
+            !known.contains(&(r.at, kind_tag(r.kind), r.author.as_deref(), r.text.as_str()))
+        });
+        let imported = records.len();
+        history.append(&mut records);
+        // stable, so messages of the same millisecond keep their order
+        history.sort_by_key(|r| r.at);
+        self.storage.replace(&self.peer, &history)?;
+        Ok(imported)
+    }
+
+    /// History as it is now, for reading it backwards.
+    pub fn archive(&self) -> io::Result<Archive> {
+        self.storage.archive(&self.peer)
+    }
+
+    /// Millis up to which the history was read, `None` if it never was or can't be told.
+    pub fn read_marker(&self) -> Option<u64> {
+        self.storage
+            .read_marker(&self.peer)
+            .map_err(|e| warn!("Failed to read how far {} was read: {e}", self.peer))
+            .ok()
+            .flatten()
+    }
+
+    /// Deletes the whole history.
+    pub fn purge(&self) -> io::Result<()> {
+        self.storage.purge(&self.peer)
+    }
+
+    /// Notes everything up to now as read.
+    pub fn mark_read(&self) {
+        let now = crate::timestamp::now_millis();
+        if let Err(e) = self.storage.set_read_marker(&self.peer, now) {
+            error!("Failed to note how far {} was read: {e}", self.peer);
+        }
+    }
+}
+
+/// Bytes read from the end of a history file at a time.
+const CHUNK: usize = 64 * 1024;
+
+/// History of a peer as it was when opened, handed out newest first a page at a time.
+#[derive(Debug)]
+pub struct Archive {
+    peer: String,
+    source: Source,
+}
+
+#[derive(Debug)]
+enum Source {
+    /// Lines are only read once they are scrolled to. Whatever happens to the file meanwhile,
+    /// reading it can only come up short.
+    File {
+        file: fs::File,
+        /// Where in the file `tail` starts
+        offset: u64,
+        /// Read but not handed out yet
+        tail: Vec<u8>,
+    },
+    /// Records not handed out yet
+    Records(Vec<Record>),
+}
+
+impl Archive {
+    pub fn is_of(&self, store: &Store) -> bool {
+        self.peer == store.peer
+    }
+
+    /// Up to `count` records right before the ones handed out already, oldest first.
+    pub fn older(&mut self, count: usize) -> Vec<Record> {
+        let (file, offset, tail) = match &mut self.source {
+            Source::Records(records) => {
+                return records.split_off(records.len().saturating_sub(count))
+            }
+            Source::File { file, offset, tail } => (file, offset, tail),
+        };
+        let mut records = Vec::new();
+        while records.len() < count {
+            // skip the newline ending the line before
+            let line_end = match tail.last() {
+                Some(b'\n') => tail.len() - 1,
+                _ => tail.len(),
+            };
+            let start = match tail[..line_end].iter().rposition(|&b| b == b'\n') {
+                Some(i) => i + 1,
+                None if *offset == 0 => 0,
+                None => {
+                    let len = CHUNK.min(*offset as usize);
+                    let mut chunk = vec![0; len];
+                    if let Err(e) = file.read_exact_at(&mut chunk, *offset - len as u64) {
+                        warn!("History of {} changed while reading it: {e}", self.peer);
+                        tail.clear();
+                        *offset = 0;
+                        break;
+                    }
+                    *offset -= len as u64;
+                    chunk.extend_from_slice(tail);
+                    *tail = chunk;
+                    continue;
+                }
+            };
+            records.extend(Record::parse(&String::from_utf8_lossy(
+                &tail[start..line_end],
+            )));
+            tail.truncate(start);
+            if tail.is_empty() && *offset == 0 {
+                break;
+            }
+        }
+        records.reverse();
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(at: u64, kind: Kind, author: Option<&str>, text: &str) -> Record {
+        Record {
+            at,
+            kind,
+            author: author.map(str::to_string),
+            text: text.to_string(),
+        }
+    }
+
+    fn texts(records: &[Record]) -> Vec<&str> {
+        records.iter().map(|r| r.text.as_str()).collect()
+    }
+
+    #[test]
+    fn records_survive_a_line_of_their_own() {
+        let imported = record(
+            1_700_000_000_000,
+            Kind::Incoming,
+            Some("al\tice"),
+            "a\\b\nc",
+        );
+        let line = imported.to_line();
+        assert_eq!(line, "1700000000000\tin\tal\\tice\ta\\\\b\\nc\n");
+        let parsed = Record::parse(line.trim_end()).unwrap();
+        assert_eq!(parsed.at, imported.at);
+        assert_eq!(parsed.kind, Kind::Incoming);
+        assert_eq!(parsed.author, imported.author);
+        assert_eq!(parsed.text, imported.text);
+
+        let parsed = Record::parse("5\tsys\tconnected").unwrap();
+        assert_eq!(
+            (parsed.kind, parsed.author, parsed.text.as_str()),
+            (Kind::System, None, "connected")
+        );
+        assert!(Record::parse("5\tsideways\ttext").is_none());
+        assert!(Record::parse("soon\tin\ttext").is_none());
+    }
+
+    #[test]
+    fn every_peer_has_a_history_of_its_own() {
+        let storage: Arc<dyn Storage> = Arc::new(Memory::default());
+        let ada = Store::new(Arc::clone(&storage), "ada");
+        let bob = Store::new(storage, "bob");
+        ada.append_records(&[record(1, Kind::Outgoing, None, "hi ada")]);
+        bob.append_records(&[record(2, Kind::Outgoing, None, "hi bob")]);
+        ada.append_records(&[record(3, Kind::Incoming, None, "hi")]);
+        assert_eq!(texts(&ada.read().unwrap()), ["hi ada", "hi"]);
+        assert_eq!(texts(&bob.read().unwrap()), ["hi bob"]);
+
+        assert_eq!(
+            file_name("relay-example.org-7000-#rust/x"),
+            "relay-example.org-7000-_rust_x"
+        );
+    }
+
+    #[test]
+    fn archives_hand_out_older_pages() {
+        let dir = std::env::temp_dir().join(format!("chatterbox-archive-{}", std::process::id()));
+        let files = Files {
+            dir: dir.join("history"),
+            read_dir: dir.join("read"),
+        };
+        let store = Store::new(Arc::new(files), "ada");
+        let mut empty = store.archive().unwrap();
+        assert!(empty.older(10).is_empty());
+
+        let records: Vec<_> = (1..=5)
+            .map(|i| record(i, Kind::Incoming, None, &format!("line\n{i}")))
+            .collect();
+        store.append_records(&records);
+        let mut archive = store.archive().unwrap();
+        // written after opening it, only shown live
+        store.append_records(&[record(6, Kind::Incoming, None, "later")]);
+        let pages: Vec<Vec<u64>> = (0..4)
+            .map(|_| archive.older(2).iter().map(|r| r.at).collect())
+            .collect();
+        assert_eq!(pages, [vec![4, 5], vec![2, 3], vec![1], vec![]]);
+        assert!(archive.is_of(&store));
+        assert_eq!(store.read().unwrap()[0].text, "line\n1");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_config_picks_the_backend() {
+        let config: Config = "[history]\nbackend = \"memory\"\n".parse().unwrap();
+        let storage = from_config(&config).unwrap();
+        assert!(format!("{storage:?}").starts_with("Memory"));
+
+        let store = Store::new(storage, "ada");
+        assert_eq!(store.read_marker(), None);
+        store.append(Kind::Outgoing, "hi");
+        store.mark_read();
+        assert!(store.read_marker().is_some());
+        assert_eq!(texts(&store.read().unwrap()), ["hi"]);
+        store.purge().unwrap();
+        assert!(store.read().unwrap().is_empty());
+        assert_eq!(store.read_marker(), None);
+    }
+
+    #[test]
+    fn imports_are_merged_by_time() {
+        let dir = std::env::temp_dir().join(format!("chatterbox-import-{}", std::process::id()));
+        let files = Files {
+            dir: dir.join("history"),
+            read_dir: dir.join("read"),
+        };
+        let store = Store::new(Arc::new(files), "ada");
+        let imported = vec![
+            record(1, Kind::Incoming, Some("ada"), "long ago"),
+            record(5, Kind::Outgoing, Some("me"), "in between"),
+        ];
+        assert_eq!(store.import(imported.clone()).unwrap(), 2);
+        store.append_records(&[record(3, Kind::Incoming, None, "live")]);
+        store.append_records(&[record(9, Kind::Incoming, None, "latest")]);
+        // importing the same file again adds nothing
+        assert_eq!(store.import(imported).unwrap(), 0);
+        assert_eq!(
+            store
+                .import(vec![record(7, Kind::Incoming, Some("ada"), "later on")])
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            texts(&store.read().unwrap()),
+            ["long ago", "live", "in between", "later on", "latest"]
+        );
+        let newest = store.archive().unwrap().older(1);
+        assert_eq!(texts(&newest), ["latest"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn archives_survive_the_file_changing_under_them() {
+        let dir = std::env::temp_dir().join(format!("chatterbox-changing-{}", std::process::id()));
+        let files = Files {
+            dir: dir.join("history"),
+            read_dir: dir.join("read"),
+        };
+        let path = files.path("ada");
+        let store = Store::new(Arc::new(files), "ada");
+        let long = "x".repeat(CHUNK + 10);
+        store.append_records(&[
+            record(1, Kind::Incoming, None, "first"),
+            record(2, Kind::Incoming, None, &long),
+            record(3, Kind::Incoming, None, "last"),
+        ]);
+        let mut archive = store.archive().unwrap();
+        assert_eq!(texts(&archive.older(2)), [long.as_str(), "last"]);
+        let mut archive = store.archive().unwrap();
+        assert_eq!(texts(&archive.older(1)), ["last"]);
+        // cut off by something else, what is left to read is gone
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        assert!(archive.older(2).is_empty());
+
+        store.append_records(&[record(4, Kind::Incoming, None, "again")]);
+        let mut archive = store.archive().unwrap();
+        store.purge().unwrap();
+        assert_eq!(texts(&archive.older(2)), ["again"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+//! Tables pasted as Markdown, TSV or CSV, drawn with aligned columns.
+
+use ratatui::prelude::*;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Table {
+    rows: Vec<Vec<String>>,
+    /// Whether the first row names the columns
+    header: bool,
+}
+
+/// Recognizes a message made up entirely of a table.
+pub fn detect(text: &str) -> Option<Table> {
+    let lines: Vec<&str> = text.trim_matches('\n').lines().collect();
+    if lines.len() < 2 {
+        return None;
+    }
+    markdown(&lines)
+        .or_else(|| {
+            delimited(&lines, |line| {
+                line.split('\t').map(str::to_string).collect()
+            })
+        })
+        .or_else(|| {
+            // commas are common in prose, so ask for a bit more than two by two
+            let table = delimited(&lines, csv_fields)?;
+            (table.rows.len() > 2 || table.rows[0].len() > 2).then_some(table)
+        })
+}
+
+fn markdown(lines: &[&str]) -> Option<Table> {
+    let cells = |line: &str| -> Option<Vec<String>> {
+        let line = line.trim();
+        let line = line.strip_prefix('|').unwrap_or(line);
+        let line = line.strip_suffix('|').unwrap_or(line);
+        line.contains('|')
+            .then(|| line.split('|').map(|c| c.trim().to_string()).collect())
+    };
+    let separator = cells(lines[1])?;
+    let is_separator = separator
+        .iter()
+        .all(|c| c.contains('-') && c.chars().all(|c| matches!(c, '-' | ':' | ' ')));
+    if !is_separator {
+        return None;
+    }
+    let rows = lines
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 1)
+        .map(|(_, line)| cells(line))
+        .collect::<Option<Vec<_>>>()?;
+    Some(Table { rows, header: true })
+}
+
+/// Rows split by `fields`, if every one has the same number of at least two columns.
+fn delimited(lines: &[&str], fields: impl Fn(&str) -> Vec<String>) -> Option<Table> {
+    let rows: Vec<Vec<String>> = lines.iter().map(|line| fields(line)).collect();
+    let columns = rows[0].len();
+    if columns < 2 || rows.iter().any(|row| row.len() != columns) {
+        return None;
+    }
+    // a header names things, the rows below usually hold some numbers
+    let numeric = |cell: &String| cell.trim().parse::<f64>().is_ok();
+    let header = !rows[0].iter().any(numeric) && rows[1..].iter().flatten().any(numeric);
+    Some(Table { rows, header })
+}
+
+/// Fields of a CSV line, quotes may contain commas and `""` for a quote.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().expect("starts with a field");
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted || field.trim().is_empty() => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    fields.iter().map(|f| f.trim().to_string()).collect()
+}
+
+impl Table {
+    /// Aligned rows, with the leftmost `scroll` columns of the terminal cut off.
+    pub fn lines(&self, indent: &str, scroll: usize) -> Vec<Line<'static>> {
+        let columns = self.rows.iter().map(Vec::len).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|i| {
+                self.rows
+                    .iter()
+                    .filter_map(|row| row.get(i))
+                    .map(|cell| cell.width())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let mut lines = Vec::new();
+        for (i, row) in self.rows.iter().enumerate() {
+            let cells: Vec<String> = widths
+                .iter()
+                .enumerate()
+                .map(|(column, &width)| {
+                    let cell = row.get(column).map_or("", String::as_str);
+                    let pad = " ".repeat(width - cell.width());
+                    // numbers line up on the right
+                    if cell.parse::<f64>().is_ok() {
+                        format!("{pad}{cell}")
+                    } else {
+                        format!("{cell}{pad}")
+                    }
+                })
+                .collect();
+            let text = skip_columns(&cells.join(" │ "), scroll);
+            let header = self.header && i == 0;
+            let style = if header {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            lines.push(Line::from(vec![
+                Span::raw(indent.to_string()),
+                Span::styled(text, style),
+            ]));
+            if header {
+                let rule: Vec<String> = widths.iter().map(|&w| "─".repeat(w)).collect();
+                lines.push(Line::from(vec![
+                    Span::raw(indent.to_string()),
+                    Span::styled(
+                        skip_columns(&rule.join("─┼─"), scroll),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                ]));
+            }
+        }
+        lines
+    }
+}
+
+/// Drops the first `columns` terminal columns of `text`, a wide character cut in half leaves a
+/// space.
+fn skip_columns(text: &str, columns: usize) -> String {
+    let mut skipped = 0;
+    let mut out = String::new();
+    for c in text.chars() {
+        if skipped >= columns {
+            out.push(c);
+            continue;
+        }
+        skipped += c.width().unwrap_or(0);
+        if skipped > columns {
+            out.push(' ');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(table: &Table) -> Vec<Vec<&str>> {
+        table
+            .rows
+            .iter()
+            .map(|row| row.iter().map(String::as_str).collect())
+            .collect()
+    }
+
+    fn texts(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| line.spans.iter().map(|s| s.content.as_ref()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn tables_of_every_kind_are_recognized() {
+        let table = detect("| name | age |\n|:-----|----:|\n| ada | 36 |\n").unwrap();
+        assert_eq!(rows(&table), [["name", "age"], ["ada", "36"]]);
+        assert!(table.header);
+
+        let table = detect("name\tage\nada\t36\nbob\t7").unwrap();
+        assert_eq!(rows(&table)[2], ["bob", "7"]);
+        assert!(table.header);
+        let table = detect("a\tb\nc\td").unwrap();
+        assert!(!table.header);
+
+        let table = detect("city,\"greeting, long\",n\nParis,\"say \"\"hi\"\"\",2").unwrap();
+        assert_eq!(rows(&table)[1], ["Paris", "say \"hi\"", "2"]);
+    }
+
+    #[test]
+    fn prose_is_not_a_table() {
+        assert_eq!(detect("single\tline"), None);
+        assert_eq!(detect("well, yes\nand, no"), None);
+        assert_eq!(detect("a\tb\nc"), None);
+        assert_eq!(detect("| a | b |\nnot a separator\n| c | d |"), None);
+    }
+
+    #[test]
+    fn columns_are_aligned() {
+        let table = detect("item\tprice\ntea\t3.5\ncoffee\t12").unwrap();
+        assert_eq!(
+            texts(&table.lines("  ", 0)),
+            [
+                "  item   │ price",
+                "  ───────┼──────",
+                "  tea    │   3.5",
+                "  coffee │    12",
+            ]
+        );
+        assert_eq!(texts(&table.lines("", 7))[2], "│   3.5");
+        assert_eq!(skip_columns("漢字", 1), " 字");
+    }
+}
@@ -0,0 +1,158 @@
+//! Split screen like talk(1), `--mode talk`: your half on top, the peer's below, and every key
+//! sent as it is typed.
+//!
+//! Keys go out as `\u{1}TALK <keys>`, with the editing keys of talk as control characters. A
+//! finished line is recorded as a message, so a peer in the chat mode sees whole lines.
+
+use std::io::Write;
+
+use unicode_width::UnicodeWidthChar;
+
+/// Removes the character before the cursor, ^H
+pub const ERASE: char = '\u{8}';
+/// Removes the word before the cursor, ^W
+pub const WORD_ERASE: char = '\u{17}';
+/// Removes the line, ^U
+pub const KILL: char = '\u{15}';
+/// Rings the bell of the peer, ^G
+pub const BELL: char = '\u{7}';
+/// Lines kept of each half.
+const MAX_LINES: usize = 500;
+
+/// How the conversation is shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// messages one below the other
+    #[default]
+    Chat,
+    /// split screen, sent as typed
+    Talk,
+}
+
+/// Text of one half of the screen.
+#[derive(Debug, Default)]
+pub struct Window {
+    text: String,
+}
+
+/// What became of typed keys.
+#[derive(Debug, Default, PartialEq)]
+pub struct Typed {
+    /// Lines finished with Enter
+    pub lines: Vec<String>,
+    pub rang: bool,
+}
+
+impl Window {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Line being typed.
+    pub fn current_line(&self) -> &str {
+        self.text.rsplit('\n').next().unwrap_or_default()
+    }
+
+    pub fn apply(&mut self, keys: &str) -> Typed {
+        let mut typed = Typed::default();
+        for key in keys.chars() {
+            match key {
+                // finished lines are out already
+                ERASE if !self.current_line().is_empty() => {
+                    self.text.pop();
+                }
+                WORD_ERASE => {
+                    let line = self.current_line().trim_end();
+                    let word = line.rfind(' ').map_or(0, |i| i + 1);
+                    let end = self.text.len() - self.current_line().len() + word;
+                    self.text.truncate(end);
+                }
+                KILL => self
+                    .text
+                    .truncate(self.text.len() - self.current_line().len()),
+                BELL => typed.rang = true,
+                '\n' => {
+                    typed.lines.push(self.current_line().to_string());
+                    self.text.push('\n');
+                }
+                ERASE => {}
+                c if c.is_control() && c != '\t' => {}
+                c => self.text.push(c),
+            }
+        }
+        let lines = self.text.matches('\n').count();
+        if lines > MAX_LINES {
+            let mut starts = self.text.match_indices('\n').map(|(i, _)| i + 1);
+            let start = starts.nth(lines - MAX_LINES - 1).unwrap_or_default();
+            self.text.drain(..start);
+        }
+        typed
+    }
+
+    /// Adds a whole line, like a message of a peer in the chat mode.
+    pub fn append_line(&mut self, line: &str) {
+        if !self.current_line().is_empty() {
+            self.text.push('\n');
+        }
+        self.text.push_str(&line.replace('\n', " "));
+        self.apply("\n");
+    }
+
+    /// Rows of at most `width` columns, the last `height` of them.
+    pub fn rows(&self, width: usize, height: usize) -> Vec<String> {
+        let width = width.max(1);
+        let mut rows = Vec::new();
+        for line in self.text.split('\n') {
+            let mut row = String::new();
+            let mut columns = 0;
+            for c in line.chars() {
+                let w = c.width().unwrap_or(0);
+                if columns + w > width {
+                    rows.push(std::mem::take(&mut row));
+                    columns = 0;
+                }
+                row.push(c);
+                columns += w;
+            }
+            rows.push(row);
+        }
+        let skip = rows.len().saturating_sub(height);
+        rows.split_off(skip)
+    }
+}
+
+/// Rings the bell of the terminal.
+pub fn ring() {
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(b"\x07");
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editing_keys_work_like_talk() {
+        let mut window = Window::default();
+        let typed = window.apply("helo\u{8}lo world\u{17}there\n");
+        assert_eq!(typed.lines, ["hello there"]);
+        assert!(!typed.rang);
+        let typed = window.apply("oops\u{15}ok\u{7}\u{1b}\u{8}\u{8}\u{8}");
+        assert!(typed.rang);
+        // a finished line can't be erased into
+        assert_eq!(window.text(), "hello there\n");
+
+        window.apply("half");
+        window.append_line("from\nchat");
+        assert_eq!(window.text(), "hello there\nhalf\nfrom chat\n");
+    }
+
+    #[test]
+    fn long_lines_wrap_into_rows() {
+        let mut window = Window::default();
+        window.apply("abcdef\n漢字漢\nx");
+        assert_eq!(window.rows(4, 10), ["abcd", "ef", "漢字", "漢", "x"]);
+        assert_eq!(window.rows(4, 2), ["漢", "x"]);
+    }
+}
@@ -0,0 +1,149 @@
+//! Registry of the background threads, listed with `/tasks` to find out what a stuck connection
+//! is waiting for.
+//!
+//! Threads started through [`spawn`] are listed until they end, panicking or not, and may report
+//! what they are doing with [`set_state`].
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::timestamp::format_duration;
+
+static TASKS: Mutex<Vec<Task>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// Task run by this thread, if it was started through [`spawn`].
+    static CURRENT: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+struct Task {
+    id: u64,
+    name: String,
+    /// When the task entered its state
+    since: Instant,
+    state: String,
+}
+
+/// Takes the task off the list when its thread is done.
+struct Registration(u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut tasks) = TASKS.lock() {
+            tasks.retain(|t| t.id != self.0);
+        }
+    }
+}
+
+/// Runs `f` on a thread of its own, listed under `name` while it runs.
+pub fn spawn<F, T>(name: impl Into<String>, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let name = name.into();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut tasks) = TASKS.lock() {
+        tasks.push(Task {
+            id,
+            name: name.clone(),
+            since: Instant::now(),
+            state: "running".to_string(),
+        });
+    }
+    std::thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            let _registration = Registration(id);
+            CURRENT.with(|current| current.set(Some(id)));
+            f()
+        })
+        .expect("failed to spawn thread")
+}
+
+/// Tells what the task of the calling thread is doing now.
+pub fn set_state(state: impl Into<String>) {
+    let Some(id) = CURRENT.with(Cell::get) else {
+        return;
+    };
+    if let Ok(mut tasks) = TASKS.lock() {
+        if let Some(task) = tasks.iter_mut().find(|t| t.id == id) {
+            task.state = state.into();
+            task.since = Instant::now();
+        }
+    }
+}
+
+/// A line per running task, oldest first.
+pub fn list() -> Vec<String> {
+    let Ok(tasks) = TASKS.lock() else {
+        return Vec::new();
+    };
+    tasks
+        .iter()
+        .map(|t| {
+            // to the second, there is no point in more
+            let elapsed = Duration::from_secs(t.since.elapsed().as_secs());
+            format!(
+                "#{} {}: {} for {}",
+                t.id,
+                t.name,
+                t.state,
+                format_duration(elapsed)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn listed(name: &str) -> Vec<String> {
+        list()
+            .into_iter()
+            .map(|line| line.split_once(' ').expect("after the id").1.to_string())
+            .filter(|line| line.starts_with(name))
+            .collect()
+    }
+
+    #[test]
+    fn tasks_are_listed_while_they_run() {
+        let (started, wait_started) = mpsc::channel();
+        let (finish, finished) = mpsc::channel::<()>();
+        let handle = spawn("tasks-test", move || {
+            set_state("waiting for the test");
+            started.send(()).unwrap();
+            finished.recv().unwrap();
+        });
+        wait_started.recv().unwrap();
+        assert_eq!(
+            listed("tasks-test"),
+            ["tasks-test: waiting for the test for 0s"]
+        );
+        finish.send(()).unwrap();
+        handle.join().unwrap();
+        assert!(listed("tasks-test").is_empty());
+
+        // threads of their own aren't tasks
+        set_state("nothing");
+        assert!(listed("tasks-test").is_empty());
+    }
+
+    #[test]
+    fn panicking_tasks_are_taken_off_the_list() {
+        let handle = spawn("tasks-panic", || panic!("on purpose"));
+        assert!(handle.join().is_err());
+        assert!(listed("tasks-panic").is_empty());
+    }
+}
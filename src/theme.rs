@@ -0,0 +1,308 @@
+//! Colors of the interface. There are the built-in `dark`, `light` and `solarized` themes and
+//! those of the config, which start from one of them:
+//!
+//! ```toml
+//! [theme]
+//! name = "mine"          # dark by default
+//! own = "green bold"     # changes to the chosen theme
+//!
+//! [[themes]]
+//! name = "mine"
+//! base = "solarized"
+//! border = "#268bd2"
+//! senders = "cyan magenta yellow"
+//! selection = "black on #b58900"
+//! ```
+//!
+//! A style is a list of words, colors by name, number or `#rrggbb`, modifiers like `bold` or
+//! `reversed`, and a color after `on` for the background.
+
+use std::str::FromStr;
+
+use ratatui::style::{Color, Modifier, Style};
+use tracing::warn;
+
+use crate::config::Config;
+
+/// Config table picking the theme.
+pub const TABLE: &str = "theme";
+/// Config array of the user's themes.
+pub const THEMES: &str = "themes";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    /// Borders of the panes and popups
+    pub border: Style,
+    /// Input box while typing
+    pub editing: Style,
+    pub timestamp: Style,
+    /// Names on our messages
+    pub own: Style,
+    /// Names on the peer's messages
+    pub peer: Style,
+    /// Foreground of the names in a room with more people, picked by name, `peer` without any
+    pub senders: Vec<Color>,
+    /// Events of the app itself
+    pub system: Style,
+    /// Tags like `[queued]`, someone typing and offline users
+    pub muted: Style,
+    pub selection: Style,
+    /// Messages matching the search
+    pub search: Style,
+    /// Messages matching a highlight trigger
+    pub highlight: Style,
+    pub error: Style,
+    pub success: Style,
+    pub warning: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let gray = Style::default().fg(Color::DarkGray);
+        Self {
+            border: Style::default(),
+            editing: Style::default().fg(Color::Yellow),
+            timestamp: gray,
+            own: bold.fg(Color::Green),
+            peer: bold.fg(Color::Cyan),
+            senders: vec![
+                Color::Cyan,
+                Color::Yellow,
+                Color::LightBlue,
+                Color::Magenta,
+                Color::LightGreen,
+                Color::LightRed,
+            ],
+            system: gray,
+            muted: gray,
+            selection: Style::default().add_modifier(Modifier::REVERSED),
+            search: Style::default().bg(Color::DarkGray),
+            highlight: bold.fg(Color::Magenta),
+            error: Style::default().fg(Color::Red),
+            success: Style::default().fg(Color::Green),
+            warning: Style::default().fg(Color::Yellow),
+        }
+    }
+
+    /// For terminals with a light background, where yellow and gray are hard to read.
+    pub fn light() -> Self {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let gray = Style::default().fg(Color::Rgb(0x75, 0x75, 0x75));
+        Self {
+            border: Style::default().fg(Color::Rgb(0x9e, 0x9e, 0x9e)),
+            editing: Style::default().fg(Color::Rgb(0x15, 0x65, 0xc0)),
+            timestamp: gray,
+            own: bold.fg(Color::Rgb(0x2e, 0x7d, 0x32)),
+            peer: bold.fg(Color::Rgb(0x15, 0x65, 0xc0)),
+            senders: vec![
+                Color::Rgb(0x15, 0x65, 0xc0),
+                Color::Rgb(0x6a, 0x1b, 0x9a),
+                Color::Rgb(0x00, 0x83, 0x8f),
+                Color::Rgb(0xef, 0x6c, 0x00),
+                Color::Rgb(0xad, 0x14, 0x57),
+            ],
+            system: gray,
+            muted: gray,
+            selection: Style::default().add_modifier(Modifier::REVERSED),
+            search: Style::default().bg(Color::Rgb(0xe0, 0xe0, 0xe0)),
+            highlight: bold.fg(Color::Rgb(0xad, 0x14, 0x57)),
+            error: Style::default().fg(Color::Rgb(0xc6, 0x28, 0x28)),
+            success: Style::default().fg(Color::Rgb(0x2e, 0x7d, 0x32)),
+            warning: Style::default().fg(Color::Rgb(0xef, 0x6c, 0x00)),
+        }
+    }
+
+    /// Ethan Schoonover's palette, readable on its dark and light backgrounds alike.
+    pub fn solarized() -> Self {
+        const BASE01: Color = Color::Rgb(0x58, 0x6e, 0x75);
+        const YELLOW: Color = Color::Rgb(0xb5, 0x89, 0x00);
+        const ORANGE: Color = Color::Rgb(0xcb, 0x4b, 0x16);
+        const RED: Color = Color::Rgb(0xdc, 0x32, 0x2f);
+        const MAGENTA: Color = Color::Rgb(0xd3, 0x36, 0x82);
+        const VIOLET: Color = Color::Rgb(0x6c, 0x71, 0xc4);
+        const BLUE: Color = Color::Rgb(0x26, 0x8b, 0xd2);
+        const CYAN: Color = Color::Rgb(0x2a, 0xa1, 0x98);
+        const GREEN: Color = Color::Rgb(0x85, 0x99, 0x00);
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        Self {
+            border: Style::default().fg(BASE01),
+            editing: Style::default().fg(YELLOW),
+            timestamp: Style::default().fg(BASE01),
+            own: bold.fg(GREEN),
+            peer: bold.fg(BLUE),
+            senders: vec![BLUE, CYAN, VIOLET, MAGENTA, ORANGE, YELLOW],
+            system: Style::default().fg(BASE01),
+            muted: Style::default().fg(BASE01),
+            selection: Style::default().add_modifier(Modifier::REVERSED),
+            search: Style::default().bg(Color::Rgb(0x07, 0x36, 0x42)),
+            highlight: bold.fg(MAGENTA),
+            error: Style::default().fg(RED),
+            success: Style::default().fg(GREEN),
+            warning: Style::default().fg(ORANGE),
+        }
+    }
+
+    /// Built-in theme called `name`.
+    pub fn built_in(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "solarized" => Some(Self::solarized()),
+            _ => None,
+        }
+    }
+
+    /// Theme named in `[theme]`, with the changes made there.
+    pub fn from_config(config: &Config) -> Self {
+        let settings = config.strings(TABLE);
+        let name = settings
+            .iter()
+            .find_map(|(key, val)| (key == "name").then_some(val.as_str()))
+            .unwrap_or("dark");
+        let user = config.tables(THEMES).into_iter().find(|theme| {
+            theme
+                .iter()
+                .any(|(key, val)| key == "name" && val.as_str() == name)
+        });
+        let mut theme = match user {
+            Some(user) => {
+                let base = user
+                    .iter()
+                    .find_map(|(key, val)| (key == "base").then_some(val.as_str()))
+                    .unwrap_or("dark");
+                let mut theme = Self::built_in(base).unwrap_or_else(|| {
+                    warn!("Ignoring base {base} of theme {name}, it isn't a built-in theme");
+                    Self::dark()
+                });
+                theme.set_all(&format!("themes.{name}"), &user);
+                theme
+            }
+            None => Self::built_in(name).unwrap_or_else(|| {
+                warn!("Ignoring theme {name}, it is neither built in nor in [[themes]]");
+                Self::dark()
+            }),
+        };
+        theme.set_all(TABLE, &settings);
+        theme
+    }
+
+    /// Applies the entries of a table, `table` names it in the warnings.
+    fn set_all(&mut self, table: &str, entries: &[(String, String)]) {
+        for (key, val) in entries {
+            if key == "name" || key == "base" {
+                continue;
+            }
+            if let Err(e) = self.set(key, val) {
+                warn!("Ignoring {table}.{key}, {e}");
+            }
+        }
+    }
+
+    /// Changes one part of the theme.
+    pub fn set(&mut self, key: &str, val: &str) -> Result<(), String> {
+        if key == "senders" {
+            self.senders = val
+                .split_whitespace()
+                .map(|word| Color::from_str(word).map_err(|_| format!("{word} isn't a color")))
+                .collect::<Result<_, _>>()?;
+            return Ok(());
+        }
+        let style = match key {
+            "border" => &mut self.border,
+            "editing" => &mut self.editing,
+            "timestamp" => &mut self.timestamp,
+            "own" => &mut self.own,
+            "peer" => &mut self.peer,
+            "system" => &mut self.system,
+            "muted" => &mut self.muted,
+            "selection" => &mut self.selection,
+            "search" => &mut self.search,
+            "highlight" => &mut self.highlight,
+            "error" => &mut self.error,
+            "success" => &mut self.success,
+            "warning" => &mut self.warning,
+            _ => return Err("there is no such part of a theme".to_string()),
+        };
+        *style = parse_style(val)?;
+        Ok(())
+    }
+
+    /// Style of the name of someone else, the same one for them every time.
+    pub fn sender(&self, name: &str) -> Style {
+        if self.senders.is_empty() {
+            return self.peer;
+        }
+        // not the std hasher, its output may change between releases
+        let hash = name
+            .bytes()
+            .fold(0u32, |hash, b| hash.wrapping_mul(31).wrapping_add(b as u32));
+        self.peer
+            .fg(self.senders[hash as usize % self.senders.len()])
+    }
+}
+
+/// Style from words like `bold yellow on blue`.
+pub fn parse_style(text: &str) -> Result<Style, String> {
+    let mut style = Style::default();
+    let mut words = text.split_whitespace();
+    while let Some(word) = words.next() {
+        let modifier = match word.to_lowercase().as_str() {
+            "bold" => Modifier::BOLD,
+            "dim" => Modifier::DIM,
+            "italic" => Modifier::ITALIC,
+            "underlined" => Modifier::UNDERLINED,
+            "reversed" => Modifier::REVERSED,
+            "crossed-out" => Modifier::CROSSED_OUT,
+            "on" => {
+                let color = words.next().ok_or("on is missing a color")?;
+                style =
+                    style.bg(Color::from_str(color).map_err(|_| format!("{color} isn't a color"))?);
+                continue;
+            }
+            _ => {
+                style = style.fg(Color::from_str(word)
+                    .map_err(|_| format!("{word} isn't a color or a modifier"))?);
+                continue;
+            }
+        };
+        style = style.add_modifier(modifier);
+    }
+    Ok(style)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn styles_are_read_from_words() {
+        assert_eq!(
+            parse_style("bold #268bd2 on black"),
+            Ok(Style::default()
+                .fg(Color::Rgb(0x26, 0x8b, 0xd2))
+                .bg(Color::Black)
+                .add_modifier(Modifier::BOLD))
+        );
+        assert_eq!(parse_style(""), Ok(Style::default()));
+        assert!(parse_style("yellow on").is_err());
+        assert!(parse_style("blinking").is_err());
+    }
+
+    #[test]
+    fn senders_keep_their_color() {
+        let mut theme = Theme::dark();
+        assert_eq!(theme.sender("alice"), theme.sender("alice"));
+        theme.set("senders", "red").unwrap();
+        assert_eq!(theme.sender("bob").fg, Some(Color::Red));
+        theme.set("senders", "").unwrap();
+        assert_eq!(theme.sender("bob"), theme.peer);
+        assert!(theme.set("background", "red").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Wall clock helpers, timestamps are kept as milliseconds since the unix epoch.
+
+use std::{
+    ffi::CString,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// `millis` in the local time zone, formatted like strftime(3) does. Empty if the format can't
+/// be used.
+pub fn format_local(millis: u64, format: &str) -> String {
+    let Ok(format) = CString::new(format) else {
+        return String::new();
+    };
+    let secs = (millis / 1000) as libc::time_t;
+    // SAFETY: tm is plain data, filled in by localtime_r
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the call
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return String::new();
+    }
+    let mut buf = [0u8; 128];
+    // SAFETY: the buffer is valid for its length, what doesn't fit makes strftime return 0
+    let len = unsafe { libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm) };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Calendar date and time of `millis`, in UTC.
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn from_millis(millis: u64) -> Self {
+        let secs = millis / 1000;
+        let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+        // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u32,
+            minute: (rem % 3600 / 60) as u32,
+            second: (rem % 60) as u32,
+        }
+    }
+
+    pub fn to_millis(&self) -> u64 {
+        // days from civil, the inverse of the above
+        let year = self.year - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let mp = i64::from((self.month + 9) % 12);
+        let doy = (153 * mp + 2) / 5 + i64::from(self.day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        let secs = days * 86400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second);
+        secs.max(0) as u64 * 1000
+    }
+
+    /// Parses `YYYY-MM-DD HH:MM[:SS]`, a `T` in place of the space is fine too.
+    pub fn parse_iso(s: &str) -> Option<Self> {
+        let (date, time) = s.split_once(['T', ' '])?;
+        let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+        let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+        // ignore fractions of a second and the time zone
+        let time = time.trim_end_matches('Z');
+        let time = time.split(['.', '+']).next()?;
+        let mut time = time.splitn(3, ':').map(str::parse::<u32>);
+        let (hour, minute) = (time.next()?.ok()?, time.next()?.ok()?);
+        let second = time.next().transpose().ok()?.unwrap_or(0);
+        let valid = (1..=12).contains(&month) && (1..=31).contains(&day) && hour < 24;
+        valid.then_some(Self {
+            year,
+            month: month as u32,
+            day: day as u32,
+            hour,
+            minute,
+            second,
+        })
+    }
+}
+
+impl std::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Parses durations like `90s`, `15m` or `1h30m`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        total += digits.parse::<u64>().ok()? * unit;
+        digits.clear();
+    }
+    // a number without unit is either a typo or ambiguous
+    (digits.is_empty() && total > 0).then(|| Duration::from_secs(total))
+}
+
+/// Renders a duration the way [`parse_duration`] accepts them, down to minutes.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs % 3600 / 60) {
+        (0, 0) => format!("{secs}s"),
+        (0, m) => format!("{m}m"),
+        (h, 0) => format!("{h}h"),
+        (h, m) => format!("{h}h{m}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_convert_both_ways() {
+        // a leap day
+        let millis = 1_709_210_096_000;
+        let date = DateTime::from_millis(millis + 999);
+        assert_eq!(date.to_string(), "2024-02-29 12:34:56");
+        assert_eq!(date.to_millis(), millis);
+        assert_eq!(DateTime::from_millis(0).to_string(), "1970-01-01 00:00:00");
+    }
+
+    #[test]
+    fn local_times_follow_the_format() {
+        // minutes and seconds are the same in every time zone
+        assert_eq!(format_local(1_709_210_096_000, "%M:%S"), "34:56");
+        assert_eq!(format_local(0, "bad\0format"), "");
+        assert_eq!(format_local(0, &"%Y".repeat(100)), "");
+    }
+}
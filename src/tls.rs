@@ -0,0 +1,967 @@
+//! TLS for the connection to the peer, through the system's OpenSSL.
+//!
+//! The server needs `--cert` and `--key`. A client checks the server against the system's
+//! certificate authorities and its address, or, given `--cert`, only accepts that certificate or
+//! ones it signed, which suits the self-signed certificates of a LAN.
+//!
+//! `--pin-cert` makes a client accept only the server whose certificate, or public key, has the
+//! given SHA-256, whoever signed it. The pins saved with `/pin-cert` apply without the option:
+//!
+//! ```toml
+//! [pins]
+//! "example.org:8989" = "<sha256 in hex>"
+//! ```
+//!
+//! Both ends derive the short authentication string from the keys of the session, a man in the
+//! middle runs one session with each of them and can't make theirs match.
+//!
+//! The encrypted connection is run by a task of its own, passing the plain text on over a local
+//! connection, so the rest of the app keeps reading and writing a `TcpStream`.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_void, CStr, CString},
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+use crate::tasks;
+
+/// How long the other side has for the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, clap::Args)]
+#[group(id = "tls_options")]
+pub struct Options {
+    /// encrypt the connection with TLS
+    #[arg(long, conflicts_with = "multi")]
+    pub tls: bool,
+    /// certificate of the server, or the one a client trusts instead of the system's
+    #[arg(long, value_name = "FILE", requires = "tls")]
+    pub cert: Option<PathBuf>,
+    /// private key of the certificate of the server
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    pub key: Option<PathBuf>,
+    /// only accept a server whose certificate or public key has this SHA-256, in hex
+    #[arg(long, value_name = "SHA256", requires = "tls")]
+    pub pin_cert: Option<String>,
+}
+
+/// Config table holding the pins, by the address connected to.
+pub const PINS_TABLE: &str = "pins";
+
+/// Settings shared by all connections, set up once.
+pub struct Context {
+    ctx: *mut ffi::SSL_CTX,
+    server: bool,
+    /// Whether the certificate of the server is checked against its address
+    check_host: bool,
+    /// Whether the certificate of the server has to be signed by somebody trusted
+    verify: bool,
+    /// SHA-256 the certificate or public key of the server has to have, in lower case hex
+    pin: Option<String>,
+    /// Certificates for the ACME authority, handed to the callback of the context
+    challenges: Option<Arc<Challenges>>,
+}
+
+/// ALPN protocol of the TLS-ALPN-01 challenge of ACME.
+const ACME_ALPN: &[u8] = b"acme-tls/1";
+/// Label of the keying material the short authentication string is derived from.
+const SAS_LABEL: &str = "EXPORTER-chatterbox-sas";
+
+/// Certificate with its private key.
+pub(crate) struct Identity {
+    pub(crate) cert: *mut ffi::X509,
+    pub(crate) key: *mut ffi::EVP_PKEY,
+}
+
+// SAFETY: only read once created, OpenSSL counts the references itself
+unsafe impl Send for Identity {}
+
+impl Drop for Identity {
+    fn drop(&mut self) {
+        // SAFETY: owned by the identity, whoever uses them took references of their own
+        unsafe {
+            ffi::X509_free(self.cert);
+            ffi::EVP_PKEY_free(self.key);
+        }
+    }
+}
+
+/// Certificates proving control of a domain to an ACME authority, by domain.
+#[derive(Default)]
+pub struct Challenges(Mutex<HashMap<String, Identity>>);
+
+impl Challenges {
+    pub(crate) fn insert(&self, domain: &str, identity: Identity) {
+        self.0
+            .lock()
+            .expect("challenges lock is poisoned")
+            .insert(domain.to_string(), identity);
+    }
+
+    pub(crate) fn remove(&self, domain: &str) {
+        self.0
+            .lock()
+            .expect("challenges lock is poisoned")
+            .remove(domain);
+    }
+}
+
+// SAFETY: the context isn't changed after it is set up, which OpenSSL allows to share
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+impl std::fmt::Debug for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Context")
+            .field("server", &self.server)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        // SAFETY: created in `new`, the connections hold references of their own
+        unsafe { ffi::SSL_CTX_free(self.ctx) };
+    }
+}
+
+impl Context {
+    /// Loads the certificates, `None` without `--tls`.
+    pub fn new(options: &Options, server: bool) -> io::Result<Option<Arc<Self>>> {
+        if !options.tls {
+            return Ok(None);
+        }
+        let mut context = Self::create(server)?;
+        if let (Some(pin), false) = (&options.pin_cert, server) {
+            context.pin = Some(parse_pin(pin)?);
+        }
+        match (server, &options.cert, &options.key) {
+            (true, Some(cert), Some(key)) => context.load_identity(cert, key)?,
+            (true, ..) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--tls needs --cert and --key when running as server",
+                ))
+            }
+            (false, Some(cert), _) => context.trust(cert)?,
+            // the pin is all the trust there is
+            (false, None, _) if context.pin.is_some() => context.verify = false,
+            (false, None, _) => {
+                // SAFETY: a valid context
+                if unsafe { ffi::SSL_CTX_set_default_verify_paths(context.ctx) } != 1 {
+                    return Err(last_error("failed to load the system's certificates"));
+                }
+                context.check_host = true;
+            }
+        }
+        Ok(Some(Arc::new(context)))
+    }
+
+    /// Server context answering the TLS-ALPN-01 challenges of an ACME authority, serving the
+    /// certificate and key from the files once there are some.
+    pub fn acme(identity: Option<(&Path, &Path)>, challenges: Arc<Challenges>) -> io::Result<Self> {
+        let mut context = Self::create(true)?;
+        if let Some((cert, key)) = identity {
+            context.load_identity(cert, key)?;
+        }
+        // SAFETY: a valid context, the challenges live as long as it as they are kept in it
+        unsafe {
+            ffi::SSL_CTX_set_client_hello_cb(
+                context.ctx,
+                Some(challenge_certificate),
+                Arc::as_ptr(&challenges) as *mut c_void,
+            );
+            ffi::SSL_CTX_set_alpn_select_cb(context.ctx, Some(select_acme), std::ptr::null_mut());
+        }
+        context.challenges = Some(challenges);
+        Ok(context)
+    }
+
+    /// Context with the settings of every connection, for a server or a client.
+    fn create(server: bool) -> io::Result<Self> {
+        // SAFETY: plain constructor, checked for failure below
+        let ctx = unsafe {
+            ffi::SSL_CTX_new(match server {
+                true => ffi::TLS_server_method(),
+                false => ffi::TLS_client_method(),
+            })
+        };
+        if ctx.is_null() {
+            return Err(last_error("failed to set up TLS"));
+        }
+        let context = Self {
+            ctx,
+            server,
+            check_host: false,
+            verify: !server,
+            pin: None,
+            challenges: None,
+        };
+        // SAFETY: a valid context
+        if unsafe {
+            ffi::SSL_CTX_ctrl(
+                ctx,
+                ffi::SSL_CTRL_SET_MIN_PROTO_VERSION,
+                ffi::TLS1_2_VERSION,
+                std::ptr::null_mut(),
+            )
+        } != 1
+        {
+            return Err(last_error("failed to require TLS 1.2"));
+        }
+        // a peer quitting without saying goodbye is no attack on a chat, just a dropped connection
+        // SAFETY: a valid context
+        unsafe { ffi::SSL_CTX_set_options(ctx, ffi::SSL_OP_IGNORE_UNEXPECTED_EOF) };
+        Ok(context)
+    }
+
+    fn load_identity(&self, cert: &Path, key: &Path) -> io::Result<()> {
+        let (cert_path, key_path) = (c_path(cert)?, c_path(key)?);
+        // SAFETY: a valid context and nul terminated paths
+        unsafe {
+            if ffi::SSL_CTX_use_certificate_chain_file(self.ctx, cert_path.as_ptr()) != 1 {
+                return Err(last_error(&format!("failed to load {}", cert.display())));
+            }
+            if ffi::SSL_CTX_use_PrivateKey_file(self.ctx, key_path.as_ptr(), ffi::SSL_FILETYPE_PEM)
+                != 1
+            {
+                return Err(last_error(&format!("failed to load {}", key.display())));
+            }
+            if ffi::SSL_CTX_check_private_key(self.ctx) != 1 {
+                return Err(last_error("the key doesn't belong to the certificate"));
+            }
+        }
+        Ok(())
+    }
+
+    fn trust(&self, cert: &Path) -> io::Result<()> {
+        let path = c_path(cert)?;
+        // SAFETY: a valid context and a nul terminated path
+        if unsafe { ffi::SSL_CTX_load_verify_locations(self.ctx, path.as_ptr(), std::ptr::null()) }
+            != 1
+        {
+            return Err(last_error(&format!("failed to load {}", cert.display())));
+        }
+        Ok(())
+    }
+
+    /// Runs the handshake on `tcp`, then encrypts it in the background.
+    ///
+    /// Returns the local end to use instead, and what was agreed on. `host` is the address the
+    /// client connected to, checked against the certificate unless one was given to trust.
+    pub fn wrap(&self, tcp: TcpStream, host: Option<&str>) -> io::Result<(TcpStream, Session)> {
+        let peer = tcp.peer_addr()?;
+        let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+        let local = tcp.local_addr()?;
+        let ssl = Ssl::new(self)?;
+        if self.verify {
+            // SAFETY: a valid connection, a peer is verified against its certificate
+            unsafe { ffi::SSL_set_verify(ssl.0, ffi::SSL_VERIFY_PEER, None) };
+        }
+        if !self.server {
+            if let Some(host) = host {
+                ssl.set_host(host, self.check_host)?;
+            }
+        }
+        // SAFETY: the socket outlives the connection, both are moved into the task below
+        if unsafe { ffi::SSL_set_fd(ssl.0, tcp.as_raw_fd()) } != 1 {
+            return Err(last_error("failed to set up TLS"));
+        }
+        tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        tcp.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        // SAFETY: a valid connection with its socket set
+        let res = unsafe {
+            match self.server {
+                true => ffi::SSL_accept(ssl.0),
+                false => ffi::SSL_connect(ssl.0),
+            }
+        };
+        if res != 1 {
+            return Err(ssl.handshake_error(res));
+        }
+        if self.server && ssl.alpn() == Some(ACME_ALPN) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "answered an ACME challenge",
+            ));
+        }
+        tcp.set_read_timeout(None)?;
+        tcp.set_write_timeout(None)?;
+        let (certificate, public_key) = ssl.peer_hashes();
+        if let Some(pin) = &self.pin {
+            if certificate.as_ref() != Some(pin) && public_key.as_ref() != Some(pin) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "certificate rejected: sha256 {} doesn't match the pin",
+                        certificate
+                            .as_deref()
+                            .unwrap_or("of nothing, none was sent")
+                    ),
+                ));
+            }
+        }
+        let sas = crate::sas::derive(&ssl.keying_material(SAS_LABEL)?);
+        let session = Session {
+            peer,
+            local,
+            server: host
+                .filter(|_| !self.server)
+                .map(|h| format!("{h}:{}", peer.port())),
+            protocol: ssl.protocol(),
+            certificate,
+            public_key,
+            sas,
+        };
+        debug!("TLS established with {peer}: {}", session.protocol);
+        let (ours, theirs) = pipe()?;
+        tasks::spawn(format!("tls {peer}"), move || {
+            tasks::set_state("encrypting");
+            if let Err(e) = pump(&ssl, &tcp, theirs) {
+                warn!("TLS connection with {peer} failed: {e}");
+            }
+        });
+        Ok((ours, session))
+    }
+}
+
+/// What an encrypted connection runs on.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub peer: SocketAddr,
+    pub local: SocketAddr,
+    /// Version and cipher, e.g. `TLSv1.3 TLS_AES_256_GCM_SHA384`
+    pub protocol: String,
+    /// Address the client connected to, as it was given
+    pub server: Option<String>,
+    /// SHA-256 of the certificate of the peer, in hex, if it sent one
+    pub certificate: Option<String>,
+    /// SHA-256 of the public key in the certificate, in hex
+    pub public_key: Option<String>,
+    /// Short authentication string, the same on both ends unless someone is in the middle
+    pub sas: String,
+}
+
+/// One connection, freed when dropped.
+struct Ssl(*mut ffi::SSL);
+
+// SAFETY: used by one thread at a time, first for the handshake and then by the pump
+unsafe impl Send for Ssl {}
+
+impl Drop for Ssl {
+    fn drop(&mut self) {
+        // SAFETY: created in `new` and not used anymore
+        unsafe { ffi::SSL_free(self.0) };
+    }
+}
+
+impl Ssl {
+    fn new(context: &Context) -> io::Result<Self> {
+        // SAFETY: a valid context
+        let ssl = unsafe { ffi::SSL_new(context.ctx) };
+        match ssl.is_null() {
+            true => Err(last_error("failed to set up TLS")),
+            false => Ok(Self(ssl)),
+        }
+    }
+
+    /// Sends the name of the server and, if `check`, makes sure the certificate is for it.
+    fn set_host(&self, host: &str, check: bool) -> io::Result<()> {
+        let name = CString::new(host)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid host name"))?;
+        let is_ip = host.parse::<IpAddr>().is_ok();
+        // SAFETY: a valid connection and a nul terminated name, both copied by OpenSSL
+        let res = unsafe {
+            if !is_ip {
+                ffi::SSL_ctrl(
+                    self.0,
+                    ffi::SSL_CTRL_SET_TLSEXT_HOSTNAME,
+                    ffi::TLSEXT_NAMETYPE_HOST_NAME,
+                    name.as_ptr() as *mut c_void,
+                );
+            }
+            match (check, is_ip) {
+                (false, _) => 1,
+                (true, true) => {
+                    ffi::X509_VERIFY_PARAM_set1_ip_asc(ffi::SSL_get0_param(self.0), name.as_ptr())
+                }
+                (true, false) => ffi::SSL_set1_host(self.0, name.as_ptr()),
+            }
+        };
+        match res {
+            1 => Ok(()),
+            _ => Err(last_error(&format!(
+                "failed to check the certificate for {host}"
+            ))),
+        }
+    }
+
+    fn handshake_error(&self, res: c_int) -> io::Error {
+        // SAFETY: a valid connection
+        let verified = unsafe { ffi::SSL_get_verify_result(self.0) };
+        if verified != ffi::X509_V_OK {
+            // SAFETY: returns a static string for any code
+            let reason = unsafe { CStr::from_ptr(ffi::X509_verify_cert_error_string(verified)) };
+            return io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("certificate rejected: {}", reason.to_string_lossy()),
+            );
+        }
+        // SAFETY: as above
+        match unsafe { ffi::SSL_get_error(self.0, res) } {
+            ffi::SSL_ERROR_WANT_READ | ffi::SSL_ERROR_WANT_WRITE => {
+                io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+            }
+            ffi::SSL_ERROR_SYSCALL => match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(0) => io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "peer closed the connection during the TLS handshake",
+                ),
+                e => e,
+            },
+            _ => last_error("TLS handshake failed"),
+        }
+    }
+
+    /// Protocol agreed on with ALPN.
+    fn alpn(&self) -> Option<&[u8]> {
+        let (mut data, mut len) = (std::ptr::null(), 0);
+        // SAFETY: an established connection, the protocol lives as long as it
+        unsafe {
+            ffi::SSL_get0_alpn_selected(self.0, &mut data, &mut len);
+            (!data.is_null()).then(|| std::slice::from_raw_parts(data, len as usize))
+        }
+    }
+
+    /// Secret of this session only, exported under `label`.
+    fn keying_material(&self, label: &str) -> io::Result<[u8; 32]> {
+        let mut material = [0; 32];
+        // SAFETY: an established connection, the buffers are valid for the lengths passed along
+        let res = unsafe {
+            ffi::SSL_export_keying_material(
+                self.0,
+                material.as_mut_ptr(),
+                material.len(),
+                label.as_ptr().cast(),
+                label.len(),
+                std::ptr::null(),
+                0,
+                0,
+            )
+        };
+        match res {
+            1 => Ok(material),
+            _ => Err(last_error("failed to export keying material")),
+        }
+    }
+
+    fn protocol(&self) -> String {
+        // SAFETY: both return static strings of an established connection
+        unsafe {
+            let version = CStr::from_ptr(ffi::SSL_get_version(self.0));
+            let cipher = CStr::from_ptr(ffi::SSL_CIPHER_get_name(ffi::SSL_get_current_cipher(
+                self.0,
+            )));
+            format!("{} {}", version.to_string_lossy(), cipher.to_string_lossy())
+        }
+    }
+
+    /// SHA-256 of the certificate of the peer and of its public key.
+    fn peer_hashes(&self) -> (Option<String>, Option<String>) {
+        // SAFETY: an established connection, the certificate is freed right after hashing it and
+        // the key belongs to it
+        unsafe {
+            let cert = ffi::SSL_get1_peer_certificate(self.0);
+            if cert.is_null() {
+                return (None, None);
+            }
+            let mut digest = [0u8; 32];
+            let mut len: c_uint = 0;
+            let res = ffi::X509_digest(cert, ffi::EVP_sha256(), digest.as_mut_ptr(), &mut len);
+            let certificate = (res == 1).then(|| hex::encode(&digest[..len as usize]));
+            // the whole SubjectPublicKeyInfo, like the pins of browsers
+            let key = ffi::X509_get0_pubkey(cert);
+            let der_len = match key.is_null() {
+                true => -1,
+                false => ffi::i2d_PUBKEY(key, std::ptr::null_mut()),
+            };
+            let public_key = (der_len > 0)
+                .then(|| {
+                    let mut der = vec![0u8; der_len as usize];
+                    let mut out = der.as_mut_ptr();
+                    ffi::i2d_PUBKEY(key, &mut out);
+                    let res = ffi::EVP_Digest(
+                        der.as_ptr().cast(),
+                        der.len(),
+                        digest.as_mut_ptr(),
+                        &mut len,
+                        ffi::EVP_sha256(),
+                        std::ptr::null_mut(),
+                    );
+                    (res == 1).then(|| hex::encode(&digest[..len as usize]))
+                })
+                .flatten();
+            ffi::X509_free(cert);
+            (certificate, public_key)
+        }
+    }
+
+    /// Reads what is there without waiting, `Ok(None)` once the peer closed the connection.
+    fn read(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+        // SAFETY: the buffer is valid for `len` bytes
+        let res = unsafe { ffi::SSL_read(self.0, buf.as_mut_ptr().cast(), len) };
+        if res > 0 {
+            return Ok(Some(res as usize));
+        }
+        // SAFETY: a valid connection
+        match unsafe { ffi::SSL_get_error(self.0, res) } {
+            ffi::SSL_ERROR_WANT_READ | ffi::SSL_ERROR_WANT_WRITE => Ok(Some(0)),
+            ffi::SSL_ERROR_ZERO_RETURN => Ok(None),
+            // closed without saying goodbye, like a plain connection may
+            ffi::SSL_ERROR_SYSCALL => Ok(None),
+            _ => Err(last_error("failed to decrypt")),
+        }
+    }
+
+    /// Writes all of `buf`, waiting for the socket whenever it is full.
+    fn write_all(&self, fd: c_int, buf: &[u8]) -> io::Result<()> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let len = rest.len().min(c_int::MAX as usize) as c_int;
+            // SAFETY: the buffer is valid for `len` bytes, and passed again if it has to be retried
+            let res = unsafe { ffi::SSL_write(self.0, rest.as_ptr().cast(), len) };
+            if res > 0 {
+                rest = &rest[res as usize..];
+                continue;
+            }
+            // SAFETY: a valid connection
+            match unsafe { ffi::SSL_get_error(self.0, res) } {
+                ffi::SSL_ERROR_WANT_READ | ffi::SSL_ERROR_WANT_WRITE => {
+                    wait(&mut [(fd, libc::POLLIN | libc::POLLOUT)])?;
+                }
+                _ => return Err(last_error("failed to encrypt")),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves the challenge certificate for the name asked for to whoever offers the ACME protocol,
+/// the authority checking the domain.
+unsafe extern "C" fn challenge_certificate(
+    ssl: *mut ffi::SSL,
+    _alert: *mut c_int,
+    challenges: *mut c_void,
+) -> c_int {
+    let extension = |kind| {
+        let (mut data, mut len) = (std::ptr::null(), 0);
+        // SAFETY: the hello lives until the callback returns, as do the extensions in it
+        match ffi::SSL_client_hello_get0_ext(ssl, kind, &mut data, &mut len) {
+            1 => Some(std::slice::from_raw_parts(data, len)),
+            _ => None,
+        }
+    };
+    let acme = extension(ffi::TLSEXT_TYPE_ALPN)
+        .and_then(|list| list.get(2..))
+        .is_some_and(|list| protocols(list).any(|p| p == ACME_ALPN));
+    // a list of names, of which there is only ever the host name: its type, its length and itself
+    let name = extension(ffi::TLSEXT_TYPE_SERVER_NAME)
+        .and_then(|list| {
+            let len = u16::from_be_bytes([*list.get(3)?, *list.get(4)?]) as usize;
+            list.get(5..5 + len)
+        })
+        .and_then(|name| std::str::from_utf8(name).ok());
+    if let (true, Some(name)) = (acme, name) {
+        // SAFETY: the context keeps the challenges alive
+        let challenges = &*(challenges as *const Challenges);
+        if let Some(identity) = challenges
+            .0
+            .lock()
+            .expect("challenges lock is poisoned")
+            .get(name)
+        {
+            // both take references of their own
+            ffi::SSL_use_certificate(ssl, identity.cert);
+            ffi::SSL_use_PrivateKey(ssl, identity.key);
+        }
+    }
+    ffi::SSL_CLIENT_HELLO_SUCCESS
+}
+
+/// Picks the ACME protocol if offered, nothing otherwise.
+unsafe extern "C" fn select_acme(
+    _ssl: *mut ffi::SSL,
+    out: *mut *const u8,
+    out_len: *mut u8,
+    offered: *const u8,
+    offered_len: c_uint,
+    _arg: *mut c_void,
+) -> c_int {
+    // SAFETY: OpenSSL passes the list with its length
+    let offered = std::slice::from_raw_parts(offered, offered_len as usize);
+    match protocols(offered).find(|&p| p == ACME_ALPN) {
+        Some(protocol) => {
+            *out = protocol.as_ptr();
+            *out_len = protocol.len() as u8;
+            ffi::SSL_TLSEXT_ERR_OK
+        }
+        None => ffi::SSL_TLSEXT_ERR_NOACK,
+    }
+}
+
+/// Protocols of an ALPN list, each after its length.
+fn protocols(mut list: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let (&len, rest) = list.split_first()?;
+        let protocol = rest.get(..len as usize)?;
+        list = &rest[len as usize..];
+        Some(protocol)
+    })
+}
+
+/// Passes plain text between the local end and the encrypted connection until either closes.
+fn pump(ssl: &Ssl, tcp: &TcpStream, mut local: TcpStream) -> io::Result<()> {
+    tcp.set_nonblocking(true)?;
+    let (fd, local_fd) = (tcp.as_raw_fd(), local.as_raw_fd());
+    let mut buf = vec![0; 16 * 1024];
+    let res = 'pump: loop {
+        // all of it, OpenSSL may hold on to more than the socket shows
+        loop {
+            match ssl.read(&mut buf) {
+                Ok(Some(0)) => break,
+                Ok(Some(n)) => local.write_all(&buf[..n])?,
+                Ok(None) => break 'pump Ok(()),
+                Err(e) => break 'pump Err(e),
+            }
+        }
+        let ready = wait(&mut [(fd, libc::POLLIN), (local_fd, libc::POLLIN)])?;
+        if ready[1] {
+            match local.read(&mut buf)? {
+                0 => {
+                    // SAFETY: a valid connection, the answer of the peer isn't waited for
+                    unsafe { ffi::SSL_shutdown(ssl.0) };
+                    break Ok(());
+                }
+                n => {
+                    if let Err(e) = ssl.write_all(fd, &buf[..n]) {
+                        break Err(e);
+                    }
+                }
+            }
+        }
+    };
+    // the app sees the connection drop like a plain one
+    let _ = local.shutdown(std::net::Shutdown::Both);
+    let _ = tcp.shutdown(std::net::Shutdown::Both);
+    res
+}
+
+/// Waits until any of the sockets is ready for the given events, returns which are.
+fn wait(fds: &mut [(c_int, libc::c_short)]) -> io::Result<Vec<bool>> {
+    let mut polled: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&(fd, events)| libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        })
+        .collect();
+    loop {
+        // SAFETY: the slice is valid for its length
+        let res = unsafe { libc::poll(polled.as_mut_ptr(), polled.len() as libc::nfds_t, -1) };
+        if res >= 0 {
+            return Ok(polled.iter().map(|p| p.revents != 0).collect());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Two connected ends on the loopback interface.
+fn pipe() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (theirs, address) = listener.accept()?;
+        // anybody else on the host could have been quicker
+        if address == ours.local_addr()? {
+            ours.set_nodelay(true)?;
+            theirs.set_nodelay(true)?;
+            return Ok((ours, theirs));
+        }
+    }
+}
+
+/// SHA-256 in hex, with or without colons between the bytes.
+fn parse_pin(pin: &str) -> io::Result<String> {
+    let pin = pin.replace(':', "").to_lowercase();
+    match hex::decode(&pin) {
+        Ok(bytes) if bytes.len() == 32 => Ok(pin),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--pin-cert takes a SHA-256 in hex, as /peerinfo shows it",
+        )),
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid path"))
+}
+
+/// Error with the reason OpenSSL gives, if any.
+fn last_error(context: &str) -> io::Error {
+    // SAFETY: reads the error queue of this thread
+    let code = unsafe { ffi::ERR_get_error() };
+    if code == 0 {
+        return io::Error::other(context.to_string());
+    }
+    let mut buf = [0 as c_char; 256];
+    // SAFETY: the buffer is valid for its length, nul terminated by OpenSSL
+    let reason = unsafe {
+        ffi::ERR_error_string_n(code, buf.as_mut_ptr(), buf.len());
+        CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+    };
+    io::Error::other(format!("{context}: {reason}"))
+}
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub(crate) mod ffi {
+    use super::*;
+
+    pub enum SSL_CTX {}
+    pub enum SSL {}
+    pub enum SSL_METHOD {}
+    pub enum SSL_CIPHER {}
+    pub enum X509 {}
+    pub enum X509_VERIFY_PARAM {}
+    pub enum EVP_MD {}
+    pub enum EVP_PKEY {}
+    pub enum ENGINE {}
+
+    pub const SSL_FILETYPE_PEM: c_int = 1;
+    pub const SSL_VERIFY_PEER: c_int = 1;
+    pub const SSL_CTRL_SET_TLSEXT_HOSTNAME: c_int = 55;
+    pub const SSL_CTRL_SET_MIN_PROTO_VERSION: c_int = 123;
+    pub const TLSEXT_NAMETYPE_HOST_NAME: c_long = 0;
+    pub const TLS1_2_VERSION: c_long = 0x0303;
+    pub const X509_V_OK: c_long = 0;
+    pub const SSL_OP_IGNORE_UNEXPECTED_EOF: u64 = 1 << 7;
+    pub const SSL_ERROR_WANT_READ: c_int = 2;
+    pub const SSL_ERROR_WANT_WRITE: c_int = 3;
+    pub const SSL_ERROR_SYSCALL: c_int = 5;
+    pub const SSL_ERROR_ZERO_RETURN: c_int = 6;
+    pub const SSL_CLIENT_HELLO_SUCCESS: c_int = 1;
+    pub const SSL_TLSEXT_ERR_OK: c_int = 0;
+    pub const SSL_TLSEXT_ERR_NOACK: c_int = 3;
+    pub const TLSEXT_TYPE_SERVER_NAME: c_uint = 0;
+    pub const TLSEXT_TYPE_ALPN: c_uint = 16;
+
+    #[link(name = "ssl")]
+    extern "C" {
+        pub fn TLS_server_method() -> *const SSL_METHOD;
+        pub fn TLS_client_method() -> *const SSL_METHOD;
+        pub fn SSL_CTX_new(method: *const SSL_METHOD) -> *mut SSL_CTX;
+        pub fn SSL_CTX_free(ctx: *mut SSL_CTX);
+        pub fn SSL_CTX_ctrl(
+            ctx: *mut SSL_CTX,
+            cmd: c_int,
+            larg: c_long,
+            parg: *mut c_void,
+        ) -> c_long;
+        pub fn SSL_CTX_use_certificate_chain_file(ctx: *mut SSL_CTX, file: *const c_char) -> c_int;
+        pub fn SSL_CTX_use_PrivateKey_file(
+            ctx: *mut SSL_CTX,
+            file: *const c_char,
+            kind: c_int,
+        ) -> c_int;
+        pub fn SSL_CTX_check_private_key(ctx: *const SSL_CTX) -> c_int;
+        pub fn SSL_CTX_load_verify_locations(
+            ctx: *mut SSL_CTX,
+            file: *const c_char,
+            path: *const c_char,
+        ) -> c_int;
+        pub fn SSL_CTX_set_default_verify_paths(ctx: *mut SSL_CTX) -> c_int;
+        pub fn SSL_CTX_set_options(ctx: *mut SSL_CTX, options: u64) -> u64;
+        pub fn SSL_new(ctx: *mut SSL_CTX) -> *mut SSL;
+        pub fn SSL_free(ssl: *mut SSL);
+        pub fn SSL_set_verify(
+            ssl: *mut SSL,
+            mode: c_int,
+            callback: Option<unsafe extern "C" fn(c_int, *mut c_void) -> c_int>,
+        );
+        pub fn SSL_ctrl(ssl: *mut SSL, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+        pub fn SSL_set1_host(ssl: *mut SSL, host: *const c_char) -> c_int;
+        pub fn SSL_get0_param(ssl: *mut SSL) -> *mut X509_VERIFY_PARAM;
+        pub fn SSL_set_fd(ssl: *mut SSL, fd: c_int) -> c_int;
+        pub fn SSL_accept(ssl: *mut SSL) -> c_int;
+        pub fn SSL_connect(ssl: *mut SSL) -> c_int;
+        pub fn SSL_read(ssl: *mut SSL, buf: *mut c_void, num: c_int) -> c_int;
+        pub fn SSL_write(ssl: *mut SSL, buf: *const c_void, num: c_int) -> c_int;
+        pub fn SSL_shutdown(ssl: *mut SSL) -> c_int;
+        pub fn SSL_get_error(ssl: *const SSL, ret: c_int) -> c_int;
+        pub fn SSL_get_verify_result(ssl: *const SSL) -> c_long;
+        pub fn SSL_get_version(ssl: *const SSL) -> *const c_char;
+        pub fn SSL_get_current_cipher(ssl: *const SSL) -> *const SSL_CIPHER;
+        pub fn SSL_CIPHER_get_name(cipher: *const SSL_CIPHER) -> *const c_char;
+        pub fn SSL_get1_peer_certificate(ssl: *const SSL) -> *mut X509;
+        pub fn SSL_export_keying_material(
+            ssl: *mut SSL,
+            out: *mut u8,
+            olen: usize,
+            label: *const c_char,
+            llen: usize,
+            context: *const u8,
+            contextlen: usize,
+            use_context: c_int,
+        ) -> c_int;
+        pub fn SSL_CTX_set_client_hello_cb(
+            ctx: *mut SSL_CTX,
+            callback: Option<unsafe extern "C" fn(*mut SSL, *mut c_int, *mut c_void) -> c_int>,
+            arg: *mut c_void,
+        );
+        pub fn SSL_client_hello_get0_ext(
+            ssl: *mut SSL,
+            kind: c_uint,
+            out: *mut *const u8,
+            out_len: *mut usize,
+        ) -> c_int;
+        pub fn SSL_CTX_set_alpn_select_cb(
+            ctx: *mut SSL_CTX,
+            callback: Option<
+                unsafe extern "C" fn(
+                    *mut SSL,
+                    *mut *const u8,
+                    *mut u8,
+                    *const u8,
+                    c_uint,
+                    *mut c_void,
+                ) -> c_int,
+            >,
+            arg: *mut c_void,
+        );
+        pub fn SSL_get0_alpn_selected(ssl: *const SSL, data: *mut *const u8, len: *mut c_uint);
+        pub fn SSL_use_certificate(ssl: *mut SSL, cert: *mut X509) -> c_int;
+        pub fn SSL_use_PrivateKey(ssl: *mut SSL, key: *mut EVP_PKEY) -> c_int;
+    }
+
+    #[link(name = "crypto")]
+    extern "C" {
+        pub fn X509_VERIFY_PARAM_set1_ip_asc(
+            param: *mut X509_VERIFY_PARAM,
+            ip: *const c_char,
+        ) -> c_int;
+        pub fn X509_verify_cert_error_string(n: c_long) -> *const c_char;
+        pub fn X509_digest(
+            cert: *const X509,
+            kind: *const EVP_MD,
+            md: *mut u8,
+            len: *mut c_uint,
+        ) -> c_int;
+        pub fn X509_free(cert: *mut X509);
+        pub fn X509_get0_pubkey(cert: *const X509) -> *mut EVP_PKEY;
+        pub fn EVP_PKEY_free(key: *mut EVP_PKEY);
+        pub fn i2d_PUBKEY(key: *const EVP_PKEY, out: *mut *mut u8) -> c_int;
+        pub fn EVP_Digest(
+            data: *const c_void,
+            count: usize,
+            md: *mut u8,
+            size: *mut c_uint,
+            kind: *const EVP_MD,
+            engine: *mut ENGINE,
+        ) -> c_int;
+        pub fn EVP_sha256() -> *const EVP_MD;
+        pub fn ERR_get_error() -> c_ulong;
+        pub fn ERR_error_string_n(code: c_ulong, buf: *mut c_char, len: usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    /// SHA-256 of the fixture certificate and of its public key
+    const CERTIFICATE: &str = "7ed5393733ea50ad1e6e2b47cb87dc37292c75eb824e378f01efe8ea984b76d2";
+    const PUBLIC_KEY: &str = "08fa52626bcbaae1413ade1143ed381df6abd2f6b2f599954d3a941805023e38";
+
+    fn options(cert: bool, key: bool, pin: Option<&str>) -> Options {
+        Options {
+            tls: true,
+            cert: cert.then(|| Path::new(FIXTURES).join("cert.pem")),
+            key: key.then(|| Path::new(FIXTURES).join("key.pem")),
+            pin_cert: pin.map(str::to_string),
+        }
+    }
+
+    /// Sessions of both ends, the client's run with `client`.
+    fn connect(client: &Options) -> io::Result<(Session, Session)> {
+        let server = Context::new(&options(true, true, None), true)?.unwrap();
+        let client = Context::new(client, false)?.unwrap();
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let tcp = TcpStream::connect(listener.local_addr()?)?;
+        let accepting = std::thread::spawn(move || {
+            let (tcp, _) = listener.accept()?;
+            server.wrap(tcp, None).map(|(_, session)| session)
+        });
+        let client = client.wrap(tcp, Some("127.0.0.1"));
+        let server = accepting.join().unwrap();
+        Ok((client?.1, server?))
+    }
+
+    #[test]
+    fn pins_are_sha256_in_hex() {
+        let colons = CERTIFICATE
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap().to_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(parse_pin(&colons).unwrap(), CERTIFICATE);
+        assert!(parse_pin(&CERTIFICATE[2..]).is_err());
+        assert!(parse_pin("not hex").is_err());
+    }
+
+    #[test]
+    fn the_trusted_certificate_is_accepted() {
+        let (client, server) = connect(&options(true, false, None)).unwrap();
+        assert_eq!(client.certificate.as_deref(), Some(CERTIFICATE));
+        assert_eq!(client.public_key.as_deref(), Some(PUBLIC_KEY));
+        assert_eq!(server.certificate, None);
+        assert_eq!(client.sas, server.sas);
+        assert_eq!(
+            client.server.as_deref(),
+            Some(&*format!("127.0.0.1:{}", client.peer.port()))
+        );
+
+        // not signed by any authority of the system
+        assert!(connect(&options(false, false, None)).is_err());
+        let err = Context::new(&options(true, false, None), true)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn only_the_pinned_certificate_or_key_is_accepted() {
+        assert!(connect(&options(false, false, Some(CERTIFICATE))).is_ok());
+        assert!(connect(&options(false, false, Some(PUBLIC_KEY))).is_ok());
+        let other = "00".repeat(32);
+        let err = connect(&options(false, false, Some(&other))).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        // a pin is checked even for a trusted certificate
+        assert!(connect(&options(true, false, Some(&other))).is_err());
+    }
+}
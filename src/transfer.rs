@@ -0,0 +1,255 @@
+//! Files sent over the chat connection, `/send <path>`.
+//!
+//! The sender offers a file with `\u{1}FILE <id> <size> <name>`, the peer answers with
+//! `\u{1}FILE-ACCEPT <id>` or `\u{1}FILE-DECLINE <id>` once its user decided. The content then
+//! follows in `\u{1}FILE-CHUNK <id> <hex>` lines, or [`cbor`](crate::cbor) ones, and
+//! `\u{1}FILE-END <id> <sha1>` closes it.
+//! Either side stops a transfer with `\u{1}FILE-ABORT <id> <reason>`.
+//!
+//! Accepted files are saved in the downloads directory, under a name of their own if it is
+//! taken. One file is sent and one is received at a time.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{cbor, dirs, protocol, tasks};
+
+/// Bytes of the file in every chunk.
+const CHUNK: usize = 16 * 1024;
+/// Chunks read ahead of the connection.
+const CHUNKS_AHEAD: usize = 16;
+/// Time spent sending chunks at once, the interface goes on in between.
+pub const SEND_SLICE: Duration = Duration::from_millis(50);
+
+/// File we offered, sent once the peer accepts it.
+#[derive(Debug)]
+pub struct Outgoing {
+    pub id: u64,
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    pub sent: u64,
+    /// Wire lines of the content, once accepted
+    pub lines: Option<mpsc::Receiver<String>>,
+    /// Message showing how far it got
+    pub shown: String,
+}
+
+impl Outgoing {
+    pub fn new(id: u64, path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a file", path.display()),
+            ));
+        }
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Self {
+            id,
+            path: path.to_path_buf(),
+            name,
+            size: metadata.len(),
+            sent: 0,
+            lines: None,
+            shown: String::new(),
+        })
+    }
+
+    /// Starts reading the file in the background, into CBOR frames if `compact`.
+    pub fn start(&mut self, compact: bool) -> io::Result<()> {
+        let mut file = File::open(&self.path)?;
+        let (tx, lines) = mpsc::sync_channel(CHUNKS_AHEAD);
+        let id = self.id;
+        tasks::spawn("file sender", move || {
+            let mut hasher = Sha1::new();
+            let mut buf = vec![0; CHUNK];
+            loop {
+                let line = match file.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        hasher.update(&buf[..len]);
+                        match compact {
+                            true => cbor::file_chunk(id, &buf[..len]),
+                            false => protocol::file_chunk(id, &buf[..len]),
+                        }
+                    }
+                    Err(e) => {
+                        let _ = tx.send(protocol::file_abort(id, &format!("failed to read: {e}")));
+                        return;
+                    }
+                };
+                // the transfer was dropped
+                if tx.send(line).is_err() {
+                    return;
+                }
+            }
+            let digest = hex::encode(hasher.finalize());
+            let _ = tx.send(protocol::file_end(id, &digest));
+        });
+        self.lines = Some(lines);
+        Ok(())
+    }
+}
+
+/// File the peer offered, saved once accepted.
+#[derive(Debug)]
+pub struct Incoming {
+    pub id: u64,
+    /// As the peer named it, without any directories
+    pub name: String,
+    pub size: u64,
+    pub received: u64,
+    /// Where it goes, once accepted
+    saving: Option<(File, PathBuf)>,
+    hasher: Sha1,
+    /// Message showing how far it got
+    pub shown: String,
+}
+
+impl Incoming {
+    pub fn new(id: u64, size: u64, name: &str) -> Self {
+        // whatever the peer puts in front, the file stays in the downloads directory
+        let name = name
+            .rsplit(['/', '\\'])
+            .next()
+            .filter(|n| !n.is_empty() && *n != "." && *n != "..")
+            .unwrap_or("download");
+        Self {
+            id,
+            name: name.to_string(),
+            size,
+            received: 0,
+            saving: None,
+            hasher: Sha1::new(),
+            shown: String::new(),
+        }
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.saving.is_some()
+    }
+
+    /// Creates the file in the downloads directory, returns where.
+    pub fn accept(&mut self) -> io::Result<PathBuf> {
+        let dir = dirs::download_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no downloads directory"))?;
+        fs::create_dir_all(&dir)?;
+        let (stem, extension) = match self.name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+            _ => (self.name.as_str(), String::new()),
+        };
+        for n in 0.. {
+            let path = match n {
+                0 => dir.join(&self.name),
+                n => dir.join(format!("{stem} ({n}){extension}")),
+            };
+            match File::options().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    self.saving = Some((file, path.clone()));
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!("some name is free")
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some((file, _)) = &mut self.saving else {
+            return Err(io::Error::other("the file wasn't accepted"));
+        };
+        self.received += data.len() as u64;
+        if self.received > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "more than the offered size arrived",
+            ));
+        }
+        self.hasher.update(data);
+        file.write_all(data)
+    }
+
+    /// Checks the file is complete, returns where it was saved.
+    pub fn finish(self, digest: &str) -> io::Result<PathBuf> {
+        let Some((file, path)) = self.saving else {
+            return Err(io::Error::other("the file wasn't accepted"));
+        };
+        file.sync_all()?;
+        if self.received != self.size || hex::encode(self.hasher.finalize()) != digest {
+            let _ = fs::remove_file(&path);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file arrived damaged",
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Removes what was saved of it.
+    pub fn discard(self) {
+        if let Some((_, path)) = self.saving {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Size in the largest unit it makes at least one of, like `1.4 MB`.
+pub fn describe_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit + 1 < UNITS.len() {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// How much of `total` is done, in whole percent.
+pub fn percent(done: u64, total: u64) -> u64 {
+    match total {
+        0 => 100,
+        total => done * 100 / total,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offered_names_stay_in_the_downloads_directory() {
+        let name = |offered| Incoming::new(1, 0, offered).name;
+        assert_eq!(name("notes.txt"), "notes.txt");
+        assert_eq!(name("../../.bashrc"), ".bashrc");
+        assert_eq!(name("/etc/passwd"), "passwd");
+        assert_eq!(name("..\\windows\\evil.dll"), "evil.dll");
+        for offered in ["", ".", "..", "dir/", "a/.."] {
+            assert_eq!(name(offered), "download", "{offered:?}");
+        }
+    }
+
+    #[test]
+    fn sizes_are_described_in_their_unit() {
+        assert_eq!(describe_size(999), "999 bytes");
+        assert_eq!(describe_size(1400), "1.4 KB");
+        assert_eq!(describe_size(2_500_000_000), "2.5 GB");
+        assert_eq!(describe_size(u64::MAX), "18446744.1 TB");
+        assert_eq!((percent(1, 3), percent(0, 0)), (33, 100));
+    }
+}
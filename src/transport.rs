@@ -0,0 +1,141 @@
+//! Streams the conversation runs over, TCP or, with `--unix`, a Unix domain socket for local
+//! programs and tests. With `--inetd` it is the socket the process was started with.
+
+use std::{
+    fs, io, mem,
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+use crate::connection;
+
+/// Connection to the peer, whatever it goes over.
+pub trait Transport: io::Read + io::Write + Send {
+    /// Another handle of the same connection, to read from while this one is written to.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Network address of the peer, an error for connections without one.
+    fn peer_address(&self) -> io::Result<SocketAddr>;
+
+    /// Network address of our end, an error for connections without one.
+    fn local_address(&self) -> io::Result<SocketAddr>;
+
+    /// Closes both directions, which ends a read waiting on another handle.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Where the peer is, as shown to the user.
+    fn describe(&self) -> String {
+        self.peer_address()
+            .map_or_else(|_| "peer".to_string(), |a| a.to_string())
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn peer_address(&self) -> io::Result<SocketAddr> {
+        connection::peer_address(self)
+    }
+
+    fn local_address(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn peer_address(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "a unix socket has no network address",
+        ))
+    }
+
+    fn local_address(&self) -> io::Result<SocketAddr> {
+        self.peer_address()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    /// The path of the socket, only the server's end is bound to it.
+    fn describe(&self) -> String {
+        let path = [self.peer_addr(), self.local_addr()]
+            .into_iter()
+            .flatten()
+            .find_map(|a| a.as_pathname().map(|p| p.display().to_string()));
+        path.unwrap_or_else(|| "unix socket".to_string())
+    }
+}
+
+/// Binds `path`, taking it over from a server which is gone.
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(e);
+            }
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        res => res,
+    }
+}
+
+/// Descriptor systemd hands the connection over on, right after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Connection the process was started with, on stdin by inetd or on fd 3 by systemd when
+/// `LISTEN_PID` names us.
+pub fn inherited() -> io::Result<Box<dyn Transport>> {
+    let ours = std::env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let fd = match ours && std::env::var("LISTEN_FDS").is_ok_and(|n| n == "1") {
+        true => LISTEN_FDS_START,
+        false => 0,
+    };
+    // the commands of triggers aren't meant to take it over
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    // SAFETY: zeroed is a valid sockaddr_storage, which is large enough for any family
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&address) as libc::socklen_t;
+    // SAFETY: `len` is the size of `address`, getpeername doesn't write beyond it
+    let res = unsafe {
+        libc::getpeername(
+            fd,
+            (&mut address as *mut libc::sockaddr_storage).cast(),
+            &mut len,
+        )
+    };
+    if res != 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            e.kind(),
+            format!("fd {fd} isn't a connection: {e}"),
+        ));
+    }
+    // SAFETY: the descriptor is a connected socket which nothing else of ours uses
+    match address.ss_family as libc::c_int {
+        libc::AF_UNIX => Ok(Box::new(unsafe { UnixStream::from_raw_fd(fd) })),
+        libc::AF_INET | libc::AF_INET6 => Ok(Box::new(unsafe { TcpStream::from_raw_fd(fd) })),
+        family => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("fd {fd} is a socket of family {family}, not TCP or unix"),
+        )),
+    }
+}
@@ -0,0 +1,208 @@
+//! Rules run against incoming messages, configured as an array of tables:
+//!
+//! ```toml
+//! [[triggers]]
+//! pattern = "(?i)urgent"
+//! action = "command"          # notify, highlight, command or reply
+//! argument = "paplay bell.oga" # command line or reply text
+//! ```
+
+use std::fmt;
+
+use regex::Regex;
+use tracing::{debug, error, warn};
+
+use crate::{config::Config, json};
+
+/// Config array holding the triggers.
+pub const TABLE: &str = "triggers";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Desktop notification, even if the terminal is focused
+    Notify,
+    Highlight,
+    /// Shell command, gets the message in `$CHATTERBOX_MESSAGE` and its annotation, if any, in
+    /// `$CHATTERBOX_DATA`
+    Command(String),
+    /// Sends the text back to the peer
+    Reply(String),
+}
+
+impl Action {
+    pub fn parse(action: &str, argument: Option<&str>) -> Result<Self, String> {
+        match (action, argument) {
+            ("notify", _) => Ok(Action::Notify),
+            ("highlight", _) => Ok(Action::Highlight),
+            ("command", Some(cmd)) => Ok(Action::Command(cmd.to_string())),
+            ("reply", Some(text)) => Ok(Action::Reply(text.to_string())),
+            ("command" | "reply", None) => Err(format!("{action} trigger needs an argument")),
+            _ => Err(format!("unknown trigger action {action}")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Notify => "notify",
+            Action::Highlight => "highlight",
+            Action::Command(_) => "command",
+            Action::Reply(_) => "reply",
+        }
+    }
+
+    pub fn argument(&self) -> Option<&str> {
+        match self {
+            Action::Notify | Action::Highlight => None,
+            Action::Command(arg) | Action::Reply(arg) => Some(arg),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Trigger {
+    pub pattern: Regex,
+    pub action: Action,
+    /// Position in the config array, invalid entries are skipped so it may differ from ours.
+    config_index: usize,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} /{}/", self.action.name(), self.pattern)?;
+        if let Some(arg) = self.action.argument() {
+            write!(f, " => {arg}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Triggers(Vec<Trigger>);
+
+impl Triggers {
+    pub fn from_config(config: &Config) -> Self {
+        let triggers = config
+            .tables(TABLE)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(config_index, entries)| {
+                let get = |key: &str| {
+                    entries
+                        .iter()
+                        .find(|(k, _)| k == key)
+                        .map(|(_, v)| v.as_str())
+                };
+                let res = get("pattern")
+                    .ok_or_else(|| "missing pattern".to_string())
+                    .and_then(|p| Regex::new(p).map_err(|e| e.to_string()))
+                    .and_then(|pattern| {
+                        let action = Action::parse(get("action").unwrap_or(""), get("argument"))?;
+                        Ok(Trigger {
+                            pattern,
+                            action,
+                            config_index,
+                        })
+                    });
+                res.map_err(|e| warn!("Ignoring trigger #{}: {e}", config_index + 1))
+                    .ok()
+            })
+            .collect();
+        Self(triggers)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Trigger> {
+        self.0.iter()
+    }
+
+    /// Config array index of the trigger at `position`.
+    pub fn config_index(&self, position: usize) -> Option<usize> {
+        self.0.get(position).map(|t| t.config_index)
+    }
+
+    pub fn matching<'a>(&'a self, text: &'a str) -> impl Iterator<Item = &'a Action> {
+        self.0
+            .iter()
+            .filter(|t| t.pattern.is_match(text))
+            .map(|t| &t.action)
+    }
+}
+
+/// Runs `cmd` through the shell in the background.
+///
+/// Output is discarded, anything written to the terminal would mess up the UI.
+pub fn run_command(cmd: &str, msg: &str, data: Option<&json::Value>) {
+    use std::process::{Command, Stdio};
+    debug!("running trigger command {cmd:?}");
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd).env("CHATTERBOX_MESSAGE", msg);
+    if let Some(data) = data {
+        command.env("CHATTERBOX_DATA", data.to_string());
+    }
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match child {
+        // reap it once done, nobody cares about the result otherwise
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => error!("Failed to run trigger command {cmd:?}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_triggers_are_skipped() {
+        let config: Config = r#"
+            [[triggers]]
+            pattern = "(?i)urgent"
+            action = "notify"
+
+            [[triggers]]
+            pattern = "(unclosed"
+            action = "notify"
+
+            [[triggers]]
+            pattern = "ping"
+            action = "reply"
+
+            [[triggers]]
+            pattern = "^ping$"
+            action = "reply"
+            argument = "pong"
+        "#
+        .parse()
+        .unwrap();
+        let triggers = Triggers::from_config(&config);
+        let shown: Vec<_> = triggers.iter().map(Trigger::to_string).collect();
+        assert_eq!(shown, ["notify /(?i)urgent/", "reply /^ping$/ => pong"]);
+        assert_eq!(triggers.config_index(1), Some(3));
+        assert_eq!(triggers.config_index(2), None);
+
+        assert_eq!(
+            triggers.matching("URGENT").collect::<Vec<_>>(),
+            [&Action::Notify]
+        );
+        assert_eq!(
+            triggers.matching("ping").collect::<Vec<_>>(),
+            [&Action::Reply("pong".to_string())]
+        );
+        assert_eq!(triggers.matching("ping me").count(), 0);
+    }
+
+    #[test]
+    fn actions_need_their_arguments() {
+        assert_eq!(Action::parse("highlight", Some("x")), Ok(Action::Highlight));
+        assert_eq!(
+            Action::parse("command", Some("paplay bell.oga")),
+            Ok(Action::Command("paplay bell.oga".to_string()))
+        );
+        assert!(Action::parse("command", None).is_err());
+        assert!(Action::parse("shout", None).is_err());
+    }
+}
@@ -0,0 +1,575 @@
+//! Terminal interface of the app.
+
+use std::sync::{atomic::Ordering, Arc};
+
+use ratatui::{prelude::*, widgets::*};
+
+use crate::{
+    ansi,
+    app::{InputMode, Verification},
+    connection::{Attempt, ATTEMPT, LISTENING},
+    diag, mdns, message, pane, skew, talk, timestamp, transfer, App,
+};
+
+/// Lines the input box grows to, longer drafts scroll in it.
+const INPUT_ROWS: usize = 8;
+/// Columns of the sidebar listing the clients of a `--multi` server.
+const USERS_WIDTH: u16 = 26;
+
+/// Draws the whole interface.
+pub fn draw<B: Backend>(f: &mut Frame<B>, app: &App) {
+    if let Some(talking) = &app.talking {
+        draw_talk(f, app, talking);
+        if let Some(hud) = &app.hud {
+            draw_hud(f, app, hud);
+        }
+        draw_popup(f, app);
+        return;
+    }
+    let rows = app.input.split('\n').count().min(INPUT_ROWS);
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Min(1),
+                Constraint::Length(rows as u16 + 2),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
+        .split(f.size());
+
+    f.render_widget(Paragraph::new(status_line(app)), chunks[2]);
+
+    // a tab is shown in one column, like `input_width` counts it
+    let shown: String = app
+        .input
+        .chars()
+        .map(|c| if c == '\t' { '→' } else { c })
+        .collect();
+    let (line, column) = app.cursor_line();
+    // keeps the line with the cursor in view
+    let scroll = (line + 1).saturating_sub(rows) as u16;
+    let mut title = match (app.live.is_active(), app.seal.is_some()) {
+        _ if app.searching.is_some() => "Search (Enter selects, Esc cancels)",
+        (true, true) => "Input 🔒 (live)",
+        (true, false) => "Input (live)",
+        (false, true) => "Input 🔒",
+        (false, false) => "Input",
+    }
+    .to_string();
+    if let Some(register) = app.macros.recording() {
+        title.push_str(&format!(" recording @{register}"));
+    }
+    let input = Paragraph::new(shown.as_str())
+        .scroll((scroll, 0))
+        .style(match app.input_mode {
+            InputMode::Normal => Style::default(),
+            InputMode::Editing => app.theme.editing,
+        })
+        .block(bordered(app).title(title));
+    f.render_widget(input, chunks[1]);
+    match app.input_mode {
+        InputMode::Normal =>
+            // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
+            {}
+
+        InputMode::Editing => {
+            // Make the cursor visible and ask ratatui to put it at the specified coordinates after
+            // rendering. The terminal draws the composition of an input method there as well, so
+            // it has to account for wide characters, which the column does.
+            f.set_cursor(
+                // Draw the cursor at the current position in the input field.
+                // This position is can be controlled via the arrow keys
+                chunks[1].x + column as u16 + 1,
+                // Move down from the border to the line of the cursor
+                chunks[1].y + 1 + line as u16 - scroll,
+            )
+        }
+    }
+    let preview = app.live.preview();
+    let chunks = match preview.is_empty() {
+        true if app.live.is_peer_typing() => {
+            let area = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+                .split(chunks[0]);
+            let name = app.peer_alias.as_deref().unwrap_or("peer");
+            let line = Line::styled(format!("{name} is typing…"), app.theme.muted);
+            f.render_widget(Paragraph::new(line), area[1]);
+            [area[0]]
+        }
+        true => [chunks[0]],
+        false => {
+            let area = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+                .split(chunks[0]);
+            let name = app.peer_alias.as_deref().unwrap_or("peer");
+            let draft: String = ansi::sanitize(&preview)
+                .chars()
+                .map(|c| if c == '\n' { '↵' } else { c })
+                .collect();
+            let line = Line::from(vec![
+                Span::styled(format!("{name} is typing: "), app.theme.muted),
+                Span::styled(draft, Style::default().add_modifier(Modifier::ITALIC)),
+                Span::styled("▏", app.theme.muted),
+            ]);
+            f.render_widget(Paragraph::new(line), area[1]);
+            [area[0]]
+        }
+    };
+    let chunks = match app.pane.lock().unwrap().as_ref() {
+        Some(view) => {
+            let area = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(3), Constraint::Length(pane::ROWS + 2)].as_ref())
+                .split(chunks[0]);
+            let title = match &view.ended {
+                Some(status) => format!("Shared pane, ended: {status} (p closes)"),
+                None => "Shared pane (p closes)".to_string(),
+            };
+            let text = Paragraph::new(view.text.as_str()).block(bordered(app).title(title));
+            f.render_widget(text, area[1]);
+            [area[0]]
+        }
+        None => [chunks[0]],
+    };
+    let presence = app.presence.lock().unwrap();
+    let chunks = match app.show_users && !presence.is_empty() {
+        true => {
+            let area = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(20), Constraint::Length(USERS_WIDTH)].as_ref())
+                .split(chunks[0]);
+            let users: Vec<ListItem> = presence
+                .iter()
+                .map(|(name, &online)| {
+                    let (mark, style) = match online {
+                        true => ("●", app.theme.success),
+                        false => ("○", app.theme.muted),
+                    };
+                    let name = ansi::sanitize(name).into_owned();
+                    ListItem::new(Line::from(vec![
+                        Span::styled(mark, style),
+                        Span::raw(format!(" {name}")),
+                    ]))
+                })
+                .collect();
+            let online = presence.values().filter(|&&online| online).count();
+            let title = format!("Users ({online} online)");
+            f.render_widget(List::new(users).block(bordered(app).title(title)), area[1]);
+            [area[0]]
+        }
+        false => [chunks[0]],
+    };
+    drop(presence);
+    let mut lock = app.messages.lock().unwrap();
+    // ignore borders
+    let height = chunks[0].height.saturating_sub(2) as usize;
+    let render = message::Render {
+        timestamps: app
+            .show_timestamps
+            .then(|| Arc::clone(&app.timestamp_format)),
+        raw: app.raw,
+        ansi: app.ansi,
+        table_scroll: app.table_scroll,
+        width: chunks[0].width.saturating_sub(2) as usize,
+        theme: Arc::clone(&app.theme),
+    };
+    let (items, mut state) = lock.view(height, |m| app.is_shown(m), |m| m.height(&render));
+    // items fitting at the tail, the rest is what there is to scroll through
+    let mut used = 0;
+    let page = items
+        .iter()
+        .rev()
+        .take_while(|m| {
+            used += m.height(&render);
+            used <= height
+        })
+        .count()
+        .max(1);
+    let range = items.len().saturating_sub(page);
+    // without a selection the list scrolls up to the first item, so it only gets those from the
+    // offset on
+    let skipped = match state.selected() {
+        Some(_) => 0,
+        None => std::mem::take(state.offset_mut()),
+    };
+    if state.selected().is_some() {
+        // a match scrolled to the top doesn't leave the end of the pane empty
+        *state.offset_mut() = state.offset().min(range);
+    }
+    let query = app.search_query();
+    let messages: Vec<ListItem> = items[skipped..]
+        .iter()
+        .map(|m| {
+            let item = ListItem::new(m.to_text(&render));
+            match &query {
+                Some(query) if app.matches(m, query) => item.style(app.theme.search),
+                _ => item,
+            }
+        })
+        .collect();
+    let mut title = match (&app.current_room, &app.peer_alias, &app.relay) {
+        (Some(room), ..) => format!("Messages in {room}"),
+        (None, Some(alias), _) => format!("Messages with {alias}"),
+        (None, None, Some(route)) if route.is_room() => format!("Messages in {}", route.to),
+        (None, None, _) => "Messages".to_string(),
+    };
+    match &app.encryption {
+        Some(_) if app.plaintext.load(Ordering::Acquire) => {
+            title.push_str(" 🔓 plaintext from the peer")
+        }
+        Some(status) if status.is_encrypted() => title.push_str(&format!(" 🔒 {status}")),
+        Some(_) => title.push_str(" 🔓 plaintext"),
+        None => {}
+    }
+    if let Some(topic) = app.room.lock().unwrap().topic.as_ref() {
+        title.push_str(&format!(": {}", ansi::sanitize(topic)));
+    }
+    if let Some(i) = lock.selected() {
+        title.push_str(&format!(" [{}/{}]", i + 1, lock.len()));
+        if let Some(msg) = lock.get(i).filter(|m| m.envelope.is_some()) {
+            let envelope = msg.envelope.as_ref().expect("filtered on it");
+            title.push_str(&format!(
+                " from {} at {}",
+                ansi::sanitize(&envelope.sender),
+                timestamp::DateTime::from_millis(skew::adjust(envelope.sent, msg.skew))
+            ));
+            if msg.skew != 0 {
+                title.push_str(" by our clock");
+            }
+        }
+    }
+    if lock.selected().is_none() && lock.scrolled() > 0 {
+        title.push_str(" (scrolled back)");
+    }
+    if let Some(query) = &query {
+        let matching = items.iter().filter(|m| app.matches(m, query)).count();
+        title.push_str(&format!(" ({matching} matching {query:?})"));
+    }
+    if !app.show_system {
+        title.push_str(" (system hidden)");
+    }
+    if app.raw {
+        title.push_str(" (raw)");
+    }
+    match app.unread.load(Ordering::Relaxed) {
+        0 => {}
+        unread => title.push_str(&format!(" ({unread} unread)")),
+    }
+    let messages = List::new(messages)
+        .block(bordered(app).title(title))
+        .highlight_style(app.theme.selection);
+    f.render_stateful_widget(messages, chunks[0], &mut state);
+    let offset = skipped + state.offset();
+    lock.set_offset(offset);
+    if offset > 0 || lock.scrolled() > 0 {
+        let mut scrollbar = ScrollbarState::default()
+            .content_length(range as u16)
+            .viewport_content_length(page as u16)
+            .position(offset as u16);
+        f.render_stateful_widget(
+            Scrollbar::default().orientation(ScrollbarOrientation::VerticalRight),
+            chunks[0].inner(&Margin {
+                vertical: 1,
+                horizontal: 0,
+            }),
+            &mut scrollbar,
+        );
+    }
+    // the HUD counts the messages itself
+    drop(lock);
+
+    if let Some(hud) = &app.hud {
+        draw_hud(f, app, hud);
+    }
+    draw_popup(f, app);
+}
+
+/// Screen of `--discover` listing the servers found so far, or why there are none.
+pub fn draw_picker<B: Backend>(
+    f: &mut Frame<B>,
+    services: &[mdns::Service],
+    state: &mut ListState,
+    error: Option<&str>,
+) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("Servers on the local network (Enter connects, Esc quits)");
+    if services.is_empty() {
+        let text = match error {
+            Some(e) => format!("failed to look for servers: {e}"),
+            None => "looking for servers started with --discover…".to_string(),
+        };
+        f.render_widget(Paragraph::new(text).block(block), f.size());
+        return;
+    }
+    let items: Vec<ListItem> = services
+        .iter()
+        .map(|s| ListItem::new(ansi::sanitize(&s.to_string()).into_owned()))
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(list, f.size(), state);
+}
+
+/// Counters in the top right corner, toggled with `H`.
+fn draw_hud<B: Backend>(f: &mut Frame<B>, app: &App, hud: &diag::Hud) {
+    let messages = app.messages.lock().unwrap().len();
+    let heap = diag::allocated().map_or_else(
+        || "unknown".to_string(),
+        |bytes| transfer::describe_size(bytes as u64),
+    );
+    let lines = vec![
+        Line::from(format!("messages: {messages}")),
+        Line::from(format!("outbox: {}", app.queued.len())),
+        Line::from(format!("redraws/s: {}", hud.per_second)),
+        Line::from(format!("heap: {heap}")),
+    ];
+    let size = f.size();
+    let width = 24.min(size.width);
+    let height = (lines.len() as u16 + 2).min(size.height);
+    let area = Rect::new(size.width - width, 0, width, height);
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(lines).block(bordered(app).title("HUD")),
+        area,
+    );
+}
+
+/// Shows the popup over everything, if there is one.
+fn draw_popup<B: Backend>(f: &mut Frame<B>, app: &App) {
+    let Some(popup) = &app.popup else {
+        return;
+    };
+    let size = f.size();
+    let width = 60.min(size.width.saturating_sub(4));
+    let height = (popup.lines.len() as u16 + 2).min(size.height.saturating_sub(2));
+    let area = Rect::new(
+        (size.width - width) / 2,
+        (size.height - height) / 2,
+        width,
+        height,
+    );
+    let text: Vec<Line> = popup
+        .lines
+        .iter()
+        .map(|l| Line::from(ansi::sanitize(l).into_owned()))
+        .collect();
+    let title = format!("{} (Esc closes)", popup.title);
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(text)
+            .wrap(Wrap { trim: false })
+            .block(bordered(app).title(title)),
+        area,
+    );
+}
+
+/// Split screen of `--mode talk`, our half on top and the peer's below.
+fn draw_talk<B: Backend>(f: &mut Frame<B>, app: &App, talking: &talk::Window) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Ratio(1, 2),
+                Constraint::Min(3),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
+        .split(f.size());
+    f.render_widget(Paragraph::new(status_line(app)), chunks[2]);
+
+    let inner = |area: Rect| (area.width.saturating_sub(2), area.height.saturating_sub(2));
+    let (width, height) = inner(chunks[0]);
+    let ours = talking.rows(width as usize, height as usize);
+    if matches!(app.input_mode, InputMode::Editing) {
+        let row = ours.len().saturating_sub(1) as u16;
+        let column = ours.last().map_or(0, |r| {
+            unicode_width::UnicodeWidthStr::width(r.as_str()) as u16
+        });
+        f.set_cursor(
+            chunks[0].x + 1 + column.min(width.saturating_sub(1)),
+            chunks[0].y + 1 + row,
+        );
+    }
+    let style = match app.input_mode {
+        InputMode::Normal => Style::default(),
+        InputMode::Editing => app.theme.editing,
+    };
+    let you = Paragraph::new(ours.join("\n"))
+        .style(style)
+        .block(bordered(app).title("You"));
+    f.render_widget(you, chunks[0]);
+
+    let (width, height) = inner(chunks[1]);
+    let theirs = app
+        .peer_talk
+        .lock()
+        .unwrap()
+        .rows(width as usize, height as usize);
+    let name = app.peer_alias.as_deref().unwrap_or("peer");
+    let peer = Paragraph::new(ansi::sanitize(&theirs.join("\n")).into_owned())
+        .block(bordered(app).title(name));
+    f.render_widget(peer, chunks[1]);
+}
+
+/// Bottom line: connection state, the last notice or how the session is verified.
+fn status_line(app: &App) -> Line<'_> {
+    let listening = LISTENING.lock().unwrap();
+    match (&app.notice, &app.verification) {
+        (None, _) if !app.connected && !listening.is_empty() => Line::styled(
+            format!(
+                "waiting for a client on {}, messages will be queued",
+                listening.join(" and ")
+            ),
+            app.theme.warning,
+        ),
+        (None, _) if !app.connected => Line::styled(
+            match ATTEMPT.lock().unwrap().as_ref() {
+                Some(Attempt::Connecting(peer)) => {
+                    format!("connecting to {peer}, messages will be queued")
+                }
+                Some(Attempt::Waiting {
+                    failed,
+                    error,
+                    next,
+                }) => format!(
+                    "retry {failed} in {}s, {error}, messages will be queued",
+                    next.saturating_duration_since(std::time::Instant::now())
+                        .as_secs_f32()
+                        .ceil()
+                ),
+                None => "not connected, messages will be queued".to_string(),
+            },
+            app.theme.warning,
+        ),
+        (Some(notice), _) => Line::styled(notice.as_str(), app.theme.error),
+        (None, Verification::Unavailable) => Line::default(),
+        (None, Verification::Pending(sas)) => Line::from(vec![
+            Span::raw("Compare with peer: "),
+            Span::styled(sas.as_str(), Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw("  /confirm or /deny"),
+        ]),
+        (None, Verification::Verified) => Line::styled("✔ session verified", app.theme.success),
+        (None, Verification::Rejected) => Line::styled(
+            "✘ verification failed, peer may be impersonated",
+            app.theme.error,
+        ),
+    }
+}
+
+/// Block around a pane, in the colors of the theme.
+fn bordered(app: &App) -> Block<'static> {
+    Block::default()
+        .borders(Borders::ALL)
+        .border_style(app.theme.border)
+}
+
+/// Breaks `line` into lines at most `width` columns wide, at spaces where it can, continuing
+/// `indent` columns in.
+pub fn wrap(line: Line<'static>, width: usize, indent: usize) -> Vec<Line<'static>> {
+    use unicode_width::UnicodeWidthChar;
+
+    if width == 0 || line.width() <= width {
+        return vec![line];
+    }
+    // a pane too narrow for the indent gets all of its width
+    let indent = if indent < width / 2 { indent } else { 0 };
+    let chars: Vec<(char, Style)> = line
+        .spans
+        .iter()
+        .flat_map(|span| span.content.chars().map(move |c| (c, span.style)))
+        .collect();
+    // words and the spaces between them, in turn
+    let mut words: Vec<&[(char, Style)]> = Vec::new();
+    let mut start = 0;
+    for i in 1..=chars.len() {
+        if i == chars.len() || (chars[i].0 == ' ') != (chars[i - 1].0 == ' ') {
+            words.push(&chars[start..i]);
+            start = i;
+        }
+    }
+    let columns = |word: &[(char, Style)]| -> usize {
+        word.iter().map(|(c, _)| c.width().unwrap_or(0)).sum()
+    };
+    let mut lines = vec![Vec::new()];
+    let mut used = 0;
+    for word in words {
+        let space = word[0].0 == ' ';
+        let word_width = columns(word);
+        if used + word_width > width && used > indent {
+            lines.push(vec![(' ', Style::default()); indent]);
+            used = indent;
+            // no line continues with the spaces it broke at
+            if space {
+                continue;
+            }
+        }
+        if used + word_width <= width {
+            lines.last_mut().unwrap().extend_from_slice(word);
+            used += word_width;
+            continue;
+        }
+        // longer than a whole line, broken anywhere
+        for &(c, style) in word {
+            let w = c.width().unwrap_or(0);
+            if used + w > width && used > indent {
+                lines.push(vec![(' ', Style::default()); indent]);
+                used = indent;
+            }
+            lines.last_mut().unwrap().push((c, style));
+            used += w;
+        }
+    }
+    lines
+        .into_iter()
+        .map(|chars| {
+            let mut spans: Vec<Span<'static>> = Vec::new();
+            for (c, style) in chars {
+                match spans.last_mut() {
+                    Some(span) if span.style == style => span.content.to_mut().push(c),
+                    _ => spans.push(Span::styled(c.to_string(), style)),
+                }
+            }
+            Line::from(spans)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| line.spans.iter().map(|s| s.content.as_ref()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn long_lines_wrap_under_the_text() {
+        let line = Line::from(vec![
+            Span::raw("--> "),
+            Span::styled("hello there world", Style::default().fg(Color::Red)),
+        ]);
+        let lines = wrap(line, 12, 4);
+        assert_eq!(texts(&lines), ["--> hello ", "    there ", "    world"]);
+        assert_eq!(lines[1].spans[1].style.fg, Some(Color::Red));
+
+        // words longer than a line are broken anywhere
+        let lines = wrap(Line::from("--> abcdefghijkl"), 10, 4);
+        assert_eq!(texts(&lines), ["--> abcdef", "    ghijkl"]);
+        // the indent would leave too little
+        assert_eq!(
+            texts(&wrap(Line::from("*** a b c d"), 6, 4)),
+            ["*** a ", "b c d"]
+        );
+        assert_eq!(texts(&wrap(Line::from("short"), 0, 0)), ["short"]);
+    }
+}
@@ -0,0 +1,650 @@
+//! HTTP endpoint for other systems, like CI or monitoring, to post messages into a conversation.
+//!
+//! `POST /hook/<conversation>` with the token as `Authorization: Bearer <token>` or `?token=`,
+//! the conversation percent-encoded like `%23ops` for `#ops`. It listens on the loopback interface
+//! unless `--webhook-listen` names another, the relay's on the one of the relay.
+//! A JSON body is shown through the template, where `{field}` or `{field.nested}` stand for its
+//! values. Without a template its `text` or `message` is shown, any other body as it is.
+//!
+//! The other way round, the relay posts the messages passing through it to the hooks configured
+//! as an array of tables, every filter is optional:
+//!
+//! ```toml
+//! [[webhooks]]
+//! url = "http://ntfy.lan/alerts"
+//! pattern = "(?i)urgent"  # only messages matching it
+//! from = "alice"          # only messages sent by her
+//! to = "bob"              # only messages for him
+//! token = "secret"        # sent as `Authorization: Bearer`
+//! ```
+//!
+//! The body is a JSON object with `from`, `to`, `text` and `at` in milliseconds.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use regex::Regex;
+use tracing::{debug, info, warn};
+
+use crate::{config::Config, json, secret, tasks};
+
+/// Config array holding the outgoing hooks.
+pub const TABLE: &str = "webhooks";
+/// Largest body accepted, in bytes.
+const MAX_BODY: usize = 64 * 1024;
+/// Bytes of the request line and the headers together.
+const MAX_HEAD: u64 = 16 * 1024;
+/// Requests served at once, others are turned away until one is done.
+const MAX_REQUESTS: usize = 16;
+/// Tries at posting to a hook before the message is dropped.
+const ATTEMPTS: u32 = 5;
+/// Pause after the first failed try, doubled after every other.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a hook has to answer, and a client to send its request.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Options {
+    /// accept messages posted to http://<host>:<port>/hook
+    #[arg(long, value_name = "PORT")]
+    pub webhook: Option<u16>,
+    /// address the webhook listens on, the loopback interface without one
+    #[arg(long, value_name = "ADDRESS", requires = "webhook")]
+    pub webhook_listen: Option<String>,
+    /// token the posts have to carry, asked for, or read from --webhook-token-file or
+    /// $CHATTERBOX_WEBHOOK_TOKEN
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        num_args = 0..=1,
+        default_missing_value = "",
+        requires = "webhook"
+    )]
+    pub webhook_token: Option<String>,
+    /// file holding the token of --webhook-token
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "webhook",
+        conflicts_with = "webhook_token"
+    )]
+    pub webhook_token_file: Option<std::path::PathBuf>,
+    /// how a JSON body is shown, e.g. "{repository.name}: {status}"
+    #[arg(long, value_name = "TEMPLATE")]
+    pub webhook_template: Option<String>,
+}
+
+impl Options {
+    /// Fills in the token given in a file or the environment, asking for one given without value.
+    pub fn resolve_token(&mut self) -> io::Result<()> {
+        if self.webhook.is_none() {
+            return Ok(());
+        }
+        self.webhook_token = secret::resolve(
+            "webhook token",
+            self.webhook_token.take(),
+            self.webhook_token_file.as_deref(),
+            "CHATTERBOX_WEBHOOK_TOKEN",
+        )?;
+        match self.webhook_token {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--webhook needs a token, from --webhook-token-file or $CHATTERBOX_WEBHOOK_TOKEN",
+            )),
+        }
+    }
+}
+
+/// Conversation named in the path, if any, and the text to show there.
+pub type Deliver = dyn Fn(Option<&str>, String) -> Result<(), String> + Send + Sync;
+
+/// Serves the endpoint in the background, if one was asked for. Returns where it listens.
+pub fn spawn(options: &Options, deliver: Arc<Deliver>) -> io::Result<Option<SocketAddr>> {
+    let (Some(port), Some(token)) = (options.webhook, options.webhook_token.clone()) else {
+        return Ok(None);
+    };
+    let listen = options.webhook_listen.as_deref().unwrap_or("127.0.0.1");
+    let listener = TcpListener::bind((listen, port))?;
+    let address = listener.local_addr()?;
+    info!("webhook listening on {address}");
+    let template = options.webhook_template.clone();
+    let serving = Arc::new(AtomicUsize::new(0));
+    tasks::spawn("webhook", move || {
+        tasks::set_state(format!("listening on {address}"));
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept webhook connection: {e}");
+                    continue;
+                }
+            };
+            let Some(slot) = Slot::take(&serving) else {
+                debug!("turned away a webhook request, {MAX_REQUESTS} are being served");
+                let _ = stream.set_write_timeout(Some(TIMEOUT));
+                let _ = respond(&mut stream, 503, "too many requests at once, try again");
+                continue;
+            };
+            let (token, template, deliver) = (token.clone(), template.clone(), deliver.clone());
+            tasks::spawn("webhook request", move || {
+                let _slot = slot;
+                if let Err(e) = serve(stream, &token, template.as_deref(), &*deliver) {
+                    warn!("Webhook request failed: {e}");
+                }
+            });
+        }
+    });
+    Ok(Some(address))
+}
+
+/// One of the requests served at once, given back when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(serving: &Arc<AtomicUsize>) -> Option<Self> {
+        let slot = Self(Arc::clone(serving));
+        (serving.fetch_add(1, Ordering::AcqRel) < MAX_REQUESTS).then_some(slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn serve(
+    mut stream: TcpStream,
+    token: &str,
+    template: Option<&str>,
+    deliver: &Deliver,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HEAD));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace().map(str::to_string);
+    let (method, target) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    let mut length = 0;
+    let mut authorization = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if reader.get_ref().limit() == 0 {
+            return respond(&mut stream, 431, "headers too large");
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.trim().parse().unwrap_or(0),
+            "authorization" => authorization = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query_token = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .and_then(percent_decode);
+    let matches =
+        |given: Option<&str>| given.is_some_and(|t| secret::eq(t.as_bytes(), token.as_bytes()));
+    let authorized = matches(
+        authorization
+            .as_deref()
+            .and_then(|a| a.strip_prefix("Bearer ")),
+    ) || matches(query_token.as_deref());
+    let conversation = match path.strip_prefix("/hook") {
+        Some("" | "/") => Ok(None),
+        Some(rest) => rest
+            .strip_prefix('/')
+            .and_then(percent_decode)
+            .map(Some)
+            .ok_or(()),
+        None => Err(()),
+    };
+    match (method.as_str(), conversation) {
+        (_, Err(())) => respond(&mut stream, 404, "no such endpoint"),
+        ("POST", _) if !authorized => respond(&mut stream, 401, "invalid token"),
+        ("POST", _) if length > MAX_BODY => respond(&mut stream, 413, "body too large"),
+        ("POST", Ok(conversation)) => {
+            let mut body = vec![0; length];
+            reader.get_mut().set_limit(MAX_BODY as u64);
+            reader.read_exact(&mut body)?;
+            let body = String::from_utf8_lossy(&body);
+            let text = render(template, &body);
+            debug!("webhook for {conversation:?}: {text:?}");
+            match deliver(conversation.as_deref(), text) {
+                Ok(()) => respond(&mut stream, 204, ""),
+                Err(reason) => respond(&mut stream, 404, &reason),
+            }
+        }
+        _ => respond(&mut stream, 405, "only POST is accepted"),
+    }
+}
+
+/// `text` with its `%XX` escapes replaced, `None` if one is broken or it isn't UTF-8.
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&b, after)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(b);
+            rest = after;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &str) -> io::Result<()> {
+    let reason = match status {
+        204 => "No Content",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes())
+}
+
+/// Text of the message for a posted body.
+fn render(template: Option<&str>, body: &str) -> String {
+    let Ok(value) = json::parse(body) else {
+        return body.trim().to_string();
+    };
+    let Some(template) = template else {
+        return ["text", "message"]
+            .iter()
+            .find_map(|key| value.get(key).and_then(json::Value::as_str))
+            .map_or_else(|| value.to_string(), str::to_string);
+    };
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let path = &rest[start + 1..start + len];
+        let field = path
+            .split('.')
+            .try_fold(&value, |value, key| value.get(key));
+        match field {
+            Some(json::Value::String(s)) => out.push_str(s),
+            Some(json::Value::Null) | None => {}
+            Some(other) => out.push_str(&other.to_string()),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Where and when to post messages.
+#[derive(Debug, Clone)]
+struct Hook {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+    pattern: Option<Regex>,
+    from: Option<String>,
+    to: Option<String>,
+    token: Option<String>,
+}
+
+impl Hook {
+    fn parse(entries: &[(String, String)]) -> Result<Self, String> {
+        let get = |key: &str| {
+            entries
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        let url = get("url").ok_or("missing url")?;
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("{url} isn't a http url"))?;
+        let (server, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match server.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse().map_err(|_| format!("invalid port {port}"))?,
+            ),
+            None => (server, 80),
+        };
+        let pattern = get("pattern")
+            .map(|p| Regex::new(&p).map_err(|e| e.to_string()))
+            .transpose()?;
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            url,
+            pattern,
+            from: get("from"),
+            to: get("to"),
+            token: get("token"),
+        })
+    }
+
+    fn matches(&self, from: &str, to: &str, text: &str) -> bool {
+        self.from.as_ref().is_none_or(|f| f == from)
+            && self.to.as_ref().is_none_or(|t| t == to)
+            && self.pattern.as_ref().is_none_or(|p| p.is_match(text))
+    }
+
+    /// Posts `body` once, returns the status of the response.
+    fn post(&self, body: &str) -> io::Result<u16> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let authorization = self
+            .token
+            .as_ref()
+            .map_or(String::new(), |t| format!("Authorization: Bearer {t}\r\n"));
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n{authorization}Connection: close\r\n\r\n{body}",
+            self.path,
+            self.host,
+            body.len()
+        );
+        stream.write_all(request.as_bytes())?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        status
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a http response"))
+    }
+
+    /// Posts `body`, trying again while the hook is unreachable or failing.
+    fn deliver(&self, body: &str) {
+        let mut pause = RETRY_INTERVAL;
+        for attempt in 1..=ATTEMPTS {
+            let failure = match self.post(body) {
+                Ok(status) if (200..300).contains(&status) => {
+                    debug!("posted to {}", self.url);
+                    return;
+                }
+                // the hook won't change its mind about this one
+                Ok(status) if (400..500).contains(&status) && status != 429 => {
+                    warn!("{} refused the message with {status}", self.url);
+                    return;
+                }
+                Ok(status) => format!("status {status}"),
+                Err(e) => e.to_string(),
+            };
+            warn!(
+                "Failed to post to {} ({attempt}/{ATTEMPTS}): {failure}",
+                self.url
+            );
+            if attempt < ATTEMPTS {
+                tasks::set_state(format!("retrying {}, {failure}", self.url));
+                std::thread::sleep(pause);
+                pause *= 2;
+            }
+        }
+    }
+}
+
+/// Outgoing hooks, see the module docs.
+#[derive(Debug, Default)]
+pub struct Hooks(Vec<Hook>);
+
+impl Hooks {
+    pub fn from_config(config: &Config) -> Self {
+        let hooks = config
+            .tables(TABLE)
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entries)| {
+                Hook::parse(entries)
+                    .map_err(|e| warn!("Ignoring webhook #{}: {e}", i + 1))
+                    .ok()
+            })
+            .collect();
+        Self(hooks)
+    }
+
+    /// Posts the message to every hook it matches, in the background.
+    pub fn fire(&self, from: &str, to: &str, text: &str) {
+        let matching: Vec<Hook> = self
+            .0
+            .iter()
+            .filter(|h| h.matches(from, to, text))
+            .cloned()
+            .collect();
+        if matching.is_empty() {
+            return;
+        }
+        let field = |key: &str, value: json::Value| (key.to_string(), value);
+        let body = json::Value::Object(vec![
+            field("from", json::Value::String(from.to_string())),
+            field("to", json::Value::String(to.to_string())),
+            field("text", json::Value::String(text.to_string())),
+            field(
+                "at",
+                json::Value::Number(crate::timestamp::now_millis() as f64),
+            ),
+        ])
+        .to_string();
+        for hook in matching {
+            let body = body.clone();
+            tasks::spawn("webhook post", move || hook.deliver(&body));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Response of `serve` to `request`, and what it delivered.
+    fn exchange(request: &[u8]) -> (String, Vec<(Option<String>, String)>) {
+        exchange_with("token", request)
+    }
+
+    fn exchange_with(token: &str, request: &[u8]) -> (String, Vec<(Option<String>, String)>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let into = Arc::clone(&delivered);
+        let deliver = move |to: Option<&str>, text: String| {
+            into.lock().unwrap().push((to.map(str::to_string), text));
+            Ok(())
+        };
+        // the request may be more than the socket buffers hold, it is written meanwhile
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _ = client.write_all(request);
+            });
+            serve(stream, token, None, &deliver).unwrap();
+        });
+        let mut response = String::new();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let _ = client.read_to_string(&mut response);
+        let delivered = delivered.lock().unwrap().clone();
+        (response, delivered)
+    }
+
+    #[test]
+    fn posts_carry_the_token() {
+        let post = b"POST /hook/bob HTTP/1.1\r\nAuthorization: Bearer token\r\n\
+                     Content-Length: 16\r\n\r\n{\"text\": \"hi\"}  ";
+        let (response, delivered) = exchange(post);
+        assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+        assert_eq!(delivered, [(Some("bob".to_string()), "hi".to_string())]);
+
+        let (response, delivered) = exchange(b"POST /hook?token=tokem HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+        assert!(delivered.is_empty());
+        let (response, _) = exchange(b"POST /hook?token=token HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+
+        let post = b"POST /hook?token=a%26b%3Dc HTTP/1.1\r\n\r\n";
+        let (response, _) = exchange_with("a&b=c", post);
+        assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+    }
+
+    #[test]
+    fn the_endpoint_listens_on_loopback_and_turns_away_the_surplus() {
+        let options = Options {
+            webhook: Some(0),
+            webhook_listen: None,
+            webhook_token: Some("token".to_string()),
+            webhook_token_file: None,
+            webhook_template: None,
+        };
+        let address = spawn(&options, Arc::new(|_, _| Ok(()))).unwrap().unwrap();
+        assert!(address.ip().is_loopback());
+        // these say nothing, keeping every slot taken until they time out
+        let _silent: Vec<_> = (0..MAX_REQUESTS)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let mut surplus = TcpStream::connect(address).unwrap();
+        let mut response = String::new();
+        surplus.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    }
+
+    #[test]
+    fn endless_headers_are_cut_short() {
+        // all of it is read, closing with some left would reset the connection
+        let mut request = b"POST /hook?token=token HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.resize(MAX_HEAD as usize, b'a');
+        let (response, delivered) = exchange(&request);
+        assert!(response.starts_with("HTTP/1.1 431"), "{response}");
+        assert!(delivered.is_empty());
+
+        let big = format!(
+            "POST /hook?token=token HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        let (response, _) = exchange(big.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+    }
+
+    #[test]
+    fn conversations_are_percent_decoded() {
+        let (_, delivered) = exchange(b"POST /hook/%23ops?token=token HTTP/1.1\r\n\r\n");
+        assert_eq!(delivered, [(Some("#ops".to_string()), String::new())]);
+        let (response, delivered) = exchange(b"POST /hook/%2?token=token HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        assert!(delivered.is_empty());
+
+        assert_eq!(
+            percent_decode("caf%C3%A9%20bar").as_deref(),
+            Some("café bar")
+        );
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[test]
+    fn templates_pick_fields_of_the_body() {
+        let body = r#"{"repository": {"name": "chatterbox"}, "status": "failed", "runs": 3}"#;
+        let template = Some("{repository.name}: {status} after {runs}{missing}");
+        assert_eq!(render(template, body), "chatterbox: failed after 3");
+        assert_eq!(render(None, r#"{"message": "up"}"#), "up");
+        assert_eq!(render(None, " plain text\n"), "plain text");
+    }
+
+    #[test]
+    fn hooks_are_http_urls() {
+        let entries = |url: &str| vec![("url".to_string(), url.to_string())];
+        let hook = Hook::parse(&entries("http://ntfy.lan:8080/alerts")).unwrap();
+        assert_eq!((hook.host.as_str(), hook.port), ("ntfy.lan", 8080));
+        assert_eq!(hook.path, "/alerts");
+        let hook = Hook::parse(&entries("http://ntfy.lan")).unwrap();
+        assert_eq!((hook.port, hook.path.as_str()), (80, "/"));
+        assert!(Hook::parse(&entries("https://ntfy.lan")).is_err());
+        assert!(Hook::parse(&entries("http://ntfy.lan:port")).is_err());
+        assert!(Hook::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn hooks_match_sender_recipient_and_pattern() {
+        let config: Config = r#"
+            [[webhooks]]
+            url = "http://ntfy.lan/alerts"
+            to = "ops"
+            pattern = "(?i)down"
+
+            [[webhooks]]
+            url = "ftp://ntfy.lan"
+        "#
+        .parse()
+        .unwrap();
+        let hooks = Hooks::from_config(&config);
+        assert_eq!(hooks.0.len(), 1);
+        let hook = &hooks.0[0];
+        assert!(hook.matches("ada", "ops", "db is DOWN"));
+        assert!(!hook.matches("ada", "bob", "db is down"));
+        assert!(!hook.matches("ada", "ops", "db is up"));
+    }
+
+    #[test]
+    fn posts_are_json_with_the_token() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut request).unwrap() > 2 {}
+            let mut body = [0; 2];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            (request, body)
+        });
+        let hook = Hook::parse(&[
+            ("url".to_string(), format!("http://127.0.0.1:{port}/in")),
+            ("token".to_string(), "s3cret".to_string()),
+        ])
+        .unwrap();
+        assert_eq!(hook.post("{}").unwrap(), 204);
+        let (request, body) = server.join().unwrap();
+        assert!(request.starts_with("POST /in HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        assert!(request.contains("Content-Length: 2\r\n"));
+        assert!(request.contains("Authorization: Bearer s3cret\r\n"));
+        assert_eq!(&body, b"{}");
+    }
+}
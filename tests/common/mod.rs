@@ -0,0 +1,184 @@
+//! Runs chatterbox the way a script would, with `--follow --json`, every instance in a
+//! directory of its own so that histories, outboxes and downloads don't mix.
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chatterbox::json::{self, Value};
+
+/// How long anything may take before the test gives up, reconnects included.
+pub const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Port nobody listens on right now.
+pub fn free_port() -> u16 {
+    TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|l| l.local_addr())
+        .expect("no free port")
+        .port()
+}
+
+/// Waits until something accepts connections on `port`, which has to cope with a connection
+/// closed right away.
+pub fn wait_for_port(port: u16) {
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(started.elapsed() < TIMEOUT, "nothing listens on {port}");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Directory removed again once the test is done with it.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "chatterbox-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).expect("can't create the test directory");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Running instance, killed when dropped.
+pub struct Peer {
+    child: Child,
+    stdin: ChildStdin,
+    lines: mpsc::Receiver<Value>,
+    /// Holds its data, config and downloads, until handed on by [`Peer::kill`]
+    home: Option<TempDir>,
+}
+
+impl Peer {
+    /// Starts chatterbox with `args`, printing what happens as JSON.
+    pub fn spawn(args: &[&str]) -> Self {
+        Self::spawn_in(TempDir::new(), args)
+    }
+
+    /// Like [`Peer::spawn`], keeping what an earlier instance left in `home`.
+    pub fn spawn_in(home: TempDir, args: &[&str]) -> Self {
+        let dir = home.path();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_chatterbox"))
+            .args(args)
+            .arg("--json")
+            .env("HOME", dir)
+            .env("XDG_DATA_HOME", dir.join("data"))
+            .env("XDG_CONFIG_HOME", dir.join("config"))
+            .env("XDG_RUNTIME_DIR", dir)
+            .env("XDG_DOWNLOAD_DIR", dir.join("downloads"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("can't run chatterbox");
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                let value = json::parse(&line)
+                    .unwrap_or_else(|e| panic!("printed {line:?}, not JSON: {e}"));
+                if tx.send(value).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            child,
+            stdin,
+            lines,
+            home: Some(home),
+        }
+    }
+
+    /// Types `line` as if it was entered, a message or a command.
+    pub fn send(&mut self, line: &str) {
+        writeln!(self.stdin, "{line}").expect("chatterbox went away");
+    }
+
+    /// Skips printed objects until one of `kind` has a text containing `text`.
+    pub fn expect(&self, kind: &str, text: &str) -> Value {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let value = self
+                .lines
+                .recv_timeout(left)
+                .unwrap_or_else(|_| panic!("no {kind} message with {text:?}"));
+            let field = |name| value.get(name).and_then(Value::as_str).unwrap_or_default();
+            if field("kind") == kind && field("text").contains(text) {
+                return value;
+            }
+        }
+    }
+
+    pub fn expect_incoming(&self, text: &str) -> Value {
+        self.expect("incoming", text)
+    }
+
+    pub fn expect_system(&self, text: &str) -> Value {
+        self.expect("system", text)
+    }
+
+    /// Where received files are saved.
+    pub fn downloads(&self) -> PathBuf {
+        self.home().join("downloads")
+    }
+
+    pub fn home(&self) -> &Path {
+        self.home.as_ref().expect("only taken when killed").path()
+    }
+
+    /// Stops it, handing its directory back to start another one in.
+    pub fn kill(mut self) -> TempDir {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.home.take().expect("only taken when killed")
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Waits until `path` holds `content`, files are written as they arrive.
+pub fn wait_for_file(path: &Path, content: &[u8]) {
+    let started = Instant::now();
+    while fs::read(path).ok().as_deref() != Some(content) {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "{} never arrived",
+            path.display()
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
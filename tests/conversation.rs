@@ -0,0 +1,440 @@
+//! Two instances talking directly, one of them the server.
+//!
+//! The client is started right away, it tries again until the server listens. Anything else
+//! connecting to the server would be taken for the peer, unless it runs with `--multi`.
+
+mod common;
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    os::{fd::OwnedFd, unix::net::UnixStream},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chatterbox::{broadcast, protocol, relay};
+use common::{free_port, Peer};
+
+fn server(port: u16) -> Vec<String> {
+    [
+        "-a",
+        "127.0.0.1",
+        "-p",
+        &port.to_string(),
+        "--server",
+        "--follow",
+        "--name",
+        "sam",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn client(port: u16) -> Vec<String> {
+    [
+        "-a",
+        "127.0.0.1",
+        "-p",
+        &port.to_string(),
+        "--follow",
+        "--name",
+        "cleo",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn spawn(args: &[String]) -> Peer {
+    Peer::spawn(&args.iter().map(String::as_str).collect::<Vec<_>>())
+}
+
+#[test]
+fn messages_go_both_ways() {
+    let port = free_port();
+    let mut server = spawn(&server(port));
+    let mut client = spawn(&client(port));
+    client.expect_system("connected to");
+    server.expect_system("connected to");
+
+    client.send("hello sam");
+    let hello = server.expect_incoming("hello sam");
+    assert_eq!(hello.get("author").and_then(|a| a.as_str()), Some("cleo"));
+
+    server.send("hi cleo");
+    let hi = client.expect_incoming("hi cleo");
+    assert_eq!(hi.get("author").and_then(|a| a.as_str()), Some("sam"));
+}
+
+#[test]
+fn annotations_reach_the_peer_alongside_the_text() {
+    let port = free_port();
+    let mut server = spawn(&server(port));
+    let mut client = spawn(&client(port));
+    client.expect_system("connected to");
+    server.expect_system("connected to");
+
+    client.send(r#"{"text": "build done", "data": {"status": "ok", "runs": 3}}"#);
+    let done = server.expect_incoming("build done");
+    let data = done.get("data").expect("the annotation arrives");
+    assert_eq!(data.get("status").and_then(|s| s.as_str()), Some("ok"));
+
+    server.send("plain");
+    let plain = client.expect_incoming("plain");
+    assert!(plain.get("data").is_none());
+}
+
+#[test]
+fn client_reconnects_to_a_restarted_server() {
+    let port = free_port();
+    let server_args = server(port);
+    let server = spawn(&server_args);
+    let mut client = spawn(&client(port));
+    client.expect_system("connected to");
+    server.expect_system("connected to");
+
+    let home = server.kill();
+    client.expect_system("disconnected");
+    // what is typed meanwhile waits in the outbox
+    client.send("are you back?");
+
+    let args: Vec<_> = server_args.iter().map(String::as_str).collect();
+    let mut server = Peer::spawn_in(home, &args);
+    client.expect_system("connected to");
+    server.expect_incoming("are you back?");
+    server.send("I am");
+    client.expect_incoming("I am");
+}
+
+#[test]
+fn queued_messages_outlive_the_run_they_were_typed_in() {
+    let port = free_port();
+    let client_args = client(port);
+    let mut client = spawn(&client_args);
+    client.send("still there?");
+    let outbox = client
+        .home()
+        .join("data/chatterbox/outboxes")
+        .join(format!("127.0.0.1-{port}"));
+    common::wait_for_file(&outbox, b"still there?\n");
+
+    let home = client.kill();
+    let args: Vec<_> = client_args.iter().map(String::as_str).collect();
+    let client = Peer::spawn_in(home, &args);
+    let server = spawn(&server(port));
+    server.expect_incoming("still there?");
+    client.expect_system("sent 1 queued message(s)");
+    // the file is rewritten before that is said
+    assert!(!outbox.exists());
+}
+
+#[test]
+fn unix_socket_carries_the_conversation() {
+    let dir = common::TempDir::new();
+    let socket = dir.path().join("chat.sock");
+    let socket = socket.to_str().expect("temporary paths are UTF-8");
+    let mut server = Peer::spawn(&["--unix", socket, "--server", "--follow", "--name", "sam"]);
+    let mut client = Peer::spawn(&["--unix", socket, "--follow", "--name", "cleo"]);
+    client.expect_system(&format!("connected to {socket}"));
+    server.expect_system(&format!("connected to {socket}"));
+
+    client.send("over the socket");
+    server.expect_incoming("over the socket");
+    server.send("and back");
+    client.expect_incoming("and back");
+}
+
+#[test]
+fn a_peer_quitting_is_told_apart_from_a_lost_connection() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let client = spawn(&client(listener.local_addr().unwrap().port()));
+    let (mut peer, _) = listener.accept().unwrap();
+    peer.write_all(format!("hello\n{}\n", protocol::goodbye()).as_bytes())
+        .unwrap();
+    drop(peer);
+    client.expect_incoming("hello");
+    client.expect_system("peer left the chat");
+}
+
+#[test]
+fn a_peer_clock_hours_off_is_noticed() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let client = spawn(&client(listener.local_addr().unwrap().port()));
+    let (mut peer, _) = listener.accept().unwrap();
+    peer.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    let lines = BufReader::new(peer.try_clone().unwrap()).lines();
+    let sent = lines
+        .map_while(Result::ok)
+        .find_map(|l| match protocol::decode(&l) {
+            protocol::Payload::Clock(sent) => Some(sent),
+            _ => None,
+        })
+        .unwrap();
+    let ahead = sent + 3 * 3600 * 1000;
+    peer.write_all(format!("{}\n", protocol::clock_echo(sent, ahead)).as_bytes())
+        .unwrap();
+    client.expect_system("the clock of the peer is 3h ahead of ours");
+}
+
+#[test]
+fn messages_are_numbered_and_acknowledged() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let mut client = spawn(&client(listener.local_addr().unwrap().port()));
+    let (mut peer, _) = listener.accept().unwrap();
+    peer.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    let mut lines = BufReader::new(peer.try_clone().unwrap()).lines();
+
+    client.send("numbered");
+    let id = lines
+        .by_ref()
+        .map_while(Result::ok)
+        .find_map(|l| match protocol::decode(&l) {
+            protocol::Payload::Message { envelope, .. } => envelope.id,
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(id, 1);
+
+    let envelope = protocol::Envelope {
+        sender: "sam".to_string(),
+        sent: 1_700_000_000_000,
+        id: Some(7),
+    };
+    peer.write_all(format!("{}\n", protocol::message(&envelope, "got it", None)).as_bytes())
+        .unwrap();
+    client.expect_incoming("got it");
+    // nothing keeps it from being seen right away
+    let receipt = lines
+        .map_while(Result::ok)
+        .map(|l| protocol::decode(&l))
+        .find(|p| {
+            matches!(
+                p,
+                protocol::Payload::Received(_) | protocol::Payload::Seen(_)
+            )
+        });
+    assert_eq!(receipt, Some(protocol::Payload::Seen(7)));
+}
+
+#[test]
+fn sealed_messages_keep_flowing_over_a_new_connection() {
+    let port = free_port();
+    let sealed = |mut args: Vec<String>| {
+        args.extend(["--key-phrase", "correct horse"].map(String::from));
+        args
+    };
+    let server_args = sealed(server(port));
+    let server = spawn(&server_args);
+    let mut client = spawn(&sealed(client(port)));
+    client.expect_system("connected to");
+    server.expect_system("connected to");
+    client.send("sealed hello");
+    server.expect_incoming("sealed hello");
+
+    let home = server.kill();
+    client.expect_system("disconnected");
+    let args: Vec<_> = server_args.iter().map(String::as_str).collect();
+    let mut server = Peer::spawn_in(home, &args);
+    client.expect_system("connected to");
+    client.send("still sealed");
+    server.expect_incoming("still sealed");
+    server.send("and back");
+    client.expect_incoming("and back");
+}
+
+#[test]
+fn plaintext_in_a_sealed_conversation_raises_the_alarm() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let mut args = client(listener.local_addr().unwrap().port());
+    args.extend(["--key-phrase", "correct horse"].map(String::from));
+    let client = spawn(&args);
+    let (mut peer, _) = listener.accept().unwrap();
+    peer.write_all(b"in the clear\n").unwrap();
+    client.expect_system("WARNING: the peer sent plaintext");
+}
+
+#[test]
+fn an_encrypted_conversation_coming_back_in_plaintext_is_a_downgrade() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let plain = client(listener.local_addr().unwrap().port());
+    let mut sealed = plain.clone();
+    sealed.extend(["--key-phrase", "correct horse"].map(String::from));
+    let client = spawn(&sealed);
+    let _first = listener.accept().unwrap();
+    client.expect_system("connected to");
+
+    let args: Vec<_> = plain.iter().map(String::as_str).collect();
+    let client = Peer::spawn_in(client.kill(), &args);
+    let _second = listener.accept().unwrap();
+    client.expect_system("WARNING: 127.0.0.1 was encrypted before");
+}
+
+#[test]
+fn room_lines_only_reach_its_members() {
+    let port = free_port();
+    let mut args = server(port);
+    args.push("--multi".to_string());
+    let host = spawn(&args);
+    common::wait_for_port(port);
+    let connect = || {
+        let stream = TcpStream::connect(("127.0.0.1", port)).expect("the server went away");
+        stream.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+        let lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        (stream, lines.map_while(Result::ok))
+    };
+    let (mut ada, _) = connect();
+    let (mut bob, mut bob_lines) = connect();
+    let (_cleo, mut cleo_lines) = connect();
+
+    // everybody in the room hears of who joins, the one joining included
+    bob.write_all(relay::join("#rust").as_bytes()).unwrap();
+    bob_lines.find(|l| l.contains("joined #rust")).unwrap();
+    ada.write_all(relay::join("#rust").as_bytes()).unwrap();
+    bob_lines.find(|l| l.contains("joined #rust")).unwrap();
+
+    ada.write_all(relay::post("#rust", "borrowck again").as_bytes())
+        .unwrap();
+    let line = bob_lines.find(|l| l.contains("borrowck")).unwrap();
+    assert!(line.starts_with("\u{1}ROOM #rust "), "{line:?}");
+
+    ada.write_all(b"hello everybody\n").unwrap();
+    host.expect_incoming("hello everybody");
+    let seen: Vec<_> = cleo_lines
+        .by_ref()
+        .take_while(|l| !l.contains("hello"))
+        .collect();
+    assert!(seen.iter().all(|l| !l.contains("borrowck")), "{seen:?}");
+}
+
+#[test]
+fn clients_hear_who_comes_and_goes() {
+    let port = free_port();
+    let mut args = server(port);
+    args.push("--multi".to_string());
+    let _host = spawn(&args);
+    common::wait_for_port(port);
+    let ada = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    ada.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    let mut lines = BufReader::new(ada).lines().map_while(Result::ok);
+
+    let bob = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let bob_name = bob.local_addr().unwrap().to_string();
+    let mut presence = lines.by_ref().filter_map(|l| {
+        broadcast::parse_presence(&l).map(|(name, online)| (name.to_string(), online))
+    });
+    presence.find(|p| *p == (bob_name.clone(), true)).unwrap();
+    drop(bob);
+    presence.find(|p| *p == (bob_name.clone(), false)).unwrap();
+}
+
+#[test]
+fn inetd_serves_the_connection_on_stdin_until_it_ends() {
+    let home = common::TempDir::new();
+    let config = home.path().join("config/chatterbox");
+    fs::create_dir_all(&config).unwrap();
+    let trigger = "[[triggers]]\npattern = \"ping\"\naction = \"reply\"\nargument = \"pong\"\n";
+    fs::write(config.join("config.toml"), trigger).unwrap();
+    let (ours, theirs) = UnixStream::pair().unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_chatterbox"))
+        .arg("--inetd")
+        .env("HOME", home.path())
+        .env("XDG_DATA_HOME", home.path().join("data"))
+        .env("XDG_CONFIG_HOME", home.path().join("config"))
+        .stdin(Stdio::from(OwnedFd::from(theirs.try_clone().unwrap())))
+        .stdout(Stdio::from(OwnedFd::from(theirs)))
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("can't run chatterbox");
+
+    (&ours).write_all(b"ping\n").unwrap();
+    ours.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    let mut lines = BufReader::new(&ours).lines().map_while(Result::ok);
+    lines.find(|l| l.contains("pong")).unwrap();
+
+    drop(lines);
+    drop(ours);
+    let started = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        assert!(started.elapsed() < common::TIMEOUT, "still running");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn no_tui_sends_what_is_piped_in_and_quits() {
+    let port = free_port();
+    let server = spawn(&server(port));
+    let home = common::TempDir::new();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_chatterbox"))
+        .args(["-a", "127.0.0.1", "-p", &port.to_string(), "--no-tui"])
+        .args(["--name", "cleo"])
+        .env("HOME", home.path())
+        .env("XDG_DATA_HOME", home.path().join("data"))
+        .env("XDG_CONFIG_HOME", home.path().join("config"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("can't run chatterbox");
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"hello sam\nbye\n").unwrap();
+    drop(stdin);
+
+    server.expect_incoming("hello sam");
+    server.expect_incoming("bye");
+    let started = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        assert!(started.elapsed() < common::TIMEOUT, "still running");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn a_second_exec_request_cant_take_the_place_of_the_first() {
+    let port = free_port();
+    let home = common::TempDir::new();
+    let config = home.path().join("config/chatterbox");
+    fs::create_dir_all(&config).unwrap();
+    let commands = "[exec]\ngreet = \"echo hello\"\nwipe = \"echo wiped\"\n";
+    fs::write(config.join("config.toml"), commands).unwrap();
+    let args = server(port);
+    let mut server = Peer::spawn_in(home, &args.iter().map(String::as_str).collect::<Vec<_>>());
+    let mut client = spawn(&client(port));
+    client.expect_system("connected to");
+    server.expect_system("connected to");
+
+    client.send("/exec greet");
+    server.expect_system("the peer asks to run greet");
+    client.send("/exec wipe");
+    server.expect_system("refused while greet waits");
+    client.expect_incoming("greet waits for an answer");
+    server.send("/accept");
+    client.expect_incoming("hello");
+}
+
+#[test]
+fn both_ends_of_a_tls_session_show_the_same_authentication_string() {
+    let port = free_port();
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let (cert, key) = (
+        format!("{fixtures}/cert.pem"),
+        format!("{fixtures}/key.pem"),
+    );
+    let mut server_args = server(port);
+    server_args.extend(["--tls", "--cert", &cert, "--key", &key].map(String::from));
+    let mut client_args = client(port);
+    client_args.extend(["--tls", "--cert", &cert].map(String::from));
+    let server = spawn(&server_args);
+    let client = spawn(&client_args);
+
+    let shown = |peer: &Peer| {
+        let msg = peer.expect_system("with the peer, then /confirm");
+        msg.get("text")
+            .and_then(|t| t.as_str())
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(shown(&server), shown(&client));
+}
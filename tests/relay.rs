@@ -0,0 +1,129 @@
+//! Clients of a relay, which holds messages for whoever isn't connected.
+
+mod common;
+
+use common::{free_port, Peer};
+
+fn relay(port: u16, extra: &[&str]) -> Peer {
+    let port_arg = port.to_string();
+    let args = ["relay", "--listen", "127.0.0.1", "-p", &port_arg];
+    let peer = Peer::spawn(&[&args[..], extra].concat());
+    common::wait_for_port(port);
+    peer
+}
+
+fn client(port: u16, name: &str, to: Option<&str>) -> Peer {
+    let port = port.to_string();
+    let args = [
+        "-a",
+        "127.0.0.1",
+        "-p",
+        &port,
+        "--relay",
+        "--name",
+        name,
+        "--follow",
+    ];
+    let to = to.map(|to| ["--to", to]);
+    let peer = Peer::spawn(&[&args[..], to.as_ref().map_or(&[][..], |t| &t[..])].concat());
+    peer.expect_system("connected to");
+    peer
+}
+
+#[test]
+fn messages_are_held_until_the_recipient_connects() {
+    let port = free_port();
+    let _relay = relay(port, &[]);
+    let mut ada = client(port, "ada", Some("bob"));
+    ada.send("left for you");
+    // the relay has it once it acknowledged storing it, bob isn't around yet
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    let mut bob = client(port, "bob", Some("ada"));
+    bob.expect_incoming("left for you");
+    bob.send("got it");
+    ada.expect_incoming("got it");
+}
+
+#[test]
+fn clients_without_a_recipient_land_in_the_lobby() {
+    let port = free_port();
+    let _relay = relay(port, &["--lobby", "#lobby"]);
+    let mut ada = client(port, "ada", None);
+    ada.expect_system("the relay put you in #lobby");
+    let bob = client(port, "bob", None);
+    bob.expect_system("the relay put you in #lobby");
+
+    ada.send("welcome, bob");
+    bob.expect_incoming("welcome, bob");
+}
+
+#[test]
+fn a_relay_without_a_lobby_needs_a_recipient() {
+    let port = free_port();
+    let _relay = relay(port, &[]);
+    let port = port.to_string();
+    let args = ["-a", "127.0.0.1", "-p", &port, "--relay", "--name", "ada"];
+    let ada = Peer::spawn(&[&args[..], &["--follow"]].concat());
+    ada.expect_system("the relay has no lobby");
+}
+
+#[test]
+fn a_name_is_only_shared_with_its_key() {
+    let port = free_port();
+    let _relay = relay(port, &[]);
+    let ada = client(port, "ada", Some("bob"));
+    let port = port.to_string();
+    let args = ["-a", "127.0.0.1", "-p", &port, "--relay", "--name", "ada"];
+    let args = [&args[..], &["--to", "bob", "--follow"]].concat();
+    let impostor = Peer::spawn(&args);
+    impostor.expect_system("registered with another key");
+
+    let key = ada.home().join("data/chatterbox/relay-key");
+    let key = key.to_str().unwrap();
+    let device = Peer::spawn(&[&args[..], &["--relay-key-file", key]].concat());
+    device.expect_system("connected to");
+}
+
+#[test]
+fn outsiders_are_refused_from_a_private_room() {
+    let port = free_port();
+    let _relay = relay(port, &[]);
+    let port_arg = port.to_string();
+    let args = ["-a", "127.0.0.1", "-p", &port_arg, "--relay", "--follow"];
+    let ada = Peer::spawn(
+        &[
+            &args[..],
+            &["--name", "ada", "--to", "#secret", "--private"],
+        ]
+        .concat(),
+    );
+    ada.expect_system("connected to");
+
+    let eve = Peer::spawn(&[&args[..], &["--name", "eve", "--to", "#secret"]].concat());
+    eve.expect_system("invitation only");
+    // nor does posing as a member get anybody in
+    let posing = Peer::spawn(&[&args[..], &["--name", "ada", "--to", "#secret"]].concat());
+    posing.expect_system("registered with another key");
+}
+
+#[test]
+fn webhook_posts_reach_the_members_of_a_room() {
+    let (port, hook) = (free_port(), free_port());
+    let hook_arg = hook.to_string();
+    let _relay = relay(port, &["--webhook", &hook_arg, "--webhook-token", "token"]);
+    common::wait_for_port(hook);
+    let ada = client(port, "ada", Some("#builds"));
+
+    let mut post = std::net::TcpStream::connect(("127.0.0.1", hook)).unwrap();
+    let body = r#"{"text": "main is green"}"#;
+    let request = format!(
+        "POST /hook/%23builds?token=token HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    std::io::Write::write_all(&mut post, request.as_bytes()).unwrap();
+    let mut response = String::new();
+    std::io::Read::read_to_string(&mut post, &mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+    ada.expect_incoming("main is green");
+}
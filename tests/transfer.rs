@@ -0,0 +1,73 @@
+//! Files sent with `/send`, accepted or refused by the peer.
+
+mod common;
+
+use std::fs;
+
+use common::{free_port, wait_for_file, Peer};
+
+fn connected_pair() -> (Peer, Peer) {
+    let port = free_port().to_string();
+    let server = Peer::spawn(&["-a", "127.0.0.1", "-p", &port, "--server", "--follow"]);
+    let client = Peer::spawn(&["-a", "127.0.0.1", "-p", &port, "--follow"]);
+    client.expect_system("connected to");
+    server.expect_system("connected to");
+    (server, client)
+}
+
+#[test]
+fn accepted_file_arrives_whole() {
+    let (mut sender, mut receiver) = connected_pair();
+    // several chunks, not a multiple of their size
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let path = sender.home().join("notes.bin");
+    fs::write(&path, &content).expect("can't write the file to send");
+
+    sender.send(&format!("/send {}", path.display()));
+    sender.expect_system("waiting for the peer to accept");
+    receiver.expect_system("the peer sends notes.bin");
+    receiver.send("/accept");
+    receiver.expect_system("receiving notes.bin");
+
+    wait_for_file(&receiver.downloads().join("notes.bin"), &content);
+}
+
+#[test]
+fn refused_file_is_not_saved() {
+    let (mut sender, mut receiver) = connected_pair();
+    let path = sender.home().join("secret.txt");
+    fs::write(&path, "nothing to see").expect("can't write the file to send");
+
+    sender.send(&format!("/send {}", path.display()));
+    receiver.expect_system("the peer sends secret.txt");
+    receiver.send("/refuse");
+    sender.expect_system("secret.txt");
+    assert!(!receiver.downloads().join("secret.txt").exists());
+}
+
+#[test]
+fn offered_paths_are_saved_in_the_downloads_directory() {
+    use std::{io::Write, net::TcpListener};
+
+    use chatterbox::protocol;
+    use sha1::{Digest, Sha1};
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let mut receiver = Peer::spawn(&["-a", "127.0.0.1", "-p", &port, "--follow"]);
+    let (mut sender, _) = listener.accept().unwrap();
+    receiver.expect_system("connected to");
+
+    let content = b"escaped?";
+    let offer = protocol::file_offer(1, content.len() as u64, "../escape.txt");
+    writeln!(sender, "{offer}").unwrap();
+    receiver.expect_system("the peer sends escape.txt");
+    receiver.send("/accept");
+    receiver.expect_system("receiving escape.txt");
+    writeln!(sender, "{}", protocol::file_chunk(1, content)).unwrap();
+    let digest = hex::encode(Sha1::digest(content));
+    writeln!(sender, "{}", protocol::file_end(1, &digest)).unwrap();
+
+    wait_for_file(&receiver.downloads().join("escape.txt"), content);
+    assert!(!receiver.home().join("escape.txt").exists());
+}